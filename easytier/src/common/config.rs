@@ -2,6 +2,7 @@ use std::{
     hash::Hasher,
     net::{IpAddr, SocketAddr},
     path::PathBuf,
+    str::FromStr,
     sync::{Arc, Mutex},
 };
 
@@ -12,7 +13,9 @@ use serde::{Deserialize, Serialize};
 use crate::{
     proto::{
         acl::Acl,
-        common::{CompressionAlgoPb, PortForwardConfigPb, SocketType},
+        common::{
+            CompressionAlgoPb, DnsRecordConfigPb, PortForwardConfigPb, ServiceConfigPb, SocketType,
+        },
     },
     tunnel::generate_digest_from_str,
 };
@@ -50,6 +53,16 @@ pub fn gen_default_flags() -> Flags {
         multi_thread_count: 2,
         encryption_algorithm: "aes-gcm".to_string(),
         disable_sym_hole_punching: false,
+        link_probe_interval_ms: 0,
+        link_probe_multiplier: 3,
+        area_id: 0,
+        enable_tcp_hole_punching: false,
+        enable_fec: false,
+        enable_port_mapping: false,
+        enable_tun_offload: false,
+        tun_queues: 1,
+        udp_proxy_full_cone: false,
+        udp_proxy_port_restricted: false,
     }
 }
 
@@ -176,6 +189,9 @@ pub trait ConfigLoader: Send + Sync {
     fn get_vpn_portal_config(&self) -> Option<VpnPortalConfig>;
     fn set_vpn_portal_config(&self, config: VpnPortalConfig);
 
+    fn get_kernel_route_config(&self) -> Option<KernelRouteConfig>;
+    fn set_kernel_route_config(&self, config: Option<KernelRouteConfig>);
+
     fn get_flags(&self) -> Flags;
     fn set_flags(&self, flags: Flags);
 
@@ -188,9 +204,27 @@ pub trait ConfigLoader: Send + Sync {
     fn get_socks5_portal(&self) -> Option<url::Url>;
     fn set_socks5_portal(&self, addr: Option<url::Url>);
 
+    fn get_upstream_proxy(&self) -> Option<url::Url>;
+    fn set_upstream_proxy(&self, proxy: Option<url::Url>);
+
     fn get_port_forwards(&self) -> Vec<PortForwardConfig>;
     fn set_port_forwards(&self, forwards: Vec<PortForwardConfig>);
 
+    fn get_dns_records(&self) -> Vec<DnsRecordConfig>;
+    fn set_dns_records(&self, records: Vec<DnsRecordConfig>);
+
+    fn get_dns_forwards(&self) -> Vec<DnsForwardConfig>;
+    fn set_dns_forwards(&self, forwards: Vec<DnsForwardConfig>);
+
+    fn get_dns_upstreams(&self) -> Vec<url::Url>;
+    fn set_dns_upstreams(&self, upstreams: Vec<url::Url>);
+
+    fn get_services(&self) -> Vec<ServiceConfig>;
+    fn set_services(&self, services: Vec<ServiceConfig>);
+
+    fn get_udp_proxy_timeouts(&self) -> Vec<UdpProxyTimeoutConfig>;
+    fn set_udp_proxy_timeouts(&self, timeouts: Vec<UdpProxyTimeoutConfig>);
+
     fn get_acl(&self) -> Option<Acl>;
     fn set_acl(&self, acl: Option<Acl>);
 
@@ -342,6 +376,20 @@ pub struct VpnPortalConfig {
     pub wireguard_listen: SocketAddr,
 }
 
+/// Exchange routes with the linux kernel routing table, so routing daemons like
+/// BIRD or FRR can feed prefixes into the mesh and learn prefixes from it.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct KernelRouteConfig {
+    /// route protocols (names in /etc/iproute2/rt_protos or numbers) whose routes
+    /// are advertised as proxy cidrs. import is disabled if empty.
+    #[serde(default)]
+    pub import_protocols: Vec<String>,
+    /// table to import routes from, main table if not set.
+    pub import_table: Option<u32>,
+    /// table to install routes learned from the mesh into.
+    pub export_table: Option<u32>,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
 pub struct PortForwardConfig {
     pub bind_addr: SocketAddr,
@@ -377,6 +425,275 @@ impl From<PortForwardConfig> for PortForwardConfigPb {
     }
 }
 
+/// An extra magic dns record published by this node, e.g. an A record for a
+/// host in a proxied subnet.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct DnsRecordConfig {
+    /// relative to the magic dns zone, or absolute if ends with a dot.
+    pub name: String,
+    /// A, AAAA, CNAME, SRV or TXT
+    #[serde(rename = "type")]
+    pub rr_type: String,
+    /// record data in zone file format, e.g. `10 5 8080 web.et.net.` for SRV.
+    pub value: String,
+    /// ttl in seconds
+    pub ttl: Option<u32>,
+}
+
+impl FromStr for DnsRecordConfig {
+    type Err = anyhow::Error;
+
+    /// parse `<name> <type> <value>`, the same layout as a zone file line.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(3, char::is_whitespace);
+        let (Some(name), Some(rr_type), Some(value)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(anyhow::anyhow!(
+                "invalid dns record: {}, expect format like \"printer.office A 192.168.1.10\"",
+                s
+            ));
+        };
+        Ok(DnsRecordConfig {
+            name: name.to_string(),
+            rr_type: rr_type.to_uppercase(),
+            value: value.trim().to_string(),
+            ttl: None,
+        })
+    }
+}
+
+impl From<DnsRecordConfigPb> for DnsRecordConfig {
+    fn from(config: DnsRecordConfigPb) -> Self {
+        DnsRecordConfig {
+            name: config.name,
+            rr_type: config.r#type,
+            value: config.value,
+            ttl: if config.ttl == 0 {
+                None
+            } else {
+                Some(config.ttl)
+            },
+        }
+    }
+}
+
+impl From<DnsRecordConfig> for DnsRecordConfigPb {
+    fn from(val: DnsRecordConfig) -> Self {
+        DnsRecordConfigPb {
+            name: val.name,
+            r#type: val.rr_type,
+            value: val.value,
+            ttl: val.ttl.unwrap_or_default(),
+        }
+    }
+}
+
+/// Split dns rule of magic dns, queries under `domain` are forwarded to the
+/// nameservers, which are usually in a proxied subnet. By default the queries
+/// are routed like any other packet to that address; with `via` they are sent
+/// to the peer with this virtual ip, which must proxy the nameserver's subnet.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct DnsForwardConfig {
+    pub domain: String,
+    pub nameservers: Vec<SocketAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub via: Option<IpAddr>,
+}
+
+impl FromStr for DnsForwardConfig {
+    type Err = anyhow::Error;
+
+    /// parse `<domain>=<nameserver>[,<nameserver>...][ via <peer virtual ip>]`,
+    /// port 53 if omitted.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, via) = match s.split_once(" via ") {
+            Some((rule, via)) => {
+                let via = via
+                    .trim()
+                    .parse::<IpAddr>()
+                    .with_context(|| format!("invalid via peer: {}", via))?;
+                (rule, Some(via))
+            }
+            None => (s, None),
+        };
+        let Some((domain, nameservers)) = s.split_once('=') else {
+            return Err(anyhow::anyhow!(
+                "invalid dns forward rule: {}, expect format like \"corp.example=192.168.1.53\"",
+                s
+            ));
+        };
+        let nameservers = nameservers
+            .split(',')
+            .map(|ns| {
+                let ns = ns.trim();
+                ns.parse::<SocketAddr>()
+                    .or_else(|_| ns.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                    .with_context(|| format!("invalid nameserver: {}", ns))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(DnsForwardConfig {
+            domain: domain.trim().to_string(),
+            nameservers,
+            via,
+        })
+    }
+}
+
+/// A service running on this node, published in magic dns as dns-sd records
+/// `_<name>._<protocol>.et.net`, so peers can find it without knowing the port.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct ServiceConfig {
+    /// dns-sd service name without the leading underscore, e.g. ssh, http.
+    pub name: String,
+    /// tcp or udp
+    pub protocol: String,
+    pub port: u16,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl ServiceConfig {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        // rfc 6335, at most 15 chars of letters, digits and hyphens
+        if self.name.is_empty()
+            || self.name.len() > 15
+            || self.name.starts_with('-')
+            || self.name.ends_with('-')
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(anyhow::anyhow!("invalid service name: {}", self.name));
+        }
+        if self.protocol != "tcp" && self.protocol != "udp" {
+            return Err(anyhow::anyhow!(
+                "invalid service protocol: {}, expect tcp or udp",
+                self.protocol
+            ));
+        }
+        if self.port == 0 {
+            return Err(anyhow::anyhow!("invalid service port: 0"));
+        }
+        if let Some(tag) = self.tags.iter().find(|t| t.len() > 255) {
+            return Err(anyhow::anyhow!("service tag too long: {}", tag));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ServiceConfig {
+    type Err = anyhow::Error;
+
+    /// parse `<name>:<port>[/<protocol>][,<tag>...]`, tcp if protocol omitted.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().split(',');
+        let service = parts.next().unwrap_or_default();
+        let Some((name, port)) = service.split_once(':') else {
+            return Err(anyhow::anyhow!(
+                "invalid service: {}, expect format like \"ssh:22\" or \"http:8080/tcp,path=/\"",
+                s
+            ));
+        };
+        let (port, protocol) = port.split_once('/').unwrap_or((port, "tcp"));
+        let ret = ServiceConfig {
+            name: name.trim().to_lowercase(),
+            protocol: protocol.trim().to_lowercase(),
+            port: port
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid service port: {}", port))?,
+            tags: parts.map(|t| t.trim().to_string()).collect(),
+        };
+        ret.validate()?;
+        Ok(ret)
+    }
+}
+
+impl TryFrom<ServiceConfigPb> for ServiceConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(config: ServiceConfigPb) -> Result<Self, Self::Error> {
+        Ok(ServiceConfig {
+            port: u16::try_from(config.port)
+                .with_context(|| format!("invalid service port: {}", config.port))?,
+            name: config.name,
+            protocol: config.protocol,
+            tags: config.tags,
+        })
+    }
+}
+
+impl From<ServiceConfig> for ServiceConfigPb {
+    fn from(val: ServiceConfig) -> Self {
+        ServiceConfigPb {
+            name: val.name,
+            protocol: val.protocol,
+            port: val.port as u32,
+            tags: val.tags,
+        }
+    }
+}
+
+/// Idle timeout of the udp proxy nat entries created by packets to a
+/// destination port in `start_port..=end_port`.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct UdpProxyTimeoutConfig {
+    pub start_port: u16,
+    pub end_port: u16,
+    /// in seconds
+    pub timeout: u64,
+}
+
+impl UdpProxyTimeoutConfig {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start_port..=self.end_port).contains(&port)
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.start_port > self.end_port || self.timeout == 0 {
+            return Err(anyhow::anyhow!(
+                "invalid udp proxy timeout: {}-{}:{}",
+                self.start_port,
+                self.end_port,
+                self.timeout
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for UdpProxyTimeoutConfig {
+    type Err = anyhow::Error;
+
+    /// parse `<port>[-<port>]:<seconds>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((ports, timeout)) = s.trim().split_once(':') else {
+            return Err(anyhow::anyhow!(
+                "invalid udp proxy timeout: {}, expect format like \"53:30\" or \"27000-27100:600\"",
+                s
+            ));
+        };
+        let (start_port, end_port) = ports.split_once('-').unwrap_or((ports, ports));
+        let ret = UdpProxyTimeoutConfig {
+            start_port: start_port
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid port: {}", start_port))?,
+            end_port: end_port
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid port: {}", end_port))?,
+            timeout: timeout
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid timeout: {}", timeout))?,
+        };
+        ret.validate()?;
+        Ok(ret)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 struct Config {
     netns: Option<String>,
@@ -399,12 +716,26 @@ struct Config {
 
     vpn_portal_config: Option<VpnPortalConfig>,
 
+    kernel_route: Option<KernelRouteConfig>,
+
     routes: Option<Vec<cidr::Ipv4Cidr>>,
 
     socks5_proxy: Option<url::Url>,
 
+    // http or socks5 proxy used by tcp / ws / wss connectors
+    upstream_proxy: Option<url::Url>,
+
     port_forward: Option<Vec<PortForwardConfig>>,
 
+    dns_record: Option<Vec<DnsRecordConfig>>,
+    dns_forward: Option<Vec<DnsForwardConfig>>,
+    // udp://, tcp://, tls:// (DoT) or https:// (DoH) resolvers of magic dns
+    dns_upstream: Option<Vec<url::Url>>,
+
+    service: Option<Vec<ServiceConfig>>,
+
+    udp_proxy_timeout: Option<Vec<UdpProxyTimeoutConfig>>,
+
     flags: Option<serde_json::Map<String, serde_json::Value>>,
 
     #[serde(skip)]
@@ -706,6 +1037,14 @@ impl ConfigLoader for TomlConfigLoader {
         self.config.lock().unwrap().vpn_portal_config = Some(config);
     }
 
+    fn get_kernel_route_config(&self) -> Option<KernelRouteConfig> {
+        self.config.lock().unwrap().kernel_route.clone()
+    }
+
+    fn set_kernel_route_config(&self, config: Option<KernelRouteConfig>) {
+        self.config.lock().unwrap().kernel_route = config;
+    }
+
     fn get_flags(&self) -> Flags {
         self.config
             .lock()
@@ -748,6 +1087,14 @@ impl ConfigLoader for TomlConfigLoader {
         self.config.lock().unwrap().socks5_proxy = addr;
     }
 
+    fn get_upstream_proxy(&self) -> Option<url::Url> {
+        self.config.lock().unwrap().upstream_proxy.clone()
+    }
+
+    fn set_upstream_proxy(&self, proxy: Option<url::Url>) {
+        self.config.lock().unwrap().upstream_proxy = proxy;
+    }
+
     fn get_port_forwards(&self) -> Vec<PortForwardConfig> {
         self.config
             .lock()
@@ -761,6 +1108,91 @@ impl ConfigLoader for TomlConfigLoader {
         self.config.lock().unwrap().port_forward = Some(forwards);
     }
 
+    fn get_dns_records(&self) -> Vec<DnsRecordConfig> {
+        self.config
+            .lock()
+            .unwrap()
+            .dns_record
+            .clone()
+            .unwrap_or_default()
+    }
+
+    fn set_dns_records(&self, records: Vec<DnsRecordConfig>) {
+        self.config.lock().unwrap().dns_record = Some(records);
+    }
+
+    fn get_dns_forwards(&self) -> Vec<DnsForwardConfig> {
+        self.config
+            .lock()
+            .unwrap()
+            .dns_forward
+            .clone()
+            .unwrap_or_default()
+    }
+
+    fn set_dns_forwards(&self, forwards: Vec<DnsForwardConfig>) {
+        self.config.lock().unwrap().dns_forward = Some(forwards);
+    }
+
+    fn get_dns_upstreams(&self) -> Vec<url::Url> {
+        self.config
+            .lock()
+            .unwrap()
+            .dns_upstream
+            .clone()
+            .unwrap_or_default()
+    }
+
+    fn set_dns_upstreams(&self, upstreams: Vec<url::Url>) {
+        self.config.lock().unwrap().dns_upstream = Some(upstreams);
+    }
+
+    fn get_services(&self) -> Vec<ServiceConfig> {
+        // entries loaded from toml do not go through from_str
+        self.config
+            .lock()
+            .unwrap()
+            .service
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|s| match s.validate() {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::warn!(?e, "ignore invalid service");
+                    false
+                }
+            })
+            .collect()
+    }
+
+    fn set_services(&self, services: Vec<ServiceConfig>) {
+        self.config.lock().unwrap().service = Some(services);
+    }
+
+    fn get_udp_proxy_timeouts(&self) -> Vec<UdpProxyTimeoutConfig> {
+        // entries loaded from toml do not go through from_str
+        self.config
+            .lock()
+            .unwrap()
+            .udp_proxy_timeout
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|t| match t.validate() {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::warn!(?e, "ignore invalid udp proxy timeout");
+                    false
+                }
+            })
+            .collect()
+    }
+
+    fn set_udp_proxy_timeouts(&self, timeouts: Vec<UdpProxyTimeoutConfig>) {
+        self.config.lock().unwrap().udp_proxy_timeout = Some(timeouts);
+    }
+
     fn get_acl(&self) -> Option<Acl> {
         self.config.lock().unwrap().acl.clone()
     }
@@ -874,6 +1306,26 @@ stun_servers = [
         assert_eq!(stun_servers[2], "txt:stun.easytier.cn");
     }
 
+    #[test]
+    fn test_kernel_route_toml_parsing() {
+        let config_str = r#"
+instance_name = "test"
+
+[kernel_route]
+import_protocols = ["bird", "186"]
+export_table = 100
+"#;
+
+        let config = TomlConfigLoader::new_from_str(config_str).unwrap();
+        let kernel_route = config.get_kernel_route_config().unwrap();
+        assert_eq!(kernel_route.import_protocols, vec!["bird", "186"]);
+        assert_eq!(kernel_route.import_table, None);
+        assert_eq!(kernel_route.export_table, Some(100));
+
+        let config = TomlConfigLoader::new_from_str(&config.dump()).unwrap();
+        assert_eq!(config.get_kernel_route_config(), Some(kernel_route));
+    }
+
     #[tokio::test]
     async fn full_example_test() {
         let config_str = r#"
@@ -943,4 +1395,173 @@ proto = "tcp"
         );
         println!("{}", ret.dump());
     }
+
+    #[test]
+    fn test_dns_record_toml_parsing() {
+        let config_str = r#"
+instance_name = "test"
+
+[[dns_record]]
+name = "printer.office"
+type = "A"
+value = "192.168.94.40"
+ttl = 300
+"#;
+
+        let config = TomlConfigLoader::new_from_str(config_str).unwrap();
+        assert_eq!(
+            vec![DnsRecordConfig {
+                name: "printer.office".to_string(),
+                rr_type: "A".to_string(),
+                value: "192.168.94.40".to_string(),
+                ttl: Some(300),
+            }],
+            config.get_dns_records()
+        );
+        assert_eq!(
+            "_http._tcp SRV 10 5 80 web.et.net."
+                .parse::<DnsRecordConfig>()
+                .unwrap()
+                .value,
+            "10 5 80 web.et.net."
+        );
+    }
+
+    #[test]
+    fn test_dns_forward_toml_parsing() {
+        let config_str = r#"
+instance_name = "test"
+
+[[dns_forward]]
+domain = "corp.example"
+nameservers = ["10.147.223.53:53"]
+
+[[dns_forward]]
+domain = "lab.example"
+nameservers = ["10.147.224.53:53"]
+via = "10.144.144.2"
+"#;
+
+        let config = TomlConfigLoader::new_from_str(config_str).unwrap();
+        assert_eq!(
+            vec![
+                "corp.example=10.147.223.53"
+                    .parse::<DnsForwardConfig>()
+                    .unwrap(),
+                "lab.example=10.147.224.53 via 10.144.144.2"
+                    .parse::<DnsForwardConfig>()
+                    .unwrap()
+            ],
+            config.get_dns_forwards()
+        );
+        assert_eq!(
+            Some("10.144.144.2".parse().unwrap()),
+            config.get_dns_forwards()[1].via
+        );
+    }
+
+    #[test]
+    fn test_dns_upstream_toml_parsing() {
+        let config_str = r#"
+instance_name = "test"
+dns_upstream = [ "https://1.1.1.1/dns-query", "tls://dns.google" ]
+"#;
+
+        let config = TomlConfigLoader::new_from_str(config_str).unwrap();
+        assert_eq!(
+            vec![
+                "https://1.1.1.1/dns-query".parse::<url::Url>().unwrap(),
+                "tls://dns.google".parse::<url::Url>().unwrap()
+            ],
+            config.get_dns_upstreams()
+        );
+    }
+
+    #[test]
+    fn test_service_toml_parsing() {
+        let config_str = r#"
+instance_name = "test"
+
+[[service]]
+name = "http"
+protocol = "tcp"
+port = 8080
+tags = ["path=/admin"]
+"#;
+
+        let config = TomlConfigLoader::new_from_str(config_str).unwrap();
+        assert_eq!(
+            vec!["http:8080,path=/admin".parse::<ServiceConfig>().unwrap()],
+            config.get_services()
+        );
+        assert_eq!(
+            "dns:53/udp".parse::<ServiceConfig>().unwrap().protocol,
+            "udp"
+        );
+        assert!("_ssh:22".parse::<ServiceConfig>().is_err());
+        assert!("ssh:22/sctp".parse::<ServiceConfig>().is_err());
+        assert!("ssh:0".parse::<ServiceConfig>().is_err());
+        assert!(ServiceConfig::try_from(ServiceConfigPb {
+            name: "ssh".to_string(),
+            protocol: "tcp".to_string(),
+            port: 65536 + 22,
+            tags: vec![],
+        })
+        .is_err());
+
+        // toml entries bypass from_str, invalid ones are dropped when read
+        let invalid = TomlConfigLoader::new_from_str(
+            r#"
+[[service]]
+name = "_ssh"
+protocol = "tcp"
+port = 22
+
+[[service]]
+name = "ssh"
+protocol = "sctp"
+port = 22
+"#,
+        )
+        .unwrap();
+        assert!(invalid.get_services().is_empty());
+    }
+
+    #[test]
+    fn test_udp_proxy_timeout_toml_parsing() {
+        let config_str = r#"
+instance_name = "test"
+
+[[udp_proxy_timeout]]
+start_port = 27000
+end_port = 27100
+timeout = 600
+"#;
+
+        let config = TomlConfigLoader::new_from_str(config_str).unwrap();
+        assert_eq!(
+            vec!["27000-27100:600".parse::<UdpProxyTimeoutConfig>().unwrap()],
+            config.get_udp_proxy_timeouts()
+        );
+        let timeout = "53:30".parse::<UdpProxyTimeoutConfig>().unwrap();
+        assert!(timeout.contains(53) && !timeout.contains(54));
+        assert!("100-99:30".parse::<UdpProxyTimeoutConfig>().is_err());
+
+        // toml entries bypass from_str, invalid ones are dropped when read
+        let invalid = TomlConfigLoader::new_from_str(
+            r#"
+[[udp_proxy_timeout]]
+start_port = 100
+end_port = 99
+timeout = 30
+
+[[udp_proxy_timeout]]
+start_port = 53
+end_port = 53
+timeout = 0
+"#,
+        )
+        .unwrap();
+        assert!(invalid.get_udp_proxy_timeouts().is_empty());
+    }
 }
@@ -31,6 +31,8 @@ define_global_var!(DIRECT_CONNECT_TO_PUBLIC_SERVER, bool, true);
 
 pub const UDP_HOLE_PUNCH_CONNECTOR_SERVICE_ID: u32 = 2;
 
+pub const MAGIC_DNS_INSTANCE_ADDR: &str = "tcp://127.0.0.1:49813";
+
 pub const WIN_SERVICE_WORK_DIR_REG_KEY: &str = "SOFTWARE\\EasyTier\\Service\\WorkDir";
 
 pub const EASYTIER_VERSION: &str = git_version::git_version!(
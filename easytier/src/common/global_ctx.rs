@@ -50,6 +50,13 @@ pub enum GlobalCtxEvent {
     DhcpIpv4Conflicted(Option<cidr::Ipv4Inet>),
 
     PortForwardAdded(PortForwardConfigPb),
+
+    RouteNextHopChanged(PeerId, Option<PeerId>, Option<PeerId>), // (dst, old next hop, new next hop)
+    RouteCostChanged(PeerId, i32, i32),                          // (dst, old cost, new cost)
+    RouteProxyCidrsChanged(PeerId, Vec<String>, Vec<String>),    // (dst, old cidrs, new cidrs)
+
+    DnsHostnameConflict(String, Vec<String>), // (hostname, instance ids reporting it)
+    DnsSystemConfigured(String),              // (configuration method)
 }
 
 pub type EventBus = tokio::sync::broadcast::Sender<GlobalCtxEvent>;
@@ -75,6 +82,10 @@ pub struct GlobalCtx {
     stun_info_collection: Mutex<Arc<dyn StunInfoCollectorTrait>>,
 
     running_listeners: Mutex<Vec<url::Url>>,
+    port_mapped_listeners: Mutex<Vec<url::Url>>,
+
+    // proxy cidrs imported from kernel routes, not persisted into config.
+    imported_proxy_cidrs: Mutex<Vec<cidr::Ipv4Cidr>>,
 
     enable_exit_node: bool,
     proxy_forward_by_system: bool,
@@ -137,6 +148,7 @@ impl GlobalCtx {
         let feature_flags = PeerFeatureFlag {
             kcp_input: !config_fs.get_flags().disable_kcp_input,
             no_relay_kcp: config_fs.get_flags().disable_relay_kcp,
+            support_conn_list_sync: true,
             ..Default::default()
         };
 
@@ -162,6 +174,9 @@ impl GlobalCtx {
             stun_info_collection: Mutex::new(stun_info_collector),
 
             running_listeners: Mutex::new(Vec::new()),
+            port_mapped_listeners: Mutex::new(Vec::new()),
+
+            imported_proxy_cidrs: Mutex::new(Vec::new()),
 
             enable_exit_node,
             proxy_forward_by_system,
@@ -285,6 +300,32 @@ impl GlobalCtx {
         }
     }
 
+    pub fn get_port_mapped_listeners(&self) -> Vec<url::Url> {
+        self.port_mapped_listeners.lock().unwrap().clone()
+    }
+
+    pub fn add_port_mapped_listener(&self, url: url::Url) {
+        let mut l = self.port_mapped_listeners.lock().unwrap();
+        if !l.contains(&url) {
+            l.push(url);
+        }
+    }
+
+    pub fn remove_port_mapped_listener(&self, url: &url::Url) {
+        self.port_mapped_listeners
+            .lock()
+            .unwrap()
+            .retain(|x| x != url);
+    }
+
+    pub fn get_imported_proxy_cidrs(&self) -> Vec<cidr::Ipv4Cidr> {
+        self.imported_proxy_cidrs.lock().unwrap().clone()
+    }
+
+    pub fn set_imported_proxy_cidrs(&self, cidrs: Vec<cidr::Ipv4Cidr>) {
+        *self.imported_proxy_cidrs.lock().unwrap() = cidrs;
+    }
+
     pub fn get_vpn_portal_cidr(&self) -> Option<cidr::Ipv4Cidr> {
         self.config.get_vpn_portal_config().map(|x| x.client_cidr)
     }
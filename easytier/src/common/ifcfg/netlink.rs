@@ -367,6 +367,89 @@ impl NetlinkIfConfiger {
 
         Ok(ret_vec)
     }
+
+    fn get_route_table_id(msg: &RouteMessage) -> u32 {
+        // tables above 255 are only carried in the RTA_TABLE attribute
+        msg.attributes
+            .iter()
+            .find_map(|attr| match attr {
+                RouteAttribute::Table(t) => Some(*t),
+                _ => None,
+            })
+            .unwrap_or(msg.header.table as u32)
+    }
+
+    /// List unicast ipv4 routes of the given table, optionally filtered by the
+    /// route protocol (rtm_protocol) which identifies the daemon installed it.
+    pub(crate) fn list_ipv4_routes_in_table(
+        table: u32,
+        protocols: &[u8],
+    ) -> Result<Vec<Route>, Error> {
+        Ok(Self::list_routes()?
+            .into_iter()
+            .filter(|msg| msg.header.kind == RouteType::Unicast)
+            .filter(|msg| Self::get_route_table_id(msg) == table)
+            .filter(|msg| {
+                protocols.is_empty() || protocols.contains(&u8::from(msg.header.protocol))
+            })
+            .map(Route::from)
+            .collect())
+    }
+
+    pub(crate) fn add_ipv4_route_to_table(
+        name: &str,
+        address: Ipv4Addr,
+        cidr_prefix: u8,
+        table: u32,
+        protocol: u8,
+    ) -> Result<(), Error> {
+        let mut message = RouteMessage::default();
+
+        message.header.table = if table < 256 {
+            table as u8
+        } else {
+            RouteHeader::RT_TABLE_UNSPEC
+        };
+        message.header.protocol = protocol.into();
+        message.header.scope = RouteScope::Universe;
+        message.header.kind = RouteType::Unicast;
+        message.header.address_family = AddressFamily::Inet;
+        message.attributes.push(RouteAttribute::Table(table));
+        message
+            .attributes
+            .push(RouteAttribute::Oif(NetlinkIfConfiger::get_interface_index(
+                name,
+            )?));
+        message.header.destination_prefix_length = cidr_prefix;
+        message
+            .attributes
+            .push(RouteAttribute::Destination(RouteAddress::Inet(address)));
+
+        send_netlink_req_and_wait_one_resp(RouteNetlinkMessage::NewRoute(message), false)
+    }
+
+    pub(crate) fn remove_ipv4_route_from_table(
+        address: Ipv4Addr,
+        cidr_prefix: u8,
+        table: u32,
+        protocol: u8,
+    ) -> Result<(), Error> {
+        for msg in Self::list_routes()? {
+            if Self::get_route_table_id(&msg) != table || u8::from(msg.header.protocol) != protocol
+            {
+                continue;
+            }
+            let other_route: Route = msg.clone().into();
+            if other_route.destination == std::net::IpAddr::V4(address)
+                && other_route.prefix == cidr_prefix
+            {
+                send_netlink_req_and_wait_one_resp(RouteNetlinkMessage::DelRoute(msg), true)?;
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -418,6 +501,7 @@ impl IfConfiguerTrait for NetlinkIfConfiger {
             if other_route.destination == std::net::IpAddr::V4(address)
                 && other_route.prefix == cidr_prefix
                 && other_route.ifindex == Some(ifidx)
+                && other_route.table == RouteHeader::RT_TABLE_MAIN
             {
                 send_netlink_req_and_wait_one_resp(RouteNetlinkMessage::DelRoute(msg), true)?;
                 return Ok(());
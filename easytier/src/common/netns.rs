@@ -111,3 +111,39 @@ impl NetNS {
         self.name.clone()
     }
 }
+
+/// The netns of the thread that created it, so tasks running on other threads
+/// (without a netns name at hand) can switch back to it later.
+#[derive(Clone, Debug)]
+pub struct ThreadNetNS {
+    #[cfg(target_os = "linux")]
+    ns: Option<std::sync::Arc<std::fs::File>>,
+}
+
+impl ThreadNetNS {
+    pub fn current() -> Self {
+        ThreadNetNS {
+            #[cfg(target_os = "linux")]
+            ns: std::fs::File::open("/proc/thread-self/ns/net")
+                .ok()
+                .map(std::sync::Arc::new),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn guard(&self) -> Box<NetNSGuard> {
+        let Some(ns) = self.ns.as_ref() else {
+            return Box::new(NetNSGuard { old_ns: None });
+        };
+        let old_ns = std::fs::File::open("/proc/thread-self/ns/net").unwrap();
+        setns(ns.as_fd(), CloneFlags::CLONE_NEWNET).unwrap();
+        Box::new(NetNSGuard {
+            old_ns: Some(old_ns),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn guard(&self) -> Box<NetNSGuard> {
+        Box::new(NetNSGuard {})
+    }
+}
@@ -68,6 +68,16 @@ pub enum MetricName {
     CompressionBytesTxAfter,
 
     TcpProxyConnect,
+
+    /// Magic dns queries answered from the upstream cache
+    DnsCacheHit,
+    /// Magic dns queries sent to the upstreams
+    DnsCacheMiss,
+    /// Magic dns upstream lookups failed
+    DnsUpstreamErrors,
+
+    /// Packets dropped because the tun queue of their flow is full
+    TunQueueDropped,
 }
 
 impl fmt::Display for MetricName {
@@ -116,6 +126,12 @@ impl fmt::Display for MetricName {
             MetricName::CompressionBytesTxAfter => write!(f, "compression_bytes_tx_after"),
 
             MetricName::TcpProxyConnect => write!(f, "tcp_proxy_connect"),
+
+            MetricName::DnsCacheHit => write!(f, "dns_cache_hit"),
+            MetricName::DnsCacheMiss => write!(f, "dns_cache_miss"),
+            MetricName::DnsUpstreamErrors => write!(f, "dns_upstream_errors"),
+
+            MetricName::TunQueueDropped => write!(f, "tun_queue_dropped"),
         }
     }
 }
@@ -147,6 +163,8 @@ pub enum LabelType {
     DstIp(String),
     /// Mapped Dst Ip
     MappedDstIp(String),
+    /// Dns upstream
+    Upstream(String),
 }
 
 impl fmt::Display for LabelType {
@@ -164,6 +182,7 @@ impl fmt::Display for LabelType {
             LabelType::Status(status) => write!(f, "status={}", status),
             LabelType::DstIp(ip) => write!(f, "dst_ip={}", ip),
             LabelType::MappedDstIp(ip) => write!(f, "mapped_dst_ip={}", ip),
+            LabelType::Upstream(upstream) => write!(f, "upstream={}", upstream),
         }
     }
 }
@@ -183,6 +202,7 @@ impl LabelType {
             LabelType::Status(_) => "status",
             LabelType::DstIp(_) => "dst_ip",
             LabelType::MappedDstIp(_) => "mapped_dst_ip",
+            LabelType::Upstream(_) => "upstream",
         }
     }
 
@@ -200,6 +220,7 @@ impl LabelType {
             LabelType::Status(status) => status.clone(),
             LabelType::DstIp(ip) => ip.clone(),
             LabelType::MappedDstIp(ip) => ip.clone(),
+            LabelType::Upstream(upstream) => upstream.clone(),
         }
     }
 }
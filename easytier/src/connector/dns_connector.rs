@@ -15,7 +15,7 @@ use rand::{seq::SliceRandom, Rng as _};
 
 use crate::proto::common::TunnelInfo;
 
-use super::{create_connector_by_url, http_connector::TunnelWithInfo};
+use super::{create_peer_connector_by_url, http_connector::TunnelWithInfo};
 
 fn weighted_choice<T>(options: &[(T, u64)]) -> Option<&T> {
     let total_weight = options.iter().map(|(_, weight)| *weight).sum();
@@ -77,7 +77,7 @@ impl DNSTunnelConnector {
             })?;
 
         let connector =
-            create_connector_by_url(url.as_str(), &self.global_ctx, self.ip_version).await?;
+            create_peer_connector_by_url(url.as_str(), &self.global_ctx, self.ip_version).await?;
         Ok(connector)
     }
 
@@ -158,7 +158,7 @@ impl DNSTunnelConnector {
         })?;
 
         let connector =
-            create_connector_by_url(url.as_str(), &self.global_ctx, self.ip_version).await?;
+            create_peer_connector_by_url(url.as_str(), &self.global_ctx, self.ip_version).await?;
         Ok(connector)
     }
 }
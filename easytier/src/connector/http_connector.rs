@@ -16,7 +16,7 @@ use crate::{
 
 use crate::proto::common::TunnelInfo;
 
-use super::create_connector_by_url;
+use super::create_peer_connector_by_url;
 
 pub struct TunnelWithInfo {
     inner: Box<dyn Tunnel>,
@@ -92,7 +92,7 @@ impl HttpTunnelConnector {
             if !query.is_empty() {
                 tracing::info!("try to create connector by url: {}", query[0]);
                 self.redirect_type = HttpRedirectType::RedirectToQuery;
-                return create_connector_by_url(
+                return create_peer_connector_by_url(
                     query[0].as_ref(),
                     &self.global_ctx,
                     self.ip_version,
@@ -104,7 +104,7 @@ impl HttpTunnelConnector {
             {
                 // stripe the scheme and create connector by url
                 self.redirect_type = HttpRedirectType::RedirectToUrl;
-                return create_connector_by_url(
+                return create_peer_connector_by_url(
                     new_url.as_str(),
                     &self.global_ctx,
                     self.ip_version,
@@ -117,8 +117,12 @@ impl HttpTunnelConnector {
             )));
         } else {
             self.redirect_type = HttpRedirectType::RedirectToUrl;
-            return create_connector_by_url(new_url.as_str(), &self.global_ctx, self.ip_version)
-                .await;
+            return create_peer_connector_by_url(
+                new_url.as_str(),
+                &self.global_ctx,
+                self.ip_version,
+            )
+            .await;
         }
     }
 
@@ -148,7 +152,7 @@ impl HttpTunnelConnector {
                 continue;
             }
             self.redirect_type = HttpRedirectType::BodyUrls;
-            return create_connector_by_url(line, &self.global_ctx, self.ip_version).await;
+            return create_peer_connector_by_url(line, &self.global_ctx, self.ip_version).await;
         }
 
         Err(Error::InvalidUrl(format!(
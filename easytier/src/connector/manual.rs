@@ -23,7 +23,11 @@ use crate::{
         },
         rpc_types::{self, controller::BaseController},
     },
-    tunnel::{IpVersion, TunnelConnector},
+    tunnel::{
+        obfs::remove_obfs_key_from_url,
+        upstream_proxy::{redact_proxy_credential, remove_proxy_from_url},
+        IpVersion, TunnelConnector,
+    },
 };
 
 use crate::{
@@ -40,7 +44,7 @@ use crate::{
     use_global_var,
 };
 
-use super::create_connector_by_url;
+use super::{create_peer_connector_by_url, get_upstream_proxy_for_url};
 
 type ConnectorMap = Arc<DashSet<String>>;
 
@@ -112,17 +116,25 @@ impl ManualConnectorManager {
     }
 
     pub async fn remove_connector(&self, url: url::Url) -> Result<(), Error> {
-        tracing::info!("remove_connector: {}", url);
-        let url = url.into();
-        if !self
-            .list_connectors()
-            .await
+        tracing::info!(
+            "remove_connector: {}",
+            redact_proxy_credential(url.as_str())
+        );
+        // the url may be the redacted one shown by list_connectors.
+        let Some(conn_url) = self
+            .data
+            .connectors
             .iter()
-            .any(|x| x.url.as_ref() == Some(&url))
-        {
+            .map(|x| x.key().clone())
+            .chain(self.data.reconnecting.iter().map(|x| x.clone()))
+            .find(|x| {
+                url::Url::parse(x).is_ok_and(|x| x == url)
+                    || redact_proxy_credential(x) == url.as_str()
+            })
+        else {
             return Err(Error::NotFound);
-        }
-        self.data.removed_conn_urls.insert(url.to_string());
+        };
+        self.data.removed_conn_urls.insert(conn_url);
         Ok(())
     }
 
@@ -149,7 +161,7 @@ impl ManualConnectorManager {
             ret.insert(
                 0,
                 Connector {
-                    url: Some(conn_url.parse().unwrap()),
+                    url: Some(redact_proxy_credential(&conn_url).parse().unwrap()),
                     status: status.into(),
                 },
             );
@@ -162,7 +174,7 @@ impl ManualConnectorManager {
             ret.insert(
                 0,
                 Connector {
-                    url: Some(conn_url.parse().unwrap()),
+                    url: Some(redact_proxy_credential(&conn_url).parse().unwrap()),
                     status: ConnectorStatus::Connecting.into(),
                 },
             );
@@ -279,14 +291,17 @@ impl ManualConnectorManager {
         for it in data.removed_conn_urls.iter() {
             let url = it.key();
             if data.connectors.remove(url).is_some() {
-                tracing::warn!("connector: {}, removed", url);
+                tracing::warn!("connector: {}, removed", redact_proxy_credential(url));
                 continue;
             } else if data.reconnecting.contains(url) {
-                tracing::warn!("connector: {}, reconnecting, remove later.", url);
+                tracing::warn!(
+                    "connector: {}, reconnecting, remove later.",
+                    redact_proxy_credential(url)
+                );
                 remove_later.insert(url.clone());
                 continue;
             } else {
-                tracing::warn!("connector: {}, not found", url);
+                tracing::warn!("connector: {}, not found", redact_proxy_credential(url));
             }
         }
         data.removed_conn_urls.clear();
@@ -300,7 +315,9 @@ impl ManualConnectorManager {
         let all_urls: BTreeSet<String> = data.connectors.iter().map(|x| x.key().clone()).collect();
         let mut ret = BTreeSet::new();
         for url in all_urls.iter() {
-            if !data.alive_conn_urls.contains(url) {
+            // tunnel info reports the url without the proxy and obfs query.
+            let tunnel_url = remove_obfs_key_from_url(&remove_proxy_from_url(url));
+            if !data.alive_conn_urls.contains(&tunnel_url) {
                 ret.insert(url.clone());
             }
         }
@@ -313,7 +330,7 @@ impl ManualConnectorManager {
         ip_version: IpVersion,
     ) -> Result<ReconnResult, Error> {
         let connector =
-            create_connector_by_url(&dead_url, &data.global_ctx.clone(), ip_version).await?;
+            create_peer_connector_by_url(&dead_url, &data.global_ctx.clone(), ip_version).await?;
 
         data.global_ctx
             .issue_event(GlobalCtxEvent::Connecting(connector.remote_url().clone()));
@@ -325,6 +342,7 @@ impl ManualConnectorManager {
         };
 
         let (peer_id, conn_id) = pm.try_direct_connect(connector).await?;
+        let dead_url = redact_proxy_credential(&dead_url);
         tracing::info!("reconnect succ: {} {} {}", peer_id, conn_id, dead_url);
         Ok(ReconnResult {
             dead_url,
@@ -337,19 +355,25 @@ impl ManualConnectorManager {
         data: Arc<ConnectorManagerData>,
         dead_url: String,
     ) -> Result<ReconnResult, Error> {
-        tracing::info!("reconnect: {}", dead_url);
+        // do not expose the credential of upstream proxy in logs and events.
+        let shown_url = redact_proxy_credential(&dead_url);
+        tracing::info!("reconnect: {}", shown_url);
 
         let mut ip_versions = vec![];
-        let u = url::Url::parse(&dead_url)
-            .with_context(|| format!("failed to parse connector url {:?}", dead_url))?;
-        if u.scheme() == "ring" || u.scheme() == "txt" || u.scheme() == "srv" {
+        let mut u = url::Url::parse(&dead_url)
+            .with_context(|| format!("failed to parse connector url {:?}", shown_url))?;
+        // the upstream proxy resolves the host, it may be not resolvable locally.
+        let use_upstream_proxy = matches!(u.scheme(), "tcp" | "ws" | "wss")
+            && get_upstream_proxy_for_url(&mut u, &data.global_ctx)?.is_some();
+        if u.scheme() == "ring" || u.scheme() == "txt" || u.scheme() == "srv" || use_upstream_proxy
+        {
             ip_versions.push(IpVersion::Both);
         } else {
             let addrs = match socket_addrs(&u, || Some(1000)).await {
                 Ok(addrs) => addrs,
                 Err(e) => {
                     data.global_ctx.issue_event(GlobalCtxEvent::ConnectError(
-                        dead_url.clone(),
+                        shown_url.clone(),
                         format!("{:?}", IpVersion::Both),
                         format!("{:?}", e),
                     ));
@@ -359,7 +383,7 @@ impl ManualConnectorManager {
                     )));
                 }
             };
-            tracing::info!(?addrs, ?dead_url, "get ip from url done");
+            tracing::info!(?addrs, ?shown_url, "get ip from url done");
             let mut has_ipv4 = false;
             let mut has_ipv6 = false;
             for addr in addrs {
@@ -390,7 +414,7 @@ impl ManualConnectorManager {
                 Self::conn_reconnect_with_ip_version(data.clone(), dead_url.clone(), ip_version),
             )
             .await;
-            tracing::info!("reconnect: {} done, ret: {:?}", dead_url, ret);
+            tracing::info!("reconnect: {} done, ret: {:?}", shown_url, ret);
 
             match ret {
                 Ok(Ok(_)) => {
@@ -410,7 +434,7 @@ impl ManualConnectorManager {
 
             // 发送事件（只有在未 break 时才执行）
             data.global_ctx.issue_event(GlobalCtxEvent::ConnectError(
-                dead_url.clone(),
+                shown_url.clone(),
                 format!("{:?}", ip_version),
                 format!("{:?}", reconn_ret),
             ));
@@ -12,13 +12,20 @@ use crate::tunnel::wireguard::{WgConfig, WgTunnelConnector};
 use crate::{
     common::{error::Error, global_ctx::ArcGlobalCtx, network::IPCollector},
     tunnel::{
-        check_scheme_and_get_socket_addr, ring::RingTunnelConnector, tcp::TcpTunnelConnector,
-        udp::UdpTunnelConnector, IpVersion, TunnelConnector,
+        check_scheme_and_get_socket_addr,
+        kcp::KcpTunnelConnector,
+        obfs::{take_obfs_key_from_url, ObfsTunnelConnector},
+        ring::RingTunnelConnector,
+        tcp::TcpTunnelConnector,
+        udp::UdpTunnelConnector,
+        upstream_proxy::{take_proxy_from_url, upstream_proxy_from_env},
+        IpVersion, TunnelConnector,
     },
 };
 
 pub mod direct;
 pub mod manual;
+pub mod tcp_hole_punch;
 pub mod udp_hole_punch;
 
 pub mod dns_connector;
@@ -52,17 +59,84 @@ async fn set_bind_addr_for_peer_connector(
     let _ = connector;
 }
 
+/// upstream proxy of tcp based connectors, in the order of url query, config and env.
+/// the proxy query is removed from the url so the credential is not exposed in
+/// tunnel info.
+pub(crate) fn get_upstream_proxy_for_url(
+    url: &mut url::Url,
+    global_ctx: &ArcGlobalCtx,
+) -> Result<Option<url::Url>, Error> {
+    if let Some(proxy) = take_proxy_from_url(url)? {
+        return Ok(proxy);
+    }
+    if let Some(proxy) = global_ctx.config.get_upstream_proxy() {
+        return Ok(Some(proxy));
+    }
+    Ok(url.host_str().and_then(upstream_proxy_from_env))
+}
+
+/// create connector for the peer urls given by user (manual or configured peers), which
+/// are the only connectors going through the upstream proxy. urls learned from other
+/// peers (direct connect, hole punching) always connect directly.
+pub async fn create_peer_connector_by_url(
+    url: &str,
+    global_ctx: &ArcGlobalCtx,
+    ip_version: IpVersion,
+) -> Result<Box<dyn TunnelConnector + 'static>, Error> {
+    let mut url = url::Url::parse(url).map_err(|_| Error::InvalidUrl(url.to_owned()))?;
+    if !matches!(url.scheme(), "tcp" | "ws" | "wss") {
+        return create_connector_by_url(url.as_str(), global_ctx, ip_version).await;
+    }
+    let Some(proxy) = get_upstream_proxy_for_url(&mut url, global_ctx)? else {
+        return create_connector_by_url(url.as_str(), global_ctx, ip_version).await;
+    };
+
+    let mut obfs_key = take_obfs_key_from_url(&mut url);
+
+    // the proxy resolves the destination, skip local resolving and binding.
+    let mut connector: Box<dyn TunnelConnector + 'static> = match url.scheme() {
+        "tcp" => {
+            let mut connector = TcpTunnelConnector::new(url);
+            connector.set_upstream_proxy(Some(proxy));
+            connector.set_obfs_key(obfs_key.take());
+            Box::new(connector)
+        }
+        #[cfg(feature = "websocket")]
+        "ws" | "wss" => {
+            let mut connector = crate::tunnel::websocket::WSTunnelConnector::new(url);
+            connector.set_upstream_proxy(Some(proxy));
+            Box::new(connector)
+        }
+        _ => {
+            return Err(Error::InvalidUrl(url.into()));
+        }
+    };
+    connector.set_ip_version(ip_version);
+    if let Some(key) = obfs_key {
+        connector = Box::new(ObfsTunnelConnector::new(connector, key));
+    }
+
+    Ok(connector)
+}
+
 pub async fn create_connector_by_url(
     url: &str,
     global_ctx: &ArcGlobalCtx,
     ip_version: IpVersion,
 ) -> Result<Box<dyn TunnelConnector + 'static>, Error> {
-    let url = url::Url::parse(url).map_err(|_| Error::InvalidUrl(url.to_owned()))?;
+    let mut url = url::Url::parse(url).map_err(|_| Error::InvalidUrl(url.to_owned()))?;
+    // http and dns connectors resolve to other urls, which carry their own obfs key.
+    let mut obfs_key = if matches!(url.scheme(), "http" | "https" | "txt" | "srv") {
+        None
+    } else {
+        take_obfs_key_from_url(&mut url)
+    };
     let mut connector: Box<dyn TunnelConnector + 'static> = match url.scheme() {
         "tcp" => {
             let dst_addr =
                 check_scheme_and_get_socket_addr::<SocketAddr>(&url, "tcp", ip_version).await?;
             let mut connector = TcpTunnelConnector::new(url);
+            connector.set_obfs_key(obfs_key.take());
             if global_ctx.config.get_flags().bind_device {
                 set_bind_addr_for_peer_connector(
                     &mut connector,
@@ -77,6 +151,21 @@ pub async fn create_connector_by_url(
             let dst_addr =
                 check_scheme_and_get_socket_addr::<SocketAddr>(&url, "udp", ip_version).await?;
             let mut connector = UdpTunnelConnector::new(url);
+            connector.set_obfs_key(obfs_key.take());
+            if global_ctx.config.get_flags().bind_device {
+                set_bind_addr_for_peer_connector(
+                    &mut connector,
+                    dst_addr.is_ipv4(),
+                    &global_ctx.get_ip_collector(),
+                )
+                .await;
+            }
+            Box::new(connector)
+        }
+        "kcp" => {
+            let dst_addr =
+                check_scheme_and_get_socket_addr::<SocketAddr>(&url, "kcp", ip_version).await?;
+            let mut connector = KcpTunnelConnector::new(url);
             if global_ctx.config.get_flags().bind_device {
                 set_bind_addr_for_peer_connector(
                     &mut connector,
@@ -161,6 +250,10 @@ pub async fn create_connector_by_url(
         }
     };
     connector.set_ip_version(ip_version);
+    // tcp and udp obfuscate the whole traffic themselves.
+    if let Some(key) = obfs_key {
+        connector = Box::new(ObfsTunnelConnector::new(connector, key));
+    }
 
     Ok(connector)
 }
@@ -0,0 +1,543 @@
+// TCP hole punching with simultaneous open.
+//
+// Both peers bind a socket with SO_REUSEADDR/SO_REUSEPORT, exchange the predicted
+// public addresses of the socket over peer rpc and then connect to each other at
+// the same time. When the SYNs cross, cone-like NATs let them in and both sides get
+// a connected stream. The initiator (smaller peer id) acts as the tunnel client.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Error};
+use futures::{stream::FuturesUnordered, StreamExt};
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::{
+    common::{global_ctx::ArcGlobalCtx, netns::NetNS, stun::StunInfoCollectorTrait, PeerId},
+    connector::udp_hole_punch::{handle_rpc_result, BackOff, BLACKLIST_TIMEOUT_SEC},
+    peers::{
+        peer_manager::PeerManager,
+        peer_task::{PeerTaskLauncher, PeerTaskManager},
+    },
+    proto::{
+        common::NatType,
+        peer_rpc::{
+            ExchangeTcpPunchAddrsRequest, ExchangeTcpPunchAddrsResponse, RoutePeerInfo,
+            TcpHolePunchRpc, TcpHolePunchRpcClientFactory, TcpHolePunchRpcServer,
+        },
+        rpc_types::{self, controller::BaseController},
+    },
+    tunnel::{build_url_from_socket_addr, tcp::get_tunnel_with_tcp_stream, Tunnel},
+};
+
+// give the rpc response some time to arrive before connecting.
+const PUNCH_DELAY_MS: u32 = 300;
+// the delay comes from the remote peer, do not let it hold a punch slot for long.
+const MAX_PUNCH_DELAY_MS: u32 = 1000;
+const PUNCH_DURATION: Duration = Duration::from_secs(6);
+const PUNCH_CONNECT_TIMEOUT: Duration = Duration::from_millis(1000);
+const PUNCH_ROUND_INTERVAL: Duration = Duration::from_millis(200);
+// how many following ports to try if the nat allocates ports incrementally.
+const PREDICT_PORT_COUNT: u16 = 3;
+const MAX_CONCURRENT_SERVER_PUNCH: usize = 4;
+// give up and blacklist the peer after this many failed attempts.
+const MAX_PUNCH_ATTEMPTS: usize = 6;
+
+fn bind_punch_socket(net_ns: &NetNS, local_addr: SocketAddr) -> Result<TcpSocket, Error> {
+    let _g = net_ns.guard();
+    let socket = TcpSocket::new_v4()?;
+    socket.set_reuseaddr(true)?;
+    #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
+    socket.set_reuseport(true)?;
+    socket.bind(local_addr)?;
+    Ok(socket)
+}
+
+/// predict the public addresses of the punch socket. TCP mapping is not probed by
+/// stun, so assume the nat preserves the port, and follow the udp nat behavior for
+/// nats allocating ports sequentially.
+fn predict_punch_addrs(global_ctx: &ArcGlobalCtx, local_port: u16) -> Vec<SocketAddr> {
+    let stun_info = global_ctx.get_stun_info_collector().get_stun_info();
+    let udp_nat_type = NatType::try_from(stun_info.udp_nat_type).unwrap_or(NatType::Unknown);
+
+    let mut ports = vec![local_port];
+    for i in 1..=PREDICT_PORT_COUNT {
+        match udp_nat_type {
+            NatType::SymmetricEasyInc => ports.push(local_port.wrapping_add(i)),
+            NatType::SymmetricEasyDec => ports.push(local_port.wrapping_sub(i)),
+            _ => {}
+        }
+    }
+
+    stun_info
+        .public_ip
+        .iter()
+        .filter_map(|ip| ip.parse::<Ipv4Addr>().ok())
+        .flat_map(|ip| {
+            ports
+                .iter()
+                .filter(|port| **port != 0)
+                .map(move |port| SocketAddr::new(IpAddr::V4(ip), *port))
+        })
+        .collect()
+}
+
+/// keep only the addrs the remote peer can have predicted for its punch socket,
+/// so a peer can not make this node connect to arbitrary hosts.
+fn filter_remote_punch_addrs(
+    peer_mgr: &PeerManager,
+    peer_info: Option<&RoutePeerInfo>,
+    addrs: impl IntoIterator<Item = SocketAddr>,
+) -> Vec<SocketAddr> {
+    let public_ips = peer_info
+        .map(|info| {
+            info.stun_public_ips
+                .iter()
+                .filter_map(|ip| ip.parse::<Ipv4Addr>().ok())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let allow_loopback = peer_mgr.allow_loopback_tunnel();
+
+    addrs
+        .into_iter()
+        .filter(|addr| {
+            let IpAddr::V4(ip) = addr.ip() else {
+                return false;
+            };
+            addr.port() != 0
+                && (allow_loopback || !ip.is_loopback())
+                && !ip.is_private()
+                && !ip.is_link_local()
+                && public_ips.contains(&ip)
+        })
+        .take(public_ips.len() * (PREDICT_PORT_COUNT as usize + 1))
+        .collect()
+}
+
+/// connect to all remote addrs from the same local port until some of them succeed.
+async fn punch_with_simultaneous_open(
+    net_ns: NetNS,
+    local_addr: SocketAddr,
+    remote_addrs: Vec<SocketAddr>,
+) -> Vec<(TcpStream, SocketAddr)> {
+    let start = Instant::now();
+    while start.elapsed() < PUNCH_DURATION {
+        let mut futures = FuturesUnordered::new();
+        for remote_addr in remote_addrs.iter().copied() {
+            let socket = match bind_punch_socket(&net_ns, local_addr) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    tracing::warn!(?e, ?local_addr, "bind tcp punch socket failed");
+                    continue;
+                }
+            };
+            futures.push(async move {
+                let ret =
+                    tokio::time::timeout(PUNCH_CONNECT_TIMEOUT, socket.connect(remote_addr)).await;
+                (ret, remote_addr)
+            });
+        }
+
+        let mut streams = vec![];
+        while let Some((ret, remote_addr)) = futures.next().await {
+            match ret {
+                Ok(Ok(stream)) => {
+                    tracing::info!(?local_addr, ?remote_addr, "tcp hole punched");
+                    streams.push((stream, remote_addr));
+                }
+                Ok(Err(e)) => {
+                    tracing::trace!(?e, ?remote_addr, "tcp punch connect failed");
+                }
+                Err(_) => {
+                    tracing::trace!(?remote_addr, "tcp punch connect timeout");
+                }
+            }
+        }
+
+        if !streams.is_empty() {
+            return streams;
+        }
+        tokio::time::sleep(PUNCH_ROUND_INTERVAL).await;
+    }
+
+    vec![]
+}
+
+fn stream_to_tunnel(stream: TcpStream, remote_addr: SocketAddr) -> Result<Box<dyn Tunnel>, Error> {
+    let remote_url = build_url_from_socket_addr(&remote_addr.to_string(), "tcp");
+    Ok(get_tunnel_with_tcp_stream(stream, remote_url, None)?)
+}
+
+struct TcpHolePunchServer {
+    peer_mgr: Arc<PeerManager>,
+    tasks: std::sync::Mutex<JoinSet<()>>,
+}
+
+impl TcpHolePunchServer {
+    fn new(peer_mgr: Arc<PeerManager>) -> Arc<Self> {
+        Arc::new(Self {
+            peer_mgr,
+            tasks: std::sync::Mutex::new(JoinSet::new()),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl TcpHolePunchRpc for TcpHolePunchServer {
+    type Controller = BaseController;
+
+    #[tracing::instrument(skip(self), ret, err)]
+    async fn exchange_tcp_punch_addrs(
+        &self,
+        _ctrl: Self::Controller,
+        input: ExchangeTcpPunchAddrsRequest,
+    ) -> rpc_types::error::Result<ExchangeTcpPunchAddrsResponse> {
+        let peer_info = self
+            .peer_mgr
+            .get_route()
+            .get_peer_info(input.my_peer_id)
+            .await;
+        let remote_addrs = filter_remote_punch_addrs(
+            &self.peer_mgr,
+            peer_info.as_ref(),
+            input
+                .connect_addrs
+                .iter()
+                .map(|addr| SocketAddr::from(*addr)),
+        );
+        if remote_addrs.is_empty() {
+            return Err(anyhow::anyhow!("tcp punch request has no valid connect addr").into());
+        }
+
+        let mut tasks = self.tasks.lock().unwrap();
+        while tasks.try_join_next().is_some() {}
+        if tasks.len() >= MAX_CONCURRENT_SERVER_PUNCH {
+            return Err(anyhow::anyhow!("too many tcp punch in progress").into());
+        }
+
+        let global_ctx = self.peer_mgr.get_global_ctx();
+        let holder = bind_punch_socket(&global_ctx.net_ns, "0.0.0.0:0".parse().unwrap())?;
+        let local_addr = holder
+            .local_addr()
+            .with_context(|| "failed to get local addr of tcp punch socket")?;
+        let connect_addrs = predict_punch_addrs(&global_ctx, local_addr.port());
+        if connect_addrs.is_empty() {
+            return Err(anyhow::anyhow!("no public ipv4 address for tcp punch").into());
+        }
+
+        let peer_mgr = self.peer_mgr.clone();
+        let punch_delay =
+            Duration::from_millis(input.punch_delay_ms.min(MAX_PUNCH_DELAY_MS) as u64);
+        tasks.spawn(async move {
+            // hold the port until punching finishes.
+            let _holder = holder;
+            tokio::time::sleep(punch_delay).await;
+
+            let net_ns = peer_mgr.get_global_ctx().net_ns.clone();
+            let streams = punch_with_simultaneous_open(net_ns, local_addr, remote_addrs).await;
+            // the client picks one of the punched streams, the others fail in handshake.
+            let mut handshakes = JoinSet::new();
+            for (stream, remote_addr) in streams {
+                let Ok(tunnel) = stream_to_tunnel(stream, remote_addr) else {
+                    continue;
+                };
+                let peer_mgr = peer_mgr.clone();
+                handshakes.spawn(async move {
+                    if let Err(e) = peer_mgr.add_tunnel_as_server(tunnel, false).await {
+                        tracing::info!(?e, "add tcp punched tunnel as server failed");
+                    }
+                });
+            }
+            while handshakes.join_next().await.is_some() {}
+        });
+
+        Ok(ExchangeTcpPunchAddrsResponse {
+            connect_addrs: connect_addrs.into_iter().map(Into::into).collect(),
+        })
+    }
+}
+
+struct TcpHolePunchConnectorData {
+    peer_mgr: Arc<PeerManager>,
+    blacklist: Arc<timedmap::TimedMap<PeerId, ()>>,
+}
+
+impl TcpHolePunchConnectorData {
+    fn new(peer_mgr: Arc<PeerManager>) -> Arc<Self> {
+        Arc::new(Self {
+            peer_mgr,
+            blacklist: Arc::new(timedmap::TimedMap::new()),
+        })
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn do_hole_punching(
+        &self,
+        dst_peer_id: PeerId,
+    ) -> Result<Option<Box<dyn Tunnel>>, Error> {
+        if self.blacklist.contains(&dst_peer_id) {
+            tracing::debug!(
+                ?dst_peer_id,
+                "peer is blacklisted, skipping tcp hole punching"
+            );
+            return Ok(None);
+        }
+
+        let global_ctx = self.peer_mgr.get_global_ctx();
+        let holder = bind_punch_socket(&global_ctx.net_ns, "0.0.0.0:0".parse().unwrap())?;
+        let local_addr = holder.local_addr()?;
+        let connect_addrs = predict_punch_addrs(&global_ctx, local_addr.port());
+        if connect_addrs.is_empty() {
+            return Err(anyhow::anyhow!("no public ipv4 address for tcp punch"));
+        }
+
+        let rpc_stub = self
+            .peer_mgr
+            .get_peer_rpc_mgr()
+            .rpc_client()
+            .scoped_client::<TcpHolePunchRpcClientFactory<BaseController>>(
+                self.peer_mgr.my_peer_id(),
+                dst_peer_id,
+                global_ctx.get_network_name(),
+            );
+        let resp = rpc_stub
+            .exchange_tcp_punch_addrs(
+                BaseController::default(),
+                ExchangeTcpPunchAddrsRequest {
+                    connect_addrs: connect_addrs.into_iter().map(Into::into).collect(),
+                    punch_delay_ms: PUNCH_DELAY_MS,
+                    my_peer_id: self.peer_mgr.my_peer_id(),
+                },
+            )
+            .await;
+        let resp = handle_rpc_result(resp, dst_peer_id, &self.blacklist)?;
+        let peer_info = self.peer_mgr.get_route().get_peer_info(dst_peer_id).await;
+        let remote_addrs = filter_remote_punch_addrs(
+            &self.peer_mgr,
+            peer_info.as_ref(),
+            resp.connect_addrs.into_iter().map(SocketAddr::from),
+        );
+        if remote_addrs.is_empty() {
+            return Err(anyhow::anyhow!(
+                "tcp punch response has no valid connect addr"
+            ));
+        }
+        tracing::debug!(?local_addr, ?remote_addrs, "start tcp simultaneous open");
+
+        tokio::time::sleep(Duration::from_millis(PUNCH_DELAY_MS as u64)).await;
+        let streams =
+            punch_with_simultaneous_open(global_ctx.net_ns.clone(), local_addr, remote_addrs).await;
+        drop(holder);
+
+        let Some((stream, remote_addr)) = streams.into_iter().next() else {
+            return Ok(None);
+        };
+        Ok(Some(stream_to_tunnel(stream, remote_addr)?))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn punch_to_peer(self: Arc<Self>, dst_peer_id: PeerId) -> Result<(), Error> {
+        let mut backoff = BackOff::new(vec![3000, 4000, 8000, 16000, 32000, 64000]);
+
+        for _ in 0..MAX_PUNCH_ATTEMPTS {
+            backoff.sleep_for_next_backoff().await;
+
+            // the peer may be connected directly by the other side or other connectors.
+            let conns = self
+                .peer_mgr
+                .get_peer_map()
+                .list_peer_conns(dst_peer_id)
+                .await;
+            if conns.is_some_and(|conns| !conns.is_empty()) {
+                return Ok(());
+            }
+
+            match self.do_hole_punching(dst_peer_id).await {
+                Ok(Some(tunnel)) => {
+                    tracing::info!(?tunnel, "tcp hole punching get tunnel success");
+                    match self.peer_mgr.add_client_tunnel(tunnel, false).await {
+                        Ok(_) => return Ok(()),
+                        Err(e) => {
+                            tracing::warn!(?e, "add tcp punched client tunnel failed");
+                        }
+                    }
+                }
+                Ok(None) => {
+                    tracing::info!("tcp hole punching failed, no punched stream");
+                }
+                Err(e) => {
+                    tracing::info!(?e, "tcp hole punching failed");
+                }
+            }
+        }
+
+        tracing::info!(?dst_peer_id, "tcp hole punching give up");
+        self.blacklist
+            .insert(dst_peer_id, (), Duration::from_secs(BLACKLIST_TIMEOUT_SEC));
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct TcpHolePunchPeerTaskLauncher {}
+
+#[async_trait::async_trait]
+impl PeerTaskLauncher for TcpHolePunchPeerTaskLauncher {
+    type Data = Arc<TcpHolePunchConnectorData>;
+    type CollectPeerItem = PeerId;
+    type TaskRet = ();
+
+    fn new_data(&self, peer_mgr: Arc<PeerManager>) -> Self::Data {
+        TcpHolePunchConnectorData::new(peer_mgr)
+    }
+
+    async fn collect_peers_need_task(&self, data: &Self::Data) -> Vec<Self::CollectPeerItem> {
+        let my_peer_id = data.peer_mgr.my_peer_id();
+        data.blacklist.cleanup();
+
+        let mut peers_to_connect = Vec::new();
+        for route in data.peer_mgr.list_routes().await.iter() {
+            if route
+                .feature_flag
+                .map(|x| x.is_public_server)
+                .unwrap_or(false)
+            {
+                continue;
+            }
+
+            let peer_id: PeerId = route.peer_id;
+            // the peer with smaller peer id initiates, the other one only responds.
+            if peer_id < my_peer_id || data.blacklist.contains(&peer_id) {
+                continue;
+            }
+
+            let conns = data.peer_mgr.get_peer_map().list_peer_conns(peer_id).await;
+            if conns.is_some_and(|conns| !conns.is_empty()) {
+                continue;
+            }
+
+            tracing::info!(?peer_id, "found peer to do tcp hole punching");
+            peers_to_connect.push(peer_id);
+        }
+
+        peers_to_connect
+    }
+
+    async fn launch_task(
+        &self,
+        data: &Self::Data,
+        item: Self::CollectPeerItem,
+    ) -> JoinHandle<Result<Self::TaskRet, Error>> {
+        tokio::spawn(data.clone().punch_to_peer(item))
+    }
+
+    fn loop_interval_ms(&self) -> u64 {
+        10000
+    }
+}
+
+pub struct TcpHolePunchConnector {
+    server: Arc<TcpHolePunchServer>,
+    client: PeerTaskManager<TcpHolePunchPeerTaskLauncher>,
+    peer_mgr: Arc<PeerManager>,
+}
+
+impl TcpHolePunchConnector {
+    pub fn new(peer_mgr: Arc<PeerManager>) -> Self {
+        Self {
+            server: TcpHolePunchServer::new(peer_mgr.clone()),
+            client: PeerTaskManager::new(TcpHolePunchPeerTaskLauncher {}, peer_mgr.clone()),
+            peer_mgr,
+        }
+    }
+
+    pub async fn run_as_client(&mut self) -> Result<(), Error> {
+        self.client.start();
+        Ok(())
+    }
+
+    pub async fn run_as_server(&mut self) -> Result<(), Error> {
+        self.peer_mgr
+            .get_peer_rpc_mgr()
+            .rpc_server()
+            .registry()
+            .register(
+                TcpHolePunchRpcServer::new(self.server.clone()),
+                &self.peer_mgr.get_global_ctx().get_network_name(),
+            );
+
+        Ok(())
+    }
+
+    pub async fn run(&mut self) -> Result<(), Error> {
+        let global_ctx = self.peer_mgr.get_global_ctx();
+
+        if global_ctx.get_flags().disable_p2p {
+            return Ok(());
+        }
+        // punching every peer with a higher id is costly, only do it when asked to.
+        if !global_ctx.get_flags().enable_tcp_hole_punching {
+            return Ok(());
+        }
+
+        self.run_as_client().await?;
+        self.run_as_server().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        connector::udp_hole_punch::tests::create_mock_peer_manager_with_mock_stun,
+        peers::tests::{connect_peer_manager, wait_route_appear, wait_route_appear_with_cost},
+        proto::common::NatType,
+    };
+
+    use super::TcpHolePunchConnector;
+
+    #[tokio::test]
+    async fn hole_punching_tcp() {
+        let p_a = create_mock_peer_manager_with_mock_stun(NatType::Symmetric).await;
+        let p_b = create_mock_peer_manager_with_mock_stun(NatType::Symmetric).await;
+        let p_c = create_mock_peer_manager_with_mock_stun(NatType::Symmetric).await;
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+        connect_peer_manager(p_b.clone(), p_c.clone()).await;
+
+        wait_route_appear(p_a.clone(), p_c.clone()).await.unwrap();
+
+        for p in [&p_a, &p_c] {
+            let mut flags = p.get_global_ctx().get_flags();
+            flags.enable_tcp_hole_punching = true;
+            p.get_global_ctx().set_flags(flags);
+        }
+
+        let mut hole_punching_a = TcpHolePunchConnector::new(p_a.clone());
+        let mut hole_punching_c = TcpHolePunchConnector::new(p_c.clone());
+
+        hole_punching_a.run().await.unwrap();
+        hole_punching_c.run().await.unwrap();
+
+        hole_punching_a.client.run_immediately().await;
+        hole_punching_c.client.run_immediately().await;
+
+        wait_route_appear_with_cost(p_a.clone(), p_c.my_peer_id(), Some(1))
+            .await
+            .unwrap();
+
+        let conns = p_a
+            .get_peer_map()
+            .list_peer_conns(p_c.my_peer_id())
+            .await
+            .unwrap();
+        assert_eq!("tcp", conns[0].tunnel.as_ref().unwrap().tunnel_type);
+    }
+}
@@ -13,13 +13,15 @@ use zerocopy::FromBytes as _;
 
 use crate::{
     common::{
-        error::Error, global_ctx::ArcGlobalCtx, join_joinset_background, netns::NetNS,
-        stun::StunInfoCollectorTrait as _, PeerId,
+        config::ConfigLoader as _, error::Error, global_ctx::ArcGlobalCtx, join_joinset_background,
+        netns::NetNS, stun::StunInfoCollectorTrait as _, PeerId,
     },
     defer,
     peers::peer_manager::PeerManager,
     proto::common::NatType,
     tunnel::{
+        build_url_from_socket_addr,
+        kcp::{accept_maybe_kcp, KcpTunnelConfig, KcpTunnelConnector},
         packet_def::{UDPTunnelHeader, UdpPacketType, UDP_TUNNEL_HEADER_SIZE},
         udp::{new_hole_punch_packet, UdpTunnelConnector, UdpTunnelListener},
         Tunnel, TunnelConnCounter, TunnelListener as _,
@@ -406,6 +408,16 @@ impl UdpHolePunchListener {
                 tracing::warn!(?conn, "udp hole punching listener got peer connection");
                 let peer_mgr = peer_mgr.clone();
                 tokio::spawn(async move {
+                    let kcp_config = KcpTunnelConfig::from_listeners(
+                        &peer_mgr.get_global_ctx().config.get_listener_uris(),
+                    );
+                    let conn = match accept_maybe_kcp(conn, kcp_config).await {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            tracing::error!(?e, "hole punch listener got invalid first packet");
+                            return;
+                        }
+                    };
                     if let Err(e) = peer_mgr.add_tunnel_as_server(conn, false).await {
                         tracing::error!(
                             ?e,
@@ -630,20 +642,25 @@ pub(crate) async fn try_connect_with_socket(
     socket: Arc<UdpSocket>,
     remote_mapped_addr: SocketAddr,
 ) -> Result<Box<dyn Tunnel>, Error> {
-    let connector = UdpTunnelConnector::new(
-        format!(
-            "udp://{}:{}",
-            remote_mapped_addr.ip(),
-            remote_mapped_addr.port()
-        )
-        .to_string()
-        .parse()
-        .unwrap(),
-    );
-
+    let use_kcp = global_ctx.get_flags().default_protocol == "kcp";
+    let kcp_config = KcpTunnelConfig::from_listeners(&global_ctx.config.get_listener_uris());
     check_udp_socket_local_addr(global_ctx, remote_mapped_addr).await?;
 
-    connector
+    let url = build_url_from_socket_addr(
+        &remote_mapped_addr.to_string(),
+        if use_kcp { "kcp" } else { "udp" },
+    );
+    if use_kcp {
+        // the hole punch listener detects kcp from the first packet
+        let mut connector = KcpTunnelConnector::new(url);
+        connector.set_config(kcp_config);
+        return connector
+            .try_connect_with_socket(socket, remote_mapped_addr)
+            .await
+            .map_err(Error::from);
+    }
+
+    UdpTunnelConnector::new(url)
         .try_connect_with_socket(socket, remote_mapped_addr)
         .await
         .map_err(Error::from)
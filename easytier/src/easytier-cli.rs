@@ -22,7 +22,7 @@ use tokio::time::timeout;
 use easytier::{
     common::{
         config::PortForwardConfig,
-        constants::EASYTIER_VERSION,
+        constants::{EASYTIER_VERSION, MAGIC_DNS_INSTANCE_ADDR},
         stun::{StunInfoCollector, StunInfoCollectorTrait},
     },
     peers,
@@ -31,17 +31,21 @@ use easytier::{
             list_peer_route_pair, AclManageRpc, AclManageRpcClientFactory, AddPortForwardRequest,
             ConnectorManageRpc, ConnectorManageRpcClientFactory, DumpRouteRequest,
             GetAclStatsRequest, GetPrometheusStatsRequest, GetStatsRequest,
-            GetVpnPortalInfoRequest, GetWhitelistRequest, ListConnectorRequest,
-            ListForeignNetworkRequest, ListGlobalForeignNetworkRequest, ListMappedListenerRequest,
-            ListPeerRequest, ListPeerResponse, ListPortForwardRequest, ListRouteRequest,
-            ListRouteResponse, ManageMappedListenerRequest, MappedListenerManageAction,
-            MappedListenerManageRpc, MappedListenerManageRpcClientFactory, NodeInfo, PeerManageRpc,
+            GetVpnPortalInfoRequest, GetWhitelistRequest, IcmpProxyRpc, IcmpProxyRpcClientFactory,
+            KillIcmpProxyEntryRequest, KillTcpProxyEntryRequest, KillUdpProxyEntryRequest,
+            ListConnectorRequest, ListForeignNetworkRequest, ListGlobalForeignNetworkRequest,
+            ListMappedListenerRequest, ListPeerRequest, ListPeerResponse, ListPortForwardRequest,
+            ListRouteHistoryRequest, ListRouteRequest, ListRouteResponse,
+            ManageMappedListenerRequest, MappedListenerManageAction, MappedListenerManageRpc,
+            MappedListenerManageRpcClientFactory, NodeInfo, PeerManageRpc,
             PeerManageRpcClientFactory, PortForwardManageRpc, PortForwardManageRpcClientFactory,
-            RemovePortForwardRequest, SetWhitelistRequest, ShowNodeInfoRequest, StatsRpc,
-            StatsRpcClientFactory, TcpProxyEntryState, TcpProxyEntryTransportType, TcpProxyRpc,
-            TcpProxyRpcClientFactory, VpnPortalRpc, VpnPortalRpcClientFactory,
+            RemovePortForwardRequest, RouteChangeType, SetWhitelistRequest, ShowNodeInfoRequest,
+            StatsRpc, StatsRpcClientFactory, TcpProxyEntry, TcpProxyEntryState,
+            TcpProxyEntryTransportType, TcpProxyRpc, TcpProxyRpcClientFactory, UdpProxyRpc,
+            UdpProxyRpcClientFactory, VpnPortalRpc, VpnPortalRpcClientFactory,
         },
-        common::{NatType, SocketType},
+        common::{NatType, SocketType, Void},
+        magic_dns::{MagicDnsServerRpc, MagicDnsServerRpcClientFactory},
         peer_rpc::{GetGlobalPeerMapRequest, PeerCenterRpc, PeerCenterRpcClientFactory},
         rpc_impl::standalone::StandAloneClient,
         rpc_types::controller::BaseController,
@@ -95,8 +99,14 @@ enum SubCommand {
     Node(NodeArgs),
     #[command(about = "manage easytier-core as a system service")]
     Service(ServiceArgs),
-    #[command(about = "show tcp/kcp proxy status")]
-    Proxy,
+    #[command(
+        about = "show services advertised by nodes in the network (named mesh-service because `service` manages easytier-core as a system service)"
+    )]
+    MeshService(MeshServiceArgs),
+    #[command(
+        about = "show tcp/kcp/quic proxy status, list and kill tcp/udp/icmp proxy entries with subcommands"
+    )]
+    Proxy(ProxyArgs),
     #[command(about = "show ACL rules statistics")]
     Acl(AclArgs),
     #[command(about = "manage port forwarding")]
@@ -105,6 +115,8 @@ enum SubCommand {
     Whitelist(WhitelistArgs),
     #[command(about = "show statistics information")]
     Stats(StatsArgs),
+    #[command(about = "show magic dns names of nodes")]
+    Dns,
     #[command(about = t!("core_clap.generate_completions").to_string())]
     GenAutocomplete { shell: Shell },
 }
@@ -140,6 +152,8 @@ struct RouteArgs {
 enum RouteSubCommand {
     List,
     Dump,
+    /// show recent route changes (next hop, cost and proxy cidrs)
+    History,
 }
 
 #[derive(Args, Debug)]
@@ -202,6 +216,31 @@ enum AclSubCommand {
     Stats,
 }
 
+#[derive(Args, Debug)]
+struct ProxyArgs {
+    #[command(subcommand)]
+    sub_command: Option<ProxySubCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum ProxySubCommand {
+    /// List proxy entries
+    List {
+        #[arg(help = "Protocol (tcp/udp/icmp), all protocols if not set")]
+        protocol: Option<String>,
+    },
+    /// Kill proxy entries of a source. A udp or icmp flow that keeps sending
+    /// gets a new entry with its next packet
+    Kill {
+        #[arg(help = "Protocol (tcp/udp/icmp)")]
+        protocol: String,
+        #[arg(help = "Source address (e.g., 10.1.1.1:5000, or 10.1.1.1 for icmp)")]
+        src: String,
+        #[arg(help = "Optional destination address, not supported for udp (e.g., 10.1.1.2:80)")]
+        dst: Option<String>,
+    },
+}
+
 #[derive(Args, Debug)]
 struct PortForwardArgs {
     #[command(subcommand)]
@@ -295,6 +334,18 @@ enum ServiceSubCommand {
     Stop,
 }
 
+#[derive(Args, Debug)]
+struct MeshServiceArgs {
+    #[command(subcommand)]
+    sub_command: Option<MeshServiceSubCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum MeshServiceSubCommand {
+    /// List services advertised by nodes in the network
+    List,
+}
+
 #[derive(Args, Debug)]
 struct InstallArgs {
     #[arg(long, default_value = env!("CARGO_PKG_DESCRIPTION"), help = "service description")]
@@ -333,6 +384,8 @@ struct CommandHandler<'a> {
 
 type RpcClient = StandAloneClient<TcpTunnelConnector>;
 
+const TCP_PROXY_CLIENT_TYPES: [&str; 5] = ["tcp", "kcp_src", "kcp_dst", "quic_src", "quic_dst"];
+
 impl CommandHandler<'_> {
     async fn get_peer_manager_client(
         &self,
@@ -419,6 +472,30 @@ impl CommandHandler<'_> {
             .with_context(|| "failed to get vpn portal client")?)
     }
 
+    async fn get_udp_proxy_client(
+        &self,
+    ) -> Result<Box<dyn UdpProxyRpc<Controller = BaseController>>, Error> {
+        Ok(self
+            .client
+            .lock()
+            .await
+            .scoped_client::<UdpProxyRpcClientFactory<BaseController>>("".to_string())
+            .await
+            .with_context(|| "failed to get udp proxy client")?)
+    }
+
+    async fn get_icmp_proxy_client(
+        &self,
+    ) -> Result<Box<dyn IcmpProxyRpc<Controller = BaseController>>, Error> {
+        Ok(self
+            .client
+            .lock()
+            .await
+            .scoped_client::<IcmpProxyRpcClientFactory<BaseController>>("".to_string())
+            .await
+            .with_context(|| "failed to get icmp proxy client")?)
+    }
+
     async fn get_port_forward_manager_client(
         &self,
     ) -> Result<Box<dyn PortForwardManageRpc<Controller = BaseController>>, Error> {
@@ -625,6 +702,93 @@ impl CommandHandler<'_> {
         Ok(())
     }
 
+    async fn handle_route_history(&self) -> Result<(), Error> {
+        #[derive(tabled::Tabled, serde::Serialize)]
+        struct RouteHistoryItem {
+            time: String,
+            dst: String,
+            change: String,
+            old: String,
+            new: String,
+        }
+
+        let client = self.get_peer_manager_client().await?;
+        let events = client
+            .list_route_history(
+                BaseController::default(),
+                ListRouteHistoryRequest::default(),
+            )
+            .await?
+            .events;
+
+        if self.verbose {
+            println!("{}", serde_json::to_string_pretty(&events)?);
+            return Ok(());
+        }
+
+        let hostnames = self
+            .list_routes()
+            .await?
+            .routes
+            .into_iter()
+            .map(|r| (r.peer_id, r.hostname))
+            .collect::<std::collections::HashMap<_, _>>();
+        let format_next_hop = |peer_id: Option<u32>| match peer_id {
+            None => "unreachable".to_string(),
+            Some(peer_id) => match hostnames.get(&peer_id) {
+                Some(hostname) => format!("{}({})", hostname, peer_id),
+                None => peer_id.to_string(),
+            },
+        };
+
+        let items = events
+            .iter()
+            .rev()
+            .map(|e| {
+                let (change, old, new) = match e.change_type() {
+                    RouteChangeType::NextHopChanged => (
+                        "next_hop",
+                        format!(
+                            "{} cost: {}",
+                            format_next_hop(e.old_next_hop_peer_id),
+                            e.old_cost
+                        ),
+                        format!(
+                            "{} cost: {}",
+                            format_next_hop(e.new_next_hop_peer_id),
+                            e.new_cost
+                        ),
+                    ),
+                    RouteChangeType::CostChanged => {
+                        ("cost", e.old_cost.to_string(), e.new_cost.to_string())
+                    }
+                    RouteChangeType::ProxyCidrsChanged => (
+                        "proxy_cidrs",
+                        e.old_proxy_cidrs.join(","),
+                        e.new_proxy_cidrs.join(","),
+                    ),
+                };
+                RouteHistoryItem {
+                    time: chrono::DateTime::<chrono::Utc>::from_timestamp_millis(
+                        (e.time * 1000) as i64,
+                    )
+                    .unwrap()
+                    .with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string(),
+                    dst: format!("{}({})", e.dst_hostname, e.dst_peer_id),
+                    change: change.to_string(),
+                    old,
+                    new,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        print_output(&items, self.output_format)?;
+
+        Ok(())
+    }
+
     async fn handle_foreign_network_list(&self) -> Result<(), Error> {
         let client = self.get_peer_manager_client().await?;
         let request = ListForeignNetworkRequest::default();
@@ -713,6 +877,7 @@ impl CommandHandler<'_> {
             path_len_lat_first: i32,
             path_latency_lat_first: i32,
 
+            area: String,
             version: String,
         }
 
@@ -757,6 +922,7 @@ impl CommandHandler<'_> {
             path_len_lat_first: 0,
             path_latency_lat_first: 0,
 
+            area: node_info.area_id.to_string(),
             version: node_info.version.clone(),
         });
         for p in peer_routes.iter() {
@@ -826,6 +992,11 @@ impl CommandHandler<'_> {
                 path_latency_lat_first: route.path_latency_latency_first.unwrap_or_default(),
                 path_len_lat_first: route.cost_latency_first.unwrap_or_default(),
 
+                area: if route.is_area_border {
+                    format!("{} (border)", route.area_id)
+                } else {
+                    route.area_id.to_string()
+                },
                 version: if route.version.is_empty() {
                     "unknown".to_string()
                 } else {
@@ -839,6 +1010,116 @@ impl CommandHandler<'_> {
         Ok(())
     }
 
+    async fn handle_dns_list(&self) -> Result<(), Error> {
+        #[derive(tabled::Tabled, serde::Serialize)]
+        struct DnsTableItem {
+            hostname: String,
+            dns_names: String,
+            ipv4: String,
+            inst_id: String,
+            conflict: bool,
+        }
+
+        // names are assigned by the magic dns server shared by the local
+        // instances, ask it instead of guessing from the routes of this one
+        let mut rpc_client = RpcClient::new(TcpTunnelConnector::new(
+            MAGIC_DNS_INSTANCE_ADDR.parse().unwrap(),
+        ));
+        let client = rpc_client
+            .scoped_client::<MagicDnsServerRpcClientFactory<BaseController>>("".to_string())
+            .await
+            .with_context(|| "failed to connect to the magic dns server, is it enabled?")?;
+        let zones = client
+            .get_dns_host_names(BaseController::default(), Void::default())
+            .await
+            .with_context(|| "failed to get dns host names from the magic dns server")?
+            .hosts;
+
+        let items = zones
+            .into_values()
+            .flat_map(|list| list.hosts)
+            .map(|host| DnsTableItem {
+                hostname: host.hostname,
+                dns_names: host
+                    .names
+                    .iter()
+                    .map(|name| name.trim_end_matches('.'))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                ipv4: host.ipv4_addr.map(|ip| ip.to_string()).unwrap_or_default(),
+                inst_id: if host.inst_id.is_empty() {
+                    host.peer_id.to_string()
+                } else {
+                    host.inst_id
+                },
+                conflict: host.conflicted,
+            })
+            .collect::<Vec<_>>();
+
+        if self.verbose {
+            println!("{}", serde_json::to_string_pretty(&items)?);
+            return Ok(());
+        }
+
+        print_output(&items, self.output_format)?;
+
+        Ok(())
+    }
+
+    async fn handle_service_list(&self) -> Result<(), Error> {
+        #[derive(tabled::Tabled, serde::Serialize)]
+        struct ServiceTableItem {
+            service: String,
+            port: u32,
+            hostname: String,
+            ipv4: String,
+            tags: String,
+        }
+
+        let client = self.get_peer_manager_client().await?;
+        let node_info = client
+            .show_node_info(BaseController::default(), ShowNodeInfoRequest::default())
+            .await?
+            .node_info
+            .ok_or(anyhow::anyhow!("node info not found"))?;
+        let routes = self.list_routes().await?.routes;
+
+        let mut items = vec![];
+        let nodes = std::iter::once((node_info.hostname, node_info.ipv4_addr, node_info.services))
+            .chain(routes.into_iter().map(|route| {
+                (
+                    route.hostname,
+                    route.ipv4_addr.map(|ip| ip.to_string()).unwrap_or_default(),
+                    route.services,
+                )
+            }));
+        for (hostname, ipv4, services) in nodes {
+            for service in services {
+                items.push(ServiceTableItem {
+                    service: format!("_{}._{}", service.name, service.protocol),
+                    port: service.port,
+                    hostname: hostname.clone(),
+                    ipv4: ipv4.clone(),
+                    tags: service.tags.join(", "),
+                });
+            }
+        }
+        items.sort_by(|a, b| {
+            a.service
+                .cmp(&b.service)
+                .then_with(|| a.hostname.cmp(&b.hostname))
+        });
+
+        if self.verbose {
+            println!("{}", serde_json::to_string_pretty(&items)?);
+            return Ok(());
+        }
+
+        print_output(&items, self.output_format)?;
+
+        Ok(())
+    }
+
     async fn handle_connector_list(&self) -> Result<(), Error> {
         let client = self.get_connector_manager_client().await?;
         let request = ListConnectorRequest::default();
@@ -1044,6 +1325,263 @@ impl CommandHandler<'_> {
         Ok(())
     }
 
+    async fn list_tcp_proxy_entries(&self) -> Result<Vec<TcpProxyEntry>, Error> {
+        let mut entries = vec![];
+        for client_type in TCP_PROXY_CLIENT_TYPES {
+            let client = self.get_tcp_proxy_client(client_type).await?;
+            let ret = client
+                .list_tcp_proxy_entry(BaseController::default(), Default::default())
+                .await;
+            entries.extend(ret.unwrap_or_default().entries);
+        }
+        Ok(entries)
+    }
+
+    async fn handle_proxy_list(&self, protocol: Option<&str>) -> Result<(), Error> {
+        if let Some(protocol) = protocol {
+            if !["tcp", "udp", "icmp"].contains(&protocol) {
+                return Err(anyhow::anyhow!("Protocol must be 'tcp', 'udp' or 'icmp'"));
+            }
+        }
+        let show = |p: &str| protocol.is_none() || protocol == Some(p);
+
+        let tcp_entries = if show("tcp") {
+            self.list_tcp_proxy_entries().await?
+        } else {
+            vec![]
+        };
+        let udp_entries = if show("udp") {
+            let client = self.get_udp_proxy_client().await?;
+            let ret = client
+                .list_udp_proxy_entry(BaseController::default(), Default::default())
+                .await;
+            ret.unwrap_or_default().entries
+        } else {
+            vec![]
+        };
+        let icmp_entries = if show("icmp") {
+            let client = self.get_icmp_proxy_client().await?;
+            let ret = client
+                .list_icmp_proxy_entry(BaseController::default(), Default::default())
+                .await;
+            ret.unwrap_or_default().entries
+        } else {
+            vec![]
+        };
+
+        if self.verbose {
+            // one protocol is printed as an array, like `proxy` without subcommand
+            match protocol {
+                Some("tcp") => println!("{}", serde_json::to_string_pretty(&tcp_entries)?),
+                Some("udp") => println!("{}", serde_json::to_string_pretty(&udp_entries)?),
+                Some("icmp") => println!("{}", serde_json::to_string_pretty(&icmp_entries)?),
+                _ => {
+                    let ret = serde_json::json!({
+                        "tcp": tcp_entries,
+                        "udp": udp_entries,
+                        "icmp": icmp_entries,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&ret)?);
+                }
+            }
+            return Ok(());
+        }
+
+        #[derive(tabled::Tabled, serde::Serialize)]
+        struct TcpTableItem {
+            src: String,
+            dst: String,
+            start_time: String,
+            state: String,
+            transport_type: String,
+        }
+
+        #[derive(tabled::Tabled, serde::Serialize)]
+        struct UdpTableItem {
+            src: String,
+            dst: String,
+            mapped: String,
+            age: String,
+            idle: String,
+            tx: String,
+            rx: String,
+            full_cone: bool,
+            port_restricted: bool,
+        }
+
+        #[derive(tabled::Tabled, serde::Serialize)]
+        struct IcmpTableItem {
+            src: String,
+            dst: String,
+            icmp_id: u32,
+            age: String,
+            idle: String,
+            tx: String,
+            rx: String,
+        }
+
+        let now = chrono::Local::now().timestamp() as u64;
+        let secs_since = |t: u64| format!("{}s", now.saturating_sub(t));
+        let traffic = |packets: u64, bytes: u64| {
+            format!(
+                "{} pkts / {}",
+                packets,
+                format_size(bytes, humansize::DECIMAL)
+            )
+        };
+
+        let tcp_rows = tcp_entries
+            .iter()
+            .map(|e| TcpTableItem {
+                src: SocketAddr::from(e.src.unwrap_or_default()).to_string(),
+                dst: SocketAddr::from(e.dst.unwrap_or_default()).to_string(),
+                start_time: chrono::DateTime::<chrono::Utc>::from_timestamp_millis(
+                    (e.start_time * 1000) as i64,
+                )
+                .unwrap()
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+                state: format!("{:?}", TcpProxyEntryState::try_from(e.state).unwrap()),
+                transport_type: format!(
+                    "{:?}",
+                    TcpProxyEntryTransportType::try_from(e.transport_type).unwrap()
+                ),
+            })
+            .collect::<Vec<_>>();
+
+        let udp_rows = udp_entries
+            .iter()
+            .map(|e| UdpTableItem {
+                src: SocketAddr::from(e.src.unwrap_or_default()).to_string(),
+                dst: SocketAddr::from(e.dst.unwrap_or_default()).to_string(),
+                mapped: SocketAddr::from(e.mapped.unwrap_or_default()).to_string(),
+                age: secs_since(e.start_time),
+                idle: secs_since(e.last_active_time),
+                tx: traffic(e.tx_packets, e.tx_bytes),
+                rx: traffic(e.rx_packets, e.rx_bytes),
+                full_cone: e.full_cone,
+                port_restricted: e.port_restricted,
+            })
+            .collect::<Vec<_>>();
+
+        let icmp_rows = icmp_entries
+            .iter()
+            .map(|e| IcmpTableItem {
+                src: e.src.map(|ip| ip.to_string()).unwrap_or_default(),
+                dst: e.dst.map(|ip| ip.to_string()).unwrap_or_default(),
+                icmp_id: e.icmp_id,
+                age: secs_since(e.start_time),
+                idle: secs_since(e.last_active_time),
+                tx: traffic(e.tx_packets, e.tx_bytes),
+                rx: traffic(e.rx_packets, e.rx_bytes),
+            })
+            .collect::<Vec<_>>();
+
+        match protocol {
+            Some("tcp") => print_output(&tcp_rows, self.output_format)?,
+            Some("udp") => print_output(&udp_rows, self.output_format)?,
+            Some("icmp") => print_output(&icmp_rows, self.output_format)?,
+            _ if *self.output_format == OutputFormat::Json => {
+                let ret = serde_json::json!({
+                    "tcp": tcp_rows,
+                    "udp": udp_rows,
+                    "icmp": icmp_rows,
+                });
+                println!("{}", serde_json::to_string_pretty(&ret)?);
+            }
+            _ => {
+                println!("TCP:");
+                print_output(&tcp_rows, self.output_format)?;
+                println!("UDP:");
+                print_output(&udp_rows, self.output_format)?;
+                println!("ICMP:");
+                print_output(&icmp_rows, self.output_format)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_proxy_kill(
+        &self,
+        protocol: &str,
+        src: &str,
+        dst: Option<&str>,
+    ) -> Result<(), Error> {
+        let killed = match protocol {
+            "tcp" => {
+                let src: SocketAddr = src
+                    .parse()
+                    .with_context(|| format!("Invalid source address: {}", src))?;
+                let dst = dst
+                    .map(|dst| {
+                        dst.parse::<SocketAddr>()
+                            .with_context(|| format!("Invalid destination address: {}", dst))
+                    })
+                    .transpose()?;
+                let request = KillTcpProxyEntryRequest {
+                    src: Some(src.into()),
+                    dst: dst.map(Into::into),
+                };
+                let mut killed = 0;
+                for client_type in TCP_PROXY_CLIENT_TYPES {
+                    let client = self.get_tcp_proxy_client(client_type).await?;
+                    let ret = client
+                        .kill_tcp_proxy_entry(BaseController::default(), request.clone())
+                        .await;
+                    killed += ret.map(|r| r.killed).unwrap_or_default();
+                }
+                killed
+            }
+            "udp" => {
+                if dst.is_some() {
+                    return Err(anyhow::anyhow!(
+                        "udp entries can only be killed by source address"
+                    ));
+                }
+                let src: SocketAddr = src
+                    .parse()
+                    .with_context(|| format!("Invalid source address: {}", src))?;
+                let client = self.get_udp_proxy_client().await?;
+                let request = KillUdpProxyEntryRequest {
+                    src: Some(src.into()),
+                };
+                client
+                    .kill_udp_proxy_entry(BaseController::default(), request)
+                    .await?
+                    .killed
+            }
+            "icmp" => {
+                let src: std::net::Ipv4Addr = src
+                    .parse()
+                    .with_context(|| format!("Invalid source address: {}", src))?;
+                let dst = dst
+                    .map(|dst| {
+                        dst.parse::<std::net::Ipv4Addr>()
+                            .with_context(|| format!("Invalid destination address: {}", dst))
+                    })
+                    .transpose()?;
+                let client = self.get_icmp_proxy_client().await?;
+                let request = KillIcmpProxyEntryRequest {
+                    src: Some(src.into()),
+                    dst: dst.map(Into::into),
+                };
+                client
+                    .kill_icmp_proxy_entry(BaseController::default(), request)
+                    .await?
+                    .killed
+            }
+            _ => {
+                return Err(anyhow::anyhow!("Protocol must be 'tcp', 'udp' or 'icmp'"));
+            }
+        };
+        println!("Killed {} {} proxy entries", killed, protocol);
+        if protocol != "tcp" && killed > 0 {
+            println!("The next packet of a killed flow creates a new entry");
+        }
+        Ok(())
+    }
+
     async fn handle_whitelist_set_tcp(&self, ports: &str) -> Result<(), Error> {
         let tcp_ports = Self::parse_port_list(ports)?;
         let client = self.get_acl_manager_client().await?;
@@ -1540,6 +2078,7 @@ async fn main() -> Result<(), Error> {
         SubCommand::Route(route_args) => match route_args.sub_command {
             Some(RouteSubCommand::List) | None => handler.handle_route_list().await?,
             Some(RouteSubCommand::Dump) => handler.handle_route_dump().await?,
+            Some(RouteSubCommand::History) => handler.handle_route_history().await?,
         },
         SubCommand::Stun => {
             timeout(Duration::from_secs(25), async move {
@@ -1725,6 +2264,7 @@ async fn main() -> Result<(), Error> {
                         node_info.proxy_cidrs.join(", ").as_str(),
                     ]);
                     builder.push_record(vec!["Peer ID", node_info.peer_id.to_string().as_str()]);
+                    builder.push_record(vec!["Area ID", node_info.area_id.to_string().as_str()]);
                     stun_info.public_ip.iter().for_each(|ip| {
                         let Ok(ip) = ip.parse::<IpAddr>() else {
                             return;
@@ -1759,6 +2299,14 @@ async fn main() -> Result<(), Error> {
                 }
             }
         }
+        SubCommand::Dns => {
+            handler.handle_dns_list().await?;
+        }
+        SubCommand::MeshService(mesh_service_args) => match mesh_service_args.sub_command {
+            Some(MeshServiceSubCommand::List) | None => {
+                handler.handle_service_list().await?;
+            }
+        },
         SubCommand::Service(service_args) => {
             let service = Service::new(service_args.name)?;
             match service_args.sub_command {
@@ -1833,53 +2381,20 @@ async fn main() -> Result<(), Error> {
                 }
             }
         }
-        SubCommand::Proxy => {
-            let mut entries = vec![];
-
-            for client_type in &["tcp", "kcp_src", "kcp_dst", "quic_src", "quic_dst"] {
-                let client = handler.get_tcp_proxy_client(client_type).await?;
-                let ret = client
-                    .list_tcp_proxy_entry(BaseController::default(), Default::default())
-                    .await;
-                entries.extend(ret.unwrap_or_default().entries);
+        SubCommand::Proxy(proxy_args) => match &proxy_args.sub_command {
+            Some(ProxySubCommand::List { protocol }) => {
+                handler.handle_proxy_list(protocol.as_deref()).await?;
             }
-
-            if cli.verbose {
-                println!("{}", serde_json::to_string_pretty(&entries)?);
-                return Ok(());
+            Some(ProxySubCommand::Kill { protocol, src, dst }) => {
+                handler
+                    .handle_proxy_kill(protocol, src, dst.as_deref())
+                    .await?;
             }
-
-            #[derive(tabled::Tabled, serde::Serialize)]
-            struct TableItem {
-                src: String,
-                dst: String,
-                start_time: String,
-                state: String,
-                transport_type: String,
+            // the output of `proxy` stays the tcp entries only
+            None => {
+                handler.handle_proxy_list(Some("tcp")).await?;
             }
-
-            let table_rows = entries
-                .iter()
-                .map(|e| TableItem {
-                    src: SocketAddr::from(e.src.unwrap_or_default()).to_string(),
-                    dst: SocketAddr::from(e.dst.unwrap_or_default()).to_string(),
-                    start_time: chrono::DateTime::<chrono::Utc>::from_timestamp_millis(
-                        (e.start_time * 1000) as i64,
-                    )
-                    .unwrap()
-                    .with_timezone(&chrono::Local)
-                    .format("%Y-%m-%d %H:%M:%S")
-                    .to_string(),
-                    state: format!("{:?}", TcpProxyEntryState::try_from(e.state).unwrap()),
-                    transport_type: format!(
-                        "{:?}",
-                        TcpProxyEntryTransportType::try_from(e.transport_type).unwrap()
-                    ),
-                })
-                .collect::<Vec<_>>();
-
-            print_output(&table_rows, &cli.output_format)?;
-        }
+        },
         SubCommand::Acl(acl_args) => match &acl_args.sub_command {
             Some(AclSubCommand::Stats) | None => {
                 handler.handle_acl_stats().await?;
@@ -18,8 +18,9 @@ use clap_complete::Shell;
 use easytier::{
     common::{
         config::{
-            get_avaliable_encrypt_methods, ConfigLoader, ConsoleLoggerConfig, FileLoggerConfig,
-            LoggingConfigLoader, NetworkIdentity, PeerConfig, PortForwardConfig, TomlConfigLoader,
+            get_avaliable_encrypt_methods, ConfigLoader, ConsoleLoggerConfig, DnsForwardConfig,
+            DnsRecordConfig, FileLoggerConfig, LoggingConfigLoader, NetworkIdentity, PeerConfig,
+            PortForwardConfig, ServiceConfig, TomlConfigLoader, UdpProxyTimeoutConfig,
             VpnPortalConfig,
         },
         constants::EASYTIER_VERSION,
@@ -31,7 +32,7 @@ use easytier::{
     instance_manager::NetworkInstanceManager,
     launcher::{add_proxy_network_to_config, ConfigSource},
     proto::common::{CompressionAlgoPb, NatType},
-    tunnel::{IpVersion, PROTO_PORT_OFFSET},
+    tunnel::{upstream_proxy::check_upstream_proxy_url, IpVersion, PROTO_PORT_OFFSET},
     utils::{init_logger, setup_panic_handler},
     web_client,
 };
@@ -394,6 +395,29 @@ struct NetworkOptions {
     )]
     manual_routes: Option<Vec<String>>,
 
+    #[arg(
+        long,
+        env = "ET_KERNEL_ROUTE_IMPORT_PROTOCOLS",
+        value_delimiter = ',',
+        help = t!("core_clap.kernel_route_import_protocols").to_string(),
+        num_args = 0..
+    )]
+    kernel_route_import_protocols: Option<Vec<String>>,
+
+    #[arg(
+        long,
+        env = "ET_KERNEL_ROUTE_IMPORT_TABLE",
+        help = t!("core_clap.kernel_route_import_table").to_string()
+    )]
+    kernel_route_import_table: Option<u32>,
+
+    #[arg(
+        long,
+        env = "ET_KERNEL_ROUTE_EXPORT_TABLE",
+        help = t!("core_clap.kernel_route_export_table").to_string()
+    )]
+    kernel_route_export_table: Option<u32>,
+
     // if not in relay_network_whitelist:
     // for foreign virtual network, will refuse the incoming connection
     // for local virtual network, will refuse relaying tun packet
@@ -450,6 +474,13 @@ struct NetworkOptions {
     )]
     socks5: Option<u16>,
 
+    #[arg(
+        long,
+        env = "ET_UPSTREAM_PROXY",
+        help = t!("core_clap.upstream_proxy").to_string()
+    )]
+    upstream_proxy: Option<String>,
+
     #[arg(
         long,
         env = "ET_COMPRESSION",
@@ -516,6 +547,46 @@ struct NetworkOptions {
     )]
     accept_dns: Option<bool>,
 
+    #[arg(
+        long,
+        env = "ET_DNS_RECORD",
+        help = t!("core_clap.dns_record").to_string(),
+        num_args = 1..
+    )]
+    dns_record: Vec<String>,
+
+    #[arg(
+        long,
+        env = "ET_DNS_FORWARD",
+        help = t!("core_clap.dns_forward").to_string(),
+        num_args = 1..
+    )]
+    dns_forward: Vec<String>,
+
+    #[arg(
+        long,
+        env = "ET_DNS_UPSTREAM",
+        help = t!("core_clap.dns_upstream").to_string(),
+        num_args = 1..
+    )]
+    dns_upstream: Vec<String>,
+
+    #[arg(
+        long,
+        env = "ET_SERVICE",
+        help = t!("core_clap.service").to_string(),
+        num_args = 1..
+    )]
+    service: Vec<String>,
+
+    #[arg(
+        long,
+        env = "ET_UDP_PROXY_TIMEOUT",
+        help = t!("core_clap.udp_proxy_timeout").to_string(),
+        num_args = 1..
+    )]
+    udp_proxy_timeout: Vec<String>,
+
     #[arg(
         long,
         env = "ET_PRIVATE_MODE",
@@ -581,6 +652,88 @@ struct NetworkOptions {
         num_args = 0..
     )]
     stun_servers_v6: Option<Vec<String>>,
+
+    #[arg(
+        long,
+        env = "ET_LINK_PROBE_INTERVAL_MS",
+        help = t!("core_clap.link_probe_interval_ms").to_string(),
+    )]
+    link_probe_interval_ms: Option<u32>,
+
+    #[arg(
+        long,
+        env = "ET_LINK_PROBE_MULTIPLIER",
+        help = t!("core_clap.link_probe_multiplier").to_string(),
+    )]
+    link_probe_multiplier: Option<u32>,
+
+    #[arg(
+        long,
+        env = "ET_AREA_ID",
+        help = t!("core_clap.area_id").to_string(),
+    )]
+    area_id: Option<u32>,
+
+    #[arg(
+        long,
+        env = "ET_ENABLE_TCP_HOLE_PUNCHING",
+        help = t!("core_clap.enable_tcp_hole_punching").to_string(),
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    enable_tcp_hole_punching: Option<bool>,
+
+    #[arg(
+        long,
+        env = "ET_ENABLE_FEC",
+        help = t!("core_clap.enable_fec").to_string(),
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    enable_fec: Option<bool>,
+
+    #[arg(
+        long,
+        env = "ET_ENABLE_PORT_MAPPING",
+        help = t!("core_clap.enable_port_mapping").to_string(),
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    enable_port_mapping: Option<bool>,
+
+    #[arg(
+        long,
+        env = "ET_ENABLE_TUN_OFFLOAD",
+        help = t!("core_clap.enable_tun_offload").to_string(),
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    enable_tun_offload: Option<bool>,
+
+    #[arg(
+        long,
+        env = "ET_TUN_QUEUES",
+        help = t!("core_clap.tun_queues").to_string()
+    )]
+    tun_queues: Option<u32>,
+
+    #[arg(
+        long,
+        env = "ET_UDP_PROXY_FULL_CONE",
+        help = t!("core_clap.udp_proxy_full_cone").to_string(),
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    udp_proxy_full_cone: Option<bool>,
+
+    #[arg(
+        long,
+        env = "ET_UDP_PROXY_PORT_RESTRICTED",
+        help = t!("core_clap.udp_proxy_port_restricted").to_string(),
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    udp_proxy_port_restricted: Option<bool>,
 }
 
 #[derive(Parser, Debug)]
@@ -832,6 +985,23 @@ impl NetworkOptions {
             cfg.set_routes(Some(routes));
         }
 
+        if self.kernel_route_import_protocols.is_some()
+            || self.kernel_route_import_table.is_some()
+            || self.kernel_route_export_table.is_some()
+        {
+            let mut kernel_route = cfg.get_kernel_route_config().unwrap_or_default();
+            if let Some(protocols) = &self.kernel_route_import_protocols {
+                kernel_route.import_protocols = protocols.clone();
+            }
+            if let Some(table) = self.kernel_route_import_table {
+                kernel_route.import_table = Some(table);
+            }
+            if let Some(table) = self.kernel_route_export_table {
+                kernel_route.export_table = Some(table);
+            }
+            cfg.set_kernel_route_config(Some(kernel_route));
+        }
+
         #[cfg(feature = "socks5")]
         if let Some(socks5_proxy) = self.socks5 {
             cfg.set_socks5_portal(Some(
@@ -841,6 +1011,14 @@ impl NetworkOptions {
             ));
         }
 
+        if let Some(upstream_proxy) = &self.upstream_proxy {
+            let proxy: url::Url = upstream_proxy
+                .parse()
+                .with_context(|| format!("failed to parse upstream proxy: {}", upstream_proxy))?;
+            check_upstream_proxy_url(&proxy)?;
+            cfg.set_upstream_proxy(Some(proxy));
+        }
+
         #[cfg(feature = "socks5")]
         for port_forward in self.port_forward.iter() {
             let example_str = ", example: udp://0.0.0.0:12345/10.126.126.1:12345";
@@ -875,6 +1053,50 @@ impl NetworkOptions {
             cfg.set_port_forwards(old);
         }
 
+        if !self.dns_record.is_empty() {
+            let mut records = cfg.get_dns_records();
+            for record in self.dns_record.iter() {
+                records.push(record.parse::<DnsRecordConfig>()?);
+            }
+            cfg.set_dns_records(records);
+        }
+
+        if !self.dns_forward.is_empty() {
+            let mut forwards = cfg.get_dns_forwards();
+            for forward in self.dns_forward.iter() {
+                forwards.push(forward.parse::<DnsForwardConfig>()?);
+            }
+            cfg.set_dns_forwards(forwards);
+        }
+
+        if !self.dns_upstream.is_empty() {
+            let mut upstreams = cfg.get_dns_upstreams();
+            for upstream in self.dns_upstream.iter() {
+                upstreams.push(
+                    upstream
+                        .parse()
+                        .with_context(|| format!("failed to parse dns upstream: {}", upstream))?,
+                );
+            }
+            cfg.set_dns_upstreams(upstreams);
+        }
+
+        if !self.service.is_empty() {
+            let mut services = cfg.get_services();
+            for service in self.service.iter() {
+                services.push(service.parse::<ServiceConfig>()?);
+            }
+            cfg.set_services(services);
+        }
+
+        if !self.udp_proxy_timeout.is_empty() {
+            let mut timeouts = cfg.get_udp_proxy_timeouts();
+            for timeout in self.udp_proxy_timeout.iter() {
+                timeouts.push(timeout.parse::<UdpProxyTimeoutConfig>()?);
+            }
+            cfg.set_udp_proxy_timeouts(timeouts);
+        }
+
         let mut f = cfg.get_flags();
         if let Some(default_protocol) = &self.default_protocol {
             f.default_protocol = default_protocol.clone()
@@ -937,6 +1159,24 @@ impl NetworkOptions {
             .enable_relay_foreign_network_kcp
             .unwrap_or(f.enable_relay_foreign_network_kcp);
         f.disable_sym_hole_punching = self.disable_sym_hole_punching.unwrap_or(false);
+        f.link_probe_interval_ms = self
+            .link_probe_interval_ms
+            .unwrap_or(f.link_probe_interval_ms);
+        f.link_probe_multiplier = self
+            .link_probe_multiplier
+            .unwrap_or(f.link_probe_multiplier);
+        f.area_id = self.area_id.unwrap_or(f.area_id);
+        f.enable_tcp_hole_punching = self
+            .enable_tcp_hole_punching
+            .unwrap_or(f.enable_tcp_hole_punching);
+        f.enable_fec = self.enable_fec.unwrap_or(f.enable_fec);
+        f.enable_port_mapping = self.enable_port_mapping.unwrap_or(f.enable_port_mapping);
+        f.enable_tun_offload = self.enable_tun_offload.unwrap_or(f.enable_tun_offload);
+        f.tun_queues = self.tun_queues.unwrap_or(f.tun_queues);
+        f.udp_proxy_full_cone = self.udp_proxy_full_cone.unwrap_or(f.udp_proxy_full_cone);
+        f.udp_proxy_port_restricted = self
+            .udp_proxy_port_restricted
+            .unwrap_or(f.udp_proxy_port_restricted);
         cfg.set_flags(f);
 
         if !self.exit_nodes.is_empty() {
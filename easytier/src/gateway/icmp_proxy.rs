@@ -1,12 +1,16 @@
 use std::{
     mem::MaybeUninit,
     net::{IpAddr, Ipv4Addr, SocketAddrV4},
-    sync::{Arc, Weak},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
     thread,
     time::Duration,
 };
 
 use anyhow::Context;
+use crossbeam::atomic::AtomicCell;
 use pnet::packet::{
     icmp::{self, echo_reply::MutableEchoReplyPacket, IcmpCode, IcmpTypes, MutableIcmpPacket},
     ip::IpNextHeaderProtocols,
@@ -25,6 +29,13 @@ use crate::{
     common::{error::Error, global_ctx::ArcGlobalCtx, PeerId},
     gateway::ip_reassembler::ComposeIpv4PacketArgs,
     peers::{peer_manager::PeerManager, PeerPacketFilter},
+    proto::{
+        cli::{
+            IcmpProxyEntry, IcmpProxyRpc, KillIcmpProxyEntryRequest, KillIcmpProxyEntryResponse,
+            ListIcmpProxyEntryRequest, ListIcmpProxyEntryResponse,
+        },
+        rpc_types::{self, controller::BaseController},
+    },
     tunnel::packet_def::{PacketType, ZCPacket},
 };
 
@@ -33,6 +44,8 @@ use super::{
     CidrSet,
 };
 
+const ICMP_NAT_ENTRY_TIMEOUT: Duration = Duration::from_secs(20);
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 struct IcmpNatKey {
     real_dst_ip: std::net::IpAddr,
@@ -47,6 +60,7 @@ struct IcmpNatEntry {
     src_ip: IpAddr,
     start_time: std::time::Instant,
     mapped_dst_ip: std::net::Ipv4Addr,
+    session: Arc<IcmpSession>,
 }
 
 impl IcmpNatEntry {
@@ -55,6 +69,7 @@ impl IcmpNatEntry {
         my_peer_id: PeerId,
         src_ip: IpAddr,
         mapped_dst_ip: Ipv4Addr,
+        session: Arc<IcmpSession>,
     ) -> Result<Self, Error> {
         Ok(Self {
             src_peer_id,
@@ -62,11 +77,80 @@ impl IcmpNatEntry {
             src_ip,
             start_time: std::time::Instant::now(),
             mapped_dst_ip,
+            session,
         })
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct IcmpSessionKey {
+    src_ip: Ipv4Addr,
+    // destination as addressed by the source
+    dst_ip: Ipv4Addr,
+    icmp_id: u16,
+}
+
+impl IcmpSessionKey {
+    fn matches(&self, src_ip: Ipv4Addr, dst_ip: Option<Ipv4Addr>) -> bool {
+        self.src_ip == src_ip && (dst_ip.is_none() || dst_ip == Some(self.dst_ip))
+    }
+}
+
+/// The echo requests of one ping share a session, the nat entries are per
+/// request and removed once answered.
+#[derive(Debug)]
+struct IcmpSession {
+    start_time_local: chrono::DateTime<chrono::Local>,
+    last_active_time: AtomicCell<std::time::Instant>,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+}
+
+impl IcmpSession {
+    fn new() -> Self {
+        Self {
+            start_time_local: chrono::Local::now(),
+            last_active_time: AtomicCell::new(std::time::Instant::now()),
+            tx_packets: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            rx_packets: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
+        }
+    }
+
+    fn add_tx(&self, len: usize) {
+        self.last_active_time.store(std::time::Instant::now());
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn add_rx(&self, len: usize) {
+        self.last_active_time.store(std::time::Instant::now());
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn parse_as_pb(&self, key: &IcmpSessionKey) -> IcmpProxyEntry {
+        let idle = self.last_active_time.load().elapsed();
+        IcmpProxyEntry {
+            src: Some(key.src_ip.into()),
+            dst: Some(key.dst_ip.into()),
+            icmp_id: key.icmp_id as u32,
+            start_time: self.start_time_local.timestamp() as u64,
+            last_active_time: (chrono::Local::now().timestamp() as u64)
+                .saturating_sub(idle.as_secs()),
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
 type IcmpNatTable = Arc<dashmap::DashMap<IcmpNatKey, IcmpNatEntry>>;
+type IcmpSessionTable = Arc<dashmap::DashMap<IcmpSessionKey, Arc<IcmpSession>>>;
 type NewPacketSender = tokio::sync::mpsc::UnboundedSender<IcmpNatKey>;
 type NewPacketReceiver = tokio::sync::mpsc::UnboundedReceiver<IcmpNatKey>;
 
@@ -79,6 +163,7 @@ pub struct IcmpProxy {
     socket: std::sync::Mutex<Option<Arc<socket2::Socket>>>,
 
     nat_table: IcmpNatTable,
+    sessions: IcmpSessionTable,
 
     tasks: Mutex<JoinSet<()>>,
 
@@ -157,6 +242,7 @@ fn socket_recv_loop(
         };
 
         let payload_len = len - ipv4_packet.get_header_length() as usize * 4;
+        v.session.add_rx(payload_len);
         let id = ipv4_packet.get_identification();
         let _ = compose_ipv4_packet(
             ComposeIpv4PacketArgs {
@@ -206,6 +292,7 @@ impl IcmpProxy {
             socket: std::sync::Mutex::new(None),
 
             nat_table: Arc::new(dashmap::DashMap::new()),
+            sessions: Arc::new(dashmap::DashMap::new()),
             tasks: Mutex::new(JoinSet::new()),
 
             ip_resemmbler: Arc::new(IpReassembler::new(Duration::from_secs(10))),
@@ -250,11 +337,15 @@ impl IcmpProxy {
 
     async fn start_nat_table_cleaner(self: &Arc<Self>) -> Result<(), Error> {
         let nat_table = self.nat_table.clone();
+        let sessions = self.sessions.clone();
         self.tasks.lock().await.spawn(
             async move {
                 loop {
                     tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                    nat_table.retain(|_, v| v.start_time.elapsed().as_secs() < 20);
+                    nat_table.retain(|_, v| v.start_time.elapsed() < ICMP_NAT_ENTRY_TIMEOUT);
+                    sessions.retain(|_, v| {
+                        v.last_active_time.load().elapsed() < ICMP_NAT_ENTRY_TIMEOUT
+                    });
                 }
             }
             .instrument(tracing::info_span!("icmp proxy nat table cleaner")),
@@ -454,11 +545,22 @@ impl IcmpProxy {
             icmp_seq,
         };
 
+        let session = self
+            .sessions
+            .entry(IcmpSessionKey {
+                src_ip: ipv4.get_source(),
+                dst_ip: ipv4.get_destination(),
+                icmp_id,
+            })
+            .or_insert_with(|| Arc::new(IcmpSession::new()))
+            .clone();
+
         let value = IcmpNatEntry::new(
             hdr.from_peer_id.into(),
             hdr.to_peer_id.into(),
             ipv4.get_source().into(),
             ipv4.get_destination(),
+            session.clone(),
         )
         .ok()?;
 
@@ -466,14 +568,50 @@ impl IcmpProxy {
             tracing::info!("icmp nat table entry replaced: {:?}", old);
         }
 
-        if let Err(e) = self.send_icmp_packet(real_dst_ip, &icmp_packet) {
-            tracing::error!("send icmp packet failed: {:?}", e);
+        match self.send_icmp_packet(real_dst_ip, &icmp_packet) {
+            Ok(()) => session.add_tx(icmp_packet.packet().len()),
+            Err(e) => tracing::error!("send icmp packet failed: {:?}", e),
         }
 
         Some(())
     }
 }
 
+impl IcmpProxy {
+    pub fn list_proxy_entries(&self) -> Vec<IcmpProxyEntry> {
+        self.sessions
+            .iter()
+            .map(|item| item.value().parse_as_pb(item.key()))
+            .collect()
+    }
+
+    /// removes the sessions and the pending requests, replies arriving later
+    /// are dropped. the next echo request of a killed ping creates a new
+    /// session.
+    pub fn kill_proxy_entries(&self, src_ip: Ipv4Addr, dst_ip: Option<Ipv4Addr>) -> u32 {
+        let mut killed = 0;
+        self.sessions.retain(|k, _| {
+            let ret = k.matches(src_ip, dst_ip);
+            if ret {
+                killed += 1;
+            }
+            !ret
+        });
+        self.nat_table.retain(|k, v| {
+            let IpAddr::V4(src) = v.src_ip else {
+                return true;
+            };
+            !IcmpSessionKey {
+                src_ip: src,
+                dst_ip: v.mapped_dst_ip,
+                icmp_id: k.icmp_id,
+            }
+            .matches(src_ip, dst_ip)
+        });
+        killed
+    }
+}
+
 impl Drop for IcmpProxy {
     fn drop(&mut self) {
         tracing::info!(
@@ -486,3 +624,47 @@ impl Drop for IcmpProxy {
         }
     }
 }
+
+#[derive(Clone)]
+pub struct IcmpProxyRpcService {
+    icmp_proxy: Weak<IcmpProxy>,
+}
+
+#[async_trait::async_trait]
+impl IcmpProxyRpc for IcmpProxyRpcService {
+    type Controller = BaseController;
+    async fn list_icmp_proxy_entry(
+        &self,
+        _: BaseController,
+        _request: ListIcmpProxyEntryRequest,
+    ) -> std::result::Result<ListIcmpProxyEntryResponse, rpc_types::error::Error> {
+        let mut reply = ListIcmpProxyEntryResponse::default();
+        if let Some(icmp_proxy) = self.icmp_proxy.upgrade() {
+            reply.entries = icmp_proxy.list_proxy_entries();
+        }
+        Ok(reply)
+    }
+
+    async fn kill_icmp_proxy_entry(
+        &self,
+        _: BaseController,
+        request: KillIcmpProxyEntryRequest,
+    ) -> std::result::Result<KillIcmpProxyEntryResponse, rpc_types::error::Error> {
+        let mut reply = KillIcmpProxyEntryResponse::default();
+        let Some(src) = request.src else {
+            return Err(anyhow::anyhow!("src is required").into());
+        };
+        if let Some(icmp_proxy) = self.icmp_proxy.upgrade() {
+            reply.killed = icmp_proxy.kill_proxy_entries(src.into(), request.dst.map(Into::into));
+        }
+        Ok(reply)
+    }
+}
+
+impl IcmpProxyRpcService {
+    pub fn new(icmp_proxy: Arc<IcmpProxy>) -> Self {
+        Self {
+            icmp_proxy: Arc::downgrade(&icmp_proxy),
+        }
+    }
+}
@@ -25,7 +25,7 @@ use tokio::{
     select,
     task::JoinSet,
 };
-use tokio_util::io::InspectReader;
+use tokio_util::{io::InspectReader, sync::CancellationToken};
 
 use super::{
     tcp_proxy::{NatDstConnector, NatDstTcpConnector, TcpProxy},
@@ -41,7 +41,8 @@ use crate::{
     proto::{
         acl::{Action, ChainType, Protocol},
         cli::{
-            ListTcpProxyEntryRequest, ListTcpProxyEntryResponse, TcpProxyEntry, TcpProxyEntryState,
+            KillTcpProxyEntryRequest, KillTcpProxyEntryResponse, ListTcpProxyEntryRequest,
+            ListTcpProxyEntryResponse, TcpProxyEntry, TcpProxyEntryState,
             TcpProxyEntryTransportType, TcpProxyRpc,
         },
         peer_rpc::KcpConnData,
@@ -381,6 +382,8 @@ pub struct KcpProxyDst {
     kcp_endpoint: Arc<KcpEndpoint>,
     peer_manager: Arc<PeerManager>,
     proxy_entries: Arc<DashMap<ConnId, TcpProxyEntry>>,
+    // cancel the stream handling of an entry to kill it
+    proxy_cancels: Arc<DashMap<ConnId, CancellationToken>>,
     cidr_set: Arc<CidrSet>,
     tasks: JoinSet<()>,
 }
@@ -446,6 +449,7 @@ impl KcpProxyDst {
             kcp_endpoint: Arc::new(kcp_endpoint),
             peer_manager,
             proxy_entries: Arc::new(DashMap::new()),
+            proxy_cancels: Arc::new(DashMap::new()),
             cidr_set: Arc::new(cidr_set),
             tasks,
         }
@@ -550,6 +554,7 @@ impl KcpProxyDst {
         let kcp_endpoint = self.kcp_endpoint.clone();
         let global_ctx = self.peer_manager.get_global_ctx().clone();
         let proxy_entries = self.proxy_entries.clone();
+        let proxy_cancels = self.proxy_cancels.clone();
         let cidr_set = self.cidr_set.clone();
         let route = Arc::new(self.peer_manager.get_route());
         self.tasks.spawn(async move {
@@ -560,17 +565,26 @@ impl KcpProxyDst {
 
                 let global_ctx = global_ctx.clone();
                 let proxy_entries = proxy_entries.clone();
+                let proxy_cancels = proxy_cancels.clone();
                 let cidr_set = cidr_set.clone();
                 let route = route.clone();
+                let conn_id = stream.conn_id();
+                let cancel = CancellationToken::new();
+                proxy_cancels.insert(conn_id, cancel.clone());
                 tokio::spawn(async move {
-                    let _ = Self::handle_one_in_stream(
-                        stream,
-                        global_ctx,
-                        proxy_entries,
-                        cidr_set,
-                        route,
-                    )
-                    .await;
+                    select! {
+                        _ = Self::handle_one_in_stream(
+                            stream,
+                            global_ctx,
+                            proxy_entries,
+                            cidr_set,
+                            route,
+                        ) => {}
+                        _ = cancel.cancelled() => {
+                            tracing::info!("kcp proxy stream killed");
+                        }
+                    }
+                    proxy_cancels.remove(&conn_id);
                 });
             }
         });
@@ -588,11 +602,17 @@ impl KcpProxyDst {
 }
 
 #[derive(Clone)]
-pub struct KcpProxyDstRpcService(Weak<DashMap<ConnId, TcpProxyEntry>>);
+pub struct KcpProxyDstRpcService {
+    proxy_entries: Weak<DashMap<ConnId, TcpProxyEntry>>,
+    proxy_cancels: Weak<DashMap<ConnId, CancellationToken>>,
+}
 
 impl KcpProxyDstRpcService {
     pub fn new(kcp_proxy_dst: &KcpProxyDst) -> Self {
-        Self(Arc::downgrade(&kcp_proxy_dst.proxy_entries))
+        Self {
+            proxy_entries: Arc::downgrade(&kcp_proxy_dst.proxy_entries),
+            proxy_cancels: Arc::downgrade(&kcp_proxy_dst.proxy_cancels),
+        }
     }
 }
 
@@ -605,11 +625,36 @@ impl TcpProxyRpc for KcpProxyDstRpcService {
         _request: ListTcpProxyEntryRequest, // Accept request of type HelloRequest
     ) -> std::result::Result<ListTcpProxyEntryResponse, rpc_types::error::Error> {
         let mut reply = ListTcpProxyEntryResponse::default();
-        if let Some(tcp_proxy) = self.0.upgrade() {
+        if let Some(tcp_proxy) = self.proxy_entries.upgrade() {
             for item in tcp_proxy.iter() {
                 reply.entries.push(*item.value());
             }
         }
         Ok(reply)
     }
+
+    async fn kill_tcp_proxy_entry(
+        &self,
+        _: BaseController,
+        request: KillTcpProxyEntryRequest,
+    ) -> std::result::Result<KillTcpProxyEntryResponse, rpc_types::error::Error> {
+        let mut reply = KillTcpProxyEntryResponse::default();
+        let (Some(entries), Some(cancels)) =
+            (self.proxy_entries.upgrade(), self.proxy_cancels.upgrade())
+        else {
+            return Ok(reply);
+        };
+        let conn_ids = entries
+            .iter()
+            .filter(|item| item.value().matches_kill_request(&request))
+            .map(|item| *item.key())
+            .collect::<Vec<_>>();
+        for conn_id in conn_ids {
+            if let Some((_, cancel)) = cancels.remove(&conn_id) {
+                cancel.cancel();
+                reply.killed += 1;
+            }
+        }
+        Ok(reply)
+    }
 }
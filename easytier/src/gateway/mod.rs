@@ -2,7 +2,7 @@ use dashmap::DashMap;
 use std::sync::{Arc, Mutex};
 use tokio::task::JoinSet;
 
-use crate::common::global_ctx::ArcGlobalCtx;
+use crate::common::{config::ProxyNetworkConfig, global_ctx::ArcGlobalCtx};
 
 pub mod icmp_proxy;
 pub mod ip_reassembler;
@@ -49,7 +49,17 @@ impl CidrSet {
         self.tasks.spawn(async move {
             let mut last_cidrs = vec![];
             loop {
-                let cidrs = global_ctx.config.get_proxy_cidrs();
+                let mut cidrs = global_ctx.config.get_proxy_cidrs();
+                cidrs.extend(
+                    global_ctx
+                        .get_imported_proxy_cidrs()
+                        .into_iter()
+                        .map(|cidr| ProxyNetworkConfig {
+                            cidr,
+                            mapped_cidr: None,
+                            allow: None,
+                        }),
+                );
                 if cidrs != last_cidrs {
                     last_cidrs = cidrs.clone();
                     mapped_to_real.clear();
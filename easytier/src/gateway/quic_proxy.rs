@@ -11,6 +11,7 @@ use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio::task::JoinSet;
 use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
 
 use crate::common::acl_processor::PacketInfo;
 use crate::common::error::Result;
@@ -23,8 +24,9 @@ use crate::gateway::CidrSet;
 use crate::peers::peer_manager::PeerManager;
 use crate::proto::acl::{ChainType, Protocol};
 use crate::proto::cli::{
-    ListTcpProxyEntryRequest, ListTcpProxyEntryResponse, TcpProxyEntry, TcpProxyEntryState,
-    TcpProxyEntryTransportType, TcpProxyRpc,
+    KillTcpProxyEntryRequest, KillTcpProxyEntryResponse, ListTcpProxyEntryRequest,
+    ListTcpProxyEntryResponse, TcpProxyEntry, TcpProxyEntryState, TcpProxyEntryTransportType,
+    TcpProxyRpc,
 };
 use crate::proto::common::ProxyDstInfo;
 use crate::proto::rpc_types;
@@ -251,6 +253,8 @@ pub struct QUICProxyDst {
     global_ctx: Arc<GlobalCtx>,
     endpoint: Arc<quinn::Endpoint>,
     proxy_entries: Arc<DashMap<SocketAddr, TcpProxyEntry>>,
+    // cancel the connection handling of an entry to kill it
+    proxy_cancels: Arc<DashMap<SocketAddr, CancellationToken>>,
     tasks: Arc<Mutex<JoinSet<()>>>,
     route: Arc<dyn crate::peers::route_trait::Route + Send + Sync + 'static>,
 }
@@ -269,6 +273,7 @@ impl QUICProxyDst {
             global_ctx,
             endpoint: Arc::new(endpoint),
             proxy_entries: Arc::new(DashMap::new()),
+            proxy_cancels: Arc::new(DashMap::new()),
             tasks,
             route,
         })
@@ -280,6 +285,7 @@ impl QUICProxyDst {
         let ctx = self.global_ctx.clone();
         let cidr_set = Arc::new(CidrSet::new(ctx.clone()));
         let proxy_entries = self.proxy_entries.clone();
+        let proxy_cancels = self.proxy_cancels.clone();
         let route = self.route.clone();
 
         let task = async move {
@@ -292,16 +298,26 @@ impl QUICProxyDst {
                             );
                             return;
                         };
-                        tasks
-                            .lock()
-                            .unwrap()
-                            .spawn(Self::handle_connection_with_timeout(
-                                conn,
-                                ctx.clone(),
-                                cidr_set.clone(),
-                                proxy_entries.clone(),
-                                route.clone(),
-                            ));
+                        let remote_addr = conn.remote_address();
+                        let cancel = CancellationToken::new();
+                        proxy_cancels.insert(remote_addr, cancel.clone());
+                        let proxy_cancels = proxy_cancels.clone();
+                        let handle = Self::handle_connection_with_timeout(
+                            conn,
+                            ctx.clone(),
+                            cidr_set.clone(),
+                            proxy_entries.clone(),
+                            route.clone(),
+                        );
+                        tasks.lock().unwrap().spawn(async move {
+                            tokio::select! {
+                                _ = handle => {}
+                                _ = cancel.cancelled() => {
+                                    tracing::info!(?remote_addr, "QUIC proxy connection killed");
+                                }
+                            }
+                            proxy_cancels.remove(&remote_addr);
+                        });
                     }
                     None => {
                         return;
@@ -468,11 +484,17 @@ impl QUICProxyDst {
 }
 
 #[derive(Clone)]
-pub struct QUICProxyDstRpcService(Weak<DashMap<SocketAddr, TcpProxyEntry>>);
+pub struct QUICProxyDstRpcService {
+    proxy_entries: Weak<DashMap<SocketAddr, TcpProxyEntry>>,
+    proxy_cancels: Weak<DashMap<SocketAddr, CancellationToken>>,
+}
 
 impl QUICProxyDstRpcService {
     pub fn new(quic_proxy_dst: &QUICProxyDst) -> Self {
-        Self(Arc::downgrade(&quic_proxy_dst.proxy_entries))
+        Self {
+            proxy_entries: Arc::downgrade(&quic_proxy_dst.proxy_entries),
+            proxy_cancels: Arc::downgrade(&quic_proxy_dst.proxy_cancels),
+        }
     }
 }
 
@@ -485,11 +507,36 @@ impl TcpProxyRpc for QUICProxyDstRpcService {
         _request: ListTcpProxyEntryRequest, // Accept request of type HelloRequest
     ) -> std::result::Result<ListTcpProxyEntryResponse, rpc_types::error::Error> {
         let mut reply = ListTcpProxyEntryResponse::default();
-        if let Some(tcp_proxy) = self.0.upgrade() {
+        if let Some(tcp_proxy) = self.proxy_entries.upgrade() {
             for item in tcp_proxy.iter() {
                 reply.entries.push(*item.value());
             }
         }
         Ok(reply)
     }
+
+    async fn kill_tcp_proxy_entry(
+        &self,
+        _: BaseController,
+        request: KillTcpProxyEntryRequest,
+    ) -> std::result::Result<KillTcpProxyEntryResponse, rpc_types::error::Error> {
+        let mut reply = KillTcpProxyEntryResponse::default();
+        let (Some(entries), Some(cancels)) =
+            (self.proxy_entries.upgrade(), self.proxy_cancels.upgrade())
+        else {
+            return Ok(reply);
+        };
+        let keys = entries
+            .iter()
+            .filter(|item| item.value().matches_kill_request(&request))
+            .map(|item| *item.key())
+            .collect::<Vec<_>>();
+        for key in keys {
+            if let Some((_, cancel)) = cancels.remove(&key) {
+                cancel.cancel();
+                reply.killed += 1;
+            }
+        }
+        Ok(reply)
+    }
 }
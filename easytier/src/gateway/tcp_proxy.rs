@@ -28,8 +28,9 @@ use crate::common::stats_manager::{LabelSet, LabelType, MetricName};
 use crate::peers::peer_manager::PeerManager;
 use crate::peers::{NicPacketFilter, PeerPacketFilter};
 use crate::proto::cli::{
-    ListTcpProxyEntryRequest, ListTcpProxyEntryResponse, TcpProxyEntry, TcpProxyEntryState,
-    TcpProxyEntryTransportType, TcpProxyRpc,
+    KillTcpProxyEntryRequest, KillTcpProxyEntryResponse, ListTcpProxyEntryRequest,
+    ListTcpProxyEntryResponse, TcpProxyEntry, TcpProxyEntryState, TcpProxyEntryTransportType,
+    TcpProxyRpc,
 };
 use crate::proto::rpc_types;
 use crate::proto::rpc_types::controller::BaseController;
@@ -756,7 +757,11 @@ impl<C: NatDstConnector> TcpProxy<C> {
 
         tracing::info!(?nat_entry, ?nat_dst, "tcp connection to dst established");
 
-        assert_eq!(nat_entry.state.load(), NatDstEntryState::ConnectingDst);
+        // the entry may be killed while connecting
+        if nat_entry.state.load() != NatDstEntryState::ConnectingDst {
+            tracing::info!(?nat_entry, "nat entry closed while connecting to dst");
+            return;
+        }
         nat_entry.state.store(NatDstEntryState::Connected);
 
         Self::handle_nat_connection(
@@ -934,6 +939,41 @@ impl<C: NatDstConnector> TcpProxy<C> {
         self.syn_map.contains_key(&src) || self.addr_conn_map.contains_key(&src)
     }
 
+    pub async fn kill_proxy_entries(&self, req: &KillTcpProxyEntryRequest) -> u32 {
+        let transport_type = self.connector.transport_type();
+        let is_killed =
+            |entry: &ArcNatDstEntry| entry.parse_as_pb(transport_type).matches_kill_request(req);
+
+        // not accepted yet, the listener drops the connection without entry
+        let mut killed = 0;
+        self.syn_map.retain(|_, entry| {
+            let ret = is_killed(entry);
+            if ret {
+                killed += 1;
+            }
+            !ret
+        });
+
+        let entries = self
+            .conn_map
+            .iter()
+            .filter(|entry| is_killed(entry.value()))
+            .map(|entry| entry.value().clone())
+            .collect::<Vec<_>>();
+        for entry in entries {
+            tracing::info!(?entry, "kill nat tcp connection");
+            entry.state.store(NatDstEntryState::Closed);
+            entry.tasks.lock().await.abort_all();
+            Self::remove_entry_from_all_conn_map(
+                self.conn_map.clone(),
+                self.addr_conn_map.clone(),
+                entry,
+            );
+            killed += 1;
+        }
+        killed
+    }
+
     pub fn list_proxy_entries(&self) -> Vec<TcpProxyEntry> {
         let mut entries: Vec<TcpProxyEntry> = Vec::new();
         let transport_type = self.connector.transport_type();
@@ -970,6 +1010,18 @@ impl<C: NatDstConnector> TcpProxyRpc for TcpProxyRpcService<C> {
         }
         Ok(reply)
     }
+
+    async fn kill_tcp_proxy_entry(
+        &self,
+        _: BaseController,
+        request: KillTcpProxyEntryRequest,
+    ) -> std::result::Result<KillTcpProxyEntryResponse, rpc_types::error::Error> {
+        let mut reply = KillTcpProxyEntryResponse::default();
+        if let Some(tcp_proxy) = self.tcp_proxy.upgrade() {
+            reply.killed = tcp_proxy.kill_proxy_entries(&request).await;
+        }
+        Ok(reply)
+    }
 }
 
 impl<C: NatDstConnector> TcpProxyRpcService<C> {
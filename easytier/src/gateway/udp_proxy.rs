@@ -1,6 +1,9 @@
 use std::{
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    sync::{atomic::AtomicBool, Arc},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Weak,
+    },
     time::Duration,
 };
 
@@ -25,9 +28,19 @@ use tokio::{
 use tracing::Level;
 
 use crate::{
-    common::{error::Error, global_ctx::ArcGlobalCtx, scoped_task::ScopedTask, PeerId},
+    common::{
+        config::UdpProxyTimeoutConfig, error::Error, global_ctx::ArcGlobalCtx,
+        scoped_task::ScopedTask, PeerId,
+    },
     gateway::ip_reassembler::{compose_ipv4_packet, ComposeIpv4PacketArgs},
     peers::{peer_manager::PeerManager, PeerPacketFilter},
+    proto::{
+        cli::{
+            KillUdpProxyEntryRequest, KillUdpProxyEntryResponse, ListUdpProxyEntryRequest,
+            ListUdpProxyEntryResponse, UdpProxyEntry, UdpProxyRpc,
+        },
+        rpc_types::{self, controller::BaseController},
+    },
     tunnel::{
         common::{reserve_buf, setup_sokcet2},
         packet_def::{PacketType, ZCPacket},
@@ -36,6 +49,11 @@ use crate::{
 
 use super::{ip_reassembler::IpReassembler, CidrSet};
 
+const DEFAULT_UDP_NAT_IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+// endpoints a port restricted entry remembers, the least recently contacted
+// one is forgotten first
+const MAX_CONTACTED_ENDPOINTS: usize = 256;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 struct UdpNatKey {
     src_socket: SocketAddr,
@@ -46,38 +64,106 @@ struct UdpNatEntry {
     src_peer_id: PeerId,
     my_peer_id: PeerId,
     src_socket: SocketAddr,
+    // destination of the packet creating the entry
+    dst_socket: SocketAddr,
     socket: UdpSocket,
     forward_task: Mutex<Option<JoinHandle<()>>>,
     stopped: AtomicBool,
+    // the mapped port is the source port if it is free
+    full_cone: bool,
+    // only packets from the endpoints the source has sent to are accepted,
+    // endpoint -> last time the source sent to it
+    port_restricted: bool,
+    contacted: DashMap<SocketAddr, std::time::Instant>,
+    idle_timeout: Duration,
     start_time: std::time::Instant,
+    start_time_local: chrono::DateTime<chrono::Local>,
     last_active_time: AtomicCell<std::time::Instant>,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
 }
 
 impl UdpNatEntry {
     #[tracing::instrument(err(level = Level::WARN))]
-    fn new(src_peer_id: PeerId, my_peer_id: PeerId, src_socket: SocketAddr) -> Result<Self, Error> {
-        // TODO: try use src port, so we will be ip restricted nat type
-        let socket2_socket = socket2::Socket::new(
-            socket2::Domain::IPV4,
-            socket2::Type::DGRAM,
-            Some(socket2::Protocol::UDP),
-        )?;
-        let dst_socket_addr = "0.0.0.0:0".parse().unwrap();
-        setup_sokcet2(&socket2_socket, &dst_socket_addr)?;
-        let socket = UdpSocket::from_std(socket2_socket.into())?;
+    fn new(
+        src_peer_id: PeerId,
+        my_peer_id: PeerId,
+        src_socket: SocketAddr,
+        dst_socket: SocketAddr,
+        full_cone: bool,
+        port_restricted: bool,
+        idle_timeout: Duration,
+    ) -> Result<Self, Error> {
+        let socket = if full_cone {
+            Self::bind_src_port(src_socket.port())
+        } else {
+            None
+        };
+        let socket = match socket {
+            Some(socket) => socket,
+            None => {
+                let socket2_socket = socket2::Socket::new(
+                    socket2::Domain::IPV4,
+                    socket2::Type::DGRAM,
+                    Some(socket2::Protocol::UDP),
+                )?;
+                let dst_socket_addr = "0.0.0.0:0".parse().unwrap();
+                setup_sokcet2(&socket2_socket, &dst_socket_addr)?;
+                UdpSocket::from_std(socket2_socket.into())?
+            }
+        };
 
         Ok(Self {
             src_peer_id,
             my_peer_id,
             src_socket,
+            dst_socket,
             socket,
             forward_task: Mutex::new(None),
             stopped: AtomicBool::new(false),
+            full_cone,
+            port_restricted: port_restricted && !full_cone,
+            contacted: DashMap::new(),
+            idle_timeout,
             start_time: std::time::Instant::now(),
+            start_time_local: chrono::Local::now(),
             last_active_time: AtomicCell::new(std::time::Instant::now()),
+            tx_packets: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            rx_packets: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
         })
     }
 
+    /// Keep the source port as the mapped port if it is free, peers behind
+    /// the proxied subnet can then reach the source at a predictable port.
+    /// No SO_REUSEADDR here, the bind must fail if the port is in use.
+    fn bind_src_port(port: u16) -> Option<UdpSocket> {
+        let bind = || -> std::io::Result<UdpSocket> {
+            let socket = socket2::Socket::new(
+                socket2::Domain::IPV4,
+                socket2::Type::DGRAM,
+                Some(socket2::Protocol::UDP),
+            )?;
+            socket.set_nonblocking(true)?;
+            let addr: SocketAddr = (Ipv4Addr::UNSPECIFIED, port).into();
+            // setup_sokcet2 would set SO_REUSEADDR, only take the windows part of it.
+            #[cfg(target_os = "windows")]
+            crate::arch::windows::setup_socket_for_win(&socket, &addr, None, true)?;
+            socket.bind(&addr.into())?;
+            UdpSocket::from_std(socket.into())
+        };
+        match bind() {
+            Ok(socket) => Some(socket),
+            Err(err) => {
+                tracing::debug!(?err, ?port, "udp nat source port not available");
+                None
+            }
+        }
+    }
+
     pub fn stop(&self) {
         self.stopped
             .store(true, std::sync::atomic::Ordering::Relaxed);
@@ -164,7 +250,7 @@ impl UdpNatEntry {
                 }
 
                 let (len, src_socket) = match timeout(
-                    Duration::from_secs(120),
+                    self_clone.idle_timeout,
                     self_clone.socket.recv_buf_from(&mut cur_buf),
                 )
                 .await
@@ -175,13 +261,23 @@ impl UdpNatEntry {
                         break;
                     }
                     Err(err) => {
-                        tracing::error!(?err, "udp nat recv timeout");
+                        // the source may still be sending without getting replies
+                        if self_clone.is_active() {
+                            continue;
+                        }
+                        tracing::info!(?err, "udp nat recv timeout");
                         break;
                     }
                 };
 
                 tracing::trace!(?len, ?src_socket, "udp nat packet response received");
 
+                if self_clone.port_restricted && !self_clone.is_contacted(&src_socket) {
+                    tracing::trace!(?src_socket, "udp nat drop packet from unknown endpoint");
+                    cur_buf.clear();
+                    continue;
+                }
+
                 let ret_buf = cur_buf.split();
                 s.send((ret_buf, len, src_socket)).await.unwrap();
             }
@@ -218,6 +314,8 @@ impl UdpNatEntry {
                 else {
                     break;
                 };
+                self_clone.rx_packets.fetch_add(1, Ordering::Relaxed);
+                self_clone.rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
                 ip_id = ip_id.wrapping_add(1);
             }
         }));
@@ -227,12 +325,57 @@ impl UdpNatEntry {
         self.stop();
     }
 
+    fn add_contacted(&self, endpoint: SocketAddr) {
+        if self.contacted.len() >= MAX_CONTACTED_ENDPOINTS
+            && !self.contacted.contains_key(&endpoint)
+        {
+            self.contacted
+                .retain(|_, t| t.elapsed() < self.idle_timeout);
+            if self.contacted.len() >= MAX_CONTACTED_ENDPOINTS {
+                let oldest = self
+                    .contacted
+                    .iter()
+                    .min_by_key(|x| *x.value())
+                    .map(|x| *x.key());
+                if let Some(oldest) = oldest {
+                    self.contacted.remove(&oldest);
+                }
+            }
+        }
+        self.contacted.insert(endpoint, std::time::Instant::now());
+    }
+
+    fn is_contacted(&self, endpoint: &SocketAddr) -> bool {
+        self.contacted
+            .get(endpoint)
+            .is_some_and(|t| t.elapsed() < self.idle_timeout)
+    }
+
     fn mark_active(&self) {
         self.last_active_time.store(std::time::Instant::now());
     }
 
     fn is_active(&self) -> bool {
-        self.last_active_time.load().elapsed().as_secs() < 180
+        self.last_active_time.load().elapsed() < self.idle_timeout
+    }
+
+    fn parse_as_pb(&self) -> UdpProxyEntry {
+        let idle = self.last_active_time.load().elapsed();
+        UdpProxyEntry {
+            src: Some(self.src_socket.into()),
+            dst: Some(self.dst_socket.into()),
+            mapped: self.socket.local_addr().ok().map(Into::into),
+            start_time: self.start_time_local.timestamp() as u64,
+            last_active_time: (chrono::Local::now().timestamp() as u64)
+                .saturating_sub(idle.as_secs()),
+            idle_timeout: self.idle_timeout.as_secs() as u32,
+            full_cone: self.full_cone,
+            port_restricted: self.port_restricted,
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+        }
     }
 }
 
@@ -243,6 +386,10 @@ pub struct UdpProxy {
 
     cidr_set: CidrSet,
 
+    full_cone: bool,
+    port_restricted: bool,
+    timeouts: Vec<UdpProxyTimeoutConfig>,
+
     nat_table: Arc<DashMap<UdpNatKey, Arc<UdpNatEntry>>>,
 
     sender: Sender<ZCPacket>,
@@ -305,6 +452,17 @@ impl UdpProxy {
             "udp nat packet request received"
         );
 
+        // TODO: should it be async.
+        let dst_socket = if Some(ipv4.get_destination())
+            == self.global_ctx.get_ipv4().as_ref().map(Ipv4Inet::address)
+        {
+            format!("127.0.0.1:{}", udp_packet.get_destination())
+                .parse()
+                .unwrap()
+        } else {
+            SocketAddr::new(real_dst_ip.into(), udp_packet.get_destination())
+        };
+
         let nat_key = UdpNatKey {
             src_socket: SocketAddr::new(ipv4.get_source().into(), udp_packet.get_source()),
         };
@@ -318,6 +476,10 @@ impl UdpProxy {
                     hdr.from_peer_id.get(),
                     hdr.to_peer_id.get(),
                     nat_key.src_socket,
+                    SocketAddr::new(ipv4.get_destination().into(), udp_packet.get_destination()),
+                    self.full_cone,
+                    self.port_restricted,
+                    self.idle_timeout(udp_packet.get_destination()),
                 )?))
             })
             .ok()?
@@ -338,17 +500,9 @@ impl UdpProxy {
         }
 
         nat_entry.mark_active();
-
-        // TODO: should it be async.
-        let dst_socket = if Some(ipv4.get_destination())
-            == self.global_ctx.get_ipv4().as_ref().map(Ipv4Inet::address)
-        {
-            format!("127.0.0.1:{}", udp_packet.get_destination())
-                .parse()
-                .unwrap()
-        } else {
-            SocketAddr::new(real_dst_ip.into(), udp_packet.get_destination())
-        };
+        if nat_entry.port_restricted {
+            nat_entry.add_contacted(dst_socket);
+        }
 
         let send_ret = {
             let _g = self.global_ctx.net_ns.guard();
@@ -358,6 +512,13 @@ impl UdpProxy {
                 .await
         };
 
+        if send_ret.is_ok() {
+            nat_entry.tx_packets.fetch_add(1, Ordering::Relaxed);
+            nat_entry
+                .tx_bytes
+                .fetch_add(udp_packet.payload().len() as u64, Ordering::Relaxed);
+        }
+
         if let Err(send_err) = send_ret {
             tracing::error!(
                 ?send_err,
@@ -370,6 +531,35 @@ impl UdpProxy {
 
         Some(())
     }
+
+    fn idle_timeout(&self, dst_port: u16) -> Duration {
+        self.timeouts
+            .iter()
+            .find(|t| t.contains(dst_port))
+            .map(|t| Duration::from_secs(t.timeout))
+            .unwrap_or(DEFAULT_UDP_NAT_IDLE_TIMEOUT)
+    }
+
+    /// removes the entry and closes its socket, the next packet from `src`
+    /// creates a new entry with a new mapping.
+    pub async fn kill_proxy_entry(&self, src: SocketAddr) -> u32 {
+        let Some((_, entry)) = self.nat_table.remove(&UdpNatKey { src_socket: src }) else {
+            return 0;
+        };
+        tracing::info!(?entry, "udp nat table entry killed");
+        entry.stop();
+        if let Some(task) = entry.forward_task.lock().await.take() {
+            task.abort();
+        }
+        1
+    }
+
+    pub fn list_proxy_entries(&self) -> Vec<UdpProxyEntry> {
+        self.nat_table
+            .iter()
+            .map(|entry| entry.value().parse_as_pb())
+            .collect()
+    }
 }
 
 #[async_trait::async_trait]
@@ -390,10 +580,16 @@ impl UdpProxy {
     ) -> Result<Arc<Self>, Error> {
         let cidr_set = CidrSet::new(global_ctx.clone());
         let (sender, receiver) = channel(1024);
+        let full_cone = global_ctx.get_flags().udp_proxy_full_cone;
+        let port_restricted = global_ctx.get_flags().udp_proxy_port_restricted;
+        let timeouts = global_ctx.config.get_udp_proxy_timeouts();
         let ret = Self {
             global_ctx,
             peer_manager,
             cidr_set,
+            full_cone,
+            port_restricted,
+            timeouts,
             nat_table: Arc::new(DashMap::new()),
             sender,
             receiver: Mutex::new(Some(receiver)),
@@ -457,3 +653,47 @@ impl Drop for UdpProxy {
         }
     }
 }
+
+#[derive(Clone)]
+pub struct UdpProxyRpcService {
+    udp_proxy: Weak<UdpProxy>,
+}
+
+#[async_trait::async_trait]
+impl UdpProxyRpc for UdpProxyRpcService {
+    type Controller = BaseController;
+    async fn list_udp_proxy_entry(
+        &self,
+        _: BaseController,
+        _request: ListUdpProxyEntryRequest,
+    ) -> std::result::Result<ListUdpProxyEntryResponse, rpc_types::error::Error> {
+        let mut reply = ListUdpProxyEntryResponse::default();
+        if let Some(udp_proxy) = self.udp_proxy.upgrade() {
+            reply.entries = udp_proxy.list_proxy_entries();
+        }
+        Ok(reply)
+    }
+
+    async fn kill_udp_proxy_entry(
+        &self,
+        _: BaseController,
+        request: KillUdpProxyEntryRequest,
+    ) -> std::result::Result<KillUdpProxyEntryResponse, rpc_types::error::Error> {
+        let mut reply = KillUdpProxyEntryResponse::default();
+        let Some(src) = request.src else {
+            return Err(anyhow::anyhow!("src is required").into());
+        };
+        if let Some(udp_proxy) = self.udp_proxy.upgrade() {
+            reply.killed = udp_proxy.kill_proxy_entry(src.into()).await;
+        }
+        Ok(reply)
+    }
+}
+
+impl UdpProxyRpcService {
+    pub fn new(udp_proxy: Arc<UdpProxy>) -> Self {
+        Self {
+            udp_proxy: Arc::downgrade(&udp_proxy),
+        }
+    }
+}
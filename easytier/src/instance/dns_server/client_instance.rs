@@ -3,12 +3,13 @@ use std::{sync::Arc, time::Duration};
 use tokio::task::JoinSet;
 
 use crate::{
+    common::config::ConfigLoader as _,
     peers::peer_manager::PeerManager,
     proto::{
         cli::Route,
         common::Void,
         magic_dns::{
-            HandshakeRequest, MagicDnsServerRpc, MagicDnsServerRpcClientFactory,
+            DnsForwardRule, HandshakeRequest, MagicDnsServerRpc, MagicDnsServerRpcClientFactory,
             UpdateDnsRecordRequest,
         },
         rpc_impl::standalone::StandAloneClient,
@@ -65,12 +66,36 @@ impl MagicDnsClientInstance {
             let ctx = peer_mgr.get_global_ctx();
             routes.push(Route {
                 hostname: ctx.get_hostname(),
+                inst_id: ctx.get_id().to_string(),
                 ipv4_addr: ctx.get_ipv4().map(Into::into),
+                ipv6_addr: ctx.get_ipv6().map(Into::into),
+                dns_records: ctx
+                    .config
+                    .get_dns_records()
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+                services: ctx
+                    .config
+                    .get_services()
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
                 ..Default::default()
             });
+            let forward_rules = ctx
+                .config
+                .get_dns_forwards()
+                .into_iter()
+                .map(|x| DnsForwardRule {
+                    domain: x.domain,
+                    nameservers: x.nameservers.into_iter().map(Into::into).collect(),
+                })
+                .collect();
             let req = UpdateDnsRecordRequest {
                 routes,
                 zone: DEFAULT_ET_DNS_ZONE.to_string(),
+                forward_rules,
             };
             tracing::debug!(
                 "MagicDnsClientInstance::update_dns_task: update dns records: {:?}",
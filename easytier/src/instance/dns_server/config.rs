@@ -2,7 +2,7 @@ use hickory_proto::rr;
 use hickory_proto::rr::RData;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
 use std::time::Duration;
 
@@ -16,6 +16,37 @@ pub struct RunConfig {
     #[builder(default = Vec::new())]
     #[serde(default)]
     excluded_forward_nameservers: Vec<IpAddr>,
+
+    #[builder(default = Vec::new())]
+    #[serde(default)]
+    forward_zones: Vec<ForwardZoneConfig>,
+
+    /// upstreams of the root zone, the system resolvers are used if empty.
+    #[builder(default = Vec::new())]
+    #[serde(default)]
+    upstreams: Vec<UpstreamConfig>,
+}
+
+/// Queries under `domain` are forwarded to `nameservers` instead of the
+/// system resolvers (split dns).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ForwardZoneConfig {
+    pub domain: String,
+    pub nameservers: Vec<SocketAddr>,
+    /// source addresses of the forwarded queries, the one of the same family
+    /// as the nameserver is used. the virtual ip makes the query go through
+    /// the mesh and the reply come back to us.
+    #[serde(default)]
+    pub bind_addrs: Vec<IpAddr>,
+}
+
+/// A resolver for queries outside the mesh zones. The scheme of `url` selects
+/// the transport: `udp://`, `tcp://`, `tls://` (DoT) or `https://` (DoH).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamConfig {
+    pub url: url::Url,
+    /// resolved addresses of the host in `url`
+    pub addrs: Vec<SocketAddr>,
 }
 
 impl RunConfig {
@@ -30,6 +61,14 @@ impl RunConfig {
     pub fn excluded_forward_nameservers(&self) -> &Vec<IpAddr> {
         &self.excluded_forward_nameservers
     }
+
+    pub fn forward_zones(&self) -> &Vec<ForwardZoneConfig> {
+        &self.forward_zones
+    }
+
+    pub fn upstreams(&self) -> &Vec<UpstreamConfig> {
+        &self.upstreams
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, derive_builder::Builder)]
@@ -39,6 +78,14 @@ pub struct GeneralConfig {
 
     #[builder(setter(into, strip_option), default = None)]
     listen_udp: Option<String>,
+
+    #[builder(setter(into, strip_option), default = None)]
+    #[serde(default)]
+    listen_tcp6: Option<String>,
+
+    #[builder(setter(into, strip_option), default = None)]
+    #[serde(default)]
+    listen_udp6: Option<String>,
 }
 
 impl GeneralConfig {
@@ -49,6 +96,14 @@ impl GeneralConfig {
     pub fn listen_udp(&self) -> &Option<String> {
         &self.listen_udp
     }
+
+    pub fn listen_tcp6(&self) -> &Option<String> {
+        &self.listen_tcp6
+    }
+
+    pub fn listen_udp6(&self) -> &Option<String> {
+        &self.listen_udp6
+    }
 }
 
 pub type Zone = HashMap<String, Vec<Record>>; // domain -> records
@@ -73,7 +128,7 @@ impl Record {
         Ok(name)
     }
 
-    fn rr_type(&self) -> rr::RecordType {
+    pub fn rr_type(&self) -> rr::RecordType {
         self.rr_type
     }
 }
@@ -99,6 +154,10 @@ impl TryFrom<&Record> for rr::Record {
                 let addr: Ipv4Addr = value.value.parse()?;
                 record.set_data(RData::A(rr::rdata::a::A(addr)));
             }
+            RecordType::AAAA => {
+                let addr: Ipv6Addr = value.value.parse()?;
+                record.set_data(RData::AAAA(rr::rdata::aaaa::AAAA(addr)));
+            }
             RecordType::SOA => {
                 let soa = value.value.split_whitespace().collect::<Vec<_>>();
                 if soa.len() != 7 {
@@ -121,7 +180,32 @@ impl TryFrom<&Record> for rr::Record {
                     minimum,
                 )));
             }
-            _ => todo!(),
+            RecordType::CNAME => {
+                let target = rr::Name::from_str(value.value.as_str())?;
+                record.set_data(RData::CNAME(rr::rdata::CNAME(target)));
+            }
+            RecordType::PTR => {
+                let target = rr::Name::from_str(value.value.as_str())?;
+                record.set_data(RData::PTR(rr::rdata::PTR(target)));
+            }
+            RecordType::TXT => {
+                record.set_data(RData::TXT(rr::rdata::TXT::new(vec![value.value.clone()])));
+            }
+            RecordType::SRV => {
+                let srv = value.value.split_whitespace().collect::<Vec<_>>();
+                if srv.len() != 4 {
+                    return Err(anyhow::anyhow!(
+                        "invalid SRV record, expect \"priority weight port target\""
+                    ));
+                }
+                record.set_data(RData::SRV(rr::rdata::SRV::new(
+                    srv[0].parse()?,
+                    srv[1].parse()?,
+                    srv[2].parse()?,
+                    rr::Name::from_str(srv[3])?,
+                )));
+            }
+            t => return Err(anyhow::anyhow!("unsupported record type: {}", t)),
         }
         Ok(record)
     }
@@ -0,0 +1,234 @@
+// Names of the reverse (PTR) zones of the virtual network, a zone is rounded
+// down to a label boundary: 8 bits for in-addr.arpa, 4 bits for ip6.arpa.
+// A virtual network broader than a /24 (/64 for ipv6) only gets the zones of
+// the /24s its hosts are in, a broader proxied subnet is split into /24 zones
+// and gets none if it is broader than a /16 (/56).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use cidr::{IpCidr, IpInet};
+
+/// broader networks are split into reverse zones of this size, one zone
+/// would take over reverse lookups of unrelated addresses.
+const MIN_IPV4_PREFIX: u8 = 24;
+const MIN_IPV6_PREFIX: u8 = 64;
+/// a network is split into at most this many reverse zones.
+const MAX_SPLIT_ZONES: usize = 256;
+/// a prefix not on a label boundary is expanded into wildcards of the next
+/// boundary, at most this many.
+const MAX_WILDCARDS: usize = 16;
+
+fn ipv4_labels(addr: Ipv4Addr, octets: usize) -> String {
+    addr.octets()[..octets]
+        .iter()
+        .rev()
+        .map(|o| format!("{}.", o))
+        .collect()
+}
+
+fn ipv6_labels(addr: Ipv6Addr, nibbles: usize) -> String {
+    addr.octets()
+        .iter()
+        .flat_map(|o| [o >> 4, o & 0x0f])
+        .take(nibbles)
+        .collect::<Vec<_>>()
+        .iter()
+        .rev()
+        .map(|n| format!("{:x}.", n))
+        .collect()
+}
+
+/// `4.3.2.1.in-addr.arpa.` of an address
+pub fn reverse_name(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(addr) => format!("{}in-addr.arpa.", ipv4_labels(addr, 4)),
+        IpAddr::V6(addr) => format!("{}ip6.arpa.", ipv6_labels(addr, 32)),
+    }
+}
+
+fn reverse_prefix_name(addr: IpAddr, prefix: u8) -> String {
+    match addr {
+        IpAddr::V4(addr) => format!("{}in-addr.arpa.", ipv4_labels(addr, prefix as usize / 8)),
+        IpAddr::V6(addr) => format!("{}ip6.arpa.", ipv6_labels(addr, prefix as usize / 4)),
+    }
+}
+
+fn label_bits(addr: &IpAddr) -> u8 {
+    if addr.is_ipv4() {
+        8
+    } else {
+        4
+    }
+}
+
+fn min_prefix(addr: &IpAddr) -> u8 {
+    if addr.is_ipv4() {
+        MIN_IPV4_PREFIX
+    } else {
+        MIN_IPV6_PREFIX
+    }
+}
+
+/// `cidr` itself, or its subnets of the broadest size allowed for a reverse
+/// zone. none if there would be too many of them.
+fn split(cidr: &IpCidr) -> Vec<IpCidr> {
+    let addr = cidr.first_address();
+    let min_prefix = min_prefix(&addr);
+    if cidr.network_length() >= min_prefix {
+        return vec![*cidr];
+    }
+    let bits = min_prefix - cidr.network_length();
+    if bits > MAX_SPLIT_ZONES.ilog2() as u8 {
+        return vec![];
+    }
+    let max_len = if addr.is_ipv4() { 32 } else { 128 };
+    let step = 1u128 << (max_len - min_prefix);
+    (0..1u128 << bits)
+        .map(|i| {
+            let sub = match addr {
+                IpAddr::V4(a) => {
+                    IpAddr::V4(Ipv4Addr::from(u32::from(a).wrapping_add((i * step) as u32)))
+                }
+                IpAddr::V6(a) => IpAddr::V6(Ipv6Addr::from(u128::from(a) + i * step)),
+            };
+            IpCidr::new(sub, min_prefix).unwrap()
+        })
+        .collect()
+}
+
+/// the network a reverse zone of the virtual network is created for: the
+/// network itself, or the /24 (/64) containing `addr` if it is too broad.
+pub fn reverse_network(network: IpCidr, addr: IpAddr) -> IpCidr {
+    if network.network_length() >= min_prefix(&addr) {
+        return network;
+    }
+    IpInet::new(addr, min_prefix(&addr)).unwrap().network()
+}
+
+/// the reverse zones covering `cidr`, none if the network is too broad.
+pub fn reverse_zones(cidr: &IpCidr) -> Vec<String> {
+    split(cidr)
+        .iter()
+        .map(|cidr| {
+            let addr = cidr.first_address();
+            let bits = label_bits(&addr);
+            let prefix = cidr.network_length() / bits * bits;
+            reverse_prefix_name(addr, prefix)
+        })
+        .collect()
+}
+
+/// names matching every address of `cidr`: the addresses themselves if the
+/// next label boundary is a host, otherwise wildcards under that boundary.
+pub fn reverse_wildcards(cidr: &IpCidr) -> Vec<String> {
+    split(cidr).iter().flat_map(subnet_wildcards).collect()
+}
+
+fn subnet_wildcards(cidr: &IpCidr) -> Vec<String> {
+    let addr = cidr.first_address();
+    let bits = label_bits(&addr);
+    let max_len = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = cidr.network_length().div_ceil(bits) * bits;
+    let count = 1usize << (prefix - cidr.network_length());
+    if count > MAX_WILDCARDS {
+        return vec![];
+    }
+    let step = 1u128 << (max_len - prefix);
+    (0..count as u128)
+        .map(|i| {
+            let sub = match addr {
+                IpAddr::V4(a) => {
+                    IpAddr::V4(Ipv4Addr::from(u32::from(a).wrapping_add((i * step) as u32)))
+                }
+                IpAddr::V6(a) => IpAddr::V6(Ipv6Addr::from(u128::from(a) + i * step)),
+            };
+            if prefix == max_len {
+                reverse_name(sub)
+            } else {
+                format!("*.{}", reverse_prefix_name(sub, prefix))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(
+            reverse_name("10.144.144.5".parse().unwrap()),
+            "5.144.144.10.in-addr.arpa."
+        );
+        assert_eq!(
+            reverse_name("fd00::1".parse().unwrap()),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.d.f.ip6.arpa."
+        );
+        assert_eq!(
+            reverse_zones(&"10.144.144.0/24".parse().unwrap()),
+            vec!["144.144.10.in-addr.arpa."]
+        );
+        assert_eq!(
+            reverse_zones(&"10.144.144.5/32".parse().unwrap()),
+            vec!["5.144.144.10.in-addr.arpa."]
+        );
+        assert_eq!(
+            reverse_zones(&"fd00:144::/64".parse().unwrap()),
+            vec!["0.0.0.0.0.0.0.0.4.4.1.0.0.0.d.f.ip6.arpa."]
+        );
+        // broader networks are split into /24 (/64) zones
+        assert_eq!(
+            reverse_zones(&"10.144.0.0/22".parse().unwrap()),
+            vec![
+                "0.144.10.in-addr.arpa.",
+                "1.144.10.in-addr.arpa.",
+                "2.144.10.in-addr.arpa.",
+                "3.144.10.in-addr.arpa."
+            ]
+        );
+        assert_eq!(reverse_zones(&"10.144.0.0/16".parse().unwrap()).len(), 256);
+        assert_eq!(reverse_zones(&"fd00::/56".parse().unwrap()).len(), 256);
+        assert!(reverse_zones(&"10.144.0.0/15".parse().unwrap()).is_empty());
+        assert!(reverse_zones(&"0.0.0.0/0".parse().unwrap()).is_empty());
+        assert!(reverse_zones(&"fd00::/48".parse().unwrap()).is_empty());
+
+        assert_eq!(
+            reverse_network(
+                "10.144.0.0/16".parse().unwrap(),
+                "10.144.3.4".parse().unwrap()
+            ),
+            "10.144.3.0/24".parse::<IpCidr>().unwrap()
+        );
+        assert_eq!(
+            reverse_network(
+                "10.144.3.0/25".parse().unwrap(),
+                "10.144.3.4".parse().unwrap()
+            ),
+            "10.144.3.0/25".parse::<IpCidr>().unwrap()
+        );
+
+        assert_eq!(
+            reverse_wildcards(&"192.168.1.0/24".parse().unwrap()),
+            vec!["*.1.168.192.in-addr.arpa."]
+        );
+        assert_eq!(
+            reverse_wildcards(&"192.168.2.0/23".parse().unwrap()),
+            vec!["*.2.168.192.in-addr.arpa.", "*.3.168.192.in-addr.arpa."]
+        );
+        assert!(reverse_wildcards(&"10.0.0.0/8".parse().unwrap()).is_empty());
+        assert_eq!(
+            reverse_wildcards(&"192.168.1.4/30".parse().unwrap()),
+            vec![
+                "4.1.168.192.in-addr.arpa.",
+                "5.1.168.192.in-addr.arpa.",
+                "6.1.168.192.in-addr.arpa.",
+                "7.1.168.192.in-addr.arpa."
+            ]
+        );
+        assert_eq!(
+            reverse_wildcards(&"192.168.1.7/32".parse().unwrap()),
+            vec!["7.1.168.192.in-addr.arpa."]
+        );
+    }
+}
@@ -1,8 +1,12 @@
-use cidr::Ipv4Inet;
+use cidr::{Ipv4Inet, Ipv6Inet};
 use tokio_util::sync::CancellationToken;
 
 use crate::peers::peer_manager::PeerManager;
-use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
+};
 
 use super::{client_instance::MagicDnsClientInstance, server_instance::MagicDnsServerInstance};
 
@@ -13,16 +17,20 @@ pub struct DnsRunner {
     server: Option<MagicDnsServerInstance>,
     peer_mgr: Arc<PeerManager>,
     tun_dev: Option<String>,
-    tun_inet: Ipv4Inet,
+    tun_inet: Option<Ipv4Inet>,
+    tun_inet6: Option<Ipv6Inet>,
     fake_ip: Ipv4Addr,
+    fake_ip6: Ipv6Addr,
 }
 
 impl DnsRunner {
     pub fn new(
         peer_mgr: Arc<PeerManager>,
         tun_dev: Option<String>,
-        tun_inet: Ipv4Inet,
+        tun_inet: Option<Ipv4Inet>,
+        tun_inet6: Option<Ipv6Inet>,
         fake_ip: Ipv4Addr,
+        fake_ip6: Ipv6Addr,
     ) -> Self {
         Self {
             client: None,
@@ -30,7 +38,9 @@ impl DnsRunner {
             peer_mgr,
             tun_dev,
             tun_inet,
+            tun_inet6,
             fake_ip,
+            fake_ip6,
         }
     }
 
@@ -47,7 +57,9 @@ impl DnsRunner {
             self.peer_mgr.clone(),
             self.tun_dev.clone(),
             self.tun_inet,
+            self.tun_inet6,
             self.fake_ip,
+            self.fake_ip6,
         )
         .await
         {
@@ -2,7 +2,8 @@ use anyhow::{Context, Result};
 use hickory_proto::op::Edns;
 use hickory_proto::rr;
 use hickory_proto::rr::LowerName;
-use hickory_resolver::config::ResolverOpts;
+use hickory_proto::xfer::Protocol;
+use hickory_resolver::config::{NameServerConfig, ResolverConfig, ResolverOpts};
 use hickory_resolver::name_server::TokioConnectionProvider;
 use hickory_resolver::system_conf::read_system_conf;
 use hickory_server::authority::{AuthorityObject, Catalog, ZoneType};
@@ -20,8 +21,10 @@ use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use tokio::task::JoinSet;
 
 use crate::common::dns::get_default_resolver_config;
+use crate::common::stats_manager::StatsManager;
 
-use super::config::{GeneralConfig, Record, RunConfig};
+use super::config::{ForwardZoneConfig, GeneralConfig, Record, RunConfig};
+use super::upstream::UpstreamAuthority;
 
 pub struct Server {
     server: ServerFuture<CatalogRequestHandler>,
@@ -29,6 +32,8 @@ pub struct Server {
     general_config: GeneralConfig,
     udp_local_addr: Option<SocketAddr>,
     tcp_local_addr: Option<SocketAddr>,
+    udp6_local_addr: Option<SocketAddr>,
+    tcp6_local_addr: Option<SocketAddr>,
     tasks: JoinSet<()>,
 }
 
@@ -75,12 +80,40 @@ pub fn build_authority(domain: &str, records: &[Record]) -> Result<InMemoryAutho
     Ok(authority)
 }
 
+pub fn build_forward_authority(config: &ForwardZoneConfig) -> Result<ForwardAuthority> {
+    let origin = rr::Name::from_str(&config.domain)?;
+    let mut name_servers = vec![];
+    for ns in config.nameservers.iter() {
+        for protocol in [Protocol::Udp, Protocol::Tcp] {
+            let mut ns_config = NameServerConfig::new(*ns, protocol);
+            ns_config.bind_addr = config
+                .bind_addrs
+                .iter()
+                .find(|ip| ip.is_ipv4() == ns.is_ipv4())
+                .map(|ip| SocketAddr::new(*ip, 0));
+            name_servers.push(ns_config);
+        }
+    }
+    let forward_config = ForwardConfig {
+        name_servers: name_servers.into(),
+        options: None,
+    };
+    ForwardAuthority::builder_with_config(forward_config, TokioConnectionProvider::default())
+        .with_origin(origin)
+        .build()
+        .map_err(|e| anyhow::anyhow!("failed to build forward authority: {}", e))
+}
+
 impl Server {
     pub fn new(config: RunConfig) -> Self {
-        Self::try_new(config).unwrap()
+        Self::try_new(config, None).unwrap()
     }
 
-    fn try_new(config: RunConfig) -> Result<Self> {
+    pub fn new_with_stats_manager(config: RunConfig, stats_manager: Arc<StatsManager>) -> Self {
+        Self::try_new(config, Some(stats_manager)).unwrap()
+    }
+
+    fn try_new(config: RunConfig, stats_manager: Option<Arc<StatsManager>>) -> Result<Self> {
         let mut catalog = Catalog::new();
         for (domain, records) in config.zones().iter() {
             let zone = rr::Name::from_str(domain.as_str())?;
@@ -88,33 +121,36 @@ impl Server {
             catalog.upsert(zone.clone().into(), vec![Arc::new(authroty)]);
         }
 
-        // use forwarder authority for the root zone
-        let system_conf =
+        // queries outside the mesh zones go to the upstreams, the system
+        // resolvers if none is configured
+        let (mut system_conf, system_opts) =
             read_system_conf().unwrap_or((get_default_resolver_config(), ResolverOpts::default()));
-        let forward_config = ForwardConfig {
-            name_servers: system_conf
-                .0
-                .name_servers()
-                .iter()
-                .filter(|&x| {
-                    !config
-                        .excluded_forward_nameservers()
-                        .contains(&x.socket_addr.ip())
-                })
-                .cloned()
-                .collect::<Vec<_>>()
-                .into(),
-            options: Some(system_conf.1),
-        };
-        let auth = ForwardAuthority::builder_with_config(
-            forward_config,
-            TokioConnectionProvider::default(),
-        )
-        .build()
-        .unwrap();
+        let system_name_servers = system_conf
+            .name_servers()
+            .iter()
+            .filter(|&x| {
+                !config
+                    .excluded_forward_nameservers()
+                    .contains(&x.socket_addr.ip())
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        system_conf = ResolverConfig::from_parts(
+            system_conf.domain().cloned(),
+            system_conf.search().to_vec(),
+            system_name_servers,
+        );
+        let auth =
+            UpstreamAuthority::new(config.upstreams(), system_conf, system_opts, stats_manager)?;
 
         catalog.upsert(rr::Name::from_str(".")?.into(), vec![Arc::new(auth)]);
 
+        for forward_zone in config.forward_zones().iter() {
+            let zone = rr::Name::from_str(&forward_zone.domain)?;
+            let auth = build_forward_authority(forward_zone)?;
+            catalog.upsert(zone.into(), vec![Arc::new(auth)]);
+        }
+
         let catalog = Arc::new(RwLock::new(catalog));
         let handler = CatalogRequestHandler::new(catalog.clone());
         let server = ServerFuture::new(handler);
@@ -125,6 +161,8 @@ impl Server {
             general_config: config.general().clone(),
             udp_local_addr: None,
             tcp_local_addr: None,
+            udp6_local_addr: None,
+            tcp6_local_addr: None,
             tasks: JoinSet::new(),
         })
     }
@@ -137,11 +175,19 @@ impl Server {
         self.tcp_local_addr
     }
 
+    pub fn udp6_local_addr(&self) -> Option<SocketAddr> {
+        self.udp6_local_addr
+    }
+
+    pub fn tcp6_local_addr(&self) -> Option<SocketAddr> {
+        self.tcp6_local_addr
+    }
+
     pub async fn register_udp_socket(&mut self, address: String) -> Result<SocketAddr> {
         let bind_addr = SocketAddr::from_str(&address)
             .with_context(|| format!("DNS Server failed to parse address {}", address))?;
         let socket = socket2::Socket::new(
-            socket2::Domain::IPV4,
+            socket2::Domain::for_address(bind_addr),
             socket2::Type::DGRAM,
             Some(socket2::Protocol::UDP),
         )
@@ -180,21 +226,35 @@ impl Server {
         Ok(local_addr)
     }
 
+    async fn register_tcp_listener(&mut self, address: String) -> Result<SocketAddr> {
+        let tcp_listener = TcpListener::bind(address.clone())
+            .await
+            .with_context(|| format!("DNS Server failed to bind TCP address {}", address))?;
+        let local_addr = tcp_listener.local_addr()?;
+        self.server
+            .register_listener(tcp_listener, Duration::from_secs(5));
+        Ok(local_addr)
+    }
+
     pub async fn run(&mut self) -> Result<()> {
-        if let Some(address) = self.general_config.listen_tcp() {
-            let tcp_listener = TcpListener::bind(address.clone())
-                .await
-                .with_context(|| format!("DNS Server failed to bind TCP address {}", address))?;
-            self.tcp_local_addr = Some(tcp_listener.local_addr()?);
-            self.server
-                .register_listener(tcp_listener, Duration::from_secs(5));
+        if let Some(address) = self.general_config.listen_tcp().clone() {
+            self.tcp_local_addr = Some(self.register_tcp_listener(address).await?);
         }
 
-        if let Some(address) = self.general_config.listen_udp() {
-            let local_addr = self.register_udp_socket(address.clone()).await?;
+        if let Some(address) = self.general_config.listen_udp().clone() {
+            let local_addr = self.register_udp_socket(address).await?;
             self.udp_local_addr = Some(local_addr);
         };
 
+        if let Some(address) = self.general_config.listen_tcp6().clone() {
+            self.tcp6_local_addr = Some(self.register_tcp_listener(address).await?);
+        }
+
+        if let Some(address) = self.general_config.listen_udp6().clone() {
+            let local_addr = self.register_udp_socket(address).await?;
+            self.udp6_local_addr = Some(local_addr);
+        };
+
         Ok(())
     }
 
@@ -253,8 +313,10 @@ impl Server {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::common::stats_manager::{LabelSet, MetricName};
     use crate::instance::dns_server::config::{
-        GeneralConfigBuilder, RecordBuilder, RecordType, RunConfigBuilder,
+        ForwardZoneConfig, GeneralConfigBuilder, RecordBuilder, RecordType, RunConfigBuilder,
+        UpstreamConfig,
     };
     use anyhow::Result;
     use hickory_client::client::{Client, ClientHandle};
@@ -335,4 +397,177 @@ mod tests {
         server.shutdown().await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn forward_zone_to_other_server() -> Result<()> {
+        let record = RecordBuilder::default()
+            .rr_type(RecordType::A)
+            .name("git.corp.example.".to_string())
+            .value("192.168.1.20".to_string())
+            .ttl(Duration::from_secs(60))
+            .build()?;
+        let soa_record = RecordBuilder::default()
+            .rr_type(RecordType::SOA)
+            .name("corp.example.".to_string())
+            .value(
+                "ns.corp.example. hostmaster.corp.example. 2023101001 7200 3600 1209600 86400"
+                    .to_string(),
+            )
+            .ttl(Duration::from_secs(60))
+            .build()?;
+        let mut upstream = Server::new(
+            RunConfigBuilder::default()
+                .general(
+                    GeneralConfigBuilder::default()
+                        .listen_udp("127.0.0.1:0")
+                        .build()?,
+                )
+                .zones(hashmap! {
+                    "corp.example.".to_string() => vec![record.clone(), soa_record],
+                })
+                .build()?,
+        );
+        upstream.run().await?;
+
+        let mut server = Server::new(
+            RunConfigBuilder::default()
+                .general(
+                    GeneralConfigBuilder::default()
+                        .listen_udp("127.0.0.1:0")
+                        .build()?,
+                )
+                .forward_zones(vec![ForwardZoneConfig {
+                    domain: "corp.example.".to_string(),
+                    nameservers: vec![upstream.udp_local_addr().unwrap()],
+                    bind_addrs: vec![],
+                }])
+                .build()?,
+        );
+        server.run().await?;
+
+        let stream = UdpClientStream::builder(
+            server.udp_local_addr().unwrap(),
+            TokioRuntimeProvider::default(),
+        )
+        .build();
+        let (mut client, background) = Client::connect(stream).await?;
+        let background_task = tokio::spawn(background);
+        let response = client
+            .query(
+                rr::Name::from_str("git.corp.example.")?,
+                rr::DNSClass::IN,
+                rr::RecordType::A,
+            )
+            .await?;
+        drop(background_task);
+
+        assert_eq!(response.answers().len(), 1, "{:?}", response.answers());
+        assert_eq!(
+            response.answers()[0]
+                .clone()
+                .into_parts()
+                .rdata
+                .into_a()
+                .unwrap()
+                .0,
+            "192.168.1.20".parse::<std::net::Ipv4Addr>()?
+        );
+
+        server.shutdown().await?;
+        upstream.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upstream_answers_are_cached() -> Result<()> {
+        let record = RecordBuilder::default()
+            .rr_type(RecordType::A)
+            .name("www.upstream.example.".to_string())
+            .value("192.168.1.30".to_string())
+            .ttl(Duration::from_secs(60))
+            .build()?;
+        let soa_record = RecordBuilder::default()
+            .rr_type(RecordType::SOA)
+            .name("upstream.example.".to_string())
+            .value(
+                "ns.upstream.example. hostmaster.upstream.example. 2023101001 7200 3600 1209600 86400"
+                    .to_string(),
+            )
+            .ttl(Duration::from_secs(60))
+            .build()?;
+        let mut upstream = Server::new(
+            RunConfigBuilder::default()
+                .general(
+                    GeneralConfigBuilder::default()
+                        .listen_udp("127.0.0.1:0")
+                        .build()?,
+                )
+                .zones(hashmap! {
+                    "upstream.example.".to_string() => vec![record.clone(), soa_record],
+                })
+                .build()?,
+        );
+        upstream.run().await?;
+
+        let upstream_addr = upstream.udp_local_addr().unwrap();
+        let stats_manager = Arc::new(StatsManager::new());
+        let mut server = Server::new_with_stats_manager(
+            RunConfigBuilder::default()
+                .general(
+                    GeneralConfigBuilder::default()
+                        .listen_udp("127.0.0.1:0")
+                        .build()?,
+                )
+                .upstreams(vec![UpstreamConfig {
+                    url: format!("udp://{}", upstream_addr).parse()?,
+                    addrs: vec![upstream_addr],
+                }])
+                .build()?,
+            stats_manager.clone(),
+        );
+        server.run().await?;
+
+        let stream = UdpClientStream::builder(
+            server.udp_local_addr().unwrap(),
+            TokioRuntimeProvider::default(),
+        )
+        .build();
+        let (mut client, background) = Client::connect(stream).await?;
+        let background_task = tokio::spawn(background);
+        for _ in 0..2 {
+            let response = client
+                .query(
+                    rr::Name::from_str("www.upstream.example.")?,
+                    rr::DNSClass::IN,
+                    rr::RecordType::A,
+                )
+                .await?;
+            assert_eq!(response.answers().len(), 1, "{:?}", response.answers());
+        }
+        // negative answers are cached too
+        for _ in 0..2 {
+            let response = client
+                .query(
+                    rr::Name::from_str("missing.upstream.example.")?,
+                    rr::DNSClass::IN,
+                    rr::RecordType::A,
+                )
+                .await?;
+            assert!(response.answers().is_empty());
+        }
+        drop(background_task);
+
+        let counter = |name| {
+            stats_manager
+                .get_metric(name, &LabelSet::new())
+                .map(|m| m.value)
+                .unwrap_or_default()
+        };
+        assert_eq!(counter(MetricName::DnsCacheMiss), 2);
+        assert_eq!(counter(MetricName::DnsCacheHit), 2);
+
+        server.shutdown().await?;
+        upstream.shutdown().await?;
+        Ok(())
+    }
 }
@@ -6,17 +6,25 @@
 // magic dns client will establish a long live tcp connection to the magic dns server, and when the server stops or crashes,
 // all the clients will exit and let the easytier instance to launch a new server instance.
 
-use std::{collections::BTreeMap, net::Ipv4Addr, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::Context;
-use cidr::Ipv4Inet;
+use cidr::{IpCidr, Ipv4Inet, Ipv6Inet};
 use dashmap::DashMap;
 use hickory_proto::rr::LowerName;
 use multimap::MultiMap;
 use pnet::packet::{
     icmp::{self, IcmpTypes, MutableIcmpPacket},
+    icmpv6::{self, Icmpv6Types, MutableIcmpv6Packet},
     ip::IpNextHeaderProtocols,
     ipv4::{self, MutableIpv4Packet},
+    ipv6::MutableIpv6Packet,
     tcp::{self, MutableTcpPacket},
     udp::{self, MutableUdpPacket},
     MutablePacket,
@@ -24,21 +32,24 @@ use pnet::packet::{
 
 use crate::{
     common::{
+        config::ServiceConfig,
+        global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
         ifcfg::{IfConfiger, IfConfiguerTrait},
         PeerId,
     },
     instance::dns_server::{
-        config::{Record, RecordBuilder, RecordType},
-        server::build_authority,
+        config::{ForwardZoneConfig, Record, RecordBuilder, RecordType},
+        server::{build_authority, build_forward_authority},
         DEFAULT_ET_DNS_ZONE,
     },
     peers::{peer_manager::PeerManager, NicPacketFilter},
     proto::{
-        cli::Route,
-        common::{TunnelInfo, Void},
+        cli::{assign_dns_host_names, dns_host_id, Route},
+        common::{DnsRecordConfigPb, ServiceConfigPb, TunnelInfo, Void},
         magic_dns::{
             dns_record::{self},
-            DnsRecord, DnsRecordA, DnsRecordList, GetDnsRecordResponse, HandshakeRequest,
+            DnsForwardRule, DnsHostName, DnsHostNameList, DnsRecord, DnsRecordA, DnsRecordAaaa,
+            DnsRecordList, GetDnsHostNamesResponse, GetDnsRecordResponse, HandshakeRequest,
             HandshakeResponse, MagicDnsServerRpc, MagicDnsServerRpcServer, UpdateDnsRecordRequest,
         },
         rpc_impl::standalone::{RpcServerHook, StandAloneServer},
@@ -48,25 +59,44 @@ use crate::{
 };
 
 use super::{
-    config::{GeneralConfigBuilder, RunConfigBuilder},
+    config::{GeneralConfigBuilder, RunConfigBuilder, UpstreamConfig},
+    reverse::{reverse_name, reverse_network, reverse_wildcards, reverse_zones},
     server::Server,
     system_config::{OSConfig, SystemConfig},
     MAGIC_DNS_INSTANCE_ADDR,
 };
 
 static NIC_PIPELINE_NAME: &str = "magic_dns_server";
+// seconds
+const DEFAULT_USER_RECORD_TTL: u64 = 60;
 
 pub(super) struct MagicDnsServerInstanceData {
     dns_server: Server,
     tun_dev: Option<String>,
-    tun_ip: Ipv4Addr,
+    tun_ip: Option<Ipv4Addr>,
     fake_ip: Ipv4Addr,
+    tun_ip6: Option<Ipv6Addr>,
+    fake_ip6: Ipv6Addr,
     my_peer_id: PeerId,
 
     // zone -> (tunnel remote addr -> route)
     route_infos: DashMap<String, MultiMap<url::Url, Route>>,
+    // tunnel remote addr -> split dns rules of that client
+    forward_rules: DashMap<url::Url, Vec<DnsForwardRule>>,
+    // forwarded domain -> the rule its authority is built from
+    forward_zones: std::sync::Mutex<BTreeMap<String, ForwardZoneConfig>>,
+    // zone -> (hostname -> instance ids reporting it), only conflicted ones
+    hostname_conflicts: DashMap<String, BTreeMap<String, Vec<String>>>,
+    // zone -> (hostname -> instance id or peer id of the node named by it)
+    hostname_holders: DashMap<String, BTreeMap<String, String>>,
+    // zone -> (user record name -> instance id of the peer publishing it)
+    user_record_owners: DashMap<String, BTreeMap<LowerName, String>>,
+    // zone -> (reverse zone built from its routes -> whether the os resolver
+    // sends it to us, only the ones of the virtual network are)
+    reverse_zones: DashMap<String, BTreeMap<String, bool>>,
+    global_ctx: ArcGlobalCtx,
 
-    system_config: Option<Box<dyn SystemConfig>>,
+    system_config: Option<Arc<dyn SystemConfig>>,
 }
 
 impl MagicDnsServerInstanceData {
@@ -75,42 +105,173 @@ impl MagicDnsServerInstanceData {
         routes: T,
         zone: &str,
     ) -> Result<(), anyhow::Error> {
+        let routes = routes.collect::<Vec<_>>();
         let mut records: Vec<Record> = vec![];
-        for route in routes {
-            if route.hostname.is_empty() {
-                continue;
+        let mut conflicts: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        // networks to create reverse zones for, whether they are of the virtual
+        // network, and (reverse name, target) of the PTR records
+        let mut reverse_cidrs: Vec<(IpCidr, bool)> = vec![];
+        let mut ptrs: Vec<(String, String)> = vec![];
+        // magic dns names of the peers -> instance id or peer id of the peer
+        let mut peer_names: Vec<(LowerName, String)> = vec![];
+        let mut holders: BTreeMap<String, String> = BTreeMap::new();
+        let hosts = assign_dns_host_names(
+            routes.iter().copied(),
+            &self
+                .hostname_holders
+                .get(zone)
+                .map(|x| x.clone())
+                .unwrap_or_default(),
+        );
+        for host in hosts {
+            let route = host.route;
+            let id = dns_host_id(route);
+            if host.holder {
+                holders.insert(route.hostname.to_lowercase(), id.clone());
+            }
+            for name in host.names.iter() {
+                if let Ok(name) = LowerName::from_str(&format!("{}.{}", name, zone)) {
+                    peer_names.push((name, id.clone()));
+                }
+            }
+            if host.conflicted {
+                let inst_ids = conflicts.entry(route.hostname.to_lowercase()).or_default();
+                // routes are grouped by instance, one may be reported by several clients
+                if inst_ids.last() != Some(&id) {
+                    inst_ids.push(id);
+                }
             }
 
-            let Some(ipv4_addr) = route.ipv4_addr.unwrap_or_default().address else {
-                continue;
-            };
+            let mut addrs: Vec<(RecordType, IpAddr)> = vec![];
+            if let Some(ipv4_addr) = route.ipv4_addr.unwrap_or_default().address {
+                addrs.push((RecordType::A, Ipv4Addr::from(ipv4_addr).into()));
+            }
+            if let Some(ipv6_addr) = route.ipv6_addr.unwrap_or_default().address {
+                addrs.push((RecordType::AAAA, Ipv6Addr::from(ipv6_addr).into()));
+            }
 
-            let record = RecordBuilder::default()
-                .rr_type(RecordType::A)
-                .name(format!("{}.{}", route.hostname, zone))
-                .value(ipv4_addr.to_string())
-                .ttl(Duration::from_secs(1))
-                .build()?;
+            let target = format!("{}.{}", host.names[0], zone);
+            let mut networks: Vec<(IpCidr, IpAddr)> = vec![];
+            if let Some(ipv4_inet) = route.ipv4_addr.filter(|x| x.address.is_some()) {
+                let inet = cidr::Ipv4Inet::from(ipv4_inet);
+                networks.push((inet.network().into(), inet.address().into()));
+            }
+            if let Some(ipv6_inet) = route.ipv6_addr.filter(|x| x.address.is_some()) {
+                let inet = cidr::Ipv6Inet::from(ipv6_inet);
+                networks.push((inet.network().into(), inet.address().into()));
+            }
+            for (network, addr) in networks {
+                // a broad network only gets the zones of the /24s with hosts in it
+                reverse_cidrs.push((reverse_network(network, addr), true));
+            }
+            for (_, addr) in addrs.iter() {
+                ptrs.push((reverse_name(*addr), target.clone()));
+            }
+            // every address of a proxied subnet points to the peer proxying it
+            for proxy_cidr in route.proxy_cidrs.iter() {
+                let Ok(proxy_cidr) = proxy_cidr.parse::<IpCidr>() else {
+                    continue;
+                };
+                reverse_cidrs.push((proxy_cidr, false));
+                ptrs.extend(
+                    reverse_wildcards(&proxy_cidr)
+                        .into_iter()
+                        .map(|name| (name, target.clone())),
+                );
+            }
+
+            'names: for name in host.names.iter() {
+                for (rr_type, addr) in addrs.iter() {
+                    let record = RecordBuilder::default()
+                        .rr_type(*rr_type)
+                        .name(format!("{}.{}", name, zone))
+                        .value(addr.to_string())
+                        .ttl(Duration::from_secs(1))
+                        .build()?;
 
-            // check record name valid for dns
-            if let Err(e) = record.name() {
-                tracing::error!("Invalid subdomain label: {}", e);
+                    // check record name valid for dns
+                    if let Err(e) = record.name() {
+                        tracing::error!("Invalid subdomain label: {}", e);
+                        break 'names;
+                    }
+
+                    records.push(record);
+                }
+            }
+
+            for service in route.services.iter() {
+                match Self::build_service_records(&host.names[0], service, zone) {
+                    Ok(service_records) => records.extend(service_records),
+                    Err(e) => {
+                        tracing::warn!(
+                            ?service,
+                            hostname = %route.hostname,
+                            "Invalid service: {:?}",
+                            e
+                        );
+                    }
+                }
+            }
+        }
+
+        let mut user_records = vec![];
+        for route in routes.iter() {
+            for user_record in route.dns_records.iter() {
+                match Self::build_user_record(user_record, zone) {
+                    Ok(record) => user_records.push((*route, user_record, record)),
+                    Err(e) => {
+                        tracing::warn!(
+                            ?user_record,
+                            hostname = %route.hostname,
+                            "Invalid user dns record: {:?}",
+                            e
+                        );
+                    }
+                }
+            }
+        }
+
+        let owners = self.assign_user_record_owners(zone, &user_records)?;
+        for (route, user_record, record) in user_records {
+            let name = LowerName::from(record.name()?);
+            let id = dns_host_id(route);
+            // names of the peers and everything under them belong to the peers
+            if let Some((peer_name, _)) = peer_names
+                .iter()
+                .find(|(peer_name, peer_id)| peer_name.zone_of(&name) && *peer_id != id)
+            {
+                tracing::warn!(
+                    ?user_record,
+                    hostname = %route.hostname,
+                    %peer_name,
+                    "User dns record collides with the name of another peer, ignored"
+                );
+                continue;
+            }
+            if owners.get(&name) != Some(&id) {
+                tracing::warn!(
+                    ?user_record,
+                    hostname = %route.hostname,
+                    owner = ?owners.get(&name),
+                    "User dns record is already published by another peer, ignored"
+                );
                 continue;
             }
 
-            records.push(record);
+            // e.g. a host in a proxied subnet, more specific than the wildcard
+            if matches!(record.rr_type(), RecordType::A | RecordType::AAAA) {
+                if let Ok(addr) = user_record.value.trim().parse::<IpAddr>() {
+                    ptrs.push((reverse_name(addr), record.name()?.to_string()));
+                }
+            }
+            records.push(record)
         }
 
-        let soa_record = RecordBuilder::default()
-            .rr_type(RecordType::SOA)
-            .name(zone.to_string())
-            .value(format!(
-                "ns.{} hostmaster.{} 2023101001 7200 3600 1209600 86400",
-                zone, zone
-            ))
-            .ttl(Duration::from_secs(60))
-            .build()?;
-        records.push(soa_record);
+        self.hostname_holders.insert(zone.to_string(), holders);
+        self.report_hostname_conflicts(zone, conflicts);
+        self.update_reverse_zones(zone, &reverse_cidrs, ptrs).await;
+
+        records.push(Self::build_soa_record(zone)?);
 
         let authority = build_authority(zone, &records)?;
 
@@ -127,6 +288,237 @@ impl MagicDnsServerInstanceData {
         Ok(())
     }
 
+    fn build_soa_record(zone: &str) -> Result<Record, anyhow::Error> {
+        Ok(RecordBuilder::default()
+            .rr_type(RecordType::SOA)
+            .name(zone.to_string())
+            .value(format!(
+                "ns.{} hostmaster.{} 2023101001 7200 3600 1209600 86400",
+                zone, zone
+            ))
+            .ttl(Duration::from_secs(60))
+            .build()?)
+    }
+
+    /// Replaces the reverse zones built from the routes of `zone`, each PTR
+    /// record goes to the most specific reverse zone containing it. the os
+    /// resolver is reconfigured when the set of reverse zones of the virtual
+    /// network changes.
+    async fn update_reverse_zones(
+        &self,
+        zone: &str,
+        reverse_cidrs: &[(IpCidr, bool)],
+        ptrs: Vec<(String, String)>,
+    ) {
+        let mut zones: BTreeMap<String, (bool, Vec<Record>)> = BTreeMap::new();
+        for (cidr, system) in reverse_cidrs {
+            for z in reverse_zones(cidr) {
+                zones.entry(z).or_default().0 |= *system;
+            }
+        }
+        for (name, target) in ptrs {
+            let Some(records) = zones
+                .iter_mut()
+                .filter(|(z, _)| name == **z || name.ends_with(&format!(".{}", z)))
+                .max_by_key(|(z, _)| z.len())
+                .map(|(_, (_, records))| records)
+            else {
+                continue;
+            };
+            let record = RecordBuilder::default()
+                .rr_type(RecordType::PTR)
+                .name(name)
+                .value(target)
+                .ttl(Duration::from_secs(1))
+                .build()
+                .unwrap();
+            match hickory_proto::rr::Record::try_from(&record) {
+                Ok(_) => records.push(record),
+                Err(e) => tracing::debug!(?record, "Invalid PTR record: {:?}", e),
+            }
+        }
+
+        let mut names = BTreeMap::new();
+        for (reverse_zone, (system, mut records)) in zones {
+            let ret = Self::build_soa_record(&reverse_zone).and_then(|soa| {
+                records.push(soa);
+                Ok((
+                    LowerName::from_str(&reverse_zone)?,
+                    build_authority(&reverse_zone, &records)?,
+                ))
+            });
+            match ret {
+                Ok((name, authority)) => {
+                    self.dns_server.upsert(name, Arc::new(authority)).await;
+                    names.insert(reverse_zone, system);
+                }
+                Err(e) => {
+                    tracing::error!(%reverse_zone, "Failed to build reverse zone: {:?}", e);
+                }
+            }
+        }
+
+        let old = self
+            .reverse_zones
+            .insert(zone.to_string(), names.clone())
+            .unwrap_or_default();
+        if old == names {
+            return;
+        }
+        for name in old.keys().filter(|z| !names.contains_key(*z)) {
+            if let Ok(name) = LowerName::from_str(name) {
+                self.dns_server.remove(&name).await;
+            }
+        }
+        tracing::info!(?names, "Updated DNS reverse zones");
+
+        let system_names = |zones: &BTreeMap<String, bool>| {
+            zones
+                .iter()
+                .filter(|(_, system)| **system)
+                .map(|(z, _)| z.clone())
+                .collect::<Vec<_>>()
+        };
+        if system_names(&old) == system_names(&names) {
+            return;
+        }
+        if let Err(e) = self.apply_system_config().await {
+            tracing::error!("Failed to configure system dns: {:?}", e);
+        }
+    }
+
+    /// A user record name belongs to one peer: the one that already published
+    /// it keeps it, a new name goes to the first peer publishing it.
+    fn assign_user_record_owners(
+        &self,
+        zone: &str,
+        user_records: &[(&Route, &DnsRecordConfigPb, Record)],
+    ) -> Result<BTreeMap<LowerName, String>, anyhow::Error> {
+        let old = self
+            .user_record_owners
+            .get(zone)
+            .map(|x| x.clone())
+            .unwrap_or_default();
+        let mut owners = BTreeMap::new();
+        for (route, _, record) in user_records.iter() {
+            let name = LowerName::from(record.name()?);
+            let id = dns_host_id(route);
+            if old.get(&name) == Some(&id) {
+                owners.insert(name, id);
+            }
+        }
+        for (route, _, record) in user_records.iter() {
+            owners
+                .entry(LowerName::from(record.name()?))
+                .or_insert_with(|| dns_host_id(route));
+        }
+        self.user_record_owners
+            .insert(zone.to_string(), owners.clone());
+        Ok(owners)
+    }
+
+    fn report_hostname_conflicts(&self, zone: &str, conflicts: BTreeMap<String, Vec<String>>) {
+        let old = self
+            .hostname_conflicts
+            .insert(zone.to_string(), conflicts.clone())
+            .unwrap_or_default();
+        for (hostname, inst_ids) in conflicts {
+            if old.get(&hostname) == Some(&inst_ids) {
+                continue;
+            }
+            tracing::warn!(
+                %hostname,
+                ?inst_ids,
+                "hostname conflict in magic dns, other nodes are named with instance id suffix"
+            );
+            self.global_ctx
+                .issue_event(GlobalCtxEvent::DnsHostnameConflict(hostname, inst_ids));
+        }
+    }
+
+    fn build_user_record(
+        user_record: &DnsRecordConfigPb,
+        zone: &str,
+    ) -> Result<Record, anyhow::Error> {
+        let name = if user_record.name.ends_with('.') {
+            user_record.name.clone()
+        } else {
+            format!("{}.{}", user_record.name, zone)
+        };
+        let record = RecordBuilder::default()
+            .rr_type(RecordType::from_str(&user_record.r#type.to_uppercase())?)
+            .name(name)
+            .value(user_record.value.clone())
+            .ttl(Duration::from_secs(if user_record.ttl == 0 {
+                DEFAULT_USER_RECORD_TTL
+            } else {
+                user_record.ttl as u64
+            }))
+            .build()?;
+
+        // the authority silently drops records outside of its zone
+        let record_name = record.name()?;
+        if !LowerName::from_str(zone)?.zone_of(&record_name.into()) {
+            return Err(anyhow::anyhow!("record is not in zone {}", zone));
+        }
+        // make sure the record data is valid before it is put into the authority
+        hickory_proto::rr::Record::try_from(&record)?;
+
+        Ok(record)
+    }
+
+    /// dns-sd records of a service (rfc 6763): the service type
+    /// `_<name>._<protocol>.<zone>` points to the instance
+    /// `<hostname>._<name>._<protocol>.<zone>`, which has the SRV and TXT
+    /// records. the SRV is also put on the service type so a plain SRV lookup
+    /// finds every peer running the service.
+    fn build_service_records(
+        hostname: &str,
+        service: &ServiceConfigPb,
+        zone: &str,
+    ) -> Result<Vec<Record>, anyhow::Error> {
+        let service = ServiceConfig::try_from(service.clone())?;
+        service.validate()?;
+
+        let service_type = format!("_{}._{}.{}", service.name, service.protocol, zone);
+        let instance = format!("{}.{}", hostname, service_type);
+        let srv = format!("0 0 {} {}.{}", service.port, hostname, zone);
+        let tags = if service.tags.is_empty() {
+            // a txt record is required even without any key
+            vec![String::new()]
+        } else {
+            service.tags
+        };
+
+        let mut rrs = vec![
+            (
+                RecordType::PTR,
+                format!("_services._dns-sd._udp.{}", zone),
+                service_type.clone(),
+            ),
+            (RecordType::PTR, service_type.clone(), instance.clone()),
+            (RecordType::SRV, instance.clone(), srv.clone()),
+            (RecordType::SRV, service_type, srv),
+        ];
+        rrs.extend(
+            tags.into_iter()
+                .map(|tag| (RecordType::TXT, instance.clone(), tag)),
+        );
+
+        let mut records = vec![];
+        for (rr_type, name, value) in rrs {
+            let record = RecordBuilder::default()
+                .rr_type(rr_type)
+                .name(name)
+                .value(value)
+                .ttl(Duration::from_secs(1))
+                .build()?;
+            hickory_proto::rr::Record::try_from(&record)?;
+            records.push(record);
+        }
+        Ok(records)
+    }
+
     pub async fn update(&self) {
         for item in self.route_infos.iter() {
             let zone = item.key();
@@ -137,14 +529,110 @@ impl MagicDnsServerInstanceData {
         }
     }
 
-    fn do_system_config(&self, zone: &str) -> Result<(), anyhow::Error> {
-        if let Some(c) = &self.system_config {
-            c.set_dns(&OSConfig {
-                nameservers: vec![self.fake_ip.to_string()],
-                search_domains: vec![zone.to_string()],
-                match_domains: vec![zone.to_string()],
-            })?;
+    /// Merges the split dns rules of all clients, only the forward
+    /// authorities whose rule changed are rebuilt, and the os resolver is
+    /// reconfigured when the set of forwarded domains changes.
+    pub async fn update_forward_zones(&self) {
+        let mut bind_addrs: Vec<IpAddr> = vec![];
+        bind_addrs.extend(self.tun_ip.map(IpAddr::from));
+        bind_addrs.extend(self.tun_ip6.map(IpAddr::from));
+
+        let mut zones: BTreeMap<String, ForwardZoneConfig> = BTreeMap::new();
+        for item in self.forward_rules.iter() {
+            for rule in item.value() {
+                let mut domain = rule.domain.trim().to_lowercase();
+                if domain.is_empty() || domain == "." {
+                    continue;
+                }
+                if !domain.ends_with('.') {
+                    domain.push('.');
+                }
+                let config = zones
+                    .entry(domain.clone())
+                    .or_insert_with(|| ForwardZoneConfig {
+                        domain,
+                        nameservers: vec![],
+                        bind_addrs: bind_addrs.clone(),
+                    });
+                for ns in rule.nameservers.iter().cloned().map(SocketAddr::from) {
+                    if !config.nameservers.contains(&ns) {
+                        config.nameservers.push(ns);
+                    }
+                }
+            }
+        }
+
+        let old_zones = self.forward_zones.lock().unwrap().clone();
+        let mut cur_zones = BTreeMap::new();
+        for (domain, config) in zones {
+            if old_zones.get(&domain) == Some(&config) {
+                cur_zones.insert(domain, config);
+                continue;
+            }
+            let ret = build_forward_authority(&config)
+                .and_then(|auth| Ok((LowerName::from_str(&domain)?, auth)));
+            match ret {
+                Ok((name, auth)) => {
+                    self.dns_server.upsert(name, Arc::new(auth)).await;
+                    cur_zones.insert(domain, config);
+                }
+                Err(e) => {
+                    tracing::error!(?config, "Failed to build forward zone: {:?}", e);
+                }
+            }
+        }
+        for domain in old_zones.keys().filter(|d| !cur_zones.contains_key(*d)) {
+            if let Ok(name) = LowerName::from_str(domain) {
+                self.dns_server.remove(&name).await;
+            }
+        }
+
+        let domains_changed = !old_zones.keys().eq(cur_zones.keys());
+        let domains = cur_zones.keys().cloned().collect::<Vec<_>>();
+        *self.forward_zones.lock().unwrap() = cur_zones;
+        if !domains_changed {
+            return;
+        }
+        tracing::info!(?domains, "Updated DNS forward zones");
+
+        if let Err(e) = self.apply_system_config().await {
+            tracing::error!("Failed to configure system dns: {:?}", e);
         }
+    }
+
+    async fn apply_system_config(&self) -> Result<(), anyhow::Error> {
+        let Some(c) = self.system_config.clone() else {
+            return Ok(());
+        };
+
+        let mut nameservers = vec![];
+        if self.tun_ip.is_some() {
+            nameservers.push(self.fake_ip.to_string());
+        }
+        if self.tun_ip6.is_some() {
+            nameservers.push(self.fake_ip6.to_string());
+        }
+        // only the mesh zone, the reverse zones of the virtual network and the
+        // forwarded domains are sent to us, proxied subnets may be local ones
+        let mut match_domains = vec![DEFAULT_ET_DNS_ZONE.to_string()];
+        for item in self.reverse_zones.iter() {
+            match_domains.extend(
+                item.value()
+                    .iter()
+                    .filter(|(_, system)| **system)
+                    .map(|(z, _)| z.clone()),
+            );
+        }
+        match_domains.extend(self.forward_zones.lock().unwrap().keys().cloned());
+        let os_config = OSConfig {
+            nameservers,
+            search_domains: vec![DEFAULT_ET_DNS_ZONE.to_string()],
+            match_domains,
+        };
+
+        tokio::task::spawn_blocking(move || c.set_dns(&os_config))
+            .await
+            .context("Failed to configure system")??;
         Ok(())
     }
 }
@@ -176,8 +664,11 @@ impl MagicDnsServerRpc for MagicDnsServerInstanceData {
             .entry(zone.clone())
             .or_default()
             .insert_many(remote_addr.clone().into(), input.routes);
+        self.forward_rules
+            .insert(remote_addr.clone().into(), input.forward_rules);
 
         self.update().await;
+        self.update_forward_zones().await;
         Ok(Default::default())
     }
 
@@ -199,12 +690,62 @@ impl MagicDnsServerRpc for MagicDnsServerInstanceData {
                         ttl: 1,
                     })),
                 });
+                if let Some(ipv6_addr) = route.ipv6_addr {
+                    dns_records.records.push(DnsRecord {
+                        record: Some(dns_record::Record::Aaaa(DnsRecordAaaa {
+                            name: format!("{}.{}", route.hostname, zone),
+                            value: ipv6_addr.address,
+                            ttl: 1,
+                        })),
+                    });
+                }
             }
             ret.insert(zone.clone(), dns_records);
         }
         Ok(GetDnsRecordResponse { records: ret })
     }
 
+    async fn get_dns_host_names(
+        &self,
+        _ctrl: Self::Controller,
+        _input: Void,
+    ) -> crate::proto::rpc_types::error::Result<GetDnsHostNamesResponse> {
+        let mut ret = BTreeMap::new();
+        for item in self.route_infos.iter() {
+            let zone = item.key();
+            let holders = self
+                .hostname_holders
+                .get(zone)
+                .map(|x| x.clone())
+                .unwrap_or_default();
+            let mut list = DnsHostNameList::default();
+            let mut last_id = None;
+            for host in assign_dns_host_names(item.value().flat_iter().map(|x| x.1), &holders) {
+                let route = host.route;
+                // routes are grouped by instance, one may be reported by several clients
+                let id = dns_host_id(route);
+                if last_id.as_ref() == Some(&id) {
+                    continue;
+                }
+                last_id = Some(id);
+                list.hosts.push(DnsHostName {
+                    hostname: route.hostname.clone(),
+                    names: host
+                        .names
+                        .iter()
+                        .map(|name| format!("{}.{}", name, zone))
+                        .collect(),
+                    inst_id: route.inst_id.clone(),
+                    peer_id: route.peer_id,
+                    ipv4_addr: route.ipv4_addr,
+                    conflicted: host.conflicted,
+                });
+            }
+            ret.insert(zone.clone(), list);
+        }
+        Ok(GetDnsHostNamesResponse { hosts: ret })
+    }
+
     async fn heartbeat(
         &self,
         _ctrl: Self::Controller,
@@ -214,12 +755,16 @@ impl MagicDnsServerRpc for MagicDnsServerInstanceData {
     }
 }
 
-#[async_trait::async_trait]
-impl NicPacketFilter for MagicDnsServerInstanceData {
-    async fn try_process_packet_from_nic(&self, zc_packet: &mut ZCPacket) -> bool {
+impl MagicDnsServerInstanceData {
+    fn process_ipv4_packet(&self, zc_packet: &mut ZCPacket) -> bool {
+        let Some(tun_ip) = self.tun_ip else {
+            return false;
+        };
         let data = zc_packet.mut_payload();
-        let mut ip_packet = MutableIpv4Packet::new(data).unwrap();
-        if ip_packet.get_version() != 4 || ip_packet.get_destination() != self.fake_ip {
+        let Some(mut ip_packet) = MutableIpv4Packet::new(data) else {
+            return false;
+        };
+        if ip_packet.get_destination() != self.fake_ip {
             return false;
         }
 
@@ -244,7 +789,7 @@ impl NicPacketFilter for MagicDnsServerInstanceData {
                 udp_packet.set_checksum(udp::ipv4_checksum(
                     &udp_packet.to_immutable(),
                     &self.fake_ip,
-                    &self.tun_ip,
+                    &tun_ip,
                 ));
             }
 
@@ -268,7 +813,7 @@ impl NicPacketFilter for MagicDnsServerInstanceData {
                 tcp_packet.set_checksum(tcp::ipv4_checksum(
                     &tcp_packet.to_immutable(),
                     &self.fake_ip,
-                    &self.tun_ip,
+                    &tun_ip,
                 ));
             }
 
@@ -289,11 +834,114 @@ impl NicPacketFilter for MagicDnsServerInstanceData {
         }
 
         ip_packet.set_source(self.fake_ip);
-        ip_packet.set_destination(self.tun_ip);
+        ip_packet.set_destination(tun_ip);
 
         ip_packet.set_checksum(ipv4::checksum(&ip_packet.to_immutable()));
-        zc_packet.mut_peer_manager_header().unwrap().to_peer_id = self.my_peer_id.into();
+        true
+    }
+
+    fn process_ipv6_packet(&self, zc_packet: &mut ZCPacket) -> bool {
+        let Some(tun_ip6) = self.tun_ip6 else {
+            return false;
+        };
+        let data = zc_packet.mut_payload();
+        let Some(mut ip_packet) = MutableIpv6Packet::new(data) else {
+            return false;
+        };
+        if ip_packet.get_destination() != self.fake_ip6 {
+            return false;
+        }
+
+        match ip_packet.get_next_header() {
+            IpNextHeaderProtocols::Udp => {
+                let Some(dns_udp_addr) = self.dns_server.udp6_local_addr() else {
+                    return false;
+                };
+
+                let Some(mut udp_packet) = MutableUdpPacket::new(ip_packet.payload_mut()) else {
+                    return false;
+                };
+                if udp_packet.get_destination() == 53 {
+                    // for dns request
+                    udp_packet.set_destination(dns_udp_addr.port());
+                } else if udp_packet.get_source() == dns_udp_addr.port() {
+                    // for dns response
+                    udp_packet.set_source(53);
+                } else {
+                    return false;
+                }
+                udp_packet.set_checksum(udp::ipv6_checksum(
+                    &udp_packet.to_immutable(),
+                    &self.fake_ip6,
+                    &tun_ip6,
+                ));
+            }
+
+            IpNextHeaderProtocols::Tcp => {
+                let Some(dns_tcp_addr) = self.dns_server.tcp6_local_addr() else {
+                    return false;
+                };
 
+                let Some(mut tcp_packet) = MutableTcpPacket::new(ip_packet.payload_mut()) else {
+                    return false;
+                };
+                if tcp_packet.get_destination() == 53 {
+                    // for dns request
+                    tcp_packet.set_destination(dns_tcp_addr.port());
+                } else if tcp_packet.get_source() == dns_tcp_addr.port() {
+                    // for dns response
+                    tcp_packet.set_source(53);
+                } else {
+                    return false;
+                }
+                tcp_packet.set_checksum(tcp::ipv6_checksum(
+                    &tcp_packet.to_immutable(),
+                    &self.fake_ip6,
+                    &tun_ip6,
+                ));
+            }
+
+            IpNextHeaderProtocols::Icmpv6 => {
+                let Some(mut icmp_packet) = MutableIcmpv6Packet::new(ip_packet.payload_mut())
+                else {
+                    return false;
+                };
+                if icmp_packet.get_icmpv6_type() != Icmpv6Types::EchoRequest {
+                    return false;
+                }
+                icmp_packet.set_icmpv6_type(Icmpv6Types::EchoReply);
+                // icmpv6 checksum covers the pseudo header, so use the swapped addresses
+                icmp_packet.set_checksum(icmpv6::checksum(
+                    &icmp_packet.to_immutable(),
+                    &self.fake_ip6,
+                    &tun_ip6,
+                ));
+            }
+
+            _ => {
+                return false;
+            }
+        }
+
+        ip_packet.set_source(self.fake_ip6);
+        ip_packet.set_destination(tun_ip6);
+        true
+    }
+}
+
+#[async_trait::async_trait]
+impl NicPacketFilter for MagicDnsServerInstanceData {
+    async fn try_process_packet_from_nic(&self, zc_packet: &mut ZCPacket) -> bool {
+        let processed = match zc_packet.payload().first().map(|b| b >> 4) {
+            Some(4) => self.process_ipv4_packet(zc_packet),
+            Some(6) => self.process_ipv6_packet(zc_packet),
+            _ => false,
+        };
+        if !processed {
+            return false;
+        }
+
+        zc_packet.mut_peer_manager_header().unwrap().to_peer_id = self.my_peer_id.into();
         true
     }
 
@@ -325,7 +973,9 @@ impl RpcServerHook for MagicDnsServerInstanceData {
             item.value_mut().remove(&remote_addr);
         }
         self.route_infos.retain(|_, v| !v.is_empty());
+        self.forward_rules.remove(&remote_addr);
         self.update().await;
+        self.update_forward_zones().await;
     }
 }
 
@@ -333,7 +983,8 @@ pub struct MagicDnsServerInstance {
     rpc_server: StandAloneServer<TcpTunnelListener>,
     pub(super) data: Arc<MagicDnsServerInstanceData>,
     peer_mgr: Arc<PeerManager>,
-    tun_inet: Ipv4Inet,
+    tun_inet: Option<Ipv4Inet>,
+    tun_inet6: Option<Ipv6Inet>,
 }
 
 fn get_system_config(
@@ -352,6 +1003,21 @@ fn get_system_config(
         return Ok(Some(Box::new(DarwinConfigurator::new())));
     }
 
+    #[cfg(target_os = "linux")]
+    {
+        use super::system_config::linux::new_os_configurator;
+        let Some(tun_name) = _tun_name else {
+            return Ok(None);
+        };
+        return match new_os_configurator(tun_name) {
+            Ok(c) => Ok(Some(c)),
+            Err(e) => {
+                tracing::warn!("system dns is not configured: {:?}", e);
+                Ok(None)
+            }
+        };
+    }
+
     #[allow(unreachable_code)]
     Ok(None)
 }
@@ -360,49 +1026,84 @@ impl MagicDnsServerInstance {
     pub async fn new(
         peer_mgr: Arc<PeerManager>,
         tun_dev: Option<String>,
-        tun_inet: Ipv4Inet,
+        tun_inet: Option<Ipv4Inet>,
+        tun_inet6: Option<Ipv6Inet>,
         fake_ip: Ipv4Addr,
+        fake_ip6: Ipv6Addr,
     ) -> Result<Self, anyhow::Error> {
+        if tun_inet.is_none() && tun_inet6.is_none() {
+            return Err(anyhow::anyhow!("No virtual ip address for magic dns"));
+        }
+
         let tcp_listener = TcpTunnelListener::new(MAGIC_DNS_INSTANCE_ADDR.parse().unwrap());
         let mut rpc_server = StandAloneServer::new(tcp_listener);
         rpc_server.serve().await?;
 
-        let bind_addr = tun_inet.address();
+        let mut general_config = GeneralConfigBuilder::default();
+        if let Some(tun_inet) = tun_inet {
+            general_config
+                .listen_udp(format!("{}:0", tun_inet.address()))
+                .listen_tcp(format!("{}:0", tun_inet.address()));
+        }
+        if let Some(tun_inet6) = tun_inet6 {
+            general_config
+                .listen_udp6(format!("[{}]:0", tun_inet6.address()))
+                .listen_tcp6(format!("[{}]:0", tun_inet6.address()));
+        }
+
+        let global_ctx = peer_mgr.get_global_ctx();
+        let mut upstreams = vec![];
+        for url in global_ctx.config.get_dns_upstreams().iter() {
+            match UpstreamConfig::resolve(url).await {
+                Ok(upstream) => upstreams.push(upstream),
+                Err(e) => tracing::warn!(?e, %url, "ignore invalid magic dns upstream"),
+            }
+        }
 
         let dns_config = RunConfigBuilder::default()
-            .general(
-                GeneralConfigBuilder::default()
-                    .listen_udp(format!("{}:0", bind_addr))
-                    .listen_tcp(format!("{}:0", bind_addr))
-                    .build()
-                    .unwrap(),
-            )
-            .excluded_forward_nameservers(vec![fake_ip.into()])
+            .general(general_config.build().unwrap())
+            .excluded_forward_nameservers(vec![fake_ip.into(), fake_ip6.into()])
+            .upstreams(upstreams)
             .build()
             .unwrap();
-        let mut dns_server = Server::new(dns_config);
+        let mut dns_server =
+            Server::new_with_stats_manager(dns_config, global_ctx.stats_manager().clone());
         dns_server.run().await?;
 
-        if !tun_inet.contains(&fake_ip) && tun_dev.is_some() {
-            let cost = if cfg!(target_os = "windows") {
-                Some(4)
-            } else {
-                None
-            };
-            let ifcfg = IfConfiger {};
-            ifcfg
-                .add_ipv4_route(tun_dev.as_ref().unwrap(), fake_ip, 32, cost)
-                .await?;
+        let cost = if cfg!(target_os = "windows") {
+            Some(4)
+        } else {
+            None
+        };
+        let ifcfg = IfConfiger {};
+        if let (Some(tun_inet), Some(tun_dev)) = (tun_inet, tun_dev.as_ref()) {
+            if !tun_inet.contains(&fake_ip) {
+                ifcfg.add_ipv4_route(tun_dev, fake_ip, 32, cost).await?;
+            }
+        }
+        if let (Some(tun_inet6), Some(tun_dev)) = (tun_inet6, tun_dev.as_ref()) {
+            if !tun_inet6.contains(&fake_ip6) {
+                ifcfg.add_ipv6_route(tun_dev, fake_ip6, 128, cost).await?;
+            }
         }
 
         let data = Arc::new(MagicDnsServerInstanceData {
             dns_server,
             tun_dev: tun_dev.clone(),
-            tun_ip: tun_inet.address(),
+            tun_ip: tun_inet.map(|x| x.address()),
             fake_ip,
+            tun_ip6: tun_inet6.map(|x| x.address()),
+            fake_ip6,
             my_peer_id: peer_mgr.my_peer_id(),
             route_infos: DashMap::new(),
-            system_config: get_system_config(tun_dev.as_deref())?,
+            forward_rules: DashMap::new(),
+            forward_zones: std::sync::Mutex::new(BTreeMap::new()),
+            hostname_conflicts: DashMap::new(),
+            hostname_holders: DashMap::new(),
+            user_record_owners: DashMap::new(),
+            reverse_zones: DashMap::new(),
+            global_ctx: global_ctx.clone(),
+            system_config: get_system_config(tun_dev.as_deref())?.map(Arc::from),
         });
 
         rpc_server
@@ -414,16 +1115,18 @@ impl MagicDnsServerInstance {
             .add_nic_packet_process_pipeline(Box::new(data.clone()))
             .await;
 
-        let data_clone = data.clone();
-        tokio::task::spawn_blocking(move || data_clone.do_system_config(DEFAULT_ET_DNS_ZONE))
-            .await
-            .context("Failed to configure system")??;
+        data.apply_system_config().await?;
+        if let Some(c) = &data.system_config {
+            tracing::info!(method = c.name(), "system dns configured");
+            global_ctx.issue_event(GlobalCtxEvent::DnsSystemConfigured(c.name().to_string()));
+        }
 
         Ok(Self {
             rpc_server,
             data,
             peer_mgr,
             tun_inet,
+            tun_inet6,
         })
     }
 
@@ -435,11 +1138,20 @@ impl MagicDnsServerInstance {
             }
         }
 
-        if !self.tun_inet.contains(&self.data.fake_ip) && self.data.tun_dev.is_some() {
-            let ifcfg = IfConfiger {};
-            let _ = ifcfg
-                .remove_ipv4_route(self.data.tun_dev.as_ref().unwrap(), self.data.fake_ip, 32)
-                .await;
+        let ifcfg = IfConfiger {};
+        if let (Some(tun_inet), Some(tun_dev)) = (self.tun_inet, self.data.tun_dev.as_ref()) {
+            if !tun_inet.contains(&self.data.fake_ip) {
+                let _ = ifcfg
+                    .remove_ipv4_route(tun_dev, self.data.fake_ip, 32)
+                    .await;
+            }
+        }
+        if let (Some(tun_inet6), Some(tun_dev)) = (self.tun_inet6, self.data.tun_dev.as_ref()) {
+            if !tun_inet6.contains(&self.data.fake_ip6) {
+                let _ = ifcfg
+                    .remove_ipv6_route(tun_dev, self.data.fake_ip6, 128)
+                    .await;
+            }
         }
 
         let _ = self
@@ -112,6 +112,10 @@ impl SystemConfig for DarwinConfigurator {
     fn close(&self) -> io::Result<()> {
         self.do_close()
     }
+
+    fn name(&self) -> &'static str {
+        "macos-resolver"
+    }
 }
 
 #[cfg(test)]
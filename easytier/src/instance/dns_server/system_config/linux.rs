@@ -2,14 +2,19 @@
 
 use crate::defer;
 use anyhow::{Context, Result};
+use dbus::arg::{PropMap, RefArg, Variant};
 use dbus::blocking::stdintf::org_freedesktop_dbus::Properties as _;
+use std::collections::HashMap;
 use std::fs;
-use std::net::Ipv4Addr;
+use std::io::Write as _;
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::Path;
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::time::Duration;
 use version_compare::Cmp;
 
+use super::{OSConfig, SystemConfig};
+
 // 声明依赖项（需要添加到Cargo.toml）
 // use dbus::blocking::Connection;
 // use nix::unistd::AccessFlags;
@@ -17,6 +22,13 @@ use version_compare::Cmp;
 
 // 常量定义
 const RESOLV_CONF: &str = "/etc/resolv.conf";
+const RESOLV_CONF_BACKUP: &str = "/etc/resolv.pre-easytier.conf";
+// 写入 /etc/resolv.conf 并创建备份的进程的 pid
+const RESOLV_CONF_OWNER: &str = "/etc/resolv.pre-easytier.pid";
+const RESOLV_CONF_HEADER: &str = "# resolv.conf(5) file generated by easytier\n\
+# DO NOT EDIT THIS FILE BY HAND -- CHANGES WILL BE OVERWRITTEN\n";
+// libc 解析器只使用前三个 nameserver
+const MAX_NAMESERVERS: usize = 3;
 const PING_TIMEOUT: Duration = Duration::from_secs(1);
 
 // 错误类型定义
@@ -42,11 +54,6 @@ struct OSConfigEnv {
     resolvconf_style: Box<dyn Fn() -> String>,
 }
 
-// DNS管理器trait
-trait OSConfigurator: Send + Sync {
-    // 实现相关方法
-}
-
 // 文件系统操作trait
 trait FileSystem {
     fn read_file(&self, path: &str) -> Result<Vec<u8>>;
@@ -108,18 +115,14 @@ pub fn resolvconf_style() -> String {
     // 执行 resolvconf --version 命令
     let output = match Command::new("resolvconf").arg("--version").output() {
         Ok(output) => output,
-        Err(e) => {
-            // 处理命令执行错误
-            if let Some(code) = e.raw_os_error() {
-                // Debian 版本的 resolvconf 不支持 --version，返回特定错误码 99
-                if code == 99 {
-                    return "debian".to_string();
-                }
-            }
-            return String::new(); // 其他错误返回空字符串
-        }
+        Err(_) => return String::new(),
     };
 
+    // Debian 版本的 resolvconf 不支持 --version，以退出码 99 结束
+    if output.status.code() == Some(99) {
+        return "debian".to_string();
+    }
+
     // 检查输出是否以 "Debian resolvconf" 开头
     if output.stdout.starts_with(b"Debian resolvconf") {
         return "debian".to_string();
@@ -141,30 +144,57 @@ fn new_os_config_env() -> OSConfigEnv {
     }
 }
 
-// 创建DNS配置器
-fn new_os_configurator(_interface_name: String) -> Result<()> {
+/// 检测系统 DNS 的管理方式并返回对应的配置器，检测到的方式不可用时直接管理
+/// /etc/resolv.conf
+pub fn new_os_configurator(interface_name: &str) -> Result<Box<dyn SystemConfig>> {
+    // 上一次运行可能没有恢复原文件就退出了
+    if let Err(e) = DirectConfigurator::restore_stale() {
+        tracing::warn!("dns: failed to restore {}: {:?}", RESOLV_CONF, e);
+    }
+
     let env = new_os_config_env();
+    let mode = match dns_mode(&env) {
+        Ok(mode) => mode,
+        Err(e) => {
+            tracing::warn!("dns: failed to detect dns mode: {:?}", e);
+            "direct".to_string()
+        }
+    };
+    tracing::info!("dns: detected {} mode", mode);
 
-    let mode = dns_mode(&env).context("Failed to detect DNS mode")?;
-
-    tracing::info!("dns: using {} mode", mode);
-
-    // match mode.as_str() {
-    //     "direct" => Ok(Box::new(DirectManager::new(env.fs)?)),
-    //     // "systemd-resolved" => Ok(Box::new(ResolvedManager::new(
-    //     //     &logf,
-    //     //     health,
-    //     //     interface_name,
-    //     // )?)),
-    //     // "network-manager" => Ok(Box::new(NMManager::new(interface_name)?)),
-    //     // "debian-resolvconf" => Ok(Box::new(DebianResolvconfManager::new(&logf)?)),
-    //     // "openresolv" => Ok(Box::new(OpenresolvManager::new(&logf)?)),
-    //     _ => {
-    //         tracing::warn!("Unexpected DNS mode {}, using direct manager", mode);
-    //         Ok(Box::new(DirectManager::new(env.fs)?))
-    //     }
-    // }
-    Ok(())
+    let mut chain = vec![mode.as_str()];
+    if mode != "direct" {
+        chain.push("direct");
+    }
+
+    let mut errors = vec![];
+    for mode in chain {
+        let ret: Result<Box<dyn SystemConfig>> = match mode {
+            "systemd-resolved" => {
+                ResolvedConfigurator::new(interface_name).map(|c| Box::new(c) as _)
+            }
+            "network-manager" => NmConfigurator::new(interface_name).map(|c| Box::new(c) as _),
+            "debian-resolvconf" | "openresolv" => {
+                ResolvconfConfigurator::new(mode, interface_name).map(|c| Box::new(c) as _)
+            }
+            _ => DirectConfigurator::new().map(|c| Box::new(c) as _),
+        };
+        match ret {
+            Ok(c) => {
+                tracing::info!("dns: using {} mode", mode);
+                return Ok(c);
+            }
+            Err(e) => {
+                tracing::warn!("dns: {} mode is not usable: {:?}", mode, e);
+                errors.push(format!("{}: {:#}", mode, e));
+            }
+        }
+    }
+
+    Err(anyhow::anyhow!(
+        "no usable dns configuration method, {}",
+        errors.join("; ")
+    ))
 }
 
 use std::io::{self, BufRead, Cursor};
@@ -231,7 +261,10 @@ fn dns_mode(env: &OSConfigEnv) -> Result<String> {
     // 读取resolv.conf
     let content = match env.fs.read_file(RESOLV_CONF) {
         Ok(content) => content,
-        Err(e) if e.to_string().contains("NotFound") => {
+        Err(e)
+            if e.downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::NotFound) =>
+        {
             dbg("rc", "missing");
             return Ok("direct".to_string());
         }
@@ -242,6 +275,10 @@ fn dns_mode(env: &OSConfigEnv) -> Result<String> {
     match resolv_owner(&content).as_str() {
         "systemd-resolved" => {
             dbg("rc", "resolved");
+            if !resolved_up {
+                dbg("resolved", "not-running");
+                return Ok("direct".to_string());
+            }
             // 检查是否实际使用resolved
             if let Err(e) = resolved_is_actually_resolver(env, &dbg, &content) {
                 tracing::warn!("resolvedIsActuallyResolver error: {}", e);
@@ -249,19 +286,46 @@ fn dns_mode(env: &OSConfigEnv) -> Result<String> {
                 return Ok("direct".to_string());
             }
 
-            // NetworkManager检查逻辑...
-
             Ok("systemd-resolved".to_string())
         }
         "resolvconf" => {
-            // resolvconf处理逻辑...
-            Ok("debian-resolvconf".to_string())
+            dbg("rc", "resolvconf");
+            match (env.resolvconf_style)().as_str() {
+                "" => {
+                    dbg("resolvconf", "no");
+                    Ok("direct".to_string())
+                }
+                "debian" => {
+                    dbg("resolvconf", "debian");
+                    Ok("debian-resolvconf".to_string())
+                }
+                _ => {
+                    dbg("resolvconf", "openresolv");
+                    Ok("openresolv".to_string())
+                }
+            }
         }
         "NetworkManager" => {
-            // NetworkManager处理逻辑...
+            dbg("rc", "nm");
+            if !resolved_up || (env.nm_is_using_resolved)().is_err() {
+                dbg("nm-resolved", "no");
+                return Ok("network-manager".to_string());
+            }
+            dbg("nm-resolved", "yes");
+            // 这些版本会覆盖其他程序在 resolved 上设置的链路 DNS
+            if (env.nm_version_between)("1.26.0", "1.26.4").unwrap_or(false) {
+                dbg("nm-safe", "no");
+                return Ok("network-manager".to_string());
+            }
             Ok("systemd-resolved".to_string())
         }
-        _ => Ok("direct".to_string()),
+        _ => {
+            dbg("rc", "unknown");
+            if resolved_up && resolved_is_actually_resolver(env, &dbg, &content).is_ok() {
+                return Ok("systemd-resolved".to_string());
+            }
+            Ok("direct".to_string())
+        }
     }
 }
 
@@ -349,6 +413,454 @@ fn is_libnss_resolve_used(env: &OSConfigEnv) -> Result<()> {
     Err(anyhow::anyhow!("libnss_resolve not used"))
 }
 
+const RESOLVED_DBUS_NAME: &str = "org.freedesktop.resolve1";
+const RESOLVED_DBUS_PATH: &str = "/org/freedesktop/resolve1";
+const RESOLVED_MANAGER_IFACE: &str = "org.freedesktop.resolve1.Manager";
+
+/// 在 tun 设备上设置 systemd-resolved 的链路 DNS。该链路不是默认路由，resolved
+/// 只把配置的域名的查询发给我们，其余的查询走其他链路（split dns）
+pub struct ResolvedConfigurator {
+    ifindex: i32,
+}
+
+impl ResolvedConfigurator {
+    pub fn new(interface_name: &str) -> Result<Self> {
+        dbus_ping(RESOLVED_DBUS_NAME, RESOLVED_DBUS_PATH)
+            .context("systemd-resolved is not running")?;
+        let ifindex = nix::net::if_::if_nametoindex(interface_name)
+            .with_context(|| format!("Failed to get index of interface {}", interface_name))?;
+        Ok(Self {
+            ifindex: ifindex as i32,
+        })
+    }
+
+    fn call<A: dbus::arg::AppendAll>(&self, method: &str, args: A) -> Result<()> {
+        let conn = dbus::blocking::Connection::new_system()?;
+        let proxy = conn.with_proxy(RESOLVED_DBUS_NAME, RESOLVED_DBUS_PATH, PING_TIMEOUT);
+        let _: () = proxy
+            .method_call(RESOLVED_MANAGER_IFACE, method, args)
+            .with_context(|| format!("resolved {} failed", method))?;
+        Ok(())
+    }
+
+    fn do_set_dns(&self, cfg: &OSConfig) -> Result<()> {
+        let mut addrs: Vec<(i32, Vec<u8>)> = vec![];
+        for ns in cfg.nameservers.iter() {
+            match ns.parse::<IpAddr>()? {
+                IpAddr::V4(ip) => addrs.push((nix::libc::AF_INET, ip.octets().to_vec())),
+                IpAddr::V6(ip) => addrs.push((nix::libc::AF_INET6, ip.octets().to_vec())),
+            }
+        }
+        self.call("SetLinkDNS", (self.ifindex, addrs))?;
+
+        // 搜索域同时也是路由域，匹配域只用于路由
+        let mut domains: Vec<(String, bool)> = cfg
+            .search_domains
+            .iter()
+            .map(|d| (d.trim_end_matches('.').to_string(), false))
+            .collect();
+        for d in cfg.match_domains.iter() {
+            let d = d.trim_end_matches('.').to_string();
+            if !domains.iter().any(|(x, _)| *x == d) {
+                domains.push((d, true));
+            }
+        }
+        self.call("SetLinkDomains", (self.ifindex, domains))?;
+
+        // systemd 240 起可用，旧版本本来就不会把只有路由域的链路当作默认路由
+        if let Err(e) = self.call("SetLinkDefaultRoute", (self.ifindex, false)) {
+            tracing::debug!("SetLinkDefaultRoute failed: {:?}", e);
+        }
+        Ok(())
+    }
+}
+
+impl SystemConfig for ResolvedConfigurator {
+    fn set_dns(&self, cfg: &OSConfig) -> io::Result<()> {
+        self.do_set_dns(cfg).map_err(io::Error::other)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.call("RevertLink", (self.ifindex,))
+            .map_err(io::Error::other)
+    }
+
+    fn name(&self) -> &'static str {
+        "systemd-resolved"
+    }
+}
+
+const NM_DBUS_NAME: &str = "org.freedesktop.NetworkManager";
+const NM_DBUS_PATH: &str = "/org/freedesktop/NetworkManager";
+const NM_DEVICE_IFACE: &str = "org.freedesktop.NetworkManager.Device";
+// 越小越优先，vpn 连接默认为 50，其他连接为 100。其他 nameserver 返回的
+// NXDOMAIN 是最终结果，所以我们必须排在最前
+const NM_DNS_PRIORITY: i32 = 40;
+
+/// NetworkManager 自己写 /etc/resolv.conf 时（default 或 dnsmasq 模式）通过它设置
+/// tun 设备的 DNS。直接修改设备当前应用的连接并重新应用，不做持久化，关闭时重新
+/// 应用第一次修改前的连接
+pub struct NmConfigurator {
+    interface_name: String,
+    original: std::sync::Mutex<Option<HashMap<String, PropMap>>>,
+}
+
+impl NmConfigurator {
+    pub fn new(interface_name: &str) -> Result<Self> {
+        let ret = Self {
+            interface_name: interface_name.to_string(),
+            original: std::sync::Mutex::new(None),
+        };
+        let conn = dbus::blocking::Connection::new_system()?;
+        let device = ret.device(&conn)?;
+        let proxy = conn.with_proxy(NM_DBUS_NAME, device, PING_TIMEOUT);
+        // 未托管的设备没有可以设置 DNS 的已应用连接，不接管该设备，交给下一种方式
+        let managed: bool = proxy.get(NM_DEVICE_IFACE, "Managed")?;
+        if !managed {
+            return Err(anyhow::anyhow!(
+                "interface {} is not managed by NetworkManager",
+                interface_name
+            ));
+        }
+        Ok(ret)
+    }
+
+    fn device(&self, conn: &dbus::blocking::Connection) -> Result<dbus::Path<'static>> {
+        let proxy = conn.with_proxy(NM_DBUS_NAME, NM_DBUS_PATH, PING_TIMEOUT);
+        let (device,): (dbus::Path<'static>,) = proxy
+            .method_call(NM_DBUS_NAME, "GetDeviceByIpIface", (&self.interface_name,))
+            .with_context(|| {
+                format!(
+                    "NetworkManager does not know interface {}",
+                    self.interface_name
+                )
+            })?;
+        Ok(device)
+    }
+
+    fn reapply(
+        &self,
+        conn: &dbus::blocking::Connection,
+        mut settings: HashMap<String, PropMap>,
+        version: u64,
+    ) -> Result<()> {
+        let device = self.device(conn)?;
+        let proxy = conn.with_proxy(NM_DBUS_NAME, device, PING_TIMEOUT);
+        // 已废弃的键与 address-data 和 route-data 重复，会导致重新应用失败
+        for family in ["ipv4", "ipv6"] {
+            if let Some(section) = settings.get_mut(family) {
+                section.remove("addresses");
+                section.remove("routes");
+            }
+        }
+        let _: () = proxy
+            .method_call(NM_DEVICE_IFACE, "Reapply", (settings, version, 0u32))
+            .context("Failed to reapply connection")?;
+        Ok(())
+    }
+
+    fn applied_connection(
+        &self,
+        conn: &dbus::blocking::Connection,
+    ) -> Result<(HashMap<String, PropMap>, u64)> {
+        let device = self.device(conn)?;
+        let proxy = conn.with_proxy(NM_DBUS_NAME, device, PING_TIMEOUT);
+        let (settings, version): (HashMap<String, PropMap>, u64) = proxy
+            .method_call(NM_DEVICE_IFACE, "GetAppliedConnection", (0u32,))
+            .context("Failed to get applied connection")?;
+        Ok((settings, version))
+    }
+
+    fn do_set_dns(&self, cfg: &OSConfig) -> Result<()> {
+        let conn = dbus::blocking::Connection::new_system()?;
+        let (mut settings, version) = self.applied_connection(&conn)?;
+        self.original
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| clone_settings(&settings));
+
+        let mut dns4 = vec![];
+        let mut dns6 = vec![];
+        for ns in cfg.nameservers.iter() {
+            match ns.parse::<IpAddr>()? {
+                IpAddr::V4(ip) => dns4.push(u32::from_ne_bytes(ip.octets())),
+                IpAddr::V6(ip) => dns6.push(ip.octets().to_vec()),
+            }
+        }
+        // 以 ~ 开头的域名只用于路由
+        let mut search: Vec<String> = cfg
+            .search_domains
+            .iter()
+            .map(|d| d.trim_end_matches('.').to_string())
+            .collect();
+        for d in cfg.match_domains.iter() {
+            let d = d.trim_end_matches('.');
+            if !search.iter().any(|x| x == d) {
+                search.push(format!("~{}", d));
+            }
+        }
+
+        fn variant<T: RefArg + 'static>(v: T) -> Variant<Box<dyn RefArg>> {
+            Variant(Box::new(v))
+        }
+        for (family, dns) in [("ipv4", variant(dns4)), ("ipv6", variant(dns6))] {
+            let section = settings.entry(family.to_string()).or_default();
+            section.insert("dns".to_string(), dns);
+            section.insert("dns-search".to_string(), variant(search.clone()));
+            section.insert("dns-priority".to_string(), variant(NM_DNS_PRIORITY));
+            section.insert("ignore-auto-dns".to_string(), variant(true));
+        }
+
+        self.reapply(&conn, settings, version)
+    }
+
+    fn do_close(&self) -> Result<()> {
+        let Some(original) = self.original.lock().unwrap().take() else {
+            return Ok(());
+        };
+        let conn = dbus::blocking::Connection::new_system()?;
+        let (_, version) = self.applied_connection(&conn)?;
+        self.reapply(&conn, original, version)
+    }
+}
+
+fn clone_settings(settings: &HashMap<String, PropMap>) -> HashMap<String, PropMap> {
+    settings
+        .iter()
+        .map(|(name, section)| {
+            let section = section
+                .iter()
+                .map(|(k, v)| (k.clone(), Variant(v.0.box_clone())))
+                .collect();
+            (name.clone(), section)
+        })
+        .collect()
+}
+
+impl SystemConfig for NmConfigurator {
+    fn set_dns(&self, cfg: &OSConfig) -> io::Result<()> {
+        self.do_set_dns(cfg).map_err(io::Error::other)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.do_close().map_err(io::Error::other)
+    }
+
+    fn name(&self) -> &'static str {
+        "network-manager"
+    }
+}
+
+/// 向 resolvconf 注册我们的 nameserver，由它与其他接口的 nameserver 合并写入
+/// /etc/resolv.conf。resolv.conf 无法按域名分流，我们的 nameserver 排在最前，
+/// 所有查询都会经过 EasyTier 的 DNS，其他域名由它转发给上游
+pub struct ResolvconfConfigurator {
+    openresolv: bool,
+    record: String,
+}
+
+impl ResolvconfConfigurator {
+    pub fn new(mode: &str, interface_name: &str) -> Result<Self> {
+        which::which("resolvconf").context("resolvconf not found")?;
+        let openresolv = mode == "openresolv";
+        // debian resolvconf 按 /etc/resolvconf/interface-order 排序，lo.* 的记录
+        // 排在真实接口之前
+        let record = if openresolv {
+            interface_name.to_string()
+        } else {
+            format!("lo.easytier-{}", interface_name)
+        };
+        Ok(Self { openresolv, record })
+    }
+
+    fn run(&self, args: &[&str], stdin: Option<&str>) -> Result<()> {
+        let mut child = Command::new("resolvconf")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to run resolvconf")?;
+        if let Some(input) = stdin {
+            child.stdin.take().unwrap().write_all(input.as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "resolvconf {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl SystemConfig for ResolvconfConfigurator {
+    fn set_dns(&self, cfg: &OSConfig) -> io::Result<()> {
+        let content = resolv_conf_content(cfg, &[]);
+        let ret = if self.openresolv {
+            // -m 0：最高优先级，我们的 nameserver 排在其他接口之前
+            self.run(&["-m", "0", "-a", &self.record], Some(&content))
+        } else {
+            self.run(&["-a", &self.record], Some(&content))
+        };
+        ret.map_err(io::Error::other)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        let ret = if self.openresolv {
+            self.run(&["-f", "-d", &self.record], None)
+        } else {
+            self.run(&["-d", &self.record], None)
+        };
+        ret.map_err(io::Error::other)
+    }
+
+    fn name(&self) -> &'static str {
+        if self.openresolv {
+            "openresolv"
+        } else {
+            "debian-resolvconf"
+        }
+    }
+}
+
+/// 没有其他程序管理时直接写 /etc/resolv.conf。第一次写入时把原文件移走，关闭时
+/// 放回，我们的 nameserver 排在最前，原有的保留作为后备。未关闭就退出的运行
+/// 在其进程结束后由下一次运行清理。
+///
+/// 该模式无法按域名分流（split dns）：libc 总是先查询第一个 nameserver，所有
+/// 查询都会经过 EasyTier 的 DNS，其他域名由它转发给上游，EasyTier 的 DNS 不可用
+/// 时才会超时换到原有的 nameserver
+pub struct DirectConfigurator {
+    original: Vec<u8>,
+}
+
+impl DirectConfigurator {
+    pub fn new() -> Result<Self> {
+        nix::unistd::access("/etc", nix::unistd::AccessFlags::W_OK)
+            .context("/etc is not writable")?;
+        let original = match fs::read(RESOLV_CONF) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e).context("reading /etc/resolv.conf"),
+        };
+        Ok(Self { original })
+    }
+
+    /// 放回原文件，没有原文件时删除我们写入的文件
+    fn restore() -> io::Result<()> {
+        if Path::new(RESOLV_CONF_BACKUP).exists() {
+            tracing::info!("dns: restoring {} from {}", RESOLV_CONF, RESOLV_CONF_BACKUP);
+            fs::rename(RESOLV_CONF_BACKUP, RESOLV_CONF)?;
+        } else if fs::read(RESOLV_CONF).is_ok_and(|c| c.starts_with(RESOLV_CONF_HEADER.as_bytes()))
+        {
+            fs::remove_file(RESOLV_CONF)?;
+        }
+        match fs::remove_file(RESOLV_CONF_OWNER) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// 恢复上一次未关闭就退出的运行留下的文件，写入它的进程仍在运行时不做任何修改
+    fn restore_stale() -> io::Result<()> {
+        let Ok(owner) = fs::read_to_string(RESOLV_CONF_OWNER) else {
+            return Ok(());
+        };
+        if let Ok(pid) = owner.trim().parse::<u32>() {
+            if pid == std::process::id() || Path::new(&format!("/proc/{}", pid)).exists() {
+                return Ok(());
+            }
+        }
+        tracing::info!("dns: owner {} of {} is gone", owner.trim(), RESOLV_CONF);
+        Self::restore()
+    }
+
+    fn do_set_dns(&self, cfg: &OSConfig) -> io::Result<()> {
+        let content = resolv_conf_content(cfg, &self.original);
+        if fs::read(RESOLV_CONF).is_ok_and(|c| c == content.as_bytes()) {
+            return Ok(());
+        }
+        if !Path::new(RESOLV_CONF_OWNER).exists() {
+            fs::write(RESOLV_CONF_OWNER, format!("{}\n", std::process::id()))?;
+        }
+        // rename 会保留符号链接本身，可以原样恢复
+        if !Path::new(RESOLV_CONF_BACKUP).exists() && fs::symlink_metadata(RESOLV_CONF).is_ok() {
+            fs::rename(RESOLV_CONF, RESOLV_CONF_BACKUP)?;
+        }
+        let tmp = format!("{}.easytier", RESOLV_CONF);
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, RESOLV_CONF)
+    }
+}
+
+impl SystemConfig for DirectConfigurator {
+    fn set_dns(&self, cfg: &OSConfig) -> io::Result<()> {
+        self.do_set_dns(cfg)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        Self::restore()
+    }
+
+    fn name(&self) -> &'static str {
+        "direct"
+    }
+}
+
+/// 生成 resolv.conf，`cfg` 的 nameserver 和搜索域在前，后面是 `original` 中的
+/// 条目和选项
+fn resolv_conf_content(cfg: &OSConfig, original: &[u8]) -> String {
+    let mut nameservers = cfg.nameservers.clone();
+    let mut search: Vec<String> = cfg
+        .search_domains
+        .iter()
+        .map(|d| d.trim_end_matches('.').to_string())
+        .collect();
+    let mut others = vec![];
+    for line in String::from_utf8_lossy(original).lines() {
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            Some("nameserver") => {
+                if let Some(ns) = parts.next() {
+                    if !nameservers.iter().any(|x| x == ns) {
+                        nameservers.push(ns.to_string());
+                    }
+                }
+            }
+            Some("search") | Some("domain") => {
+                for d in parts {
+                    let d = d.trim_end_matches('.');
+                    if !search.iter().any(|x| x == d) {
+                        search.push(d.to_string());
+                    }
+                }
+            }
+            Some(key) if !key.starts_with('#') && !key.starts_with(';') => {
+                others.push(line.trim().to_string());
+            }
+            _ => {}
+        }
+    }
+
+    let mut content = RESOLV_CONF_HEADER.to_string();
+    for ns in nameservers.iter().take(MAX_NAMESERVERS) {
+        content.push_str(&format!("nameserver {}\n", ns));
+    }
+    if !search.is_empty() {
+        content.push_str(&format!("search {}\n", search.join(" ")));
+    }
+    for line in others {
+        content.push_str(&line);
+        content.push('\n');
+    }
+    content
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -359,4 +871,103 @@ mod tests {
         let mode = dns_mode(&env).unwrap();
         println!("Detected DNS mode: {}", mode);
     }
+
+    struct FakeFS(HashMap<&'static str, &'static str>);
+
+    impl FileSystem for FakeFS {
+        fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+            self.0
+                .get(path)
+                .map(|c| c.as_bytes().to_vec())
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound).into())
+        }
+
+        fn exists(&self, path: &str) -> bool {
+            self.0.contains_key(path)
+        }
+    }
+
+    fn fake_env(resolv_conf: Option<&'static str>, resolved_up: bool, style: &str) -> OSConfigEnv {
+        let mut files = HashMap::new();
+        if let Some(c) = resolv_conf {
+            files.insert(RESOLV_CONF, c);
+        }
+        let style = style.to_string();
+        OSConfigEnv {
+            fs: Box::new(FakeFS(files)),
+            dbus_ping: Box::new(move |_, _| {
+                if resolved_up {
+                    Ok(())
+                } else {
+                    Err(anyhow::anyhow!("not running"))
+                }
+            }),
+            dbus_read_string: Box::new(|_, _, _, _| Ok(String::new())),
+            nm_is_using_resolved: Box::new(|| Err(anyhow::anyhow!("dns=default"))),
+            nm_version_between: Box::new(|_, _| Ok(false)),
+            resolvconf_style: Box::new(move || style.clone()),
+        }
+    }
+
+    #[test]
+    fn dns_mode_fallback() {
+        let resolved = "# This is /run/systemd/resolve/stub-resolv.conf managed by man:systemd-resolved(8).\nnameserver 127.0.0.53\n";
+        assert_eq!(dns_mode(&fake_env(None, false, "")).unwrap(), "direct");
+        assert_eq!(
+            dns_mode(&fake_env(Some(resolved), true, "")).unwrap(),
+            "systemd-resolved"
+        );
+        assert_eq!(
+            dns_mode(&fake_env(Some(resolved), false, "")).unwrap(),
+            "direct"
+        );
+
+        let resolvconf = "# Dynamic resolv.conf(5) file for glibc resolver(3) generated by resolvconf(8)\nnameserver 10.0.0.1\n";
+        assert_eq!(
+            dns_mode(&fake_env(Some(resolvconf), false, "debian")).unwrap(),
+            "debian-resolvconf"
+        );
+        assert_eq!(
+            dns_mode(&fake_env(Some(resolvconf), false, "openresolv")).unwrap(),
+            "openresolv"
+        );
+        assert_eq!(
+            dns_mode(&fake_env(Some(resolvconf), false, "")).unwrap(),
+            "direct"
+        );
+
+        let nm = "# Generated by NetworkManager\nnameserver 10.0.0.1\n";
+        assert_eq!(
+            dns_mode(&fake_env(Some(nm), true, "")).unwrap(),
+            "network-manager"
+        );
+        assert_eq!(
+            dns_mode(&fake_env(Some("nameserver 1.1.1.1\n"), false, "")).unwrap(),
+            "direct"
+        );
+    }
+
+    #[test]
+    fn direct_resolv_conf() {
+        let cfg = OSConfig {
+            nameservers: vec!["100.100.100.101".to_string()],
+            search_domains: vec!["et.net.".to_string()],
+            match_domains: vec![],
+        };
+        let original = "# comment\nnameserver 10.0.0.1\nnameserver 100.100.100.101\nnameserver 10.0.0.2\nnameserver 10.0.0.3\nsearch lan\noptions edns0\n";
+        assert_eq!(
+            resolv_conf_content(&cfg, original.as_bytes()),
+            format!(
+                "{}nameserver 100.100.100.101\nnameserver 10.0.0.1\nnameserver 10.0.0.2\nsearch et.net lan\noptions edns0\n",
+                RESOLV_CONF_HEADER
+            )
+        );
+        assert_eq!(
+            resolv_conf_content(&cfg, &[]),
+            format!(
+                "{}nameserver 100.100.100.101\nsearch et.net\n",
+                RESOLV_CONF_HEADER
+            )
+        );
+    }
 }
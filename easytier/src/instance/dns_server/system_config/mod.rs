@@ -17,4 +17,6 @@ pub struct OSConfig {
 pub trait SystemConfig: Send + Sync {
     fn set_dns(&self, cfg: &OSConfig) -> std::io::Result<()>;
     fn close(&self) -> std::io::Result<()>;
+    /// the mechanism used to configure the system dns, for status reports
+    fn name(&self) -> &'static str;
 }
@@ -161,6 +161,10 @@ impl SystemConfig for WindowsDNSManager {
     fn close(&self) -> io::Result<()> {
         Ok(())
     }
+
+    fn name(&self) -> &'static str {
+        "windows"
+    }
 }
 
 #[cfg(test)]
@@ -177,6 +181,7 @@ mod tests {
         use crate::instance::dns_server::{
             runner::DnsRunner,
             tests::{check_dns_record, prepare_env},
+            MAGIC_DNS_FAKE_IPV6,
         };
 
         let tun_ip = Ipv4Inet::from_str("10.144.144.10/24").unwrap();
@@ -185,7 +190,14 @@ mod tests {
 
         println!("dev_name: {}", tun_name);
         let fake_ip = Ipv4Addr::from_str("100.100.100.101").unwrap();
-        let mut dns_runner = DnsRunner::new(peer_mgr, Some(tun_name.clone()), tun_ip, fake_ip);
+        let mut dns_runner = DnsRunner::new(
+            peer_mgr,
+            Some(tun_name.clone()),
+            Some(tun_ip),
+            None,
+            fake_ip,
+            MAGIC_DNS_FAKE_IPV6.parse().unwrap(),
+        );
 
         let cancel_token = CancellationToken::new();
         let cancel_token_clone = cancel_token.clone();
@@ -1,9 +1,9 @@
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr as _;
 use std::sync::Arc;
 use std::time::Duration;
 
-use cidr::Ipv4Inet;
+use cidr::{Ipv4Inet, Ipv6Inet};
 use hickory_client::client::{Client, ClientHandle as _};
 use hickory_proto::rr;
 use hickory_proto::runtime::TokioRuntimeProvider;
@@ -11,23 +11,32 @@ use hickory_proto::udp::UdpClientStream;
 use tokio::sync::Notify;
 use tokio_util::sync::CancellationToken;
 
-use crate::common::global_ctx::tests::get_mock_global_ctx;
+use crate::common::global_ctx::{tests::get_mock_global_ctx, GlobalCtxEvent};
 use crate::connector::udp_hole_punch::tests::replace_stun_info_collector;
 
 use crate::instance::dns_server::runner::DnsRunner;
 use crate::instance::dns_server::server_instance::MagicDnsServerInstance;
-use crate::instance::dns_server::DEFAULT_ET_DNS_ZONE;
+use crate::instance::dns_server::{DEFAULT_ET_DNS_ZONE, MAGIC_DNS_FAKE_IPV6};
 use crate::instance::virtual_nic::NicCtx;
 use crate::peers::peer_manager::{PeerManager, RouteAlgoType};
 
 use crate::peers::create_packet_recv_chan;
 use crate::proto::cli::Route;
-use crate::proto::common::NatType;
+use crate::proto::common::{DnsRecordConfigPb, NatType, ServiceConfigPb};
 
 pub async fn prepare_env(dns_name: &str, tun_ip: Ipv4Inet) -> (Arc<PeerManager>, NicCtx) {
+    prepare_env_with_ipv6(dns_name, Some(tun_ip), None).await
+}
+
+pub async fn prepare_env_with_ipv6(
+    dns_name: &str,
+    tun_ip: Option<Ipv4Inet>,
+    tun_ip6: Option<Ipv6Inet>,
+) -> (Arc<PeerManager>, NicCtx) {
     let ctx = get_mock_global_ctx();
     ctx.set_hostname(dns_name.to_owned());
-    ctx.set_ipv4(Some(tun_ip));
+    ctx.set_ipv4(tun_ip);
+    ctx.set_ipv6(tun_ip6);
     let (s, r) = create_packet_recv_chan();
     let peer_mgr = Arc::new(PeerManager::new(RouteAlgoType::Ospf, ctx, s));
     peer_mgr.run().await.unwrap();
@@ -40,7 +49,7 @@ pub async fn prepare_env(dns_name: &str, tun_ip: Ipv4Inet) -> (Arc<PeerManager>,
         r,
         Arc::new(Notify::new()),
     );
-    virtual_nic.run(Some(tun_ip), None).await.unwrap();
+    virtual_nic.run(tun_ip, tun_ip6).await.unwrap();
 
     (peer_mgr, virtual_nic)
 }
@@ -73,21 +82,76 @@ pub async fn check_dns_record(fake_ip: &Ipv4Addr, domain: &str, expected_ip: &st
     );
 }
 
+pub async fn query_dns_record(
+    server_ip: IpAddr,
+    name: rr::Name,
+    record_type: rr::RecordType,
+) -> Vec<rr::Record> {
+    let stream = UdpClientStream::builder(
+        SocketAddr::new(server_ip, 53),
+        TokioRuntimeProvider::default(),
+    )
+    .build();
+    let (mut client, background) = Client::connect(stream).await.unwrap();
+    let background_task = tokio::spawn(background);
+    let response = client
+        .query(name, rr::DNSClass::IN, record_type)
+        .await
+        .unwrap();
+    drop(background_task);
+
+    response.answers().to_vec()
+}
+
 #[tokio::test]
 async fn test_magic_dns_server_instance() {
     let tun_ip = Ipv4Inet::from_str("10.144.144.10/24").unwrap();
     let (peer_mgr, virtual_nic) = prepare_env("test1", tun_ip).await;
     let tun_name = virtual_nic.ifname().await.unwrap();
     let fake_ip = Ipv4Addr::from_str("100.100.100.101").unwrap();
-    let dns_server_inst =
-        MagicDnsServerInstance::new(peer_mgr.clone(), Some(tun_name), tun_ip, fake_ip)
-            .await
-            .unwrap();
+    let dns_server_inst = MagicDnsServerInstance::new(
+        peer_mgr.clone(),
+        Some(tun_name),
+        Some(tun_ip),
+        None,
+        fake_ip,
+        MAGIC_DNS_FAKE_IPV6.parse().unwrap(),
+    )
+    .await
+    .unwrap();
 
     let routes = vec![
         Route {
             hostname: "test1".to_string(),
             ipv4_addr: Some(Ipv4Inet::from_str("8.8.8.8/24").unwrap().into()),
+            proxy_cidrs: vec!["192.168.1.0/24".to_string()],
+            dns_records: vec![
+                DnsRecordConfigPb {
+                    name: "printer.office".to_string(),
+                    r#type: "A".to_string(),
+                    value: "192.168.1.10".to_string(),
+                    ttl: 0,
+                },
+                // not in the zone, ignored
+                DnsRecordConfigPb {
+                    name: "printer.example.com.".to_string(),
+                    r#type: "A".to_string(),
+                    value: "192.168.1.10".to_string(),
+                    ttl: 0,
+                },
+                DnsRecordConfigPb {
+                    name: "bad".to_string(),
+                    r#type: "A".to_string(),
+                    value: "not an ip".to_string(),
+                    ttl: 0,
+                },
+            ],
+            services: vec![ServiceConfigPb {
+                name: "ssh".to_string(),
+                protocol: "tcp".to_string(),
+                port: 2222,
+                tags: vec!["user=admin".to_string()],
+            }],
             ..Default::default()
         },
         Route {
@@ -100,15 +164,228 @@ async fn test_magic_dns_server_instance() {
             ipv4_addr: Some(Ipv4Inet::from_str("8.8.8.8/24").unwrap().into()),
             ..Default::default()
         },
+        // networks broader than a /24, only the /24 of the host gets a
+        // zone, a proxied subnet gets one per /24
+        Route {
+            hostname: "wide".to_string(),
+            inst_id: "dddddddd-0000-0000-0000-000000000000".to_string(),
+            ipv4_addr: Some(Ipv4Inet::from_str("10.2.3.4/16").unwrap().into()),
+            proxy_cidrs: vec!["172.16.0.0/22".to_string()],
+            ..Default::default()
+        },
+        // records of another peer on names already taken are ignored
+        Route {
+            hostname: "other".to_string(),
+            inst_id: "cccccccc-0000-0000-0000-000000000000".to_string(),
+            ipv4_addr: Some(Ipv4Inet::from_str("10.1.1.3/24").unwrap().into()),
+            dns_records: vec![
+                DnsRecordConfigPb {
+                    name: "printer.office".to_string(),
+                    r#type: "A".to_string(),
+                    value: "10.1.1.99".to_string(),
+                    ttl: 0,
+                },
+                DnsRecordConfigPb {
+                    name: "dup".to_string(),
+                    r#type: "A".to_string(),
+                    value: "10.1.1.99".to_string(),
+                    ttl: 0,
+                },
+                DnsRecordConfigPb {
+                    name: "nas".to_string(),
+                    r#type: "A".to_string(),
+                    value: "10.1.1.30".to_string(),
+                    ttl: 0,
+                },
+            ],
+            ..Default::default()
+        },
+        // peers without an instance id are told apart by their peer id
+        Route {
+            hostname: "lab1".to_string(),
+            peer_id: 11,
+            ipv4_addr: Some(Ipv4Inet::from_str("10.1.1.11/24").unwrap().into()),
+            dns_records: vec![DnsRecordConfigPb {
+                name: "lab".to_string(),
+                r#type: "A".to_string(),
+                value: "10.1.1.11".to_string(),
+                ttl: 0,
+            }],
+            ..Default::default()
+        },
+        Route {
+            hostname: "lab2".to_string(),
+            peer_id: 12,
+            ipv4_addr: Some(Ipv4Inet::from_str("10.1.1.12/24").unwrap().into()),
+            dns_records: vec![DnsRecordConfigPb {
+                name: "lab".to_string(),
+                r#type: "A".to_string(),
+                value: "10.1.1.12".to_string(),
+                ttl: 0,
+            }],
+            ..Default::default()
+        },
+        // same hostname from two instances, the lowest instance id gets it
+        Route {
+            hostname: "dup".to_string(),
+            inst_id: "bbbbbbbb-0000-0000-0000-000000000000".to_string(),
+            ipv4_addr: Some(Ipv4Inet::from_str("10.1.1.2/24").unwrap().into()),
+            ..Default::default()
+        },
+        Route {
+            hostname: "dup".to_string(),
+            inst_id: "aaaaaaaa-0000-0000-0000-000000000000".to_string(),
+            ipv4_addr: Some(Ipv4Inet::from_str("10.1.1.1/24").unwrap().into()),
+            ..Default::default()
+        },
     ];
+    let mut events = peer_mgr.get_global_ctx().subscribe();
     dns_server_inst
         .data
         .update_dns_records(routes.iter(), DEFAULT_ET_DNS_ZONE)
         .await
         .unwrap();
 
+    let mut conflict_event = None;
+    while let Ok(event) = events.try_recv() {
+        if let GlobalCtxEvent::DnsHostnameConflict(hostname, inst_ids) = event {
+            conflict_event = Some((hostname, inst_ids));
+        }
+    }
+    assert_eq!(
+        conflict_event,
+        Some((
+            "dup".to_string(),
+            vec![
+                "bbbbbbbb-0000-0000-0000-000000000000".to_string(),
+                "aaaaaaaa-0000-0000-0000-000000000000".to_string()
+            ]
+        ))
+    );
+
     check_dns_record(&fake_ip, "test1.et.net", "8.8.8.8").await;
     check_dns_record(&fake_ip, "中文.et.net", "8.8.8.8").await;
+    check_dns_record(&fake_ip, "printer.office.et.net", "192.168.1.10").await;
+    check_dns_record(&fake_ip, "dup.et.net", "10.1.1.1").await;
+    check_dns_record(&fake_ip, "nas.et.net", "10.1.1.30").await;
+    check_dns_record(&fake_ip, "lab.et.net", "10.1.1.11").await;
+    check_dns_record(&fake_ip, "dup-aaaaaaaa.et.net", "10.1.1.1").await;
+    check_dns_record(&fake_ip, "dup-bbbbbbbb.et.net", "10.1.1.2").await;
+    for (addr, target) in [
+        ("10.1.1.1", "dup.et.net."),
+        ("10.1.1.2", "dup-bbbbbbbb.et.net."),
+        ("192.168.1.10", "printer.office.et.net."),
+        ("192.168.1.20", "test1.et.net."),
+        ("10.2.3.4", "wide.et.net."),
+        ("172.16.2.9", "wide.et.net."),
+    ] {
+        let answers = query_dns_record(
+            fake_ip.into(),
+            rr::Name::from(addr.parse::<IpAddr>().unwrap()),
+            rr::RecordType::PTR,
+        )
+        .await;
+        assert_eq!(answers.len(), 1, "{:?}", answers);
+        let ptr = answers[0].clone().into_parts().rdata.into_ptr().unwrap();
+        assert_eq!(ptr.0, rr::Name::from_str(target).unwrap());
+    }
+    for domain in ["_ssh._tcp.et.net", "test1._ssh._tcp.et.net"] {
+        let answers = query_dns_record(
+            fake_ip.into(),
+            rr::Name::from_str(domain).unwrap(),
+            rr::RecordType::SRV,
+        )
+        .await;
+        assert_eq!(answers.len(), 1, "{:?}", answers);
+        let srv = answers[0].clone().into_parts().rdata.into_srv().unwrap();
+        assert_eq!(srv.port(), 2222);
+        assert_eq!(srv.target(), &rr::Name::from_str("test1.et.net.").unwrap());
+    }
+
+    // the holder keeps the bare hostname whatever order the routes come in
+    let mut routes = routes;
+    routes.reverse();
+    dns_server_inst
+        .data
+        .update_dns_records(routes.iter(), DEFAULT_ET_DNS_ZONE)
+        .await
+        .unwrap();
+    check_dns_record(&fake_ip, "dup.et.net", "10.1.1.1").await;
+    check_dns_record(&fake_ip, "dup-bbbbbbbb.et.net", "10.1.1.2").await;
+    check_dns_record(&fake_ip, "lab.et.net", "10.1.1.11").await;
+
+    // and is not taken over by a new instance with a lower id
+    routes.push(Route {
+        hostname: "dup".to_string(),
+        inst_id: "00000000-0000-0000-0000-000000000000".to_string(),
+        ipv4_addr: Some(Ipv4Inet::from_str("10.1.1.4/24").unwrap().into()),
+        ..Default::default()
+    });
+    dns_server_inst
+        .data
+        .update_dns_records(routes.iter(), DEFAULT_ET_DNS_ZONE)
+        .await
+        .unwrap();
+    check_dns_record(&fake_ip, "dup.et.net", "10.1.1.1").await;
+    check_dns_record(&fake_ip, "dup-00000000.et.net", "10.1.1.4").await;
+}
+
+#[tokio::test]
+async fn test_magic_dns_server_instance_ipv6() {
+    let tun_ip = Ipv4Inet::from_str("10.144.144.30/24").unwrap();
+    let tun_ip6 = Ipv6Inet::from_str("fd00:144::30/64").unwrap();
+    let (peer_mgr, virtual_nic) = prepare_env_with_ipv6("test1", Some(tun_ip), Some(tun_ip6)).await;
+    let tun_name = virtual_nic.ifname().await.unwrap();
+    let fake_ip = Ipv4Addr::from_str("100.100.100.101").unwrap();
+    let fake_ip6 = Ipv6Addr::from_str(MAGIC_DNS_FAKE_IPV6).unwrap();
+    let dns_server_inst = MagicDnsServerInstance::new(
+        peer_mgr.clone(),
+        Some(tun_name),
+        Some(tun_ip),
+        Some(tun_ip6),
+        fake_ip,
+        fake_ip6,
+    )
+    .await
+    .unwrap();
+
+    let routes = vec![
+        Route {
+            hostname: "dual".to_string(),
+            ipv4_addr: Some(Ipv4Inet::from_str("10.144.144.40/24").unwrap().into()),
+            ipv6_addr: Some(Ipv6Inet::from_str("fd00:144::40/64").unwrap().into()),
+            ..Default::default()
+        },
+        Route {
+            hostname: "v6only".to_string(),
+            ipv6_addr: Some(Ipv6Inet::from_str("fd00:144::50/64").unwrap().into()),
+            ..Default::default()
+        },
+    ];
+    dns_server_inst
+        .data
+        .update_dns_records(routes.iter(), DEFAULT_ET_DNS_ZONE)
+        .await
+        .unwrap();
+
+    check_dns_record(&fake_ip, "dual.et.net", "10.144.144.40").await;
+    for (server_ip, domain, expected_ip) in [
+        (IpAddr::from(fake_ip), "dual.et.net", "fd00:144::40"),
+        // resolve through the ipv6 fake ip
+        (IpAddr::from(fake_ip6), "v6only.et.net", "fd00:144::50"),
+    ] {
+        let answers = query_dns_record(
+            server_ip,
+            rr::Name::from_str(domain).unwrap(),
+            rr::RecordType::AAAA,
+        )
+        .await;
+        assert_eq!(answers.len(), 1, "{:?}", answers);
+        assert_eq!(
+            answers[0].clone().into_parts().rdata.into_aaaa().unwrap().0,
+            expected_ip.parse::<Ipv6Addr>().unwrap()
+        );
+    }
 }
 
 #[tokio::test]
@@ -117,7 +394,14 @@ async fn test_magic_dns_runner() {
     let (peer_mgr, virtual_nic) = prepare_env("test1", tun_ip).await;
     let tun_name = virtual_nic.ifname().await.unwrap();
     let fake_ip = Ipv4Addr::from_str("100.100.100.101").unwrap();
-    let mut dns_runner = DnsRunner::new(peer_mgr, Some(tun_name), tun_ip, fake_ip);
+    let mut dns_runner = DnsRunner::new(
+        peer_mgr,
+        Some(tun_name),
+        Some(tun_ip),
+        None,
+        fake_ip,
+        MAGIC_DNS_FAKE_IPV6.parse().unwrap(),
+    );
 
     let cancel_token = CancellationToken::new();
     let cancel_token_clone = cancel_token.clone();
@@ -131,7 +415,14 @@ async fn test_magic_dns_runner() {
     let tun_ip2 = Ipv4Inet::from_str("10.144.144.20/24").unwrap();
     let (peer_mgr, virtual_nic) = prepare_env("test2", tun_ip2).await;
     let tun_name2 = virtual_nic.ifname().await.unwrap();
-    let mut dns_runner2 = DnsRunner::new(peer_mgr, Some(tun_name2), tun_ip2, fake_ip);
+    let mut dns_runner2 = DnsRunner::new(
+        peer_mgr,
+        Some(tun_name2),
+        Some(tun_ip2),
+        None,
+        fake_ip,
+        MAGIC_DNS_FAKE_IPV6.parse().unwrap(),
+    );
     let cancel_token2 = CancellationToken::new();
     let cancel_token2_clone = cancel_token2.clone();
     let t2 = tokio::spawn(async move {
@@ -0,0 +1,711 @@
+// Authority of the root zone. Queries outside the mesh zones are resolved by
+// one of the upstreams (plain dns, DoT or DoH, or the system resolvers when
+// none is configured), answers are kept in a cache shared by all upstreams.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use dashmap::DashMap;
+use hickory_proto::{
+    op::ResponseCode,
+    rr::{LowerName, Name, Record, RecordType},
+    xfer::Protocol,
+    ProtoErrorKind,
+};
+use hickory_resolver::{
+    config::{NameServerConfig, ResolverConfig, ResolverOpts},
+    lookup::Lookup,
+    name_server::TokioConnectionProvider,
+    ResolveError, TokioResolver,
+};
+use hickory_server::{
+    authority::{
+        Authority, LookupControlFlow, LookupError, LookupOptions, MessageRequest, UpdateResult,
+        ZoneType,
+    },
+    server::RequestInfo,
+    store::forwarder::ForwardLookup,
+};
+
+use crate::common::{
+    dns::socket_addrs,
+    stats_manager::{LabelSet, LabelType, MetricName, StatsManager},
+};
+
+use super::config::UpstreamConfig;
+
+const MAX_CACHE_ENTRIES: usize = 4096;
+// ttl of a positive answer is capped, so a record changed upstream is not
+// served forever
+const MAX_CACHE_TTL: Duration = Duration::from_secs(3600);
+// ttl of a negative answer without soa, the one of the soa is capped too
+const NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(30);
+const MAX_NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(300);
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(3);
+// an upstream is skipped after this many consecutive failures, until the
+// backoff expires
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+const MIN_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+impl UpstreamConfig {
+    /// Parses an upstream url and resolves its host, the addresses are fixed
+    /// after the server starts, as the upstream may be the only resolver.
+    pub async fn resolve(url: &url::Url) -> anyhow::Result<Self> {
+        let default_port = match url.scheme() {
+            "udp" | "tcp" => 53,
+            "tls" => 853,
+            "https" => 443,
+            scheme => {
+                return Err(anyhow::anyhow!(
+                    "unsupported dns upstream scheme: {}, expect udp, tcp, tls or https",
+                    scheme
+                ))
+            }
+        };
+        let addrs = socket_addrs(url, || Some(default_port))
+            .await
+            .with_context(|| format!("failed to resolve dns upstream {}", url))?;
+        Ok(Self {
+            url: url.clone(),
+            addrs,
+        })
+    }
+
+    fn name_servers(&self) -> anyhow::Result<Vec<NameServerConfig>> {
+        let protocol = match self.url.scheme() {
+            "udp" => Protocol::Udp,
+            "tcp" => Protocol::Tcp,
+            "tls" => Protocol::Tls,
+            "https" => Protocol::Https,
+            scheme => {
+                return Err(anyhow::anyhow!(
+                    "unsupported dns upstream scheme: {}",
+                    scheme
+                ))
+            }
+        };
+        let tls_dns_name = match self.url.host() {
+            Some(url::Host::Domain(domain)) => domain.to_string(),
+            Some(url::Host::Ipv4(ip)) => ip.to_string(),
+            Some(url::Host::Ipv6(ip)) => ip.to_string(),
+            None => return Err(anyhow::anyhow!("no host in dns upstream {}", self.url)),
+        };
+        let http_endpoint = match self.url.path() {
+            "" | "/" => "/dns-query".to_string(),
+            path => path.to_string(),
+        };
+        Ok(self
+            .addrs
+            .iter()
+            .map(|addr| {
+                let mut ns = NameServerConfig::new(*addr, protocol);
+                if matches!(protocol, Protocol::Tls | Protocol::Https) {
+                    ns.tls_dns_name = Some(tls_dns_name.clone());
+                }
+                if protocol == Protocol::Https {
+                    ns.http_endpoint = Some(http_endpoint.clone());
+                }
+                ns
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Default)]
+struct UpstreamHealth {
+    consecutive_failures: u32,
+    down_until: Option<Instant>,
+}
+
+struct Upstream {
+    name: String,
+    resolver: TokioResolver,
+    health: Mutex<UpstreamHealth>,
+}
+
+impl Upstream {
+    fn new(name: String, config: ResolverConfig, mut opts: ResolverOpts) -> Self {
+        // answers are cached by the authority, shared by all upstreams
+        opts.cache_size = 0;
+        opts.timeout = UPSTREAM_TIMEOUT;
+        opts.attempts = 1;
+        let resolver =
+            TokioResolver::builder_with_config(config, TokioConnectionProvider::default())
+                .with_options(opts)
+                .build();
+        Self {
+            name,
+            resolver,
+            health: Mutex::new(UpstreamHealth::default()),
+        }
+    }
+
+    fn is_healthy(&self, now: Instant) -> bool {
+        self.health
+            .lock()
+            .unwrap()
+            .down_until
+            .is_none_or(|t| t <= now)
+    }
+
+    fn on_success(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures = 0;
+        health.down_until = None;
+    }
+
+    fn on_failure(&self, now: Instant) {
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+            let exp = (health.consecutive_failures - MAX_CONSECUTIVE_FAILURES).min(5);
+            let backoff = (MIN_BACKOFF * 2u32.pow(exp)).min(MAX_BACKOFF);
+            health.down_until = Some(now + backoff);
+            tracing::warn!(
+                upstream = %self.name,
+                failures = health.consecutive_failures,
+                ?backoff,
+                "magic dns upstream is unhealthy"
+            );
+        }
+    }
+}
+
+/// How long a negative answer (NXDOMAIN or no records) is cached, from the
+/// soa of the answer (RFC 2308). None if `e` is not an answer but a failure,
+/// SERVFAIL and REFUSED are reported as no records found too.
+fn negative_ttl(e: &ResolveError) -> Option<Duration> {
+    let ProtoErrorKind::NoRecordsFound {
+        response_code,
+        negative_ttl,
+        ..
+    } = e.proto()?.kind()
+    else {
+        return None;
+    };
+    if !matches!(
+        response_code,
+        ResponseCode::NXDomain | ResponseCode::NoError
+    ) {
+        return None;
+    }
+    Some(
+        negative_ttl
+            .map(|ttl| Duration::from_secs(ttl as u64).min(MAX_NEGATIVE_CACHE_TTL))
+            .unwrap_or(NEGATIVE_CACHE_TTL),
+    )
+}
+
+struct CacheEntry {
+    result: Result<Lookup, ResolveError>,
+    valid_until: Instant,
+}
+
+pub struct UpstreamAuthority {
+    origin: LowerName,
+    upstreams: Vec<Upstream>,
+    cache: DashMap<(LowerName, RecordType), CacheEntry>,
+    stats_manager: Option<Arc<StatsManager>>,
+}
+
+impl UpstreamAuthority {
+    pub fn new(
+        upstreams: &[UpstreamConfig],
+        system_config: ResolverConfig,
+        system_opts: ResolverOpts,
+        stats_manager: Option<Arc<StatsManager>>,
+    ) -> anyhow::Result<Self> {
+        let mut ret = vec![];
+        for upstream in upstreams.iter() {
+            let config = ResolverConfig::from_parts(None, vec![], upstream.name_servers()?);
+            ret.push(Upstream::new(
+                upstream.url.to_string(),
+                config,
+                ResolverOpts::default(),
+            ));
+        }
+        if ret.is_empty() {
+            ret.push(Upstream::new(
+                "system".to_string(),
+                system_config,
+                system_opts,
+            ));
+        }
+
+        Ok(Self {
+            origin: Name::root().into(),
+            upstreams: ret,
+            cache: DashMap::new(),
+            stats_manager,
+        })
+    }
+
+    fn inc_counter(&self, metric: MetricName, labels: LabelSet) {
+        if let Some(stats_manager) = &self.stats_manager {
+            stats_manager.get_counter(metric, labels).inc();
+        }
+    }
+
+    fn get_cached(
+        &self,
+        key: &(LowerName, RecordType),
+        now: Instant,
+    ) -> Option<Result<Lookup, ResolveError>> {
+        let entry = self.cache.get(key)?;
+        if entry.valid_until <= now {
+            drop(entry);
+            self.cache.remove_if(key, |_, e| e.valid_until <= now);
+            return None;
+        }
+        let remaining = entry.valid_until.saturating_duration_since(now).as_secs() as u32;
+        Some(entry.result.clone().map(|lookup| {
+            // count the ttl down so clients do not cache past our expiry
+            let records = lookup
+                .records()
+                .iter()
+                .map(|r| {
+                    let mut r = r.clone();
+                    r.set_ttl(r.ttl().min(remaining));
+                    r
+                })
+                .collect::<Vec<Record>>();
+            Lookup::new_with_deadline(lookup.query().clone(), records.into(), entry.valid_until)
+        }))
+    }
+
+    fn put_cache(&self, key: (LowerName, RecordType), result: Result<Lookup, ResolveError>) {
+        let now = Instant::now();
+        let valid_until = match &result {
+            Ok(lookup) => lookup.valid_until().min(now + MAX_CACHE_TTL),
+            Err(e) => now + negative_ttl(e).unwrap_or_default(),
+        };
+        if valid_until <= now {
+            return;
+        }
+        if self.cache.len() >= MAX_CACHE_ENTRIES {
+            self.cache.retain(|_, e| e.valid_until > now);
+        }
+        if self.cache.len() >= MAX_CACHE_ENTRIES {
+            let victim = self.cache.iter().next().map(|e| e.key().clone());
+            if let Some(victim) = victim {
+                self.cache.remove(&victim);
+            }
+        }
+        self.cache.insert(
+            key,
+            CacheEntry {
+                result,
+                valid_until,
+            },
+        );
+    }
+
+    async fn resolve(&self, name: &LowerName, rtype: RecordType) -> Result<Lookup, ResolveError> {
+        let key = (name.clone(), rtype);
+        if let Some(result) = self.get_cached(&key, Instant::now()) {
+            self.inc_counter(MetricName::DnsCacheHit, LabelSet::new());
+            return result;
+        }
+        self.inc_counter(MetricName::DnsCacheMiss, LabelSet::new());
+
+        // Ignore FQDN when we forward DNS queries. Without this we can't look
+        // up addresses from system hosts file.
+        let mut query_name: Name = name.clone().into();
+        query_name.set_fqdn(false);
+
+        // healthy upstreams first, the others are still tried as last resort
+        let now = Instant::now();
+        let (healthy, unhealthy): (Vec<_>, Vec<_>) =
+            self.upstreams.iter().partition(|u| u.is_healthy(now));
+
+        let mut last_err = None;
+        for upstream in healthy.into_iter().chain(unhealthy) {
+            let ret = upstream.resolver.lookup(query_name.clone(), rtype).await;
+            match ret {
+                Ok(lookup) => {
+                    upstream.on_success();
+                    self.put_cache(key, Ok(lookup.clone()));
+                    return Ok(lookup);
+                }
+                Err(e) if negative_ttl(&e).is_some() => {
+                    // a negative answer is still an answer from a working upstream
+                    upstream.on_success();
+                    self.put_cache(key, Err(e.clone()));
+                    return Err(e);
+                }
+                Err(e) => {
+                    tracing::debug!(upstream = %upstream.name, ?e, %name, "magic dns upstream lookup failed");
+                    upstream.on_failure(Instant::now());
+                    self.inc_counter(
+                        MetricName::DnsUpstreamErrors,
+                        LabelSet::new().with_label_type(LabelType::Upstream(upstream.name.clone())),
+                    );
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| ResolveError::from("no dns upstream available")))
+    }
+}
+
+#[async_trait::async_trait]
+impl Authority for UpstreamAuthority {
+    type Lookup = ForwardLookup;
+
+    fn zone_type(&self) -> ZoneType {
+        ZoneType::External
+    }
+
+    fn is_axfr_allowed(&self) -> bool {
+        false
+    }
+
+    async fn update(&self, _update: &MessageRequest) -> UpdateResult<bool> {
+        Err(ResponseCode::NotImp)
+    }
+
+    fn origin(&self) -> &LowerName {
+        &self.origin
+    }
+
+    async fn lookup(
+        &self,
+        name: &LowerName,
+        rtype: RecordType,
+        _lookup_options: LookupOptions,
+    ) -> LookupControlFlow<Self::Lookup> {
+        match self.resolve(name, rtype).await {
+            Ok(lookup) => LookupControlFlow::Continue(Ok(ForwardLookup(lookup))),
+            Err(e) => LookupControlFlow::Continue(Err(LookupError::from(e))),
+        }
+    }
+
+    async fn search(
+        &self,
+        request_info: RequestInfo<'_>,
+        lookup_options: LookupOptions,
+    ) -> LookupControlFlow<Self::Lookup> {
+        self.lookup(
+            request_info.query.name(),
+            request_info.query.query_type(),
+            lookup_options,
+        )
+        .await
+    }
+
+    async fn get_nsec_records(
+        &self,
+        _name: &LowerName,
+        _lookup_options: LookupOptions,
+    ) -> LookupControlFlow<Self::Lookup> {
+        LookupControlFlow::Continue(Err(LookupError::from(io::Error::other(
+            "Getting NSEC records is unimplemented for the upstream authority",
+        ))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        str::FromStr as _,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    use hickory_proto::{
+        op::{Message, MessageType},
+        rr::{
+            rdata::{A, SOA},
+            RData,
+        },
+    };
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    /// A udp dns server answering every query with `respond`, counts the
+    /// queries it gets.
+    async fn fake_upstream<F>(respond: F) -> (UpstreamConfig, Arc<AtomicU32>)
+    where
+        F: Fn(&Message, &mut Message) + Send + 'static,
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicU32::new(0));
+        let queries_clone = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 4096];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let Ok(req) = Message::from_vec(&buf[..len]) else {
+                    continue;
+                };
+                queries_clone.fetch_add(1, Ordering::Relaxed);
+                let mut resp = Message::new();
+                resp.set_id(req.id())
+                    .set_message_type(MessageType::Response)
+                    .set_op_code(req.op_code())
+                    .set_recursion_desired(req.recursion_desired())
+                    .set_recursion_available(true)
+                    .add_queries(req.queries().to_vec());
+                respond(&req, &mut resp);
+                let _ = socket.send_to(&resp.to_vec().unwrap(), from).await;
+            }
+        });
+        let config = UpstreamConfig {
+            url: format!("udp://{}", addr).parse().unwrap(),
+            addrs: vec![addr],
+        };
+        (config, queries)
+    }
+
+    fn answer_a(ttl: u32) -> impl Fn(&Message, &mut Message) {
+        move |req, resp| {
+            let name = req.queries()[0].name().clone();
+            resp.add_answer(Record::from_rdata(
+                name,
+                ttl,
+                RData::A(A(Ipv4Addr::new(10, 0, 0, 1))),
+            ));
+        }
+    }
+
+    fn soa_record(ttl: u32, minimum: u32) -> Record {
+        Record::from_rdata(
+            Name::from_str("example.").unwrap(),
+            ttl,
+            RData::SOA(SOA::new(
+                Name::from_str("ns.example.").unwrap(),
+                Name::from_str("admin.example.").unwrap(),
+                1,
+                3600,
+                600,
+                86400,
+                minimum,
+            )),
+        )
+    }
+
+    fn authority(upstreams: &[UpstreamConfig]) -> UpstreamAuthority {
+        UpstreamAuthority::new(
+            upstreams,
+            ResolverConfig::default(),
+            ResolverOpts::default(),
+            None,
+        )
+        .unwrap()
+    }
+
+    fn name(s: &str) -> LowerName {
+        LowerName::from_str(s).unwrap()
+    }
+
+    #[tokio::test]
+    async fn response_cache() {
+        let (upstream, queries) = fake_upstream(answer_a(60)).await;
+        let authority = authority(&[upstream]);
+
+        let lookup = authority
+            .resolve(&name("www.example."), RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(lookup.records().len(), 1);
+        let lookup = authority
+            .resolve(&name("www.example."), RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(
+            lookup.records()[0].data(),
+            &RData::A(A(Ipv4Addr::new(10, 0, 0, 1)))
+        );
+        assert!(lookup.records()[0].ttl() <= 60);
+        assert_eq!(queries.load(Ordering::Relaxed), 1);
+
+        // another name or type is not served from the cache
+        authority
+            .resolve(&name("mail.example."), RecordType::A)
+            .await
+            .unwrap();
+        let _ = authority
+            .resolve(&name("www.example."), RecordType::AAAA)
+            .await;
+        assert_eq!(queries.load(Ordering::Relaxed), 3);
+
+        // an expired entry is looked up again
+        let key = (name("www.example."), RecordType::A);
+        authority.cache.get_mut(&key).unwrap().valid_until = Instant::now();
+        authority.resolve(&key.0, RecordType::A).await.unwrap();
+        assert_eq!(queries.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn negative_cache_ttl_from_soa() {
+        let (upstream, queries) = fake_upstream(|req, resp| {
+            resp.set_response_code(ResponseCode::NXDomain);
+            if req.queries()[0].name().to_string().starts_with("soa.") {
+                resp.add_name_server(soa_record(100, 5));
+            }
+        })
+        .await;
+        let authority = authority(&[upstream]);
+
+        for _ in 0..2 {
+            let e = authority
+                .resolve(&name("soa.example."), RecordType::A)
+                .await
+                .unwrap_err();
+            assert!(e.is_nx_domain());
+        }
+        assert_eq!(queries.load(Ordering::Relaxed), 1);
+        // min(ttl, minimum) of the soa
+        let valid_until = authority
+            .cache
+            .get(&(name("soa.example."), RecordType::A))
+            .unwrap()
+            .valid_until;
+        assert!(valid_until <= Instant::now() + Duration::from_secs(5));
+        assert!(valid_until > Instant::now() + Duration::from_secs(3));
+
+        // without a soa the default ttl is used
+        authority
+            .resolve(&name("nosoa.example."), RecordType::A)
+            .await
+            .unwrap_err();
+        let valid_until = authority
+            .cache
+            .get(&(name("nosoa.example."), RecordType::A))
+            .unwrap()
+            .valid_until;
+        assert!(valid_until > Instant::now() + NEGATIVE_CACHE_TTL - Duration::from_secs(2));
+        assert!(valid_until <= Instant::now() + NEGATIVE_CACHE_TTL);
+    }
+
+    #[tokio::test]
+    async fn servfail_is_not_cached() {
+        let (upstream, queries) = fake_upstream(|_, resp| {
+            resp.set_response_code(ResponseCode::ServFail);
+        })
+        .await;
+        let authority = authority(&[upstream]);
+
+        for _ in 0..2 {
+            authority
+                .resolve(&name("www.example."), RecordType::A)
+                .await
+                .unwrap_err();
+        }
+        assert_eq!(queries.load(Ordering::Relaxed), 2);
+        assert!(authority.cache.is_empty());
+        assert_eq!(
+            authority.upstreams[0]
+                .health
+                .lock()
+                .unwrap()
+                .consecutive_failures,
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn upstream_backoff() {
+        let upstream = Upstream::new(
+            "test".to_string(),
+            ResolverConfig::default(),
+            ResolverOpts::default(),
+        );
+        let now = Instant::now();
+        for _ in 0..MAX_CONSECUTIVE_FAILURES - 1 {
+            upstream.on_failure(now);
+            assert!(upstream.is_healthy(now));
+        }
+        upstream.on_failure(now);
+        assert!(!upstream.is_healthy(now));
+        assert!(upstream.is_healthy(now + MIN_BACKOFF));
+
+        // the backoff doubles with each further failure, up to the max
+        upstream.on_failure(now);
+        assert!(!upstream.is_healthy(now + MIN_BACKOFF));
+        assert!(upstream.is_healthy(now + MIN_BACKOFF * 2));
+        for _ in 0..10 {
+            upstream.on_failure(now);
+        }
+        assert!(!upstream.is_healthy(now + MAX_BACKOFF - Duration::from_secs(1)));
+        assert!(upstream.is_healthy(now + MAX_BACKOFF));
+
+        upstream.on_success();
+        assert!(upstream.is_healthy(now));
+    }
+
+    #[tokio::test]
+    async fn unhealthy_upstream_is_tried_last() {
+        let (bad, bad_queries) = fake_upstream(|_, resp| {
+            resp.set_response_code(ResponseCode::ServFail);
+        })
+        .await;
+        let (good, good_queries) = fake_upstream(answer_a(60)).await;
+        let authority = authority(&[bad, good]);
+
+        // every lookup falls through the failing upstream until it is down
+        for i in 0..MAX_CONSECUTIVE_FAILURES {
+            authority
+                .resolve(&name(&format!("host{}.example.", i)), RecordType::A)
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            bad_queries.load(Ordering::Relaxed),
+            MAX_CONSECUTIVE_FAILURES
+        );
+        assert!(!authority.upstreams[0].is_healthy(Instant::now()));
+
+        authority
+            .resolve(&name("other.example."), RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(
+            bad_queries.load(Ordering::Relaxed),
+            MAX_CONSECUTIVE_FAILURES
+        );
+        assert_eq!(
+            good_queries.load(Ordering::Relaxed),
+            MAX_CONSECUTIVE_FAILURES + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn parse_upstream_url() {
+        let doh = UpstreamConfig::resolve(&"https://1.1.1.1".parse().unwrap())
+            .await
+            .unwrap();
+        let ns = doh.name_servers().unwrap();
+        assert_eq!(ns.len(), 1);
+        assert_eq!(
+            ns[0].socket_addr,
+            "1.1.1.1:443".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(ns[0].protocol, Protocol::Https);
+        assert_eq!(ns[0].tls_dns_name.as_deref(), Some("1.1.1.1"));
+        assert_eq!(ns[0].http_endpoint.as_deref(), Some("/dns-query"));
+
+        let dot = UpstreamConfig::resolve(&"tls://[2606:4700:4700::1111]".parse().unwrap())
+            .await
+            .unwrap();
+        let ns = dot.name_servers().unwrap();
+        assert_eq!(
+            ns[0].socket_addr,
+            "[2606:4700:4700::1111]:853".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(ns[0].tls_dns_name.as_deref(), Some("2606:4700:4700::1111"));
+
+        assert!(UpstreamConfig::resolve(&"quic://1.1.1.1".parse().unwrap())
+            .await
+            .is_err());
+    }
+}
@@ -6,7 +6,7 @@ use std::sync::{Arc, Weak};
 use std::time::Duration;
 
 use anyhow::Context;
-use cidr::{IpCidr, Ipv4Inet};
+use cidr::{IpCidr, Ipv4Inet, Ipv6Inet};
 
 use futures::FutureExt;
 use tokio::sync::{oneshot, Notify};
@@ -21,12 +21,13 @@ use crate::common::scoped_task::ScopedTask;
 use crate::common::PeerId;
 use crate::connector::direct::DirectConnectorManager;
 use crate::connector::manual::{ConnectorManagerRpcService, ManualConnectorManager};
+use crate::connector::tcp_hole_punch::TcpHolePunchConnector;
 use crate::connector::udp_hole_punch::UdpHolePunchConnector;
-use crate::gateway::icmp_proxy::IcmpProxy;
+use crate::gateway::icmp_proxy::{IcmpProxy, IcmpProxyRpcService};
 use crate::gateway::kcp_proxy::{KcpProxyDst, KcpProxyDstRpcService, KcpProxySrc};
 use crate::gateway::quic_proxy::{QUICProxyDst, QUICProxyDstRpcService, QUICProxySrc};
 use crate::gateway::tcp_proxy::{NatDstTcpConnector, TcpProxy, TcpProxyRpcService};
-use crate::gateway::udp_proxy::UdpProxy;
+use crate::gateway::udp_proxy::{UdpProxy, UdpProxyRpcService};
 use crate::peer_center::instance::PeerCenterInstance;
 use crate::peers::peer_conn::PeerConnId;
 use crate::peers::peer_manager::{PeerManager, RouteAlgoType};
@@ -51,8 +52,11 @@ use crate::tunnel::tcp::TcpTunnelListener;
 use crate::vpn_portal::{self, VpnPortal};
 
 use super::dns_server::runner::DnsRunner;
-use super::dns_server::MAGIC_DNS_FAKE_IP;
+use super::dns_server::{MAGIC_DNS_FAKE_IP, MAGIC_DNS_FAKE_IPV6};
+#[cfg(target_os = "linux")]
+use super::kernel_route::KernelRouteImporter;
 use super::listeners::ListenerManager;
+use super::port_mapper::PortMapper;
 
 #[cfg(feature = "socks5")]
 use crate::gateway::socks5::Socks5Server;
@@ -83,8 +87,14 @@ impl IpProxy {
     }
 
     async fn start(&self) -> Result<(), Error> {
-        if (self.global_ctx.config.get_proxy_cidrs().is_empty()
-            || self.started.load(Ordering::Relaxed))
+        // proxy cidrs imported from kernel routes are only known after start
+        #[cfg(target_os = "linux")]
+        let has_proxy_cidrs = !self.global_ctx.config.get_proxy_cidrs().is_empty()
+            || KernelRouteImporter::is_enabled(&self.global_ctx);
+        #[cfg(not(target_os = "linux"))]
+        let has_proxy_cidrs = !self.global_ctx.config.get_proxy_cidrs().is_empty();
+
+        if (!has_proxy_cidrs || self.started.load(Ordering::Relaxed))
             && !self.global_ctx.enable_exit_node()
             && !self.global_ctx.no_tun()
         {
@@ -239,9 +249,14 @@ pub struct Instance {
     conn_manager: Arc<ManualConnectorManager>,
     direct_conn_manager: Arc<DirectConnectorManager>,
     udp_hole_puncher: Arc<Mutex<UdpHolePunchConnector>>,
+    tcp_hole_puncher: Arc<Mutex<TcpHolePunchConnector>>,
+    port_mapper: Arc<Mutex<PortMapper>>,
 
     ip_proxy: Option<IpProxy>,
 
+    #[cfg(target_os = "linux")]
+    kernel_route_importer: Option<KernelRouteImporter>,
+
     kcp_proxy_src: Option<KcpProxySrc>,
     kcp_proxy_dst: Option<KcpProxyDst>,
 
@@ -296,6 +311,8 @@ impl Instance {
         direct_conn_manager.run();
 
         let udp_hole_puncher = UdpHolePunchConnector::new(peer_manager.clone());
+        let tcp_hole_puncher = TcpHolePunchConnector::new(peer_manager.clone());
+        let port_mapper = PortMapper::new(global_ctx.clone());
 
         let peer_center = Arc::new(PeerCenterInstance::new(peer_manager.clone()));
 
@@ -325,8 +342,12 @@ impl Instance {
             conn_manager,
             direct_conn_manager: Arc::new(direct_conn_manager),
             udp_hole_puncher: Arc::new(Mutex::new(udp_hole_puncher)),
+            tcp_hole_puncher: Arc::new(Mutex::new(tcp_hole_puncher)),
+            port_mapper: Arc::new(Mutex::new(port_mapper)),
 
             ip_proxy: None,
+            #[cfg(target_os = "linux")]
+            kernel_route_importer: None,
             kcp_proxy_src: None,
             kcp_proxy_dst: None,
 
@@ -391,18 +412,24 @@ impl Instance {
     fn create_magic_dns_runner(
         peer_mgr: Arc<PeerManager>,
         tun_dev: Option<String>,
-        tun_ip: Ipv4Inet,
+        tun_ip: Option<Ipv4Inet>,
+        tun_ip6: Option<Ipv6Inet>,
     ) -> Option<DnsRunner> {
         let ctx = peer_mgr.get_global_ctx();
         if !ctx.config.get_flags().accept_dns {
             return None;
         }
+        if tun_ip.is_none() && tun_ip6.is_none() {
+            return None;
+        }
 
         let runner = DnsRunner::new(
             peer_mgr,
             tun_dev,
             tun_ip,
+            tun_ip6,
             MAGIC_DNS_FAKE_IP.parse().unwrap(),
+            MAGIC_DNS_FAKE_IPV6.parse().unwrap(),
         );
         Some(runner)
     }
@@ -519,7 +546,12 @@ impl Instance {
                         Self::use_new_nic_ctx(
                             nic_ctx.clone(),
                             new_nic_ctx,
-                            Self::create_magic_dns_runner(peer_manager_c.clone(), ifname, ip),
+                            Self::create_magic_dns_runner(
+                                peer_manager_c.clone(),
+                                ifname,
+                                Some(ip),
+                                global_ctx_c.get_ipv6(),
+                            ),
                         )
                         .await;
                     }
@@ -581,12 +613,8 @@ impl Instance {
                 }
                 let ifname = new_nic_ctx.ifname().await;
 
-                // Create Magic DNS runner only if we have IPv4
-                let dns_runner = if let Some(ipv4) = ipv4_addr {
-                    Self::create_magic_dns_runner(peer_manager, ifname, ipv4)
-                } else {
-                    None
-                };
+                let dns_runner =
+                    Self::create_magic_dns_runner(peer_manager, ifname, ipv4_addr, ipv6_addr);
                 Self::use_new_nic_ctx(nic_ctx.clone(), new_nic_ctx, dns_runner).await;
 
                 if let Some(output_tx) = output_tx.take() {
@@ -681,7 +709,17 @@ impl Instance {
         )?);
         self.run_ip_proxy().await?;
 
+        #[cfg(target_os = "linux")]
+        {
+            let mut importer =
+                KernelRouteImporter::new(self.get_global_ctx(), self.get_peer_manager());
+            importer.start()?;
+            self.kernel_route_importer = Some(importer);
+        }
+
         self.udp_hole_puncher.lock().await.run().await?;
+        self.tcp_hole_puncher.lock().await.run().await?;
+        self.port_mapper.lock().await.run().await?;
 
         self.peer_center.init().await;
         let route_calc = self.peer_center.get_cost_calculator();
@@ -1033,6 +1071,14 @@ impl Instance {
                 TcpProxyRpcServer::new(TcpProxyRpcService::new(ip_proxy.tcp_proxy.clone())),
                 "tcp",
             );
+            s.registry().register(
+                UdpProxyRpcServer::new(UdpProxyRpcService::new(ip_proxy.udp_proxy.clone())),
+                "",
+            );
+            s.registry().register(
+                IcmpProxyRpcServer::new(IcmpProxyRpcService::new(ip_proxy.icmp_proxy.clone())),
+                "",
+            );
         }
         if let Some(kcp_proxy) = self.kcp_proxy_src.as_ref() {
             s.registry().register(
@@ -1078,6 +1124,18 @@ impl Instance {
         self.vpn_portal.clone()
     }
 
+    pub fn get_tcp_proxy(&self) -> Option<Arc<TcpProxy<NatDstTcpConnector>>> {
+        self.ip_proxy.as_ref().map(|p| p.tcp_proxy.clone())
+    }
+
+    pub fn get_udp_proxy(&self) -> Option<Arc<UdpProxy>> {
+        self.ip_proxy.as_ref().map(|p| p.udp_proxy.clone())
+    }
+
+    pub fn get_icmp_proxy(&self) -> Option<Arc<IcmpProxy>> {
+        self.ip_proxy.as_ref().map(|p| p.icmp_proxy.clone())
+    }
+
     pub fn get_nic_ctx(&self) -> ArcNicCtx {
         self.nic_ctx.clone()
     }
@@ -1111,17 +1169,19 @@ impl Instance {
             .await
             .with_context(|| "add ip failed")?;
 
-        let magic_dns_runner = if let Some(ipv4) = global_ctx.get_ipv4() {
-            Self::create_magic_dns_runner(peer_manager.clone(), None, ipv4)
-        } else {
-            None
-        };
+        let magic_dns_runner = Self::create_magic_dns_runner(
+            peer_manager.clone(),
+            None,
+            global_ctx.get_ipv4(),
+            global_ctx.get_ipv6(),
+        );
         Self::use_new_nic_ctx(nic_ctx.clone(), new_nic_ctx, magic_dns_runner).await;
         Ok(())
     }
 
     pub async fn clear_resources(&mut self) {
         self.peer_manager.clear_resources().await;
+        self.port_mapper.lock().await.clear_mappings().await;
         let _ = self.nic_ctx.lock().await.take();
         if let Some(rpc_server) = self.rpc_server.take() {
             rpc_server.registry().unregister_all();
@@ -0,0 +1,252 @@
+// Exchange routes between the mesh and the linux kernel routing table.
+//
+// Routing daemons (BIRD, FRR/zebra, ...) install the prefixes they learned into
+// the kernel with their own route protocol id, so importing routes of these
+// protocols lets the daemon feed the mesh. In the other direction, prefixes
+// learned from the mesh are installed into a dedicated table with the
+// RTPROT_EASYTIER protocol, where the daemon can pick them up (e.g. BIRD
+// `protocol kernel { kernel table N; learn; }`).
+
+use std::{
+    collections::BTreeSet,
+    net::IpAddr,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use cidr::Ipv4Cidr;
+use tokio::task::JoinSet;
+
+use crate::{
+    common::{
+        config::ConfigLoader as _, error::Error, global_ctx::ArcGlobalCtx, ifcfg::IfConfiger,
+    },
+    peers::peer_manager::PeerManager,
+};
+
+/// route protocol id of routes installed into the export table.
+pub const RTPROT_EASYTIER: u8 = 233;
+
+const RT_TABLE_MAIN: u32 = 254;
+
+pub fn parse_route_protocol(proto: &str) -> Result<u8, Error> {
+    if let Ok(id) = proto.parse::<u8>() {
+        return Ok(id);
+    }
+
+    // same as /etc/iproute2/rt_protos
+    let id = match proto.to_lowercase().as_str() {
+        "redirect" => 1,
+        "kernel" => 2,
+        "boot" => 3,
+        "static" => 4,
+        "gated" => 8,
+        "ra" => 9,
+        "mrt" => 10,
+        "zebra" => 11,
+        "bird" => 12,
+        "dnrouted" => 13,
+        "xorp" => 14,
+        "ntk" => 15,
+        "dhcp" => 16,
+        "keepalived" => 18,
+        "babel" => 42,
+        "openr" => 99,
+        "bgp" => 186,
+        "isis" => 187,
+        "ospf" => 188,
+        "rip" => 189,
+        "eigrp" => 192,
+        _ => {
+            return Err(anyhow::anyhow!("unknown route protocol: {}", proto).into());
+        }
+    };
+    Ok(id)
+}
+
+pub struct KernelRouteImporter {
+    global_ctx: ArcGlobalCtx,
+    peer_mgr: Weak<PeerManager>,
+    tasks: JoinSet<()>,
+}
+
+impl KernelRouteImporter {
+    pub fn new(global_ctx: ArcGlobalCtx, peer_mgr: Arc<PeerManager>) -> Self {
+        Self {
+            global_ctx,
+            peer_mgr: Arc::downgrade(&peer_mgr),
+            tasks: JoinSet::new(),
+        }
+    }
+
+    pub fn is_enabled(global_ctx: &ArcGlobalCtx) -> bool {
+        global_ctx
+            .config
+            .get_kernel_route_config()
+            .is_some_and(|cfg| !cfg.import_protocols.is_empty())
+    }
+
+    pub fn start(&mut self) -> Result<(), Error> {
+        if !Self::is_enabled(&self.global_ctx) {
+            return Ok(());
+        }
+        let cfg = self.global_ctx.config.get_kernel_route_config().unwrap();
+        let protocols = cfg
+            .import_protocols
+            .iter()
+            .map(|p| parse_route_protocol(p))
+            .collect::<Result<Vec<_>, _>>()?;
+        let table = cfg.import_table.unwrap_or(RT_TABLE_MAIN);
+
+        let global_ctx = self.global_ctx.clone();
+        let peer_mgr = self.peer_mgr.clone();
+        self.tasks.spawn(async move {
+            // imported cidrs are kept in global ctx only, the config is never touched.
+            let mut imported = BTreeSet::new();
+            loop {
+                let Some(peer_mgr) = peer_mgr.upgrade() else {
+                    tracing::warn!("peer manager is dropped, stop kernel route importer.");
+                    return;
+                };
+                // do not advertise prefixes learned from the mesh back to it, they
+                // may be exported to the kernel and redistributed by the daemon.
+                let mesh_cidrs = peer_mgr
+                    .list_routes()
+                    .await
+                    .into_iter()
+                    .flat_map(|r| r.proxy_cidrs)
+                    .filter_map(|c| c.parse::<Ipv4Cidr>().ok())
+                    .collect::<BTreeSet<_>>();
+                drop(peer_mgr);
+
+                let routes = {
+                    let _g = global_ctx.net_ns.guard();
+                    IfConfiger::list_ipv4_routes_in_table(table, &protocols)
+                };
+                let routes = match routes {
+                    Ok(routes) => routes,
+                    Err(e) => {
+                        tracing::warn!(?e, table, "list kernel routes failed");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                let configured = global_ctx
+                    .config
+                    .get_proxy_cidrs()
+                    .into_iter()
+                    .map(|c| c.cidr)
+                    .collect::<BTreeSet<_>>();
+                let mut cur_imported = BTreeSet::new();
+                for r in routes {
+                    let IpAddr::V4(addr) = r.destination else {
+                        continue;
+                    };
+                    // never advertise the default route
+                    if r.prefix == 0 {
+                        continue;
+                    }
+                    let Ok(cidr) = Ipv4Cidr::new(addr, r.prefix) else {
+                        continue;
+                    };
+                    if mesh_cidrs.contains(&cidr) || configured.contains(&cidr) {
+                        continue;
+                    }
+                    cur_imported.insert(cidr);
+                }
+
+                if cur_imported != imported {
+                    for cidr in imported.difference(&cur_imported) {
+                        tracing::info!(?cidr, "kernel route withdrawn, remove proxy cidr");
+                    }
+                    for cidr in cur_imported.difference(&imported) {
+                        tracing::info!(?cidr, "kernel route imported as proxy cidr");
+                    }
+                    global_ctx.set_imported_proxy_cidrs(cur_imported.iter().copied().collect());
+                    imported = cur_imported;
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// Keeps the export table in sync with the prefixes routed into the tun device.
+/// Routes are bound to the tun device, so the kernel drops them with the device.
+pub struct KernelRouteExporter {
+    table: u32,
+    exported: BTreeSet<Ipv4Cidr>,
+}
+
+impl KernelRouteExporter {
+    pub fn new(global_ctx: &ArcGlobalCtx) -> Option<Self> {
+        let table = global_ctx.config.get_kernel_route_config()?.export_table?;
+        Some(Self {
+            table,
+            exported: BTreeSet::new(),
+        })
+    }
+
+    pub fn sync(&mut self, ifname: &str, cidrs: &BTreeSet<Ipv4Cidr>) {
+        let stale = self.exported.difference(cidrs).copied().collect::<Vec<_>>();
+        for cidr in stale {
+            let ret = IfConfiger::remove_ipv4_route_from_table(
+                cidr.first_address(),
+                cidr.network_length(),
+                self.table,
+                RTPROT_EASYTIER,
+            );
+            if ret.is_err() {
+                tracing::trace!(
+                    cidr = ?cidr,
+                    err = ?ret,
+                    table = self.table,
+                    "remove exported route failed.",
+                );
+            }
+            // the route is gone with the tun device if it cannot be removed, do not retry.
+            self.exported.remove(&cidr);
+        }
+
+        let missing = cidrs
+            .difference(&self.exported)
+            .copied()
+            .collect::<Vec<_>>();
+        for cidr in missing {
+            let ret = IfConfiger::add_ipv4_route_to_table(
+                ifname,
+                cidr.first_address(),
+                cidr.network_length(),
+                self.table,
+                RTPROT_EASYTIER,
+            );
+            if ret.is_err() {
+                tracing::trace!(
+                    cidr = ?cidr,
+                    err = ?ret,
+                    table = self.table,
+                    "export route failed.",
+                );
+                // retried in next sync.
+                continue;
+            }
+            self.exported.insert(cidr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_route_protocol() {
+        assert_eq!(parse_route_protocol("bird").unwrap(), 12);
+        assert_eq!(parse_route_protocol("Zebra").unwrap(), 11);
+        assert_eq!(parse_route_protocol("186").unwrap(), 186);
+        assert!(parse_route_protocol("unknown").is_err());
+    }
+}
@@ -21,8 +21,12 @@ use crate::{
     },
     peers::peer_manager::PeerManager,
     tunnel::{
-        ring::RingTunnelListener, tcp::TcpTunnelListener, udp::UdpTunnelListener, Tunnel,
-        TunnelListener,
+        kcp::KcpTunnelListener,
+        obfs::{take_obfs_key_from_url, ObfsTunnelListener},
+        ring::RingTunnelListener,
+        tcp::TcpTunnelListener,
+        udp::UdpTunnelListener,
+        Tunnel, TunnelListener,
     },
 };
 
@@ -30,9 +34,23 @@ pub fn get_listener_by_url(
     l: &url::Url,
     _ctx: ArcGlobalCtx,
 ) -> Result<Box<dyn TunnelListener>, Error> {
-    Ok(match l.scheme() {
-        "tcp" => Box::new(TcpTunnelListener::new(l.clone())),
-        "udp" => Box::new(UdpTunnelListener::new(l.clone())),
+    // the key is removed from the listener url, which is published to other peers.
+    let mut url = l.clone();
+    let mut obfs_key = take_obfs_key_from_url(&mut url);
+    let l = &url;
+
+    let listener: Box<dyn TunnelListener> = match l.scheme() {
+        "tcp" => {
+            let mut listener = TcpTunnelListener::new(l.clone());
+            listener.set_obfs_key(obfs_key.take());
+            Box::new(listener)
+        }
+        "udp" => {
+            let mut listener = UdpTunnelListener::new(l.clone());
+            listener.set_obfs_key(obfs_key.take());
+            Box::new(listener)
+        }
+        "kcp" => Box::new(KcpTunnelListener::new(l.clone())),
         #[cfg(feature = "wireguard")]
         "wg" => {
             let nid = _ctx.get_network_identity();
@@ -52,6 +70,12 @@ pub fn get_listener_by_url(
         _ => {
             return Err(Error::InvalidUrl(l.to_string()));
         }
+    };
+
+    // tcp and udp obfuscate the whole traffic themselves.
+    Ok(match obfs_key {
+        Some(key) => Box::new(ObfsTunnelListener::new(listener, key)),
+        None => listener,
     })
 }
 
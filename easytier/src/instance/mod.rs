@@ -3,6 +3,14 @@ pub mod dns_server;
 pub mod instance;
 
 pub mod listeners;
+pub mod port_mapper;
 
+#[cfg(target_os = "linux")]
+pub mod kernel_route;
+
+#[cfg(feature = "tun")]
+pub mod tun_offload;
+#[cfg(all(feature = "tun", target_os = "linux"))]
+pub mod tun_queue;
 #[cfg(feature = "tun")]
 pub mod virtual_nic;
@@ -0,0 +1,1154 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use rand::RngCore;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpSocket, UdpSocket},
+    sync::Mutex,
+    task::JoinSet,
+};
+
+use crate::common::{error::Error, global_ctx::ArcGlobalCtx, netns::NetNS};
+
+const PCP_VERSION: u8 = 2;
+const PCP_OPCODE_MAP: u8 = 1;
+const PCP_MAP_PACKET_SIZE: usize = 60;
+const NATPMP_VERSION: u8 = 0;
+const NATPMP_PORT: u16 = 5351;
+const PMP_RESPONSE_BIT: u8 = 0x80;
+
+const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+const SSDP_TIMEOUT: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+const IGD_SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+const MAPPING_DESCRIPTION: &str = "easytier";
+const MAPPING_LIFETIME: Duration = Duration::from_secs(7200);
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MappingProtocol {
+    Tcp,
+    Udp,
+}
+
+impl MappingProtocol {
+    fn from_scheme(scheme: &str) -> Option<Self> {
+        match scheme {
+            "tcp" | "ws" | "wss" => Some(Self::Tcp),
+            "udp" | "wg" | "quic" | "kcp" => Some(Self::Udp),
+            _ => None,
+        }
+    }
+
+    fn ip_protocol(&self) -> u8 {
+        match self {
+            Self::Tcp => 6,
+            Self::Udp => 17,
+        }
+    }
+
+    fn natpmp_opcode(&self) -> u8 {
+        match self {
+            Self::Udp => 1,
+            Self::Tcp => 2,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tcp => "TCP",
+            Self::Udp => "UDP",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MappingRequest {
+    protocol: MappingProtocol,
+    internal_ip: Ipv4Addr,
+    internal_port: u16,
+    // suggested external port, the gateway may assign another one
+    external_port: u16,
+    // zero lifetime deletes the mapping
+    lifetime: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MappingResult {
+    external_ip: Ipv4Addr,
+    external_port: u16,
+    lifetime: Duration,
+}
+
+#[derive(Debug, Clone)]
+struct IgdService {
+    control_url: url::Url,
+    service_type: String,
+}
+
+#[derive(Debug, Clone)]
+enum MappingBackend {
+    Pcp { nonce: [u8; 12] },
+    NatPmp,
+    Upnp(IgdService),
+}
+
+impl MappingBackend {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Pcp { .. } => "pcp",
+            Self::NatPmp => "nat-pmp",
+            Self::Upnp(_) => "upnp-igd",
+        }
+    }
+}
+
+fn be_u16(buf: &[u8]) -> u16 {
+    u16::from_be_bytes([buf[0], buf[1]])
+}
+
+fn be_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+// send a request to the pcp / nat-pmp server and wait for a matching response,
+// retransmit with doubled timeout as rfc 6886 suggests.
+async fn udp_request(
+    net_ns: &NetNS,
+    gateway: SocketAddr,
+    req: &[u8],
+    is_resp: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, Error> {
+    let socket = {
+        let _g = net_ns.guard();
+        UdpSocket::bind("0.0.0.0:0").await?
+    };
+    socket.connect(gateway).await?;
+    let mut buf = vec![0u8; 1100];
+    let mut wait = Duration::from_millis(250);
+    for _ in 0..3 {
+        socket.send(req).await?;
+        let deadline = tokio::time::Instant::now() + wait;
+        while let Ok(ret) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let len = ret?;
+            if is_resp(&buf[..len]) {
+                return Ok(buf[..len].to_vec());
+            }
+        }
+        wait *= 2;
+    }
+    Err(anyhow::anyhow!("no response from gateway {}", gateway).into())
+}
+
+fn encode_pcp_map_request(req: &MappingRequest, nonce: &[u8; 12]) -> [u8; PCP_MAP_PACKET_SIZE] {
+    let mut buf = [0u8; PCP_MAP_PACKET_SIZE];
+    buf[0] = PCP_VERSION;
+    buf[1] = PCP_OPCODE_MAP;
+    buf[4..8].copy_from_slice(&(req.lifetime.as_secs() as u32).to_be_bytes());
+    buf[8..24].copy_from_slice(&req.internal_ip.to_ipv6_mapped().octets());
+    buf[24..36].copy_from_slice(nonce);
+    buf[36] = req.protocol.ip_protocol();
+    buf[40..42].copy_from_slice(&req.internal_port.to_be_bytes());
+    buf[42..44].copy_from_slice(&req.external_port.to_be_bytes());
+    buf[44..60].copy_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    buf
+}
+
+fn decode_pcp_map_response(buf: &[u8], nonce: &[u8; 12]) -> Result<MappingResult, Error> {
+    // a nat-pmp only gateway answers with version 0 and UNSUPP_VERSION
+    if buf[0] != PCP_VERSION {
+        return Err(anyhow::anyhow!("gateway does not support pcp, version: {}", buf[0]).into());
+    }
+    if buf.len() < PCP_MAP_PACKET_SIZE {
+        return Err(anyhow::anyhow!("pcp response too short: {}", buf.len()).into());
+    }
+    if buf[3] != 0 {
+        return Err(anyhow::anyhow!("pcp map failed, result code: {}", buf[3]).into());
+    }
+    if &buf[24..36] != nonce {
+        return Err(anyhow::anyhow!("pcp response nonce mismatch").into());
+    }
+    let mut external_ip = [0u8; 16];
+    external_ip.copy_from_slice(&buf[44..60]);
+    let external_ip = Ipv6Addr::from(external_ip)
+        .to_ipv4_mapped()
+        .ok_or_else(|| anyhow::anyhow!("pcp assigned a non ipv4 external address"))?;
+    Ok(MappingResult {
+        external_ip,
+        external_port: be_u16(&buf[42..44]),
+        lifetime: Duration::from_secs(be_u32(&buf[4..8]) as u64),
+    })
+}
+
+async fn pcp_map(
+    net_ns: &NetNS,
+    gateway: SocketAddr,
+    req: &MappingRequest,
+    nonce: &[u8; 12],
+) -> Result<MappingResult, Error> {
+    let resp = udp_request(net_ns, gateway, &encode_pcp_map_request(req, nonce), |b| {
+        b.len() >= 4 && b[1] == PMP_RESPONSE_BIT | PCP_OPCODE_MAP
+    })
+    .await?;
+    decode_pcp_map_response(&resp, nonce)
+}
+
+async fn natpmp_external_address(net_ns: &NetNS, gateway: SocketAddr) -> Result<Ipv4Addr, Error> {
+    let resp = udp_request(net_ns, gateway, &[NATPMP_VERSION, 0], |b| {
+        b.len() >= 12 && b[0] == NATPMP_VERSION && b[1] == PMP_RESPONSE_BIT
+    })
+    .await?;
+    let result_code = be_u16(&resp[2..4]);
+    if result_code != 0 {
+        return Err(anyhow::anyhow!("nat-pmp external address failed: {}", result_code).into());
+    }
+    Ok(Ipv4Addr::new(resp[8], resp[9], resp[10], resp[11]))
+}
+
+fn encode_natpmp_map_request(req: &MappingRequest) -> [u8; 12] {
+    let mut buf = [0u8; 12];
+    buf[0] = NATPMP_VERSION;
+    buf[1] = req.protocol.natpmp_opcode();
+    buf[4..6].copy_from_slice(&req.internal_port.to_be_bytes());
+    // rfc 6886 requires the suggested port to be zero when deleting
+    if !req.lifetime.is_zero() {
+        buf[6..8].copy_from_slice(&req.external_port.to_be_bytes());
+    }
+    buf[8..12].copy_from_slice(&(req.lifetime.as_secs() as u32).to_be_bytes());
+    buf
+}
+
+async fn natpmp_map(
+    net_ns: &NetNS,
+    gateway: SocketAddr,
+    req: &MappingRequest,
+) -> Result<MappingResult, Error> {
+    let external_ip = if req.lifetime.is_zero() {
+        Ipv4Addr::UNSPECIFIED
+    } else {
+        natpmp_external_address(net_ns, gateway).await?
+    };
+    let opcode = PMP_RESPONSE_BIT | req.protocol.natpmp_opcode();
+    let resp = udp_request(net_ns, gateway, &encode_natpmp_map_request(req), |b| {
+        b.len() >= 16 && b[0] == NATPMP_VERSION && b[1] == opcode
+    })
+    .await?;
+    let result_code = be_u16(&resp[2..4]);
+    if result_code != 0 {
+        return Err(anyhow::anyhow!("nat-pmp map failed, result code: {}", result_code).into());
+    }
+    Ok(MappingResult {
+        external_ip,
+        external_port: be_u16(&resp[10..12]),
+        lifetime: Duration::from_secs(be_u32(&resp[12..16]) as u64),
+    })
+}
+
+fn find_http_header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        k.trim().eq_ignore_ascii_case(name).then(|| v.trim())
+    })
+}
+
+fn find_xml_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find(&close)? + start;
+    Some(xml[start..end].trim())
+}
+
+async fn ssdp_discover(net_ns: &NetNS, ssdp_addr: SocketAddr) -> Result<url::Url, Error> {
+    let socket = {
+        let _g = net_ns.guard();
+        UdpSocket::bind("0.0.0.0:0").await?
+    };
+    let msg = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n",
+        SSDP_ADDR
+    );
+    socket.send_to(msg.as_bytes(), ssdp_addr).await?;
+
+    let mut buf = vec![0u8; 2048];
+    let deadline = tokio::time::Instant::now() + SSDP_TIMEOUT;
+    loop {
+        let (len, _) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf))
+            .await
+            .context("no upnp gateway responded")??;
+        let resp = String::from_utf8_lossy(&buf[..len]);
+        if let Some(Ok(location)) = find_http_header(&resp, "location").map(url::Url::parse) {
+            return Ok(location);
+        }
+    }
+}
+
+// plain http/1.0 so the gateway never answers with chunked encoding
+async fn http_request(
+    net_ns: &NetNS,
+    url: &url::Url,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<(u16, String), Error> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::InvalidUrl(url.to_string()))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+
+    let mut req = format!(
+        "{} {} HTTP/1.0\r\nHost: {}:{}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        host,
+        port,
+        body.len()
+    );
+    for (k, v) in headers {
+        req.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    req.push_str("\r\n");
+    req.push_str(body);
+
+    let resp = tokio::time::timeout(HTTP_TIMEOUT, async {
+        let addr = tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| std::io::Error::other(format!("failed to resolve {}", host)))?;
+        let socket = {
+            let _g = net_ns.guard();
+            if addr.is_ipv4() {
+                TcpSocket::new_v4()?
+            } else {
+                TcpSocket::new_v6()?
+            }
+        };
+        let mut stream = socket.connect(addr).await?;
+        stream.write_all(req.as_bytes()).await?;
+        let mut resp = Vec::new();
+        stream.read_to_end(&mut resp).await?;
+        Ok::<_, std::io::Error>(resp)
+    })
+    .await??;
+
+    let resp = String::from_utf8_lossy(&resp);
+    let (head, body) = resp
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow::anyhow!("invalid http response from {}", url))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("invalid http status line from {}", url))?;
+    Ok((status, body.to_string()))
+}
+
+fn parse_igd_description(location: &url::Url, xml: &str) -> Option<IgdService> {
+    let base = find_xml_text(xml, "URLBase")
+        .and_then(|b| url::Url::parse(b).ok())
+        .unwrap_or_else(|| location.clone());
+    let mut rest = xml;
+    while let Some(start) = rest.find("<service>") {
+        let block = &rest[start..];
+        let end = block
+            .find("</service>")
+            .map(|e| e + "</service>".len())
+            .unwrap_or(block.len());
+        let service = &block[..end];
+        rest = &block[end..];
+
+        let Some(service_type) = find_xml_text(service, "serviceType") else {
+            continue;
+        };
+        if !IGD_SERVICE_TYPES.contains(&service_type) {
+            continue;
+        }
+        let Some(Ok(control_url)) = find_xml_text(service, "controlURL").map(|u| base.join(u))
+        else {
+            continue;
+        };
+        return Some(IgdService {
+            control_url,
+            service_type: service_type.to_string(),
+        });
+    }
+    None
+}
+
+async fn upnp_discover(net_ns: &NetNS, ssdp_addr: SocketAddr) -> Result<IgdService, Error> {
+    let location = ssdp_discover(net_ns, ssdp_addr).await?;
+    let (status, desc) = http_request(net_ns, &location, "GET", &[], "").await?;
+    if status != 200 {
+        return Err(anyhow::anyhow!("fetch igd description failed, status: {}", status).into());
+    }
+    parse_igd_description(&location, &desc)
+        .ok_or_else(|| anyhow::anyhow!("no wan connection service in {}", location).into())
+}
+
+fn igd_control_addr(service: &IgdService) -> Result<SocketAddr, Error> {
+    let url = &service.control_url;
+    let ip = url
+        .host_str()
+        .and_then(|h| h.parse::<IpAddr>().ok())
+        .ok_or_else(|| anyhow::anyhow!("igd control url without ip address: {}", url))?;
+    Ok(SocketAddr::new(
+        ip,
+        url.port_or_known_default().unwrap_or(80),
+    ))
+}
+
+async fn soap_call(
+    net_ns: &NetNS,
+    service: &IgdService,
+    action: &str,
+    args: &[(&str, String)],
+) -> Result<String, Error> {
+    let mut body = format!(
+        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{} xmlns:u=\"{}\">",
+        action, service.service_type
+    );
+    for (k, v) in args {
+        body.push_str(&format!("<{}>{}</{}>", k, v, k));
+    }
+    body.push_str(&format!("</u:{}></s:Body></s:Envelope>\r\n", action));
+
+    let soap_action = format!("\"{}#{}\"", service.service_type, action);
+    let (status, resp) = http_request(
+        net_ns,
+        &service.control_url,
+        "POST",
+        &[
+            ("Content-Type", "text/xml; charset=\"utf-8\""),
+            ("SOAPAction", soap_action.as_str()),
+        ],
+        &body,
+    )
+    .await?;
+    if status != 200 {
+        return Err(anyhow::anyhow!(
+            "upnp {} failed, status: {}, error code: {:?}",
+            action,
+            status,
+            find_xml_text(&resp, "errorCode")
+        )
+        .into());
+    }
+    Ok(resp)
+}
+
+async fn upnp_map(
+    net_ns: &NetNS,
+    service: &IgdService,
+    req: &MappingRequest,
+) -> Result<MappingResult, Error> {
+    if req.lifetime.is_zero() {
+        soap_call(
+            net_ns,
+            service,
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", req.external_port.to_string()),
+                ("NewProtocol", req.protocol.as_str().to_string()),
+            ],
+        )
+        .await?;
+        return Ok(MappingResult {
+            external_ip: Ipv4Addr::UNSPECIFIED,
+            external_port: req.external_port,
+            lifetime: Duration::ZERO,
+        });
+    }
+
+    let resp = soap_call(net_ns, service, "GetExternalIPAddress", &[]).await?;
+    let external_ip = find_xml_text(&resp, "NewExternalIPAddress")
+        .and_then(|ip| ip.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("invalid igd external address response"))?;
+
+    let add_mapping = |lease: u64| async move {
+        soap_call(
+            net_ns,
+            service,
+            "AddPortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", req.external_port.to_string()),
+                ("NewProtocol", req.protocol.as_str().to_string()),
+                ("NewInternalPort", req.internal_port.to_string()),
+                ("NewInternalClient", req.internal_ip.to_string()),
+                ("NewEnabled", "1".to_string()),
+                ("NewPortMappingDescription", MAPPING_DESCRIPTION.to_string()),
+                ("NewLeaseDuration", lease.to_string()),
+            ],
+        )
+        .await
+    };
+    // some igd v1 routers only accept permanent leases (error 725), the
+    // mapping is still refreshed periodically and deleted on shutdown.
+    if let Err(e) = add_mapping(req.lifetime.as_secs()).await {
+        tracing::debug!(
+            ?e,
+            "add upnp port mapping failed, retry with permanent lease"
+        );
+        add_mapping(0).await?;
+    }
+
+    Ok(MappingResult {
+        external_ip,
+        external_port: req.external_port,
+        lifetime: req.lifetime,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<Ipv4Addr> {
+    use crate::common::ifcfg::IfConfiger;
+
+    const RT_TABLE_MAIN: u32 = 254;
+    let routes = IfConfiger::list_ipv4_routes_in_table(RT_TABLE_MAIN, &[]).ok()?;
+    routes
+        .into_iter()
+        .filter(|r| r.prefix == 0)
+        .filter_map(|r| match r.gateway {
+            Some(IpAddr::V4(gw)) => Some((r.metric.unwrap_or(0), gw)),
+            _ => None,
+        })
+        .min()
+        .map(|(_, gw)| gw)
+}
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+fn default_gateway() -> Option<Ipv4Addr> {
+    let output = std::process::Command::new("route")
+        .args(["-n", "get", "default"])
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("gateway:"))
+        .and_then(|gw| gw.trim().parse().ok())
+}
+
+#[cfg(target_os = "windows")]
+fn default_gateway() -> Option<Ipv4Addr> {
+    use windows_sys::Win32::NetworkManagement::IpHelper::{GetBestRoute, MIB_IPFORWARDROW};
+
+    // the best route to a public address goes through the default gateway.
+    let dst = u32::from_ne_bytes([8, 8, 8, 8]);
+    let mut row: MIB_IPFORWARDROW = unsafe { std::mem::zeroed() };
+    if unsafe { GetBestRoute(dst, 0, &mut row) } != 0 {
+        return None;
+    }
+    let gateway = Ipv4Addr::from(row.dwForwardNextHop.to_ne_bytes());
+    (!gateway.is_unspecified()).then_some(gateway)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "windows"
+)))]
+fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+fn local_ip_towards(gateway: SocketAddr) -> Result<Ipv4Addr, Error> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(gateway)?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        ip => Err(anyhow::anyhow!("unexpected local address {}", ip).into()),
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    let shared = a == 100 && (64..128).contains(&b);
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || shared)
+}
+
+#[derive(Debug, Clone)]
+struct GatewayEndpoints {
+    pmp: SocketAddr,
+    ssdp: SocketAddr,
+}
+
+impl GatewayEndpoints {
+    fn detect(net_ns: &NetNS) -> Option<Self> {
+        let gateway = {
+            let _g = net_ns.guard();
+            default_gateway()?
+        };
+        Some(Self {
+            pmp: SocketAddr::new(gateway.into(), NATPMP_PORT),
+            ssdp: SSDP_ADDR.into(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct ActiveMapping {
+    gateway: SocketAddr,
+    backend: MappingBackend,
+    request: MappingRequest,
+    result: MappingResult,
+    external_url: url::Url,
+    renew_at: Instant,
+}
+
+impl ActiveMapping {
+    fn new(
+        listener: &url::Url,
+        gateway: SocketAddr,
+        backend: MappingBackend,
+        request: MappingRequest,
+        result: MappingResult,
+    ) -> Self {
+        let mut external_url = listener.clone();
+        let _ = external_url.set_ip_host(IpAddr::V4(result.external_ip));
+        let _ = external_url.set_port(Some(result.external_port));
+        // renew at half of the granted lifetime
+        let renew_at = Instant::now() + (result.lifetime / 2).max(SYNC_INTERVAL);
+        Self {
+            gateway,
+            backend,
+            request,
+            result,
+            external_url,
+            renew_at,
+        }
+    }
+
+    async fn remove(&self, net_ns: &NetNS) -> Result<(), Error> {
+        let req = MappingRequest {
+            external_port: self.result.external_port,
+            lifetime: Duration::ZERO,
+            ..self.request.clone()
+        };
+        PortMapper::map_with_backend(net_ns, self.gateway, &self.backend, &req).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct MappingTable {
+    active: HashMap<url::Url, ActiveMapping>,
+    retry_at: HashMap<url::Url, Instant>,
+}
+
+// keeps port mappings on the gateway router for all running listeners and
+// publishes the external addresses to peers through the ip list rpc.
+pub struct PortMapper {
+    global_ctx: ArcGlobalCtx,
+    gateway: Option<GatewayEndpoints>,
+    table: Arc<Mutex<MappingTable>>,
+    tasks: JoinSet<()>,
+}
+
+impl PortMapper {
+    pub fn new(global_ctx: ArcGlobalCtx) -> Self {
+        Self {
+            global_ctx,
+            gateway: None,
+            table: Arc::new(Mutex::new(MappingTable::default())),
+            tasks: JoinSet::new(),
+        }
+    }
+
+    async fn map_with_backend(
+        net_ns: &NetNS,
+        gateway: SocketAddr,
+        backend: &MappingBackend,
+        req: &MappingRequest,
+    ) -> Result<MappingResult, Error> {
+        match backend {
+            MappingBackend::Pcp { nonce } => pcp_map(net_ns, gateway, req, nonce).await,
+            MappingBackend::NatPmp => natpmp_map(net_ns, gateway, req).await,
+            MappingBackend::Upnp(service) => upnp_map(net_ns, service, req).await,
+        }
+    }
+
+    async fn create_mapping(
+        net_ns: &NetNS,
+        gateway: &GatewayEndpoints,
+        listener: &url::Url,
+        prev: Option<&ActiveMapping>,
+    ) -> Result<ActiveMapping, Error> {
+        let protocol = MappingProtocol::from_scheme(listener.scheme())
+            .ok_or_else(|| Error::InvalidUrl(listener.to_string()))?;
+        let internal_port = listener
+            .port()
+            .filter(|p| *p != 0)
+            .ok_or_else(|| Error::InvalidUrl(listener.to_string()))?;
+        let listen_ip = listener
+            .host_str()
+            .and_then(|h| h.parse::<Ipv4Addr>().ok())
+            .filter(|ip| !ip.is_unspecified());
+        // the internal address must be the one the mapping server sees us with.
+        let internal_ip_towards = |server: SocketAddr| match listen_ip {
+            Some(ip) => Ok(ip),
+            None => {
+                let _g = net_ns.guard();
+                local_ip_towards(server)
+            }
+        };
+        let request = MappingRequest {
+            protocol,
+            internal_ip: internal_ip_towards(gateway.pmp)?,
+            internal_port,
+            external_port: prev
+                .map(|p| p.result.external_port)
+                .unwrap_or(internal_port),
+            lifetime: MAPPING_LIFETIME,
+        };
+
+        if let Some(prev) = prev {
+            let request = MappingRequest {
+                internal_ip: internal_ip_towards(prev.gateway)?,
+                ..request.clone()
+            };
+            match Self::map_with_backend(net_ns, prev.gateway, &prev.backend, &request).await {
+                Ok(result) => {
+                    return Ok(ActiveMapping::new(
+                        listener,
+                        prev.gateway,
+                        prev.backend.clone(),
+                        request,
+                        result,
+                    ))
+                }
+                Err(e) => tracing::debug!(?e, ?listener, "renew port mapping failed"),
+            }
+        }
+
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        for backend in [MappingBackend::Pcp { nonce }, MappingBackend::NatPmp] {
+            match Self::map_with_backend(net_ns, gateway.pmp, &backend, &request).await {
+                Ok(result) => {
+                    return Ok(ActiveMapping::new(
+                        listener,
+                        gateway.pmp,
+                        backend,
+                        request,
+                        result,
+                    ))
+                }
+                Err(e) => tracing::debug!(?e, backend = backend.name(), "port mapping failed"),
+            }
+        }
+
+        // the igd may not be the default gateway, talk to the device found by ssdp.
+        let service = upnp_discover(net_ns, gateway.ssdp).await?;
+        let igd_addr = igd_control_addr(&service)?;
+        let request = MappingRequest {
+            internal_ip: internal_ip_towards(igd_addr)?,
+            ..request
+        };
+        let backend = MappingBackend::Upnp(service);
+        let result = Self::map_with_backend(net_ns, igd_addr, &backend, &request).await?;
+        Ok(ActiveMapping::new(
+            listener, igd_addr, backend, request, result,
+        ))
+    }
+
+    fn mappable_listener(l: &url::Url) -> bool {
+        MappingProtocol::from_scheme(l.scheme()).is_some()
+            && l.host_str()
+                .and_then(|h| h.parse::<Ipv4Addr>().ok())
+                .is_some_and(|ip| ip.is_unspecified() || ip.is_private())
+    }
+
+    async fn sync_mappings(
+        global_ctx: &ArcGlobalCtx,
+        gateway: &GatewayEndpoints,
+        table: &Mutex<MappingTable>,
+    ) {
+        let listeners: Vec<url::Url> = global_ctx
+            .get_running_listeners()
+            .into_iter()
+            .filter(Self::mappable_listener)
+            .collect();
+        let mut table = table.lock().await;
+
+        let stale: Vec<url::Url> = table
+            .active
+            .keys()
+            .filter(|l| !listeners.contains(l))
+            .cloned()
+            .collect();
+        for l in stale {
+            if let Some(m) = table.active.remove(&l) {
+                Self::remove_mapping(global_ctx, m).await;
+            }
+        }
+
+        let now = Instant::now();
+        for listener in listeners {
+            if table
+                .active
+                .get(&listener)
+                .is_some_and(|m| m.renew_at > now)
+                || table.retry_at.get(&listener).is_some_and(|t| *t > now)
+            {
+                continue;
+            }
+
+            let prev = table.active.remove(&listener);
+            match Self::create_mapping(&global_ctx.net_ns, gateway, &listener, prev.as_ref()).await
+            {
+                Ok(m) => {
+                    if let Some(prev) = prev {
+                        global_ctx.remove_port_mapped_listener(&prev.external_url);
+                    }
+                    if is_public_ipv4(&m.result.external_ip) {
+                        tracing::info!(
+                            ?listener,
+                            external_url = ?m.external_url,
+                            backend = m.backend.name(),
+                            "port mapping created"
+                        );
+                        global_ctx.add_port_mapped_listener(m.external_url.clone());
+                    } else {
+                        tracing::info!(
+                            ?listener,
+                            external_url = ?m.external_url,
+                            "gateway external address is not public, maybe behind another nat"
+                        );
+                    }
+                    table.retry_at.remove(&listener);
+                    table.active.insert(listener, m);
+                }
+                Err(e) => {
+                    tracing::info!(?e, ?listener, "failed to create port mapping");
+                    if let Some(prev) = prev {
+                        global_ctx.remove_port_mapped_listener(&prev.external_url);
+                    }
+                    table.retry_at.insert(listener, now + RETRY_INTERVAL);
+                }
+            }
+        }
+    }
+
+    async fn remove_mapping(global_ctx: &ArcGlobalCtx, m: ActiveMapping) {
+        global_ctx.remove_port_mapped_listener(&m.external_url);
+        if let Err(e) = m.remove(&global_ctx.net_ns).await {
+            tracing::warn!(?e, external_url = ?m.external_url, "failed to remove port mapping");
+        }
+    }
+
+    async fn clear_table(global_ctx: &ArcGlobalCtx, table: &Mutex<MappingTable>) {
+        let mut table = table.lock().await;
+        table.retry_at.clear();
+        for (_, m) in table.active.drain() {
+            Self::remove_mapping(global_ctx, m).await;
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<(), Error> {
+        if !self.global_ctx.get_flags().enable_port_mapping {
+            return Ok(());
+        }
+
+        let global_ctx = self.global_ctx.clone();
+        let gateway = self.gateway.clone();
+        let table = self.table.clone();
+        self.tasks.spawn(async move {
+            loop {
+                match gateway
+                    .clone()
+                    .or_else(|| GatewayEndpoints::detect(&global_ctx.net_ns))
+                {
+                    Some(gateway) => Self::sync_mappings(&global_ctx, &gateway, &table).await,
+                    None => tracing::debug!("no default gateway, skip port mapping"),
+                }
+                tokio::time::sleep(SYNC_INTERVAL).await;
+            }
+        });
+        Ok(())
+    }
+
+    pub async fn clear_mappings(&mut self) {
+        self.tasks.abort_all();
+        Self::clear_table(&self.global_ctx, &self.table).await;
+    }
+}
+
+impl Drop for PortMapper {
+    fn drop(&mut self) {
+        self.tasks.abort_all();
+        let global_ctx = self.global_ctx.clone();
+        let table = self.table.clone();
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                Self::clear_table(&global_ctx, &table).await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::{
+        common::global_ctx::tests::get_mock_global_ctx, tunnel::common::tests::wait_for_condition,
+    };
+
+    use super::*;
+
+    const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 9);
+
+    type Requests = Arc<StdMutex<Vec<Vec<u8>>>>;
+
+    async fn spawn_udp_stub(
+        handler: impl Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+    ) -> (SocketAddr, Requests) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let requests = Requests::default();
+        let requests_clone = requests.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 2048];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                requests_clone.lock().unwrap().push(buf[..len].to_vec());
+                if let Some(resp) = handler(&buf[..len]) {
+                    socket.send_to(&resp, from).await.unwrap();
+                }
+            }
+        });
+        (addr, requests)
+    }
+
+    async fn closed_udp_addr() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.local_addr().unwrap()
+    }
+
+    fn pcp_stub_handler(req: &[u8]) -> Option<Vec<u8>> {
+        if req.len() < PCP_MAP_PACKET_SIZE || req[0] != PCP_VERSION {
+            return None;
+        }
+        let mut resp = req.to_vec();
+        resp[1] = PMP_RESPONSE_BIT | PCP_OPCODE_MAP;
+        resp[3] = 0;
+        let external_port = be_u16(&req[40..42]) + 1000;
+        resp[42..44].copy_from_slice(&external_port.to_be_bytes());
+        resp[44..60].copy_from_slice(&EXTERNAL_IP.to_ipv6_mapped().octets());
+        Some(resp)
+    }
+
+    fn natpmp_stub_handler(req: &[u8]) -> Option<Vec<u8>> {
+        match (req[0], req[1]) {
+            // unsupported version, tells the client to fall back to nat-pmp
+            (PCP_VERSION, op) => Some(vec![0, PMP_RESPONSE_BIT | op, 0, 1, 0, 0, 0, 0]),
+            (NATPMP_VERSION, 0) => {
+                let mut resp = vec![0, PMP_RESPONSE_BIT, 0, 0, 0, 0, 0, 1];
+                resp.extend_from_slice(&EXTERNAL_IP.octets());
+                Some(resp)
+            }
+            (NATPMP_VERSION, op) if req.len() >= 12 => {
+                let mut resp = vec![0, PMP_RESPONSE_BIT | op, 0, 0, 0, 0, 0, 1];
+                resp.extend_from_slice(&req[4..6]);
+                resp.extend_from_slice(&req[6..8]);
+                resp.extend_from_slice(&req[8..12]);
+                Some(resp)
+            }
+            _ => None,
+        }
+    }
+
+    fn create_port_mapper(listener: &str, pmp: SocketAddr, ssdp: SocketAddr) -> PortMapper {
+        let global_ctx = get_mock_global_ctx();
+        let mut flags = global_ctx.get_flags();
+        flags.enable_port_mapping = true;
+        global_ctx.config.set_flags(flags);
+        global_ctx.add_running_listener(listener.parse().unwrap());
+        global_ctx.add_running_listener("ring://abc".parse().unwrap());
+
+        let mut mapper = PortMapper::new(global_ctx);
+        mapper.gateway = Some(GatewayEndpoints { pmp, ssdp });
+        mapper
+    }
+
+    async fn wait_for_mapped_listener(mapper: &PortMapper, expected: &str) {
+        let global_ctx = mapper.global_ctx.clone();
+        let expected: url::Url = expected.parse().unwrap();
+        wait_for_condition(
+            || {
+                let global_ctx = global_ctx.clone();
+                let expected = expected.clone();
+                async move { global_ctx.get_port_mapped_listeners() == vec![expected] }
+            },
+            Duration::from_secs(10),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn pcp_port_mapping() {
+        let (pmp, requests) = spawn_udp_stub(pcp_stub_handler).await;
+        let mut mapper = create_port_mapper("tcp://0.0.0.0:11010", pmp, closed_udp_addr().await);
+        mapper.run().await.unwrap();
+        wait_for_mapped_listener(&mapper, "tcp://203.0.113.9:12010").await;
+
+        mapper.clear_mappings().await;
+        assert!(mapper.global_ctx.get_port_mapped_listeners().is_empty());
+        let requests = requests.lock().unwrap();
+        let delete = requests.last().unwrap();
+        assert_eq!(be_u32(&delete[4..8]), 0);
+        assert_eq!(delete[36], MappingProtocol::Tcp.ip_protocol());
+        assert_eq!(be_u16(&delete[40..42]), 11010);
+        // the delete request must carry the same nonce as the map request
+        assert_eq!(delete[24..36], requests[0][24..36]);
+    }
+
+    #[tokio::test]
+    async fn natpmp_fallback_port_mapping() {
+        let (pmp, requests) = spawn_udp_stub(natpmp_stub_handler).await;
+        let mut mapper = create_port_mapper("udp://0.0.0.0:11011", pmp, closed_udp_addr().await);
+        mapper.run().await.unwrap();
+        wait_for_mapped_listener(&mapper, "udp://203.0.113.9:11011").await;
+
+        mapper.clear_mappings().await;
+        assert!(mapper.global_ctx.get_port_mapped_listeners().is_empty());
+        let requests = requests.lock().unwrap();
+        let delete = requests.last().unwrap();
+        assert_eq!(
+            delete.as_slice(),
+            &[0, 1, 0, 0, 0x2b, 0x03, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    async fn read_http_request(stream: &mut TcpStream) -> String {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut chunk).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            let req = String::from_utf8_lossy(&buf);
+            if let Some((head, body)) = req.split_once("\r\n\r\n") {
+                let len: usize = find_http_header(head, "content-length")
+                    .map(|l| l.parse().unwrap())
+                    .unwrap_or(0);
+                if body.len() >= len {
+                    break;
+                }
+            }
+        }
+        String::from_utf8(buf).unwrap()
+    }
+
+    async fn spawn_igd_stub() -> (SocketAddr, Arc<StdMutex<Vec<String>>>) {
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http_addr = http.local_addr().unwrap();
+        let actions = Arc::new(StdMutex::new(Vec::new()));
+        let actions_clone = actions.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = http.accept().await.unwrap();
+                let req = read_http_request(&mut stream).await;
+                let body = if req.starts_with("GET /rootDesc.xml") {
+                    "<root><device><serviceList><service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType><controlURL>/ctl/L3F</controlURL></service><service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType><controlURL>/ctl/IPConn</controlURL></service></serviceList></device></root>".to_string()
+                } else if req.starts_with("POST /ctl/IPConn") {
+                    let action = find_http_header(&req, "soapaction")
+                        .and_then(|a| a.trim_matches('"').split_once('#'))
+                        .map(|(_, a)| a.to_string())
+                        .unwrap();
+                    actions_clone.lock().unwrap().push(req.clone());
+                    format!(
+                        "<s:Envelope><s:Body><u:{}Response><NewExternalIPAddress>{}</NewExternalIPAddress></u:{}Response></s:Body></s:Envelope>",
+                        action, EXTERNAL_IP, action
+                    )
+                } else {
+                    String::new()
+                };
+                let resp = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+
+        let (ssdp, _) = spawn_udp_stub(move |req| {
+            String::from_utf8_lossy(req).starts_with("M-SEARCH").then(|| {
+                format!(
+                    "HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nLOCATION: http://{}/rootDesc.xml\r\n\r\n",
+                    http_addr
+                )
+                .into_bytes()
+            })
+        })
+        .await;
+        (ssdp, actions)
+    }
+
+    #[tokio::test]
+    async fn upnp_igd_port_mapping() {
+        let (ssdp, actions) = spawn_igd_stub().await;
+        let mut mapper = create_port_mapper("tcp://0.0.0.0:11012", closed_udp_addr().await, ssdp);
+        mapper.run().await.unwrap();
+        wait_for_mapped_listener(&mapper, "tcp://203.0.113.9:11012").await;
+
+        {
+            let actions = actions.lock().unwrap();
+            let add = actions
+                .iter()
+                .find(|a| a.contains("AddPortMapping"))
+                .unwrap();
+            assert!(add.contains("<NewInternalPort>11012</NewInternalPort>"));
+            assert!(add.contains("<NewInternalClient>127.0.0.1</NewInternalClient>"));
+            assert!(add.contains("<NewProtocol>TCP</NewProtocol>"));
+        }
+
+        mapper.clear_mappings().await;
+        assert!(mapper.global_ctx.get_port_mapped_listeners().is_empty());
+        let actions = actions.lock().unwrap();
+        let delete = actions.last().unwrap();
+        assert!(delete.contains("#DeletePortMapping"));
+        assert!(delete.contains("<NewExternalPort>11012</NewExternalPort>"));
+    }
+
+    #[test]
+    fn parse_igd_description_with_url_base() {
+        let location: url::Url = "http://192.168.1.1:5000/rootDesc.xml".parse().unwrap();
+        let xml = "<root><URLBase>http://192.168.1.1:6000/</URLBase><service><serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType><controlURL>ctl/PPP</controlURL></service></root>";
+        let service = parse_igd_description(&location, xml).unwrap();
+        assert_eq!(
+            service.control_url.as_str(),
+            "http://192.168.1.1:6000/ctl/PPP"
+        );
+        assert!(parse_igd_description(&location, "<root></root>").is_none());
+    }
+}
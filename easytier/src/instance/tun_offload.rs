@@ -0,0 +1,756 @@
+use crate::tunnel::{packet_def::ZCPacket, TunnelError};
+
+pub const VIRTIO_NET_HDR_LEN: usize = 10;
+// a tso super packet is at most one max sized ip packet
+pub const TUN_OFFLOAD_READ_SIZE: usize = VIRTIO_NET_HDR_LEN + 65535;
+// max packets drained from the peer channel before writing to the tun
+pub const TUN_OFFLOAD_BATCH_SIZE: usize = 64;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+const IPPROTO_TCP: u8 = 6;
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_ACK: u8 = 0x10;
+const TCP_FLAG_CWR: u8 = 0x80;
+const TCP_CSUM_OFFSET: u16 = 16;
+const MAX_COALESCE_SEGMENTS: usize = 64;
+
+/// The virtio_net_hdr prepended to every packet on an IFF_VNET_HDR tun, in
+/// native endian.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VirtioNetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < VIRTIO_NET_HDR_LEN {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_ne_bytes([buf[i], buf[i + 1]]);
+        Some(Self {
+            flags: buf[0],
+            gso_type: buf[1],
+            hdr_len: u16_at(2),
+            gso_size: u16_at(4),
+            csum_start: u16_at(6),
+            csum_offset: u16_at(8),
+        })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        buf[0] = self.flags;
+        buf[1] = self.gso_type;
+        buf[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        buf[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        buf[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        buf[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+    }
+}
+
+fn be_u16(buf: &[u8]) -> u16 {
+    u16::from_be_bytes([buf[0], buf[1]])
+}
+
+fn be_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn checksum_add(mut sum: u64, data: &[u8]) -> u64 {
+    let mut chunks = data.chunks_exact(2);
+    for c in &mut chunks {
+        sum += u16::from_be_bytes([c[0], c[1]]) as u64;
+    }
+    if let [b] = chunks.remainder() {
+        sum += (*b as u64) << 8;
+    }
+    sum
+}
+
+fn checksum_fold(mut sum: u64) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+fn pseudo_header_sum(ip: &[u8], l4_len: usize) -> u64 {
+    let addrs = if ip[0] >> 4 == 4 {
+        &ip[12..20]
+    } else {
+        &ip[8..40]
+    };
+    checksum_add(0, addrs) + IPPROTO_TCP as u64 + l4_len as u64
+}
+
+fn update_ipv4_checksum(ip: &mut [u8]) {
+    let ihl = ((ip[0] & 0x0f) as usize) * 4;
+    ip[10..12].fill(0);
+    let sum = !checksum_fold(checksum_add(0, &ip[..ihl]));
+    ip[10..12].copy_from_slice(&sum.to_be_bytes());
+}
+
+fn set_ip_total_len(ip: &mut [u8], ipv6: bool) {
+    let len = ip.len();
+    if ipv6 {
+        ip[4..6].copy_from_slice(&((len - 40) as u16).to_be_bytes());
+    } else {
+        ip[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        update_ipv4_checksum(ip);
+    }
+}
+
+fn write_vnet_hdr(packet: &mut ZCPacket, hdr: &VirtioNetHdr) -> Result<(), TunnelError> {
+    let offset = packet.payload_offset();
+    if offset < VIRTIO_NET_HDR_LEN {
+        return Err(TunnelError::InvalidPacket(format!(
+            "no room for vnet header, payload offset: {}",
+            offset
+        )));
+    }
+    hdr.encode(&mut packet.mut_inner()[offset - VIRTIO_NET_HDR_LEN..offset]);
+    Ok(())
+}
+
+// the checksum field holds the pseudo header sum, finish it over the l4 data
+fn complete_checksum(ip: &mut [u8], hdr: &VirtioNetHdr) {
+    let start = hdr.csum_start as usize;
+    let pos = start + hdr.csum_offset as usize;
+    if pos + 2 > ip.len() {
+        return;
+    }
+    let sum = match !checksum_fold(checksum_add(0, &ip[start..])) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    ip[pos..pos + 2].copy_from_slice(&sum.to_be_bytes());
+}
+
+fn segment_tcp(ip: &[u8], hdr: &VirtioNetHdr, max_ip_len: usize) -> Vec<ZCPacket> {
+    let ipv6 = ip[0] >> 4 == 6;
+    let l4_offset = hdr.csum_start as usize;
+    if ip.len() < l4_offset + 20 {
+        return vec![];
+    }
+    let header_len = l4_offset + ((ip[l4_offset + 12] >> 4) as usize) * 4;
+    if ip.len() < header_len {
+        return vec![];
+    }
+    let gso_size = (hdr.gso_size as usize).min(max_ip_len.saturating_sub(header_len));
+    if gso_size == 0 {
+        return vec![];
+    }
+
+    let seq = be_u32(&ip[l4_offset + 4..]);
+    let flags = ip[l4_offset + 13];
+    let ip_id = be_u16(&ip[4..]);
+    let payload = &ip[header_len..];
+    let mut seg = Vec::with_capacity(header_len + gso_size);
+    payload
+        .chunks(gso_size)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let is_last = (i + 1) * gso_size >= payload.len();
+            seg.clear();
+            seg.extend_from_slice(&ip[..header_len]);
+            seg.extend_from_slice(chunk);
+            if !ipv6 {
+                seg[4..6].copy_from_slice(&ip_id.wrapping_add(i as u16).to_be_bytes());
+            }
+            set_ip_total_len(&mut seg, ipv6);
+
+            let tcp = l4_offset;
+            seg[tcp + 4..tcp + 8]
+                .copy_from_slice(&seq.wrapping_add((i * gso_size) as u32).to_be_bytes());
+            let mut seg_flags = flags;
+            if !is_last {
+                seg_flags &= !(TCP_FLAG_FIN | TCP_FLAG_PSH);
+            }
+            if i > 0 {
+                seg_flags &= !TCP_FLAG_CWR;
+            }
+            seg[tcp + 13] = seg_flags;
+
+            seg[tcp + 16..tcp + 18].fill(0);
+            let l4_len = seg.len() - tcp;
+            let sum = pseudo_header_sum(&seg, l4_len);
+            let sum = !checksum_fold(checksum_add(sum, &seg[tcp..]));
+            seg[tcp + 16..tcp + 18].copy_from_slice(&sum.to_be_bytes());
+
+            ZCPacket::new_with_payload(&seg)
+        })
+        .collect()
+}
+
+/// A packet read from a vnet_hdr tun.
+pub enum NicPacket {
+    /// plain ip packet, the partial checksum is already completed.
+    Single(ZCPacket),
+    /// tcp super packet, kept whole through the peer path and split by
+    /// `segment_tso_packet` once the size limit towards the peers is known.
+    Tso(ZCPacket, VirtioNetHdr),
+}
+
+/// Decodes the virtio_net_hdr right before the payload of a packet read from
+/// the tun, returns None if the packet should be dropped.
+pub fn parse_nic_packet(mut packet: ZCPacket) -> Option<NicPacket> {
+    let offset = packet.payload_offset();
+    if offset < VIRTIO_NET_HDR_LEN || packet.payload().is_empty() {
+        return None;
+    }
+    let hdr = VirtioNetHdr::decode(&packet.mut_inner()[offset - VIRTIO_NET_HDR_LEN..offset])?;
+
+    match hdr.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+        VIRTIO_NET_HDR_GSO_NONE => {
+            if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+                complete_checksum(packet.mut_payload(), &hdr);
+            }
+            Some(NicPacket::Single(packet))
+        }
+        VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_TCPV6 => Some(NicPacket::Tso(packet, hdr)),
+        gso_type => {
+            tracing::warn!(?gso_type, "unsupported gso type from tun, drop packet");
+            None
+        }
+    }
+}
+
+/// Splits a tcp super packet into segments whose ip length is at most
+/// `max_ip_len`, never larger than the segment size the kernel asked for.
+pub fn segment_tso_packet(
+    packet: ZCPacket,
+    hdr: &VirtioNetHdr,
+    max_ip_len: usize,
+) -> Vec<ZCPacket> {
+    segment_tcp(packet.payload(), hdr, max_ip_len)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TcpSegment {
+    ipv6: bool,
+    l4_offset: usize,
+    header_len: usize,
+    seq: u32,
+    flags: u8,
+    payload_len: usize,
+}
+
+impl TcpSegment {
+    fn parse(ip: &[u8]) -> Option<Self> {
+        let (ipv6, l4_offset, total_len) = match ip.first()? >> 4 {
+            4 => {
+                if ip.len() < 20 || ip[9] != IPPROTO_TCP {
+                    return None;
+                }
+                // fragments can not be coalesced
+                if be_u16(&ip[6..]) & 0x3fff != 0 {
+                    return None;
+                }
+                (
+                    false,
+                    ((ip[0] & 0x0f) as usize) * 4,
+                    be_u16(&ip[2..]) as usize,
+                )
+            }
+            6 => {
+                // packets with extension headers are passed through
+                if ip.len() < 40 || ip[6] != IPPROTO_TCP {
+                    return None;
+                }
+                (true, 40, 40 + be_u16(&ip[4..]) as usize)
+            }
+            _ => return None,
+        };
+        if total_len != ip.len() || ip.len() < l4_offset + 20 {
+            return None;
+        }
+        let header_len = l4_offset + ((ip[l4_offset + 12] >> 4) as usize) * 4;
+        if header_len < l4_offset + 20 || ip.len() < header_len {
+            return None;
+        }
+        Some(Self {
+            ipv6,
+            l4_offset,
+            header_len,
+            seq: be_u32(&ip[l4_offset + 4..]),
+            flags: ip[l4_offset + 13],
+            payload_len: ip.len() - header_len,
+        })
+    }
+
+    // everything but lengths, ids, checksums, seq and flags must be identical
+    fn same_flow(&self, a: &[u8], b: &[u8]) -> bool {
+        let ip_same = if self.ipv6 {
+            a[0..4] == b[0..4] && a[6..40] == b[6..40]
+        } else {
+            a[0..2] == b[0..2]
+                && a[8..10] == b[8..10]
+                && a[12..self.l4_offset] == b[12..self.l4_offset]
+        };
+        let (ta, tb) = (
+            &a[self.l4_offset..self.header_len],
+            &b[self.l4_offset..self.header_len],
+        );
+        ip_same
+            && ta[0..4] == tb[0..4]
+            && ta[8..12] == tb[8..12]
+            && ta[14..16] == tb[14..16]
+            && ta[20..] == tb[20..]
+    }
+}
+
+struct CoalesceGroup {
+    packets: Vec<ZCPacket>,
+    first: TcpSegment,
+    last: TcpSegment,
+    ip_len: usize,
+}
+
+impl CoalesceGroup {
+    fn try_push(&mut self, packet: ZCPacket, seg: &TcpSegment) -> Result<(), ZCPacket> {
+        let first = &self.first;
+        let ok = self.packets.len() < MAX_COALESCE_SEGMENTS
+            && seg.ipv6 == first.ipv6
+            && seg.l4_offset == first.l4_offset
+            && seg.header_len == first.header_len
+            // only the last segment of a group may carry psh or be shorter
+            && self.last.flags == TCP_FLAG_ACK
+            && seg.flags & !TCP_FLAG_PSH == TCP_FLAG_ACK
+            && self.last.payload_len == first.payload_len
+            && seg.payload_len > 0
+            && seg.payload_len <= first.payload_len
+            && seg.seq == self.last.seq.wrapping_add(self.last.payload_len as u32)
+            && self.ip_len + seg.payload_len <= u16::MAX as usize
+            && first.same_flow(self.packets[0].payload(), packet.payload());
+        if !ok {
+            return Err(packet);
+        }
+        self.last = *seg;
+        self.ip_len += seg.payload_len;
+        self.packets.push(packet);
+        Ok(())
+    }
+
+    fn finish(self, out: &mut Vec<ZCPacket>) -> Result<(), TunnelError> {
+        let mut packets = self.packets;
+        if packets.len() == 1 {
+            let mut packet = packets.pop().unwrap();
+            write_vnet_hdr(&mut packet, &VirtioNetHdr::default())?;
+            out.push(packet);
+            return Ok(());
+        }
+
+        let first = self.first;
+        let mut buf = Vec::with_capacity(self.ip_len);
+        buf.extend_from_slice(&packets[0].payload()[..first.header_len]);
+        for p in packets.iter() {
+            buf.extend_from_slice(&p.payload()[first.header_len..]);
+        }
+        set_ip_total_len(&mut buf, first.ipv6);
+        let tcp = first.l4_offset;
+        buf[tcp + 13] = self.last.flags;
+        let sum = checksum_fold(pseudo_header_sum(&buf, buf.len() - tcp));
+        buf[tcp + 16..tcp + 18].copy_from_slice(&sum.to_be_bytes());
+
+        let mut packet = ZCPacket::new_with_payload(&buf);
+        write_vnet_hdr(
+            &mut packet,
+            &VirtioNetHdr {
+                flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+                gso_type: if first.ipv6 {
+                    VIRTIO_NET_HDR_GSO_TCPV6
+                } else {
+                    VIRTIO_NET_HDR_GSO_TCPV4
+                },
+                hdr_len: first.header_len as u16,
+                gso_size: first.payload_len as u16,
+                csum_start: tcp as u16,
+                csum_offset: TCP_CSUM_OFFSET,
+            },
+        )?;
+        out.push(packet);
+        Ok(())
+    }
+}
+
+/// Merges runs of consecutive segments of the same tcp flow into tso super
+/// packets and prefixes every packet with a virtio_net_hdr, so the kernel
+/// receives a whole burst with one write.
+pub fn coalesce_nic_packets(packets: Vec<ZCPacket>) -> Result<Vec<ZCPacket>, TunnelError> {
+    let mut out = Vec::with_capacity(packets.len());
+    let mut group: Option<CoalesceGroup> = None;
+    for packet in packets {
+        let seg = TcpSegment::parse(packet.payload());
+        let packet = match (group.as_mut(), seg.as_ref()) {
+            (Some(g), Some(seg)) => match g.try_push(packet, seg) {
+                Ok(()) => continue,
+                Err(packet) => packet,
+            },
+            _ => packet,
+        };
+
+        if let Some(g) = group.take() {
+            g.finish(&mut out)?;
+        }
+        match seg {
+            Some(seg) if seg.payload_len > 0 => {
+                group = Some(CoalesceGroup {
+                    ip_len: packet.payload().len(),
+                    packets: vec![packet],
+                    first: seg,
+                    last: seg,
+                });
+            }
+            _ => {
+                let mut packet = packet;
+                write_vnet_hdr(&mut packet, &VirtioNetHdr::default())?;
+                out.push(packet);
+            }
+        }
+    }
+    if let Some(g) = group.take() {
+        g.finish(&mut out)?;
+    }
+    Ok(out)
+}
+
+#[cfg(target_os = "linux")]
+mod device {
+    use std::{
+        io,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+        pin::Pin,
+        task::{ready, Context, Poll},
+    };
+
+    use nix::libc;
+    use tokio::io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf};
+
+    use super::VIRTIO_NET_HDR_LEN;
+
+    const IFF_TUN: libc::c_short = 0x0001;
+    const IFF_MULTI_QUEUE: libc::c_short = 0x0100;
+    const IFF_NO_PI: libc::c_short = 0x1000;
+    const IFF_VNET_HDR: libc::c_short = 0x4000;
+    const TUN_F_CSUM: libc::c_ulong = 0x01;
+    const TUN_F_TSO4: libc::c_ulong = 0x02;
+    const TUN_F_TSO6: libc::c_ulong = 0x04;
+
+    // the kernel limit of queues per tun device
+    const MAX_TUN_QUEUES: usize = 256;
+
+    fn check(ret: libc::c_int) -> io::Result<()> {
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// A tun device opened directly on /dev/net/tun, used when the device
+    /// needs IFF_VNET_HDR or IFF_MULTI_QUEUE which rust-tun does not expose.
+    /// With vnet_hdr every read and write carries a virtio_net_hdr, with
+    /// multi_queue each instance is one queue of the device.
+    pub struct OffloadTunDevice {
+        fd: AsyncFd<OwnedFd>,
+        name: String,
+    }
+
+    impl OffloadTunDevice {
+        pub fn open(name: &str, vnet_hdr: bool, multi_queue: bool) -> io::Result<Self> {
+            let fd = unsafe {
+                libc::open(
+                    c"/dev/net/tun".as_ptr(),
+                    libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+                )
+            };
+            check(fd)?;
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+            if name.len() >= ifr.ifr_name.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("tun name too long: {}", name),
+                ));
+            }
+            for (i, b) in name.bytes().enumerate() {
+                ifr.ifr_name[i] = b as libc::c_char;
+            }
+            let mut flags = IFF_TUN | IFF_NO_PI;
+            if vnet_hdr {
+                flags |= IFF_VNET_HDR;
+            }
+            if multi_queue {
+                flags |= IFF_MULTI_QUEUE;
+            }
+            ifr.ifr_ifru.ifru_flags = flags;
+
+            let tunsetiff = nix::request_code_write!(b'T', 202, std::mem::size_of::<libc::c_int>());
+            unsafe {
+                check(libc::ioctl(fd.as_raw_fd(), tunsetiff as _, &mut ifr))?;
+            }
+
+            if vnet_hdr {
+                let tunsetoffload =
+                    nix::request_code_write!(b'T', 208, std::mem::size_of::<libc::c_uint>());
+                let tunsetvnethdrsz =
+                    nix::request_code_write!(b'T', 216, std::mem::size_of::<libc::c_int>());
+                let hdr_len = VIRTIO_NET_HDR_LEN as libc::c_int;
+                unsafe {
+                    check(libc::ioctl(fd.as_raw_fd(), tunsetvnethdrsz as _, &hdr_len))?;
+                    check(libc::ioctl(
+                        fd.as_raw_fd(),
+                        tunsetoffload as _,
+                        TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6,
+                    ))?;
+                }
+            }
+
+            let name = ifr
+                .ifr_name
+                .iter()
+                .take_while(|c| **c != 0)
+                .map(|c| *c as u8 as char)
+                .collect();
+            Ok(Self {
+                fd: AsyncFd::new(fd)?,
+                name,
+            })
+        }
+
+        /// Opens `count` queues of the same device, the kernel spreads received
+        /// flows over them by the skb hash.
+        pub fn open_queues(name: &str, count: usize, vnet_hdr: bool) -> io::Result<Vec<Self>> {
+            let count = count.clamp(1, MAX_TUN_QUEUES);
+            let first = Self::open(name, vnet_hdr, true)?;
+            let name = first.name.clone();
+            let mut queues = vec![first];
+            for _ in 1..count {
+                queues.push(Self::open(&name, vnet_hdr, true)?);
+            }
+            Ok(queues)
+        }
+
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+    }
+
+    impl AsyncRead for OffloadTunDevice {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            loop {
+                let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+                let unfilled = buf.initialize_unfilled();
+                let ret = guard.try_io(|fd| {
+                    let n = unsafe {
+                        libc::read(
+                            fd.as_raw_fd(),
+                            unfilled.as_mut_ptr() as *mut libc::c_void,
+                            unfilled.len(),
+                        )
+                    };
+                    if n < 0 {
+                        Err(io::Error::last_os_error())
+                    } else {
+                        Ok(n as usize)
+                    }
+                });
+                match ret {
+                    Ok(Ok(n)) => {
+                        buf.advance(n);
+                        return Poll::Ready(Ok(()));
+                    }
+                    Ok(Err(e)) => return Poll::Ready(Err(e)),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+    }
+
+    impl AsyncWrite for OffloadTunDevice {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            loop {
+                let mut guard = ready!(self.fd.poll_write_ready(cx))?;
+                let ret = guard.try_io(|fd| {
+                    let n = unsafe {
+                        libc::write(
+                            fd.as_raw_fd(),
+                            buf.as_ptr() as *const libc::c_void,
+                            buf.len(),
+                        )
+                    };
+                    if n < 0 {
+                        Err(io::Error::last_os_error())
+                    } else {
+                        Ok(n as usize)
+                    }
+                });
+                match ret {
+                    Ok(ret) => return Poll::Ready(ret),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use device::OffloadTunDevice;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_tcp_v4(seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut ip = vec![0u8; 40];
+        ip[0] = 0x45;
+        ip[6] = 0x40; // don't fragment
+        ip[8] = 64;
+        ip[9] = IPPROTO_TCP;
+        ip[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        ip[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        ip[20..22].copy_from_slice(&1234u16.to_be_bytes());
+        ip[22..24].copy_from_slice(&80u16.to_be_bytes());
+        ip[24..28].copy_from_slice(&seq.to_be_bytes());
+        ip[28..32].copy_from_slice(&7u32.to_be_bytes());
+        ip[32] = 5 << 4;
+        ip[33] = flags;
+        ip[34..36].copy_from_slice(&65535u16.to_be_bytes());
+        ip.extend_from_slice(payload);
+        set_ip_total_len(&mut ip, false);
+        let sum = !checksum_fold(checksum_add(
+            pseudo_header_sum(&ip, ip.len() - 20),
+            &ip[20..],
+        ));
+        ip[36..38].copy_from_slice(&sum.to_be_bytes());
+        ip
+    }
+
+    fn verify_tcp_checksum(ip: &[u8]) -> bool {
+        checksum_fold(checksum_add(
+            pseudo_header_sum(ip, ip.len() - 20),
+            &ip[20..],
+        )) == 0xffff
+    }
+
+    fn read_vnet_hdr(packet: &mut ZCPacket) -> VirtioNetHdr {
+        let offset = packet.payload_offset();
+        VirtioNetHdr::decode(&packet.mut_inner()[offset - VIRTIO_NET_HDR_LEN..offset]).unwrap()
+    }
+
+    #[test]
+    fn coalesce_then_split_roundtrip() {
+        let payload: Vec<u8> = (0..3500u32).map(|i| i as u8).collect();
+        let segments: Vec<ZCPacket> = payload
+            .chunks(1000)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let flags = if i == 3 {
+                    TCP_FLAG_ACK | TCP_FLAG_PSH
+                } else {
+                    TCP_FLAG_ACK
+                };
+                ZCPacket::new_with_payload(&build_tcp_v4(100 + i as u32 * 1000, flags, chunk))
+            })
+            .collect();
+        let originals: Vec<Vec<u8>> = segments.iter().map(|p| p.payload().to_vec()).collect();
+
+        let mut coalesced = coalesce_nic_packets(segments).unwrap();
+        assert_eq!(coalesced.len(), 1);
+        let mut packet = coalesced.pop().unwrap();
+        let hdr = read_vnet_hdr(&mut packet);
+        assert_eq!(hdr.gso_type, VIRTIO_NET_HDR_GSO_TCPV4);
+        assert_eq!(hdr.gso_size, 1000);
+        assert_eq!(hdr.hdr_len, 40);
+        assert_eq!(packet.payload().len(), 40 + payload.len());
+        assert_eq!(&packet.payload()[40..], payload.as_slice());
+
+        // what the kernel would hand back for this super packet
+        let Some(NicPacket::Tso(packet, hdr)) = parse_nic_packet(packet) else {
+            panic!("expect a tso packet");
+        };
+        let split = segment_tso_packet(packet.clone(), &hdr, 1500);
+        assert_eq!(split.len(), 4);
+        for (p, orig) in split.iter().zip(originals.iter()) {
+            assert!(verify_tcp_checksum(p.payload()));
+            assert_eq!(p.payload()[20..], orig[20..]);
+        }
+
+        // a smaller limit towards the peers gives smaller segments
+        let split = segment_tso_packet(packet, &hdr, 540);
+        assert_eq!(split.len(), 7);
+        let mut data = vec![];
+        for p in split.iter() {
+            assert!(p.payload().len() <= 540);
+            assert!(verify_tcp_checksum(p.payload()));
+            data.extend_from_slice(&p.payload()[40..]);
+        }
+        assert_eq!(data, payload);
+    }
+
+    #[test]
+    fn coalesce_keeps_unrelated_packets() {
+        let packets = vec![
+            ZCPacket::new_with_payload(&build_tcp_v4(0, TCP_FLAG_ACK, &[1; 100])),
+            // not contiguous
+            ZCPacket::new_with_payload(&build_tcp_v4(1000, TCP_FLAG_ACK, &[2; 100])),
+            // pure ack
+            ZCPacket::new_with_payload(&build_tcp_v4(1100, TCP_FLAG_ACK, &[])),
+            ZCPacket::new_with_payload(&[0x60; 48]),
+        ];
+        let mut out = coalesce_nic_packets(packets).unwrap();
+        assert_eq!(out.len(), 4);
+        for p in out.iter_mut() {
+            assert_eq!(read_vnet_hdr(p), VirtioNetHdr::default());
+        }
+    }
+
+    #[test]
+    fn complete_partial_checksum() {
+        let mut ip = build_tcp_v4(5, TCP_FLAG_ACK, &[9; 33]);
+        let partial = checksum_fold(pseudo_header_sum(&ip, ip.len() - 20));
+        ip[36..38].copy_from_slice(&partial.to_be_bytes());
+        let mut packet = ZCPacket::new_with_payload(&ip);
+        write_vnet_hdr(
+            &mut packet,
+            &VirtioNetHdr {
+                flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+                csum_start: 20,
+                csum_offset: TCP_CSUM_OFFSET,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let Some(NicPacket::Single(out)) = parse_nic_packet(packet) else {
+            panic!("expect a plain packet");
+        };
+        assert!(verify_tcp_checksum(out.payload()));
+    }
+}
@@ -0,0 +1,62 @@
+use std::hash::{DefaultHasher, Hasher as _};
+
+/// Hash of the addresses, protocol and ports of an ip packet, so every packet
+/// of a flow is written through the same queue and stays in order.
+pub fn flow_hash(ip: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let (proto, l4) = match ip.first().map(|b| b >> 4) {
+        Some(4) if ip.len() >= 20 => {
+            hasher.write(&ip[12..20]);
+            let ihl = ((ip[0] & 0x0f) as usize) * 4;
+            // only the first fragment has the ports, hash fragments without them
+            let l4 = if u16::from_be_bytes([ip[6], ip[7]]) & 0x3fff == 0 {
+                ip.get(ihl..)
+            } else {
+                None
+            };
+            (ip[9], l4)
+        }
+        Some(6) if ip.len() >= 40 => {
+            hasher.write(&ip[8..40]);
+            (ip[6], ip.get(40..))
+        }
+        _ => return 0,
+    };
+    hasher.write_u8(proto);
+    // tcp, udp and sctp all start with the two ports
+    if let (6 | 17 | 132, Some(l4)) = (proto, l4) {
+        if l4.len() >= 4 {
+            hasher.write(&l4[..4]);
+        }
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn udp_v4(src_port: u16, dst_port: u16, payload_len: usize) -> Vec<u8> {
+        let mut ip = vec![0u8; 28 + payload_len];
+        ip[0] = 0x45;
+        ip[9] = 17;
+        ip[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        ip[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        ip[20..22].copy_from_slice(&src_port.to_be_bytes());
+        ip[22..24].copy_from_slice(&dst_port.to_be_bytes());
+        ip
+    }
+
+    #[test]
+    fn flow_hash_is_per_flow() {
+        assert_eq!(
+            flow_hash(&udp_v4(1000, 53, 10)),
+            flow_hash(&udp_v4(1000, 53, 500))
+        );
+        let hashes: std::collections::HashSet<_> = (0..64)
+            .map(|p| flow_hash(&udp_v4(1000 + p, 53, 10)) % 4)
+            .collect();
+        assert!(hashes.len() > 1);
+        assert_eq!(flow_hash(&[0x10; 4]), 0);
+    }
+}
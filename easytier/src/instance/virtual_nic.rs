@@ -13,6 +13,10 @@ use crate::{
         global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
         ifcfg::{IfConfiger, IfConfiguerTrait},
     },
+    instance::tun_offload::{
+        coalesce_nic_packets, parse_nic_packet, segment_tso_packet, NicPacket, VirtioNetHdr,
+        TUN_OFFLOAD_BATCH_SIZE, TUN_OFFLOAD_READ_SIZE, VIRTIO_NET_HDR_LEN,
+    },
     peers::{peer_manager::PeerManager, recv_packet_from_chan, PacketRecvChanReceiver},
     tunnel::{
         common::{reserve_buf, FramedWriter, TunnelWrapper, ZCPacketToBytes},
@@ -40,17 +44,19 @@ use zerocopy::{NativeEndian, NetworkEndian};
 use crate::common::ifcfg::RegistryManager;
 
 pin_project! {
-    pub struct TunStream {
+    pub struct TunStream<D> {
         #[pin]
-        l: BiLock<AsyncDevice>,
+        l: BiLock<D>,
         cur_buf: BytesMut,
         has_packet_info: bool,
         payload_offset: usize,
+        read_size: usize,
+        reserve_size: usize,
     }
 }
 
-impl TunStream {
-    pub fn new(l: BiLock<AsyncDevice>, has_packet_info: bool) -> Self {
+impl<D> TunStream<D> {
+    pub fn new(l: BiLock<D>, has_packet_info: bool) -> Self {
         let mut payload_offset = ZCPacketType::NIC.get_packet_offsets().payload_offset;
         if has_packet_info {
             payload_offset -= 4;
@@ -60,24 +66,45 @@ impl TunStream {
             cur_buf: BytesMut::new(),
             has_packet_info,
             payload_offset,
+            read_size: 2500,
+            reserve_size: 4 * 1024,
+        }
+    }
+
+    // the virtio_net_hdr is read right before the ip packet, like the packet info
+    pub fn new_with_vnet_hdr(l: BiLock<D>) -> Self {
+        let payload_offset =
+            ZCPacketType::NIC.get_packet_offsets().payload_offset - VIRTIO_NET_HDR_LEN;
+        Self {
+            l,
+            cur_buf: BytesMut::new(),
+            has_packet_info: false,
+            payload_offset,
+            read_size: TUN_OFFLOAD_READ_SIZE,
+            reserve_size: 4 * (payload_offset + TUN_OFFLOAD_READ_SIZE + TAIL_RESERVED_SIZE),
         }
     }
 }
 
-impl Stream for TunStream {
+impl<D: AsyncRead> Stream for TunStream<D> {
     type Item = StreamItem;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<StreamItem>> {
         let self_mut = self.project();
         let mut g = ready!(self_mut.l.poll_lock(cx));
-        reserve_buf(self_mut.cur_buf, 2500, 4 * 1024);
+        let read_size = *self_mut.read_size;
+        reserve_buf(
+            self_mut.cur_buf,
+            *self_mut.payload_offset + read_size + TAIL_RESERVED_SIZE,
+            *self_mut.reserve_size,
+        );
         if self_mut.cur_buf.is_empty() {
             unsafe {
                 self_mut.cur_buf.set_len(*self_mut.payload_offset);
             }
         }
         let buf = self_mut.cur_buf.chunk_mut().as_mut_ptr();
-        let buf = unsafe { std::slice::from_raw_parts_mut(buf, 2500) };
+        let buf = unsafe { std::slice::from_raw_parts_mut(buf, read_size) };
         let mut buf = ReadBuf::new(buf);
 
         let ret = ready!(g.as_pin_mut().poll_read(cx, &mut buf));
@@ -148,11 +175,24 @@ fn infer_proto(buf: &[u8]) -> PacketProtocol {
 
 struct TunZCPacketToBytes {
     has_packet_info: bool,
+    has_vnet_hdr: bool,
 }
 
 impl TunZCPacketToBytes {
     pub fn new(has_packet_info: bool) -> Self {
-        Self { has_packet_info }
+        Self {
+            has_packet_info,
+            has_vnet_hdr: false,
+        }
+    }
+
+    // the virtio_net_hdr is already filled by coalesce_nic_packets
+    #[cfg(target_os = "linux")]
+    pub fn new_with_vnet_hdr() -> Self {
+        Self {
+            has_packet_info: false,
+            has_vnet_hdr: true,
+        }
     }
 
     pub fn fill_packet_info(
@@ -175,7 +215,15 @@ impl ZCPacketToBytes for TunZCPacketToBytes {
         // we have peer manager header, so payload offset must larger than 4
         assert!(payload_offset >= 4);
 
-        let ret = if self.has_packet_info {
+        let ret = if self.has_vnet_hdr {
+            if payload_offset < VIRTIO_NET_HDR_LEN {
+                return Err(TunnelError::InvalidPacket(format!(
+                    "no room for vnet header, payload offset: {}",
+                    payload_offset
+                )));
+            }
+            inner.split_off(payload_offset - VIRTIO_NET_HDR_LEN)
+        } else if self.has_packet_info {
             let mut inner = inner.split_off(payload_offset - 4);
             let proto = infer_proto(&inner[4..]);
             self.fill_packet_info(&mut inner[0..4], proto)?;
@@ -191,13 +239,13 @@ impl ZCPacketToBytes for TunZCPacketToBytes {
 }
 
 pin_project! {
-    pub struct TunAsyncWrite {
+    pub struct TunAsyncWrite<D> {
         #[pin]
-        l: BiLock<AsyncDevice>,
+        l: BiLock<D>,
     }
 }
 
-impl AsyncWrite for TunAsyncWrite {
+impl<D: AsyncWrite> AsyncWrite for TunAsyncWrite<D> {
     fn poll_write(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -240,6 +288,8 @@ pub struct VirtualNic {
 
     ifname: Option<String>,
     ifcfg: Box<dyn IfConfiguerTrait + Send + Sync + 'static>,
+    // packets on the device carry a virtio_net_hdr
+    offload: bool,
 }
 
 impl Drop for VirtualNic {
@@ -265,6 +315,7 @@ impl VirtualNic {
             global_ctx,
             ifname: None,
             ifcfg: Box::new(IfConfiger {}),
+            offload: false,
         }
     }
 
@@ -470,6 +521,96 @@ impl VirtualNic {
         Ok(Box::new(ft))
     }
 
+    #[cfg(target_os = "linux")]
+    async fn create_queue_devs(
+        &mut self,
+        queues: usize,
+        vnet_hdr: bool,
+    ) -> Result<Vec<Box<dyn Tunnel>>, Error> {
+        use super::tun_offload::OffloadTunDevice;
+
+        Self::ensure_tun_device_node().await;
+        let devs = {
+            let _g = self.global_ctx.net_ns.guard();
+            let dev_name = self.global_ctx.get_flags().dev_name;
+            if queues > 1 {
+                OffloadTunDevice::open_queues(&dev_name, queues, vnet_hdr)?
+            } else {
+                vec![OffloadTunDevice::open(&dev_name, vnet_hdr, false)?]
+            }
+        };
+        let ifname = devs[0].name().to_owned();
+        self.ifcfg.wait_interface_show(ifname.as_str()).await?;
+
+        let flags = self.global_ctx.config.get_flags();
+        let mut mtu_in_config = flags.mtu;
+        if flags.enable_encryption {
+            mtu_in_config -= 20;
+        }
+        {
+            let _g = self.global_ctx.net_ns.guard();
+            self.ifcfg.set_mtu(ifname.as_str(), mtu_in_config).await?;
+        }
+
+        let tunnels = devs
+            .into_iter()
+            .map(|dev| {
+                let (a, b) = BiLock::new(dev);
+                let ft: Box<dyn Tunnel> = if vnet_hdr {
+                    Box::new(TunnelWrapper::new(
+                        TunStream::new_with_vnet_hdr(a),
+                        FramedWriter::new_with_converter(
+                            TunAsyncWrite { l: b },
+                            TunZCPacketToBytes::new_with_vnet_hdr(),
+                        ),
+                        None,
+                    ))
+                } else {
+                    Box::new(TunnelWrapper::new(
+                        TunStream::new(a, false),
+                        FramedWriter::new_with_converter(
+                            TunAsyncWrite { l: b },
+                            TunZCPacketToBytes::new(false),
+                        ),
+                        None,
+                    ))
+                };
+                ft
+            })
+            .collect();
+
+        self.ifname = Some(ifname);
+        self.offload = vnet_hdr;
+
+        Ok(tunnels)
+    }
+
+    /// Creates the tun device, one tunnel per queue when the device is opened
+    /// with multiple queues.
+    pub async fn create_devs(&mut self) -> Result<Vec<Box<dyn Tunnel>>, Error> {
+        #[cfg(target_os = "linux")]
+        {
+            let flags = self.global_ctx.get_flags();
+            let queues = flags.tun_queues.max(1) as usize;
+            if flags.enable_tun_offload || queues > 1 {
+                match self
+                    .create_queue_devs(queues, flags.enable_tun_offload)
+                    .await
+                {
+                    Ok(tunnels) => return Ok(tunnels),
+                    Err(e) => {
+                        tracing::warn!(
+                            ?e,
+                            "create tun with offload or multi queue failed, fallback to plain tun"
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(vec![self.create_dev().await?])
+    }
+
     pub async fn create_dev(&mut self) -> Result<Box<dyn Tunnel>, Error> {
         let dev = self.create_tun().await?;
         let ifname = dev.tun_name()?;
@@ -681,7 +822,33 @@ impl NicCtx {
         Ok(())
     }
 
-    async fn do_forward_nic_to_peers_ipv4(ret: ZCPacket, mgr: &PeerManager) {
+    async fn send_nic_packet_to_peers(
+        ret: ZCPacket,
+        dst: IpAddr,
+        tso: Option<VirtioNetHdr>,
+        mgr: &PeerManager,
+    ) {
+        // TODO: use zero-copy
+        let send_ret = match tso {
+            // route the super packet once, split it at the mtu towards the peers
+            Some(hdr) => {
+                mgr.send_super_msg_by_ip(ret, dst, |packet, max_ip_len| {
+                    segment_tso_packet(packet, &hdr, max_ip_len)
+                })
+                .await
+            }
+            None => mgr.send_msg_by_ip(ret, dst).await,
+        };
+        if send_ret.is_err() {
+            tracing::trace!(?send_ret, "[USER_PACKET] send_msg failed")
+        }
+    }
+
+    async fn do_forward_nic_to_peers_ipv4(
+        ret: ZCPacket,
+        tso: Option<VirtioNetHdr>,
+        mgr: &PeerManager,
+    ) {
         if let Some(ipv4) = Ipv4Packet::new(ret.payload()) {
             if ipv4.get_version() != 4 {
                 tracing::info!("[USER_PACKET] not ipv4 packet: {:?}", ipv4);
@@ -693,17 +860,17 @@ impl NicCtx {
                 "[USER_PACKET] recv new packet from tun device and forward to peers."
             );
 
-            // TODO: use zero-copy
-            let send_ret = mgr.send_msg_by_ip(ret, IpAddr::V4(dst_ipv4)).await;
-            if send_ret.is_err() {
-                tracing::trace!(?send_ret, "[USER_PACKET] send_msg failed")
-            }
+            Self::send_nic_packet_to_peers(ret, IpAddr::V4(dst_ipv4), tso, mgr).await;
         } else {
             tracing::warn!(?ret, "[USER_PACKET] not ipv4 packet");
         }
     }
 
-    async fn do_forward_nic_to_peers_ipv6(ret: ZCPacket, mgr: &PeerManager) {
+    async fn do_forward_nic_to_peers_ipv6(
+        ret: ZCPacket,
+        tso: Option<VirtioNetHdr>,
+        mgr: &PeerManager,
+    ) {
         if let Some(ipv6) = Ipv6Packet::new(ret.payload()) {
             if ipv6.get_version() != 6 {
                 tracing::info!("[USER_PACKET] not ipv6 packet: {:?}", ipv6);
@@ -723,25 +890,21 @@ impl NicCtx {
                 return;
             }
 
-            // TODO: use zero-copy
-            let send_ret = mgr.send_msg_by_ip(ret, IpAddr::V6(dst_ipv6)).await;
-            if send_ret.is_err() {
-                tracing::trace!(?send_ret, "[USER_PACKET] send_msg failed")
-            }
+            Self::send_nic_packet_to_peers(ret, IpAddr::V6(dst_ipv6), tso, mgr).await;
         } else {
             tracing::warn!(?ret, "[USER_PACKET] not ipv6 packet");
         }
     }
 
-    async fn do_forward_nic_to_peers(ret: ZCPacket, mgr: &PeerManager) {
+    async fn do_forward_nic_to_peers(ret: ZCPacket, tso: Option<VirtioNetHdr>, mgr: &PeerManager) {
         let payload = ret.payload();
         if payload.is_empty() {
             return;
         }
 
         match payload[0] >> 4 {
-            4 => Self::do_forward_nic_to_peers_ipv4(ret, mgr).await,
-            6 => Self::do_forward_nic_to_peers_ipv6(ret, mgr).await,
+            4 => Self::do_forward_nic_to_peers_ipv4(ret, tso, mgr).await,
+            6 => Self::do_forward_nic_to_peers_ipv6(ret, tso, mgr).await,
             _ => {
                 tracing::warn!(?ret, "[USER_PACKET] unknown IP version");
             }
@@ -751,6 +914,7 @@ impl NicCtx {
     fn do_forward_nic_to_peers_task(
         &mut self,
         mut stream: Pin<Box<dyn ZCPacketStream>>,
+        offload: bool,
     ) -> Result<(), Error> {
         // read from nic and write to corresponding tunnel
         let Some(mgr) = self.peer_mgr.upgrade() else {
@@ -763,7 +927,20 @@ impl NicCtx {
                     tracing::error!("read from nic failed: {:?}", ret);
                     break;
                 }
-                Self::do_forward_nic_to_peers(ret.unwrap(), mgr.as_ref()).await;
+                if !offload {
+                    Self::do_forward_nic_to_peers(ret.unwrap(), None, mgr.as_ref()).await;
+                    continue;
+                }
+                // one read may be a tso super packet, it is segmented after routing
+                match parse_nic_packet(ret.unwrap()) {
+                    Some(NicPacket::Single(packet)) => {
+                        Self::do_forward_nic_to_peers(packet, None, mgr.as_ref()).await;
+                    }
+                    Some(NicPacket::Tso(packet, hdr)) => {
+                        Self::do_forward_nic_to_peers(packet, Some(hdr), mgr.as_ref()).await;
+                    }
+                    None => {}
+                }
             }
             close_notifier.notify_one();
             tracing::error!("nic closed when recving from it");
@@ -772,24 +949,111 @@ impl NicCtx {
         Ok(())
     }
 
-    fn do_forward_peers_to_nic(&mut self, mut sink: Pin<Box<dyn ZCPacketSink>>) {
+    async fn do_forward_peers_to_offload_nic(
+        first: ZCPacket,
+        channel: &mut PacketRecvChanReceiver,
+        sink: &mut Pin<Box<dyn ZCPacketSink>>,
+    ) -> Result<(), TunnelError> {
+        // drain what is already queued so consecutive tcp segments can be merged
+        let mut packets = vec![first];
+        while packets.len() < TUN_OFFLOAD_BATCH_SIZE {
+            let Ok(packet) = channel.try_recv() else {
+                break;
+            };
+            packets.push(packet);
+        }
+        tracing::trace!(
+            count = packets.len(),
+            "[USER_PACKET] forward packets from peers to offload nic"
+        );
+        for packet in coalesce_nic_packets(packets)? {
+            sink.feed(packet).await?;
+        }
+        sink.flush().await
+    }
+
+    async fn do_forward_peers_to_nic_loop(
+        channel: &mut PacketRecvChanReceiver,
+        mut sink: Pin<Box<dyn ZCPacketSink>>,
+        offload: bool,
+    ) {
+        while let Ok(packet) = recv_packet_from_chan(channel).await {
+            let ret = if offload {
+                Self::do_forward_peers_to_offload_nic(packet, channel, &mut sink).await
+            } else {
+                tracing::trace!(
+                    "[USER_PACKET] forward packet from peers to nic. packet: {:?}",
+                    packet
+                );
+                sink.send(packet).await
+            };
+            if ret.is_err() {
+                tracing::error!(?ret, "do_forward_tunnel_to_nic sink error");
+            }
+        }
+    }
+
+    fn do_forward_peers_to_nic(&mut self, sink: Pin<Box<dyn ZCPacketSink>>, offload: bool) {
         let channel = self.peer_packet_receiver.clone();
         let close_notifier = self.close_notifier.clone();
+        self.tasks.spawn(async move {
+            // unlock until coroutine finished
+            let mut channel = channel.lock().await;
+            Self::do_forward_peers_to_nic_loop(&mut channel, sink, offload).await;
+            close_notifier.notify_one();
+            tracing::error!("nic closed when sending to it");
+        });
+    }
+
+    // packets of one flow always go to the same queue so they are not reordered.
+    // a full queue drops the packet instead of stalling the flows of other queues.
+    #[cfg(target_os = "linux")]
+    fn do_forward_peers_to_nic_queues(
+        &mut self,
+        sinks: Vec<Pin<Box<dyn ZCPacketSink>>>,
+        offload: bool,
+    ) {
+        use super::tun_queue::flow_hash;
+        use crate::{
+            common::stats_manager::{LabelSet, MetricName},
+            peers::create_packet_recv_chan,
+        };
+        use tokio::sync::mpsc::error::TrySendError;
+
+        let mut queue_senders = Vec::with_capacity(sinks.len());
+        for sink in sinks {
+            let (sender, mut receiver) = create_packet_recv_chan();
+            queue_senders.push(sender);
+            let close_notifier = self.close_notifier.clone();
+            self.tasks.spawn(async move {
+                Self::do_forward_peers_to_nic_loop(&mut receiver, sink, offload).await;
+                close_notifier.notify_one();
+                tracing::error!("nic queue closed when sending to it");
+            });
+        }
+
+        let channel = self.peer_packet_receiver.clone();
+        let close_notifier = self.close_notifier.clone();
+        let dropped = self
+            .global_ctx
+            .stats_manager()
+            .get_counter(MetricName::TunQueueDropped, LabelSet::new());
         self.tasks.spawn(async move {
             // unlock until coroutine finished
             let mut channel = channel.lock().await;
             while let Ok(packet) = recv_packet_from_chan(&mut channel).await {
-                tracing::trace!(
-                    "[USER_PACKET] forward packet from peers to nic. packet: {:?}",
-                    packet
-                );
-                let ret = sink.send(packet).await;
-                if ret.is_err() {
-                    tracing::error!(?ret, "do_forward_tunnel_to_nic sink error");
+                let idx = flow_hash(packet.payload()) as usize % queue_senders.len();
+                match queue_senders[idx].try_send(packet) {
+                    Ok(_) => {}
+                    Err(TrySendError::Full(_)) => {
+                        dropped.inc();
+                        tracing::trace!(idx, "nic queue is full, drop packet");
+                    }
+                    Err(TrySendError::Closed(_)) => break,
                 }
             }
             close_notifier.notify_one();
-            tracing::error!("nic closed when sending to it");
+            tracing::error!("nic closed when dispatching to queues");
         });
     }
 
@@ -803,6 +1067,9 @@ impl NicCtx {
         let ifcfg = nic.get_ifcfg();
         let ifname = nic.ifname().to_owned();
 
+        #[cfg(target_os = "linux")]
+        let mut kernel_route_exporter = super::kernel_route::KernelRouteExporter::new(&global_ctx);
+
         self.tasks.spawn(async move {
             let mut cur_proxy_cidrs = BTreeSet::new();
             loop {
@@ -873,6 +1140,12 @@ impl NicCtx {
                     }
                 }
 
+                #[cfg(target_os = "linux")]
+                if let Some(exporter) = kernel_route_exporter.as_mut() {
+                    let _g = net_ns.guard();
+                    exporter.sync(ifname.as_str(), &proxy_cidrs);
+                }
+
                 cur_proxy_cidrs = proxy_cidrs;
                 tokio::time::sleep(std::time::Duration::from_secs(1)).await;
             }
@@ -886,9 +1159,9 @@ impl NicCtx {
         ipv4_addr: Option<cidr::Ipv4Inet>,
         ipv6_addr: Option<cidr::Ipv6Inet>,
     ) -> Result<(), Error> {
-        let tunnel = {
+        let (tunnels, offload) = {
             let mut nic = self.nic.lock().await;
-            match nic.create_dev().await {
+            let tunnels = match nic.create_devs().await {
                 Ok(ret) => {
                     #[cfg(target_os = "windows")]
                     {
@@ -914,13 +1187,23 @@ impl NicCtx {
                         .issue_event(GlobalCtxEvent::TunDeviceError(err.to_string()));
                     return Err(err);
                 }
-            }
+            };
+            (tunnels, nic.offload)
         };
 
-        let (stream, sink) = tunnel.split();
-
-        self.do_forward_nic_to_peers_task(stream)?;
-        self.do_forward_peers_to_nic(sink);
+        // each queue is read by its own task, the kernel keeps flows on one queue
+        let mut sinks = Vec::with_capacity(tunnels.len());
+        for tunnel in tunnels {
+            let (stream, sink) = tunnel.split();
+            self.do_forward_nic_to_peers_task(stream, offload)?;
+            sinks.push(sink);
+        }
+        if sinks.len() == 1 {
+            self.do_forward_peers_to_nic(sinks.pop().unwrap(), offload);
+        } else {
+            #[cfg(target_os = "linux")]
+            self.do_forward_peers_to_nic_queues(sinks, offload);
+        }
 
         // Assign IPv4 address if provided
         if let Some(ipv4_addr) = ipv4_addr {
@@ -957,8 +1240,8 @@ impl NicCtx {
 
         let (stream, sink) = tunnel.split();
 
-        self.do_forward_nic_to_peers_task(stream)?;
-        self.do_forward_peers_to_nic(sink);
+        self.do_forward_nic_to_peers_task(stream, false)?;
+        self.do_forward_peers_to_nic(sink, false);
 
         Ok(())
     }
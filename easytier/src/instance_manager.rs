@@ -312,6 +312,53 @@ fn handle_event(
                             ),
                         );
                     }
+
+                    GlobalCtxEvent::RouteNextHopChanged(dst, old, new) => {
+                        print_event(
+                            instance_id,
+                            format!(
+                                "route next hop changed. dst: {}, old: {:?}, new: {:?}",
+                                dst, old, new
+                            ),
+                        );
+                    }
+
+                    GlobalCtxEvent::RouteCostChanged(dst, old, new) => {
+                        print_event(
+                            instance_id,
+                            format!(
+                                "route cost changed. dst: {}, old: {}, new: {}",
+                                dst, old, new
+                            ),
+                        );
+                    }
+
+                    GlobalCtxEvent::RouteProxyCidrsChanged(dst, old, new) => {
+                        print_event(
+                            instance_id,
+                            format!(
+                                "route proxy cidrs changed. dst: {}, old: {:?}, new: {:?}",
+                                dst, old, new
+                            ),
+                        );
+                    }
+
+                    GlobalCtxEvent::DnsHostnameConflict(hostname, inst_ids) => {
+                        print_event(
+                            instance_id,
+                            format!(
+                                "magic dns hostname conflict. hostname: {}, instances: {:?}",
+                                hostname, inst_ids
+                            ),
+                        );
+                    }
+
+                    GlobalCtxEvent::DnsSystemConfigured(method) => {
+                        print_event(
+                            instance_id,
+                            format!("system dns configured. method: {}", method),
+                        );
+                    }
                 }
             } else {
                 events = events.resubscribe();
@@ -37,6 +37,7 @@ use crate::{
         peer_rpc::HandshakeRequest,
     },
     tunnel::{
+        fec::{FecCtrl, FecTunnel, FEC_FEATURE},
         filter::{StatsRecorderTunnelFilter, TunnelFilter, TunnelWithFilter},
         mpsc::{MpscTunnel, MpscTunnelSender},
         packet_def::{PacketType, ZCPacket},
@@ -45,7 +46,10 @@ use crate::{
     },
 };
 
-use super::{peer_conn_ping::PeerConnPinger, PacketRecvChan};
+use super::{
+    peer_conn_ping::{PeerConnLivenessProbe, PeerConnPinger},
+    PacketRecvChan,
+};
 
 pub type PeerConnId = uuid::Uuid;
 
@@ -120,6 +124,8 @@ pub struct PeerConn {
     latency_stats: Arc<WindowLatency>,
     throughput: Arc<Throughput>,
     loss_rate_stats: Arc<AtomicU32>,
+    // only for udp tunnels with enable_fec set
+    fec_ctrl: Option<Arc<FecCtrl>>,
 
     counters: ArcSwapOption<PeerConnCounter>,
 }
@@ -139,6 +145,20 @@ impl PeerConn {
         let tunnel_info = tunnel.info();
         let (ctrl_sender, _ctrl_receiver) = broadcast::channel(8);
 
+        let loss_rate_stats = Arc::new(AtomicU32::new(0));
+        // the peer only announces fec if it wraps the tunnel too, see local_features.
+        let (tunnel, fec_ctrl) = if global_ctx.get_flags().enable_fec
+            && tunnel_info
+                .as_ref()
+                .is_some_and(|info| info.tunnel_type == "udp")
+        {
+            let fec_ctrl = Arc::new(FecCtrl::new(loss_rate_stats.clone()));
+            let tunnel: Box<dyn Tunnel> = Box::new(FecTunnel::new(tunnel, fec_ctrl.clone()));
+            (tunnel, Some(fec_ctrl))
+        } else {
+            (tunnel, None)
+        };
+
         let peer_conn_tunnel_filter = StatsRecorderTunnelFilter::new();
         let throughput = peer_conn_tunnel_filter.filter_output();
         let peer_conn_tunnel = TunnelWithFilter::new(tunnel, peer_conn_tunnel_filter);
@@ -174,7 +194,8 @@ impl PeerConn {
 
             latency_stats: Arc::new(WindowLatency::new(15)),
             throughput,
-            loss_rate_stats: Arc::new(AtomicU32::new(0)),
+            loss_rate_stats,
+            fec_ctrl,
 
             counters: ArcSwapOption::new(None),
         }
@@ -260,13 +281,36 @@ impl PeerConn {
         .await?
     }
 
+    fn local_features(&self) -> Vec<String> {
+        let mut features = Vec::new();
+        if self.fec_ctrl.is_some() && self.global_ctx.get_flags().enable_fec {
+            features.push(FEC_FEATURE.to_owned());
+        }
+        features
+    }
+
+    // fec is used only if both sides enable it.
+    fn negotiate_fec(&self) {
+        let Some(fec_ctrl) = &self.fec_ctrl else {
+            return;
+        };
+        let remote_support = self
+            .info
+            .as_ref()
+            .is_some_and(|info| info.features.iter().any(|f| f == FEC_FEATURE));
+        if remote_support && self.global_ctx.get_flags().enable_fec {
+            tracing::info!(conn_id = ?self.conn_id, "fec enabled for peer conn");
+            fec_ctrl.enable();
+        }
+    }
+
     async fn send_handshake(&mut self, send_secret_digest: bool) -> Result<(), Error> {
         let network = self.global_ctx.get_network_identity();
         let mut req = HandshakeRequest {
             magic: MAGIC,
             my_peer_id: self.my_peer_id,
             version: VERSION,
-            features: Vec::new(),
+            features: self.local_features(),
             network_name: network.network_name.clone(),
             ..Default::default()
         };
@@ -315,6 +359,7 @@ impl PeerConn {
         tracing::info!("handshake request: {:?}", rsp);
         self.info = Some(rsp);
         self.is_client = Some(false);
+        self.negotiate_fec();
 
         let send_digest = self.get_network_identity() == self.global_ctx.get_network_identity();
         self.send_handshake(send_digest).await?;
@@ -332,6 +377,7 @@ impl PeerConn {
         tracing::info!("handshake request: {:?}", rsp);
         self.info = Some(rsp);
         self.is_client = Some(false);
+        self.negotiate_fec();
 
         let send_digest = self.get_network_identity() == self.global_ctx.get_network_identity();
         self.send_handshake(send_digest).await?;
@@ -353,6 +399,7 @@ impl PeerConn {
         tracing::info!("handshake response: {:?}", rsp);
         self.info = Some(rsp);
         self.is_client = Some(true);
+        self.negotiate_fec();
 
         if self.get_peer_id() == self.my_peer_id {
             Err(Error::WaitRespError(
@@ -421,6 +468,10 @@ impl PeerConn {
                             tracing::error!(?e, "peer conn send req error");
                         }
                     } else if peer_mgr_hdr.packet_type == PacketType::Pong as u8 {
+                        // liveness probe only cares about ingress, its pong is not a ctrl resp
+                        if PeerConnLivenessProbe::is_probe_packet(zc_packet.payload()) {
+                            continue;
+                        }
                         if let Err(e) = ctrl_sender.send(zc_packet) {
                             tracing::error!(?e, "peer conn send ctrl resp error");
                         }
@@ -464,6 +515,27 @@ impl PeerConn {
 
             Ok(())
         });
+
+        let flags = self.global_ctx.get_flags();
+        if flags.link_probe_interval_ms == 0 {
+            return;
+        }
+
+        let probe = PeerConnLivenessProbe::new(
+            self.my_peer_id,
+            self.get_peer_id(),
+            self.sink.clone(),
+            self.throughput.clone(),
+            Duration::from_millis(flags.link_probe_interval_ms as u64),
+            flags.link_probe_multiplier,
+        );
+        let close_event_notifier = self.close_event_notifier.clone();
+        self.tasks.spawn(async move {
+            probe.run().await;
+            tracing::warn!(?probe, "liveness probe task exit");
+            close_event_notifier.notify_close();
+            Ok(())
+        });
     }
 
     pub async fn send_msg(&self, msg: ZCPacket) -> Result<(), Error> {
@@ -553,7 +625,7 @@ mod tests {
     use crate::common::new_peer_id;
     use crate::common::scoped_task::ScopedTask;
     use crate::peers::create_packet_recv_chan;
-    use crate::tunnel::filter::tests::DropSendTunnelFilter;
+    use crate::tunnel::filter::tests::{DropSendTunnelFilter, LinkCutTunnelFilter};
     use crate::tunnel::filter::PacketRecorderTunnelFilter;
     use crate::tunnel::ring::create_ring_tunnel_pair;
 
@@ -681,6 +753,51 @@ mod tests {
         peer_conn_pingpong_test_common(3, 14, true, true).await;
     }
 
+    async fn peer_conn_liveness_probe_test_common(cut_link: bool) -> bool {
+        let (c, s) = create_ring_tunnel_pair();
+        let s_filter = Arc::new(LinkCutTunnelFilter::default());
+        let s = TunnelWithFilter::new(s, s_filter.clone());
+
+        let c_ctx = get_mock_global_ctx();
+        let mut flags = c_ctx.get_flags();
+        flags.link_probe_interval_ms = 100;
+        flags.link_probe_multiplier = 3;
+        c_ctx.set_flags(flags);
+
+        let mut c_peer = PeerConn::new(new_peer_id(), c_ctx, Box::new(c));
+        let mut s_peer = PeerConn::new(new_peer_id(), get_mock_global_ctx(), Box::new(s));
+
+        let (c_ret, s_ret) = tokio::join!(
+            c_peer.do_handshake_as_client(),
+            s_peer.do_handshake_as_server()
+        );
+        c_ret.unwrap();
+        s_ret.unwrap();
+
+        s_peer.start_recv_loop(create_packet_recv_chan().0).await;
+        c_peer.start_recv_loop(create_packet_recv_chan().0).await;
+        let close_notifier = c_peer.get_close_notifier();
+        c_peer.start_pingpong();
+
+        if cut_link {
+            s_filter.cut();
+        }
+
+        // far below the pingpong timeout, only the probe can close the conn in time.
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        close_notifier.is_closed()
+    }
+
+    #[tokio::test]
+    async fn peer_conn_liveness_probe_alive() {
+        assert!(!peer_conn_liveness_probe_test_common(false).await);
+    }
+
+    #[tokio::test]
+    async fn peer_conn_liveness_probe_dead_link() {
+        assert!(peer_conn_liveness_probe_test_common(true).await);
+    }
+
     #[tokio::test]
     async fn close_tunnel_during_handshake() {
         let (c, s) = create_ring_tunnel_pair();
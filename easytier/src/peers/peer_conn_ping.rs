@@ -10,7 +10,7 @@ use rand::{thread_rng, Rng};
 use tokio::{
     sync::broadcast,
     task::JoinSet,
-    time::{timeout, Interval},
+    time::{timeout, Interval, MissedTickBehavior},
 };
 use tracing::Instrument;
 
@@ -24,6 +24,10 @@ use crate::{
     },
 };
 
+// liveness probes reuse the ping packet, the highest bit of seq marks a probe so
+// its pong never matches a pingpong round.
+const LINK_PROBE_SEQ_FLAG: u32 = 0x8000_0000;
+
 struct PingIntervalController {
     throughput: Arc<Throughput>,
     loss_counter: Arc<AtomicU32>,
@@ -347,3 +351,97 @@ impl PeerConnPinger {
         ping_res_receiver.close();
     }
 }
+
+// a bfd-like liveness detector. a probe is sent every interval, and the conn is
+// considered dead if nothing is received in `multiplier` consecutive intervals.
+pub struct PeerConnLivenessProbe {
+    my_peer_id: PeerId,
+    peer_id: PeerId,
+    sink: MpscTunnelSender,
+    throughput_stats: Arc<Throughput>,
+    interval: Duration,
+    multiplier: u32,
+}
+
+impl std::fmt::Debug for PeerConnLivenessProbe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerConnLivenessProbe")
+            .field("my_peer_id", &self.my_peer_id)
+            .field("peer_id", &self.peer_id)
+            .field("interval", &self.interval)
+            .field("multiplier", &self.multiplier)
+            .finish()
+    }
+}
+
+impl PeerConnLivenessProbe {
+    pub fn new(
+        my_peer_id: PeerId,
+        peer_id: PeerId,
+        sink: MpscTunnelSender,
+        throughput_stats: Arc<Throughput>,
+        interval: Duration,
+        multiplier: u32,
+    ) -> Self {
+        Self {
+            my_peer_id,
+            peer_id,
+            sink,
+            throughput_stats,
+            interval,
+            multiplier: std::cmp::max(multiplier, 1),
+        }
+    }
+
+    pub fn is_probe_packet(payload: &[u8]) -> bool {
+        let Some(Ok(seq_buf)) = payload.get(0..4).map(<[u8; 4]>::try_from) else {
+            return false;
+        };
+        u32::from_le_bytes(seq_buf) & LINK_PROBE_SEQ_FLAG != 0
+    }
+
+    // return when the conn is considered dead.
+    pub async fn run(&self) {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut seq: u32 = 0;
+        let mut missed = 0;
+        let mut last_rx_packets = self.throughput_stats.rx_packets();
+
+        loop {
+            interval.tick().await;
+
+            // any ingress packet proves the link is alive, not only the probe response.
+            let cur_rx_packets = self.throughput_stats.rx_packets();
+            if cur_rx_packets != last_rx_packets {
+                missed = 0;
+                last_rx_packets = cur_rx_packets;
+            } else if seq > 0 {
+                missed += 1;
+            }
+
+            if missed >= self.multiplier {
+                tracing::warn!(
+                    ?self,
+                    ?missed,
+                    "no packet received in liveness detect time, the link is dead"
+                );
+                return;
+            }
+
+            let probe = PeerConnPinger::new_ping_packet(
+                self.my_peer_id,
+                self.peer_id,
+                LINK_PROBE_SEQ_FLAG | seq,
+            );
+            // do not block on a congested sink, a missing response is counted anyway.
+            if let Err(TunnelError::Shutdown) = self.sink.try_send(probe) {
+                tracing::debug!(?self, "liveness probe sink is closed");
+                return;
+            }
+
+            seq = seq.wrapping_add(1) & !LINK_PROBE_SEQ_FLAG;
+        }
+    }
+}
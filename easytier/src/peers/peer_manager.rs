@@ -6,10 +6,12 @@ use std::{
 };
 
 use anyhow::Context;
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
 
 use dashmap::DashMap;
 
+use pnet::packet::{ip::IpNextHeaderProtocols, ipv4::Ipv4Packet, ipv6::Ipv6Packet};
 use tokio::{
     sync::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
@@ -21,6 +23,7 @@ use tokio::{
 use crate::{
     common::{
         compressor::{Compressor as _, DefaultCompressor},
+        config::ConfigLoader,
         constants::EASYTIER_VERSION,
         error::Error,
         global_ctx::{ArcGlobalCtx, NetworkIdentity},
@@ -64,6 +67,50 @@ use super::{
     BoxNicPacketFilter, BoxPeerPacketFilter, PacketRecvChan, PacketRecvChanReceiver,
 };
 
+/// (nameserver, via) pairs of the dns forward rules that set `via`.
+fn collect_dns_forward_vias(config: &dyn ConfigLoader) -> Vec<(SocketAddr, IpAddr)> {
+    config
+        .get_dns_forwards()
+        .into_iter()
+        .filter_map(|f| Some((f.nameservers, f.via?)))
+        .flat_map(|(nameservers, via)| nameservers.into_iter().map(move |ns| (ns, via)))
+        .collect()
+}
+
+/// destination address of an udp or tcp packet, or None for other packets.
+fn get_udp_or_tcp_dst(ip_packet: &[u8]) -> Option<SocketAddr> {
+    let (dst_ip, next_header, transport) = match ip_packet.first()? >> 4 {
+        4 => {
+            let ipv4 = Ipv4Packet::new(ip_packet)?;
+            // only the first fragment carries the transport header
+            if ipv4.get_fragment_offset() != 0 {
+                return None;
+            }
+            let hdr_len = ipv4.get_header_length() as usize * 4;
+            (
+                IpAddr::V4(ipv4.get_destination()),
+                ipv4.get_next_level_protocol(),
+                ip_packet.get(hdr_len..)?,
+            )
+        }
+        6 => {
+            let ipv6 = Ipv6Packet::new(ip_packet)?;
+            (
+                IpAddr::V6(ipv6.get_destination()),
+                ipv6.get_next_header(),
+                ip_packet.get(40..)?,
+            )
+        }
+        _ => return None,
+    };
+    if next_header != IpNextHeaderProtocols::Udp && next_header != IpNextHeaderProtocols::Tcp {
+        return None;
+    }
+    // the destination port is at the same offset in udp and tcp headers
+    let port = u16::from_be_bytes(transport.get(2..4)?.try_into().ok()?);
+    Some(SocketAddr::new(dst_ip, port))
+}
+
 struct RpcTransport {
     my_peer_id: PeerId,
     peers: Weak<PeerMap>,
@@ -153,6 +200,7 @@ pub struct PeerManager {
     data_compress_algo: CompressorAlgo,
 
     exit_nodes: Vec<IpAddr>,
+    dns_forward_vias: Arc<ArcSwap<Vec<(SocketAddr, IpAddr)>>>,
 
     reserved_my_peer_id_map: DashMap<String, PeerId>,
 
@@ -253,6 +301,9 @@ impl PeerManager {
             .expect("invalid data compress algo, maybe some features not enabled");
 
         let exit_nodes = global_ctx.config.get_exit_nodes();
+        let dns_forward_vias = Arc::new(ArcSwap::from_pointee(collect_dns_forward_vias(
+            global_ctx.config.as_ref(),
+        )));
 
         let stats_manager = global_ctx.stats_manager();
         let self_tx_counters = SelfTxCounters {
@@ -305,6 +356,7 @@ impl PeerManager {
             data_compress_algo,
 
             exit_nodes,
+            dns_forward_vias,
 
             reserved_my_peer_id_map: DashMap::new(),
 
@@ -319,6 +371,11 @@ impl PeerManager {
             .store(allow_loopback_tunnel, std::sync::atomic::Ordering::Relaxed);
     }
 
+    pub fn allow_loopback_tunnel(&self) -> bool {
+        self.allow_loopback_tunnel
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
     fn build_foreign_network_manager_accessor(
         peer_map: &Arc<PeerMap>,
     ) -> Box<dyn GlobalForeignNetworkAccessor> {
@@ -733,6 +790,8 @@ impl PeerManager {
 
                     compress_rx_bytes_after.add(ret.buf_len() as u64);
 
+                    // relayed packets of other areas go through the forward chain, like packets
+                    // of proxied subnets.
                     if !acl_filter.process_packet_with_acl(
                         &ret,
                         true,
@@ -743,6 +802,32 @@ impl PeerManager {
                         continue;
                     }
 
+                    // peers of other areas only know our area by prefixes, so they send the data
+                    // packets to the border node, and we relay them to the real dst peer.
+                    if let Some(relay_peer_id) =
+                        Self::get_area_relay_peer_id(&global_ctx, &route, &ret).await
+                    {
+                        let hdr = ret.mut_peer_manager_header().unwrap();
+                        hdr.to_peer_id.set(relay_peer_id);
+                        hdr.forward_counter += 1;
+                        let _ = Self::try_compress_and_encrypt(compress_algo, &encryptor, &mut ret)
+                            .await;
+                        forward_tx_bytes.add(ret.buf_len() as u64);
+                        forward_tx_packets.inc();
+                        let ret =
+                            Self::send_msg_internal(&peers, &foreign_client, ret, relay_peer_id)
+                                .await;
+                        if ret.is_err() {
+                            tracing::error!(
+                                ?ret,
+                                ?relay_peer_id,
+                                ?from_peer_id,
+                                "relay packet error"
+                            );
+                        }
+                        continue;
+                    }
+
                     let mut processed = false;
                     let mut zc_packet = Some(ret);
                     for (idx, pipeline) in pipe_line.read().await.iter().rev().enumerate() {
@@ -764,6 +849,39 @@ impl PeerManager {
         });
     }
 
+    async fn get_area_relay_peer_id(
+        global_ctx: &ArcGlobalCtx,
+        route: &(dyn Route + Send + Sync + 'static),
+        packet: &ZCPacket,
+    ) -> Option<PeerId> {
+        let hdr = packet.peer_manager_header()?;
+        if hdr.packet_type != PacketType::Data as u8 {
+            return None;
+        }
+        let payload = packet.payload();
+        // most packets are addressed to us, do not bother the route for them.
+        let dst_ip = match payload.first()? >> 4 {
+            4 => {
+                let dst = Ipv4Packet::new(payload)?.get_destination();
+                if global_ctx.get_ipv4().map(|x| x.address()) == Some(dst) {
+                    return None;
+                }
+                IpAddr::V4(dst)
+            }
+            6 => {
+                let dst = Ipv6Packet::new(payload)?.get_destination();
+                if global_ctx.get_ipv6().map(|x| x.address()) == Some(dst) {
+                    return None;
+                }
+                IpAddr::V6(dst)
+            }
+            _ => return None,
+        };
+        route
+            .get_area_relay_peer_id(hdr.from_peer_id.get(), &dst_ip)
+            .await
+    }
+
     pub async fn add_packet_process_pipeline(&self, pipeline: BoxPeerPacketFilter) {
         // newest pipeline will be executed first
         self.peer_packet_process_pipeline
@@ -924,6 +1042,10 @@ impl PeerManager {
         self.get_route().list_routes().await
     }
 
+    pub async fn list_route_history(&self) -> Vec<cli::RouteChangeEvent> {
+        self.get_route().list_route_history().await
+    }
+
     pub async fn get_route_peer_info_last_update_time(&self) -> Instant {
         self.get_route().get_peer_info_last_update_time().await
     }
@@ -1042,6 +1164,26 @@ impl PeerManager {
         }
     }
 
+    /// dns queries to the nameserver of a dns forward rule with a via peer are
+    /// sent to that peer, which proxies the nameserver's subnet. only udp and tcp
+    /// packets to the nameserver's port match, other traffic to the same host
+    /// follows the normal route. falls back to the normal route if the via peer
+    /// is not reachable.
+    async fn get_dns_forward_via_peer(&self, msg: &ZCPacket) -> Option<PeerId> {
+        let via = {
+            let vias = self.dns_forward_vias.load();
+            if vias.is_empty() {
+                return None;
+            }
+            let dst = get_udp_or_tcp_dst(msg.payload())?;
+            vias.iter().find(|(ns, _)| *ns == dst)?.1
+        };
+        match via {
+            IpAddr::V4(via) => self.peers.get_peer_id_by_ipv4(&via).await,
+            IpAddr::V6(via) => self.peers.get_peer_id_by_ipv6(&via).await,
+        }
+    }
+
     pub async fn get_msg_dst_peer(&self, ipv4_addr: &Ipv4Addr) -> (Vec<PeerId>, bool) {
         let mut is_exit_node = false;
         let mut dst_peers = vec![];
@@ -1111,6 +1253,16 @@ impl PeerManager {
         (dst_peers, is_exit_node)
     }
 
+    async fn get_msg_dst_peer_by_ip(&self, msg: &ZCPacket, ip_addr: IpAddr) -> (Vec<PeerId>, bool) {
+        if let Some(peer_id) = self.get_dns_forward_via_peer(msg).await {
+            return (vec![peer_id], false);
+        }
+        match ip_addr {
+            IpAddr::V4(ipv4_addr) => self.get_msg_dst_peer(&ipv4_addr).await,
+            IpAddr::V6(ipv6_addr) => self.get_msg_dst_peer_ipv6(&ipv6_addr).await,
+        }
+    }
+
     pub async fn try_compress_and_encrypt(
         compress_algo: CompressorAlgo,
         encryptor: &Arc<dyn Encryptor + 'static>,
@@ -1149,16 +1301,80 @@ impl PeerManager {
             .await;
         }
 
-        let (dst_peers, is_exit_node) = match ip_addr {
-            IpAddr::V4(ipv4_addr) => self.get_msg_dst_peer(&ipv4_addr).await,
-            IpAddr::V6(ipv6_addr) => self.get_msg_dst_peer_ipv6(&ipv6_addr).await,
-        };
+        let (dst_peers, is_exit_node) = self.get_msg_dst_peer_by_ip(&msg, ip_addr).await;
+
+        if dst_peers.is_empty() {
+            tracing::info!("no peer id for ip: {}", ip_addr);
+            return Ok(());
+        }
+
+        self.send_msg_to_dst_peers(msg, &dst_peers, is_exit_node)
+            .await
+    }
+
+    /// sends a tcp super packet read from an offload nic. the packet is routed once as
+    /// a whole, and `segment` splits it into packets of at most the given ip length
+    /// right before they are encrypted.
+    pub async fn send_super_msg_by_ip<F>(
+        &self,
+        mut msg: ZCPacket,
+        ip_addr: IpAddr,
+        segment: F,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce(ZCPacket, usize) -> Vec<ZCPacket> + Send,
+    {
+        let flags = self.global_ctx.get_flags();
+        let mut max_ip_len = flags.mtu as usize;
+        if flags.enable_encryption {
+            max_ip_len -= 20;
+        }
+
+        // proxies in the nic pipeline only handle mtu sized packets
+        if !self.nic_packet_process_pipeline.read().await.is_empty() {
+            let mut errs = vec![];
+            for packet in segment(msg, max_ip_len) {
+                if let Err(e) = self.send_msg_by_ip(packet, ip_addr).await {
+                    errs.push(e);
+                }
+            }
+            return match errs.pop() {
+                Some(e) => Err(e),
+                None => Ok(()),
+            };
+        }
 
+        msg.fill_peer_manager_hdr(
+            self.my_peer_id,
+            0,
+            tunnel::packet_def::PacketType::Data as u8,
+        );
+        self.run_nic_packet_process_pipeline(&mut msg).await;
+
+        let (dst_peers, is_exit_node) = self.get_msg_dst_peer_by_ip(&msg, ip_addr).await;
         if dst_peers.is_empty() {
             tracing::info!("no peer id for ip: {}", ip_addr);
             return Ok(());
         }
 
+        for mut packet in segment(msg, max_ip_len) {
+            packet.fill_peer_manager_hdr(
+                self.my_peer_id,
+                0,
+                tunnel::packet_def::PacketType::Data as u8,
+            );
+            self.send_msg_to_dst_peers(packet, &dst_peers, is_exit_node)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn send_msg_to_dst_peers(
+        &self,
+        mut msg: ZCPacket,
+        dst_peers: &[PeerId],
+        is_exit_node: bool,
+    ) -> Result<(), Error> {
         self.self_tx_counters
             .compress_tx_bytes_before
             .add(msg.buf_len() as u64);
@@ -1223,6 +1439,20 @@ impl PeerManager {
         });
     }
 
+    async fn run_dns_forward_via_refresh_routine(&self) {
+        let global_ctx = self.global_ctx.clone();
+        let dns_forward_vias = self.dns_forward_vias.clone();
+        self.tasks.lock().await.spawn(async move {
+            loop {
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                let vias = collect_dns_forward_vias(global_ctx.config.as_ref());
+                if vias != **dns_forward_vias.load() {
+                    dns_forward_vias.store(Arc::new(vias));
+                }
+            }
+        });
+    }
+
     async fn run_foriegn_network(&self) {
         self.peer_rpc_tspt
             .foreign_peers
@@ -1244,6 +1474,7 @@ impl PeerManager {
 
         self.start_peer_recv().await;
         self.run_clean_peer_without_conn_routine().await;
+        self.run_dns_forward_via_refresh_routine().await;
 
         self.run_foriegn_network().await;
 
@@ -1302,6 +1533,12 @@ impl PeerManager {
                         format!("{}->{}", x.cidr, x.mapped_cidr.unwrap())
                     }
                 })
+                .chain(
+                    self.global_ctx
+                        .get_imported_proxy_cidrs()
+                        .into_iter()
+                        .map(|x| x.to_string()),
+                )
                 .collect(),
             hostname: self.global_ctx.get_hostname(),
             stun_info: Some(self.global_ctx.get_stun_info_collector().get_stun_info()),
@@ -1316,6 +1553,14 @@ impl PeerManager {
             version: EASYTIER_VERSION.to_string(),
             feature_flag: Some(self.global_ctx.get_feature_flags()),
             ip_list: Some(self.global_ctx.get_ip_collector().collect_ip_addrs().await),
+            area_id: self.global_ctx.get_flags().area_id,
+            services: self
+                .global_ctx
+                .config
+                .get_services()
+                .into_iter()
+                .map(Into::into)
+                .collect(),
         }
     }
 
@@ -1411,10 +1656,23 @@ impl PeerManager {
 #[cfg(test)]
 mod tests {
 
-    use std::{fmt::Debug, sync::Arc, time::Duration};
+    use std::{
+        fmt::Debug,
+        net::{IpAddr, SocketAddrV4},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use pnet::packet::{
+        ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+        ipv4::MutableIpv4Packet,
+    };
 
     use crate::{
-        common::{config::Flags, global_ctx::tests::get_mock_global_ctx},
+        common::{
+            config::{ConfigLoader, DnsForwardConfig, Flags},
+            global_ctx::tests::get_mock_global_ctx,
+        },
         connector::{
             create_connector_by_url, direct::PeerManagerForDirectConnector,
             udp_hole_punch::tests::create_mock_peer_manager_with_mock_stun,
@@ -1434,6 +1692,7 @@ mod tests {
         tunnel::{
             common::tests::wait_for_condition,
             filter::{tests::DropSendTunnelFilter, TunnelWithFilter},
+            packet_def::ZCPacket,
             ring::create_ring_tunnel_pair,
             TunnelConnector, TunnelListener,
         },
@@ -1441,6 +1700,18 @@ mod tests {
 
     use super::PeerManager;
 
+    fn new_ipv4_packet_to(dst: SocketAddrV4, protocol: IpNextHeaderProtocol) -> ZCPacket {
+        let mut buf = vec![0u8; 40];
+        let mut ipv4 = MutableIpv4Packet::new(&mut buf).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length(40);
+        ipv4.set_next_level_protocol(protocol);
+        ipv4.set_destination(*dst.ip());
+        buf[22..24].copy_from_slice(&dst.port().to_be_bytes());
+        ZCPacket::new_with_payload(&buf)
+    }
+
     #[tokio::test]
     async fn drop_peer_manager() {
         let peer_mgr_a = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
@@ -1496,8 +1767,8 @@ mod tests {
     #[tokio::test]
     #[serial_test::serial(forward_packet_test)]
     async fn forward_packet(
-        #[values("tcp", "udp", "wg", "quic")] proto1: &str,
-        #[values("tcp", "udp", "wg", "quic")] proto2: &str,
+        #[values("tcp", "udp", "wg", "quic", "kcp")] proto1: &str,
+        #[values("tcp", "udp", "wg", "quic", "kcp")] proto2: &str,
     ) {
         use crate::proto::{
             rpc_impl::RpcController,
@@ -1677,6 +1948,86 @@ mod tests {
         assert_eq!(ret, Some(peer_mgr_b.my_peer_id));
     }
 
+    #[tokio::test]
+    async fn dns_forward_via_peer() {
+        // b and c both proxy 192.168.9.0/24, a forwards dns queries to its
+        // nameserver via b.
+        let global_ctx = get_mock_global_ctx();
+        global_ctx
+            .config
+            .set_dns_forwards(vec!["corp.example=192.168.9.53 via 10.144.144.2"
+                .parse::<DnsForwardConfig>()
+                .unwrap()]);
+        let (s, _r) = create_packet_recv_chan();
+        let peer_mgr_a = Arc::new(PeerManager::new(RouteAlgoType::Ospf, global_ctx.clone(), s));
+        peer_mgr_a.run().await.unwrap();
+        let peer_mgr_b = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        let peer_mgr_c = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        for (p, ipv4) in [
+            (&peer_mgr_b, "10.144.144.2/24"),
+            (&peer_mgr_c, "10.144.144.3/24"),
+        ] {
+            p.get_global_ctx().set_ipv4(Some(ipv4.parse().unwrap()));
+            p.get_global_ctx()
+                .config
+                .add_proxy_cidr("192.168.9.0/24".parse().unwrap(), None)
+                .unwrap();
+        }
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_c.clone()).await;
+
+        let nameserver: SocketAddrV4 = "192.168.9.53:53".parse().unwrap();
+        let udp_query = new_ipv4_packet_to(nameserver, IpNextHeaderProtocols::Udp);
+        let tcp_query = new_ipv4_packet_to(nameserver, IpNextHeaderProtocols::Tcp);
+        let ssh = new_ipv4_packet_to(
+            SocketAddrV4::new(*nameserver.ip(), 22),
+            IpNextHeaderProtocols::Tcp,
+        );
+        let dst_peer = |msg: &ZCPacket| {
+            let msg = msg.clone();
+            let peer_mgr_a = peer_mgr_a.clone();
+            async move {
+                peer_mgr_a
+                    .get_msg_dst_peer_by_ip(&msg, IpAddr::V4(*nameserver.ip()))
+                    .await
+            }
+        };
+        wait_for_condition(
+            || async {
+                dst_peer(&udp_query).await == (vec![peer_mgr_b.my_peer_id()], false)
+                    && dst_peer(&tcp_query).await == (vec![peer_mgr_b.my_peer_id()], false)
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        // other traffic to the nameserver follows the normal route.
+        assert_eq!(
+            dst_peer(&ssh).await,
+            peer_mgr_a.get_msg_dst_peer(nameserver.ip()).await
+        );
+
+        // rule changes at runtime take effect.
+        global_ctx
+            .config
+            .set_dns_forwards(vec!["corp.example=192.168.9.53 via 10.144.144.3"
+                .parse::<DnsForwardConfig>()
+                .unwrap()]);
+        wait_for_condition(
+            || async { dst_peer(&udp_query).await == (vec![peer_mgr_c.my_peer_id()], false) },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        // falls back to the normal route when the via peer is gone.
+        drop(peer_mgr_c);
+        wait_for_condition(
+            || async { dst_peer(&udp_query).await == (vec![peer_mgr_b.my_peer_id()], false) },
+            Duration::from_secs(10),
+        )
+        .await;
+    }
+
     #[tokio::test]
     async fn test_client_inbound_blackhole() {
         let peer_mgr_a = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
@@ -1,6 +1,6 @@
 use std::{
     collections::{
-        HashMap, {BTreeMap, BTreeSet},
+        HashMap, HashSet, VecDeque, {BTreeMap, BTreeSet},
     },
     fmt::Debug,
     net::{Ipv4Addr, Ipv6Addr},
@@ -30,19 +30,23 @@ use tokio::{
 
 use crate::{
     common::{
-        config::NetworkIdentity, constants::EASYTIER_VERSION, global_ctx::ArcGlobalCtx,
-        stun::StunInfoCollectorTrait, PeerId,
+        config::NetworkIdentity,
+        constants::EASYTIER_VERSION,
+        global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
+        stun::StunInfoCollectorTrait,
+        PeerId,
     },
     peers::route_trait::{Route, RouteInterfaceBox},
     proto::{
         acl::GroupIdentity,
+        cli::{RouteChangeEvent, RouteChangeType},
         common::{Ipv4Inet, NatType, StunInfo},
         peer_rpc::{
             route_foreign_network_infos, route_foreign_network_summary,
             ForeignNetworkRouteInfoEntry, ForeignNetworkRouteInfoKey, OspfRouteRpc,
-            OspfRouteRpcClientFactory, OspfRouteRpcServer, PeerIdVersion, RouteForeignNetworkInfos,
-            RouteForeignNetworkSummary, RoutePeerInfo, RoutePeerInfos, SyncRouteInfoError,
-            SyncRouteInfoRequest, SyncRouteInfoResponse,
+            OspfRouteRpcClientFactory, OspfRouteRpcServer, PeerIdVersion, RouteAreaSummary,
+            RouteForeignNetworkInfos, RouteForeignNetworkSummary, RoutePeerInfo, RoutePeerInfos,
+            SyncRouteInfoError, SyncRouteInfoRequest, SyncRouteInfoResponse,
         },
         rpc_types::{
             self,
@@ -67,6 +71,10 @@ static UPDATE_PEER_INFO_PERIOD: Duration = Duration::from_secs(3600);
 static REMOVE_DEAD_PEER_INFO_AFTER: Duration = Duration::from_secs(3660);
 // the cost (latency between two peers) is i32, i32::MAX is large enough.
 static AVOID_RELAY_COST: usize = i32::MAX as usize;
+static ROUTE_CHANGE_HISTORY_MAX_LEN: usize = 1000;
+// latency jitters all the time, only record cost changes larger than this (in percent and ms).
+static ROUTE_COST_CHANGE_MIN_PERCENT: i32 = 20;
+static ROUTE_COST_CHANGE_MIN_LATENCY_MS: i32 = 5;
 
 type Version = u32;
 
@@ -131,6 +139,12 @@ impl RoutePeerInfo {
             quic_port: None,
             ipv6_addr: None,
             groups: Vec::new(),
+            area_id: 0,
+            is_area_border: false,
+            area_summary: None,
+            dns_records: Vec::new(),
+            services: Vec::new(),
+            stun_public_ips: Vec::new(),
         }
     }
 
@@ -150,6 +164,7 @@ impl RoutePeerInfo {
                 .get_proxy_cidrs()
                 .iter()
                 .map(|x| x.mapped_cidr.unwrap_or(x.cidr))
+                .chain(global_ctx.get_imported_proxy_cidrs())
                 .chain(global_ctx.get_vpn_portal_cidr())
                 .map(|x| x.to_string())
                 .collect(),
@@ -174,6 +189,28 @@ impl RoutePeerInfo {
             ipv6_addr: global_ctx.get_ipv6().map(|x| x.into()),
 
             groups: global_ctx.get_acl_groups(my_peer_id),
+
+            area_id: global_ctx.get_flags().area_id,
+            // border info is computed from route table, see update_my_area_info.
+            is_area_border: self.is_area_border,
+            area_summary: self.area_summary.clone(),
+
+            dns_records: global_ctx
+                .config
+                .get_dns_records()
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            services: global_ctx
+                .config
+                .get_services()
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            stun_public_ips: global_ctx
+                .get_stun_info_collector()
+                .get_stun_info()
+                .public_ip,
         };
 
         let need_update_periodically = if let Ok(Ok(d)) =
@@ -228,6 +265,12 @@ impl From<RoutePeerInfo> for crate::proto::cli::Route {
             path_latency_latency_first: None,
 
             ipv6_addr: val.ipv6_addr,
+
+            area_id: val.area_id,
+            is_area_border: val.is_area_border,
+
+            dns_records: val.dns_records,
+            services: val.services,
         }
     }
 }
@@ -293,6 +336,103 @@ impl RouteConnBitmap {
     }
 }
 
+// the dense bitmap is only used by peers that do not support conn list sync.
+impl From<&RouteConnPeerList> for RouteConnBitmap {
+    fn from(val: &RouteConnPeerList) -> Self {
+        let mut conn_bitmap = RouteConnBitmap::new();
+        conn_bitmap.peer_ids = val.peer_conn_infos.iter().map(|x| x.0).collect();
+
+        let peer_idx_map = conn_bitmap
+            .peer_ids
+            .iter()
+            .enumerate()
+            .map(|(idx, (peer_id, _))| (*peer_id, idx))
+            .collect::<HashMap<_, _>>();
+        let peer_count = conn_bitmap.peer_ids.len();
+        conn_bitmap.bitmap = vec![0; (peer_count * peer_count).div_ceil(8)];
+
+        for (peer_idx, (_, connected)) in val.peer_conn_infos.iter().enumerate() {
+            for other_peer_id in connected.iter() {
+                let Some(idx) = peer_idx_map.get(other_peer_id) else {
+                    continue;
+                };
+                let bit_idx = peer_idx * peer_count + idx;
+                conn_bitmap.bitmap[bit_idx / 8] |= 1 << (bit_idx % 8);
+            }
+        }
+
+        conn_bitmap
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Default)]
+struct RouteConnPeerList {
+    peer_conn_infos: Vec<((PeerId, Version), BTreeSet<PeerId>)>,
+}
+
+impl From<RouteConnPeerList> for crate::proto::peer_rpc::RouteConnPeerList {
+    fn from(val: RouteConnPeerList) -> Self {
+        crate::proto::peer_rpc::RouteConnPeerList {
+            peer_conn_infos: val
+                .peer_conn_infos
+                .into_iter()
+                .map(|((peer_id, version), connected)| {
+                    crate::proto::peer_rpc::route_conn_peer_list::PeerConnInfo {
+                        peer_id: Some(PeerIdVersion { peer_id, version }),
+                        connected_peer_ids: connected.into_iter().collect(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<crate::proto::peer_rpc::RouteConnPeerList> for RouteConnPeerList {
+    fn from(v: crate::proto::peer_rpc::RouteConnPeerList) -> Self {
+        RouteConnPeerList {
+            peer_conn_infos: v
+                .peer_conn_infos
+                .into_iter()
+                .filter_map(|x| {
+                    let peer_id = x.peer_id?;
+                    Some((
+                        (peer_id.peer_id, peer_id.version),
+                        x.connected_peer_ids.into_iter().collect(),
+                    ))
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<&RouteConnBitmap> for RouteConnPeerList {
+    fn from(val: &RouteConnBitmap) -> Self {
+        RouteConnPeerList {
+            peer_conn_infos: val
+                .peer_ids
+                .iter()
+                .enumerate()
+                .map(|(peer_idx, x)| (*x, val.get_connected_peers(peer_idx)))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum RouteConnInfo {
+    Bitmap(RouteConnBitmap),
+    PeerList(RouteConnPeerList),
+}
+
+impl RouteConnInfo {
+    fn peer_id_versions(&self) -> Vec<(PeerId, Version)> {
+        match self {
+            RouteConnInfo::Bitmap(x) => x.peer_ids.clone(),
+            RouteConnInfo::PeerList(x) => x.peer_conn_infos.iter().map(|x| x.0).collect(),
+        }
+    }
+}
+
 type Error = SyncRouteInfoError;
 
 // constructed with all infos synced from all peers.
@@ -321,6 +461,21 @@ impl Debug for SyncedRouteInfo {
 }
 
 impl SyncedRouteInfo {
+    // return none if we only have an empty placeholder of the peer.
+    fn get_area_id(&self, peer_id: PeerId) -> Option<u32> {
+        self.peer_infos
+            .get(&peer_id)
+            .filter(|x| x.version != 0)
+            .map(|x| x.area_id)
+    }
+
+    fn is_area_border(&self, peer_id: PeerId) -> bool {
+        self.peer_infos
+            .get(&peer_id)
+            .map(|x| x.is_area_border)
+            .unwrap_or(false)
+    }
+
     fn get_connected_peers<T: FromIterator<PeerId>>(&self, peer_id: PeerId) -> Option<T> {
         self.conn_map
             .get(&peer_id)
@@ -464,14 +619,19 @@ impl SyncedRouteInfo {
         Ok(())
     }
 
-    fn update_conn_map(&self, conn_bitmap: &RouteConnBitmap) {
-        self.fill_empty_peer_info(&conn_bitmap.peer_ids.iter().map(|x| x.0).collect());
+    fn update_conn_map(&self, conn_peer_list: &RouteConnPeerList) {
+        self.fill_empty_peer_info(
+            &conn_peer_list
+                .peer_conn_infos
+                .iter()
+                .map(|((peer_id, _), _)| *peer_id)
+                .collect(),
+        );
 
         let mut need_inc_version = false;
 
-        for (peer_idx, (peer_id, version)) in conn_bitmap.peer_ids.iter().enumerate() {
-            let connceted_peers = conn_bitmap.get_connected_peers(peer_idx);
-            self.fill_empty_peer_info(&connceted_peers);
+        for ((peer_id, version), connceted_peers) in conn_peer_list.peer_conn_infos.iter() {
+            self.fill_empty_peer_info(connceted_peers);
 
             self.conn_map
                 .entry(*peer_id)
@@ -484,7 +644,7 @@ impl SyncedRouteInfo {
                 })
                 .or_insert_with(|| {
                     need_inc_version = true;
-                    (connceted_peers, (*version).into())
+                    (connceted_peers.clone(), (*version).into())
                 });
         }
         if need_inc_version {
@@ -534,6 +694,27 @@ impl SyncedRouteInfo {
         }
     }
 
+    fn update_my_area_info(
+        &self,
+        my_peer_id: PeerId,
+        is_area_border: bool,
+        area_summary: Option<RouteAreaSummary>,
+    ) -> bool {
+        let mut my_info = self.peer_infos.entry(my_peer_id).or_default();
+        if my_info.is_area_border == is_area_border && my_info.area_summary == area_summary {
+            return false;
+        }
+
+        my_info.is_area_border = is_area_border;
+        my_info.area_summary = area_summary;
+        my_info.last_update = Some(SystemTime::now().into());
+        my_info.version += 1;
+        drop(my_info);
+
+        self.version.inc();
+        true
+    }
+
     fn update_my_conn_info(&self, my_peer_id: PeerId, connected_peers: BTreeSet<PeerId>) -> bool {
         self.fill_empty_peer_info(&connected_peers);
 
@@ -709,6 +890,49 @@ impl SyncedRouteInfo {
     }
 }
 
+// merge the cidrs into as few prefixes as possible without covering any extra address, so areas
+// sharing one network do not take addresses of each other.
+fn aggregate_cidrs(cidrs: impl IntoIterator<Item = cidr::IpCidr>) -> Vec<cidr::IpCidr> {
+    let all = cidrs.into_iter().collect::<HashSet<_>>();
+    let mut cidrs = all
+        .iter()
+        .filter(|c| {
+            !all.iter()
+                .any(|o| o.network_length() < c.network_length() && o.contains(&c.first_address()))
+        })
+        .copied()
+        .collect::<HashSet<_>>();
+
+    for len in (1..=128u8).rev() {
+        let candidates = cidrs
+            .iter()
+            .filter(|c| c.network_length() == len)
+            .copied()
+            .collect::<Vec<_>>();
+        for c in candidates {
+            let parent = cidr::IpInet::new(c.first_address(), len - 1)
+                .unwrap()
+                .network();
+            let sibling = if parent.first_address() == c.first_address() {
+                cidr::IpInet::new(parent.last_address(), len)
+                    .unwrap()
+                    .network()
+            } else {
+                cidr::IpCidr::new(parent.first_address(), len).unwrap()
+            };
+            if cidrs.contains(&sibling) {
+                cidrs.remove(&c);
+                cidrs.remove(&sibling);
+                cidrs.insert(parent);
+            }
+        }
+    }
+
+    let mut cidrs = cidrs.into_iter().collect::<Vec<_>>();
+    cidrs.sort_by_key(|c| (c.first_address(), c.network_length()));
+    cidrs
+}
+
 type PeerGraph = Graph<PeerId, usize, Directed>;
 type PeerIdToNodexIdxMap = DashMap<PeerId, NodeIndex>;
 #[derive(Debug, Clone, Copy)]
@@ -717,6 +941,8 @@ struct NextHopInfo {
     path_latency: i32,
     path_len: usize, // path includes src and dst.
     version: Version,
+    // loop-free alternate neighbor, used when the link to next hop is withdrawn.
+    backup_next_hop_peer_id: Option<PeerId>,
 }
 // dst_peer_id -> (next_hop_peer_id, cost, path_len)
 type NextHopMap = DashMap<PeerId, NextHopInfo>;
@@ -729,6 +955,8 @@ struct RouteTable {
     ipv4_peer_id_map: DashMap<Ipv4Addr, PeerId>,
     ipv6_peer_id_map: DashMap<Ipv6Addr, PeerId>,
     cidr_peer_id_map: DashMap<cidr::IpCidr, PeerId>,
+    // prefixes of other areas -> border node announcing them.
+    area_cidr_peer_id_map: DashMap<cidr::IpCidr, PeerId>,
     next_hop_map_version: AtomicVersion,
 }
 
@@ -740,6 +968,7 @@ impl RouteTable {
             ipv4_peer_id_map: DashMap::new(),
             ipv6_peer_id_map: DashMap::new(),
             cidr_peer_id_map: DashMap::new(),
+            area_cidr_peer_id_map: DashMap::new(),
             next_hop_map_version: AtomicVersion::new(),
         }
     }
@@ -844,6 +1073,18 @@ impl RouteTable {
             // remove cidr map for peers we cannot reach.
             self.next_hop_map.contains_key(v)
         });
+        self.area_cidr_peer_id_map.retain(|_, v| {
+            // remove area cidr map for border nodes we cannot reach.
+            self.next_hop_map.contains_key(v)
+        });
+    }
+
+    fn hop_edge_cost(e: petgraph::graph::EdgeReference<usize>) -> usize {
+        if *e.weight() >= AVOID_RELAY_COST {
+            AVOID_RELAY_COST + 1
+        } else {
+            1
+        }
     }
 
     fn gen_next_hop_map_with_least_hop(
@@ -852,13 +1093,7 @@ impl RouteTable {
         start_node: &NodeIndex,
         version: Version,
     ) {
-        let normalize_edge_cost = |e: petgraph::graph::EdgeReference<usize>| {
-            if *e.weight() >= AVOID_RELAY_COST {
-                AVOID_RELAY_COST + 1
-            } else {
-                1
-            }
-        };
+        let normalize_edge_cost = Self::hop_edge_cost;
         // Step 1: 第一次 Dijkstra - 计算最短跳数
         let path_len_map = dijkstra(&graph, *start_node, None, normalize_edge_cost);
 
@@ -903,6 +1138,7 @@ impl RouteTable {
                 path_latency: (*costs.get(dst).unwrap() % AVOID_RELAY_COST) as i32,
                 path_len: { *path_len },
                 version,
+                backup_next_hop_peer_id: None,
             };
             let dst_peer_id = *graph.node_weight(*dst).unwrap();
             self.next_hop_map
@@ -918,12 +1154,114 @@ impl RouteTable {
         self.next_hop_map_version.set_if_larger(version);
     }
 
+    // compute loop-free alternates (RFC 5286) on the full graph. a neighbor N is a backup for
+    // dst D if d(N, D) < d(N, S) + d(S, D), so N never sends the packet back to us.
+    fn gen_backup_next_hop_map<F>(
+        &self,
+        graph: &PeerGraph,
+        start_node: &NodeIndex,
+        version: Version,
+        edge_cost: F,
+    ) where
+        F: Fn(petgraph::graph::EdgeReference<usize>) -> usize,
+    {
+        let my_costs = dijkstra(&graph, *start_node, None, &edge_cost);
+
+        let mut neighbors = Vec::new();
+        for edge in graph.edges(*start_node) {
+            let neighbor = edge.target();
+            if neighbor == *start_node {
+                continue;
+            }
+            let neighbor_costs = dijkstra(&graph, neighbor, None, &edge_cost);
+            neighbors.push((neighbor, edge_cost(edge), neighbor_costs));
+        }
+
+        for (dst, my_cost) in my_costs.iter() {
+            if *dst == *start_node {
+                continue;
+            }
+            let dst_peer_id = *graph.node_weight(*dst).unwrap();
+            let Some(mut info) = self.next_hop_map.get_mut(&dst_peer_id) else {
+                continue;
+            };
+            if info.version != version {
+                continue;
+            }
+
+            let mut best: Option<(usize, PeerId)> = None;
+            for (neighbor, edge_cost, neighbor_costs) in neighbors.iter() {
+                let neighbor_peer_id = *graph.node_weight(*neighbor).unwrap();
+                if neighbor_peer_id == info.next_hop_peer_id {
+                    continue;
+                }
+                let Some(n_to_dst) = neighbor_costs.get(dst) else {
+                    continue;
+                };
+                let Some(n_to_me) = neighbor_costs.get(start_node) else {
+                    continue;
+                };
+                if *n_to_dst >= n_to_me.saturating_add(*my_cost) {
+                    continue;
+                }
+                let cost = edge_cost.saturating_add(*n_to_dst);
+                if best.map(|(c, _)| cost < c).unwrap_or(true) {
+                    best = Some((cost, neighbor_peer_id));
+                }
+            }
+
+            info.backup_next_hop_peer_id = best.map(|(_, peer_id)| peer_id);
+        }
+    }
+
+    // peers of other areas are not in the route table, their addresses are routed to the
+    // border node announcing the prefix with the least (path len to border + area cost).
+    fn gen_area_cidr_peer_id_map(&self, my_peer_id: PeerId, synced_info: &SyncedRouteInfo) {
+        let my_area_id = synced_info.get_area_id(my_peer_id).unwrap_or_default();
+        let mut area_cidrs: HashMap<cidr::IpCidr, (usize, PeerId)> = HashMap::new();
+        for item in synced_info.peer_infos.iter() {
+            let info = item.value();
+            if info.version == 0 || info.area_id == my_area_id || info.peer_id == my_peer_id {
+                continue;
+            }
+            let Some(summary) = info.area_summary.as_ref() else {
+                continue;
+            };
+            let Some(next_hop) = self.get_next_hop(info.peer_id) else {
+                continue;
+            };
+
+            let cost = (next_hop.path_len + summary.cost as usize, info.peer_id);
+            for prefix in summary.prefixes.iter() {
+                let Ok(prefix) = prefix.parse() else {
+                    tracing::warn!(?prefix, ?info.peer_id, "invalid area prefix");
+                    continue;
+                };
+                area_cidrs
+                    .entry(prefix)
+                    .and_modify(|v| {
+                        if cost < *v {
+                            *v = cost;
+                        }
+                    })
+                    .or_insert(cost);
+            }
+        }
+
+        self.area_cidr_peer_id_map
+            .retain(|k, _| area_cidrs.contains_key(k));
+        for (prefix, (_, peer_id)) in area_cidrs {
+            self.area_cidr_peer_id_map.insert(prefix, peer_id);
+        }
+    }
+
     fn build_from_synced_info<T: RouteCostCalculatorInterface>(
         &self,
         my_peer_id: PeerId,
         synced_info: &SyncedRouteInfo,
         policy: NextHopPolicy,
         cost_calc: &T,
+        enable_backup_next_hop: bool,
     ) {
         let version = synced_info.version.get();
 
@@ -942,6 +1280,17 @@ impl RouteTable {
             self.gen_next_hop_map_with_least_cost(&graph, &start_node, version);
         };
 
+        self.gen_area_cidr_peer_id_map(my_peer_id, synced_info);
+
+        if enable_backup_next_hop {
+            // use the same metric as the primary path, otherwise the alternate may loop back.
+            if matches!(policy, NextHopPolicy::LeastHop) {
+                self.gen_backup_next_hop_map(&graph, &start_node, version, Self::hop_edge_cost);
+            } else {
+                self.gen_backup_next_hop_map(&graph, &start_node, version, |e| *e.weight());
+            }
+        }
+
         // build peer_infos, ipv4_peer_id_map, cidr_peer_id_map
         // only set map for peers we can reach.
         for item in self.next_hop_map.iter() {
@@ -1009,6 +1358,15 @@ impl RouteTable {
         }
         None
     }
+
+    // longest prefix match, the prefixes of different areas may overlap.
+    fn get_peer_id_for_area(&self, ip: &std::net::IpAddr) -> Option<PeerId> {
+        self.area_cidr_peer_id_map
+            .iter()
+            .filter(|item| item.key().contains(ip))
+            .max_by_key(|item| item.key().network_length())
+            .map(|item| *item.value())
+    }
 }
 
 type SessionId = u64;
@@ -1128,8 +1486,8 @@ impl SyncRouteSession {
         }
     }
 
-    fn update_dst_saved_conn_bitmap_version(&self, conn_bitmap: &RouteConnBitmap) {
-        for (peer_id, version) in conn_bitmap.peer_ids.iter() {
+    fn update_dst_saved_conn_bitmap_version(&self, conn_info: &RouteConnInfo) {
+        for (peer_id, version) in conn_info.peer_id_versions().iter() {
             self.dst_saved_conn_bitmap_version
                 .entry(*peer_id)
                 .or_insert_with(AtomicVersion::new)
@@ -1181,6 +1539,14 @@ impl Drop for SyncRouteSession {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+struct RouteSnapshotItem {
+    next_hop_peer_id: PeerId,
+    cost: i32,
+    proxy_cidrs: Vec<String>,
+    hostname: String,
+}
+
 struct PeerRouteServiceImpl {
     my_peer_id: PeerId,
     my_peer_route_id: u64,
@@ -1195,12 +1561,16 @@ struct PeerRouteServiceImpl {
     foreign_network_owner_map: DashMap<NetworkIdentity, Vec<PeerId>>,
     foreign_network_my_peer_id_map: DashMap<(String, PeerId), PeerId>,
     synced_route_info: SyncedRouteInfo,
-    cached_local_conn_map: std::sync::Mutex<RouteConnBitmap>,
+    cached_local_conn_map: std::sync::Mutex<RouteConnPeerList>,
     cached_local_conn_map_version: AtomicVersion,
 
     last_update_my_foreign_network: AtomicCell<Option<std::time::Instant>>,
 
     peer_info_last_update: AtomicCell<std::time::Instant>,
+
+    // routes seen by last update_route_table, used to generate route change events.
+    last_route_snapshot: std::sync::Mutex<BTreeMap<PeerId, RouteSnapshotItem>>,
+    route_change_history: std::sync::Mutex<VecDeque<RouteChangeEvent>>,
 }
 
 impl Debug for PeerRouteServiceImpl {
@@ -1252,12 +1622,15 @@ impl PeerRouteServiceImpl {
                 group_trust_map_cache: DashMap::new(),
                 version: AtomicVersion::new(),
             },
-            cached_local_conn_map: std::sync::Mutex::new(RouteConnBitmap::new()),
+            cached_local_conn_map: std::sync::Mutex::new(RouteConnPeerList::default()),
             cached_local_conn_map_version: AtomicVersion::new(),
 
             last_update_my_foreign_network: AtomicCell::new(None),
 
             peer_info_last_update: AtomicCell::new(std::time::Instant::now()),
+
+            last_route_snapshot: std::sync::Mutex::new(BTreeMap::new()),
+            route_change_history: std::sync::Mutex::new(VecDeque::new()),
         }
     }
 
@@ -1345,6 +1718,23 @@ impl PeerRouteServiceImpl {
             .update_my_foreign_network(self.my_peer_id, foreign_networks)
     }
 
+    // the link to next hop may be withdrawn before the route table is rebuilt, fall back to
+    // the precomputed backup next hop in this case.
+    fn select_live_next_hop(&self, info: &NextHopInfo) -> PeerId {
+        let Some(backup) = info.backup_next_hop_peer_id else {
+            return info.next_hop_peer_id;
+        };
+        let is_connected = |peer_id| {
+            self.synced_route_info
+                .is_peer_bidirectly_connected(self.my_peer_id, peer_id)
+        };
+        if !is_connected(info.next_hop_peer_id) && is_connected(backup) {
+            backup
+        } else {
+            info.next_hop_peer_id
+        }
+    }
+
     fn update_route_table(&self) {
         self.cost_calculator
             .write()
@@ -1354,12 +1744,15 @@ impl PeerRouteServiceImpl {
             .begin_update();
 
         let calc_locked = self.cost_calculator.read().unwrap();
+        // backup next hops are only useful when dead links are detected quickly.
+        let enable_backup_next_hop = self.global_ctx.get_flags().link_probe_interval_ms > 0;
 
         self.route_table.build_from_synced_info(
             self.my_peer_id,
             &self.synced_route_info,
             NextHopPolicy::LeastHop,
             calc_locked.as_ref().unwrap(),
+            enable_backup_next_hop,
         );
 
         self.route_table_with_cost.build_from_synced_info(
@@ -1367,6 +1760,7 @@ impl PeerRouteServiceImpl {
             &self.synced_route_info,
             NextHopPolicy::LeastCost,
             calc_locked.as_ref().unwrap(),
+            enable_backup_next_hop,
         );
 
         drop(calc_locked);
@@ -1377,6 +1771,154 @@ impl PeerRouteServiceImpl {
             .as_mut()
             .unwrap()
             .end_update();
+
+        self.record_route_changes();
+    }
+
+    // cost is the metric used to select the next hop, path latency if latency first is enabled,
+    // otherwise hop count.
+    fn snapshot_route_table(&self) -> BTreeMap<PeerId, RouteSnapshotItem> {
+        let latency_first = self.global_ctx.get_flags().latency_first;
+        let route_table = if latency_first {
+            &self.route_table_with_cost
+        } else {
+            &self.route_table
+        };
+
+        let mut ret = BTreeMap::new();
+        for item in route_table.peer_infos.iter() {
+            if *item.key() == self.my_peer_id {
+                continue;
+            }
+            let Some(next_hop) = route_table.get_next_hop(*item.key()) else {
+                continue;
+            };
+            ret.insert(
+                *item.key(),
+                RouteSnapshotItem {
+                    next_hop_peer_id: next_hop.next_hop_peer_id,
+                    cost: if latency_first {
+                        next_hop.path_latency
+                    } else {
+                        next_hop.path_len as i32
+                    },
+                    proxy_cidrs: item.proxy_cidrs.clone(),
+                    hostname: item.hostname.clone(),
+                },
+            );
+        }
+        ret
+    }
+
+    fn is_cost_change_significant(old_cost: i32, new_cost: i32, latency_first: bool) -> bool {
+        let diff = new_cost.saturating_sub(old_cost).saturating_abs();
+        if !latency_first {
+            // hop count changes are always meaningful.
+            return diff != 0;
+        }
+        diff >= ROUTE_COST_CHANGE_MIN_LATENCY_MS
+            && diff.saturating_mul(100)
+                >= old_cost
+                    .saturating_abs()
+                    .saturating_mul(ROUTE_COST_CHANGE_MIN_PERCENT)
+    }
+
+    fn record_route_changes(&self) {
+        let latency_first = self.global_ctx.get_flags().latency_first;
+        let mut cur_snapshot = self.snapshot_route_table();
+        let mut last_snapshot = self.last_route_snapshot.lock().unwrap();
+        // keep the last recorded cost for minor changes, so a slow drift is still reported once
+        // it adds up.
+        for (peer_id, new) in cur_snapshot.iter_mut() {
+            let Some(old) = last_snapshot.get(peer_id) else {
+                continue;
+            };
+            if old.next_hop_peer_id == new.next_hop_peer_id
+                && !Self::is_cost_change_significant(old.cost, new.cost, latency_first)
+            {
+                new.cost = old.cost;
+            }
+        }
+        if *last_snapshot == cur_snapshot {
+            return;
+        }
+
+        let now = chrono::Local::now().timestamp() as u64;
+        let mut changes = Vec::new();
+        let peer_ids = last_snapshot
+            .keys()
+            .chain(cur_snapshot.keys())
+            .copied()
+            .collect::<BTreeSet<_>>();
+        for peer_id in peer_ids {
+            let old = last_snapshot.get(&peer_id);
+            let new = cur_snapshot.get(&peer_id);
+            let mut change = RouteChangeEvent {
+                time: now,
+                dst_peer_id: peer_id,
+                dst_hostname: new.or(old).map(|x| x.hostname.clone()).unwrap_or_default(),
+                old_next_hop_peer_id: old.map(|x| x.next_hop_peer_id),
+                new_next_hop_peer_id: new.map(|x| x.next_hop_peer_id),
+                old_cost: old.map(|x| x.cost).unwrap_or_default(),
+                new_cost: new.map(|x| x.cost).unwrap_or_default(),
+                old_proxy_cidrs: old.map(|x| x.proxy_cidrs.clone()).unwrap_or_default(),
+                new_proxy_cidrs: new.map(|x| x.proxy_cidrs.clone()).unwrap_or_default(),
+                ..Default::default()
+            };
+
+            // a route appearing or disappearing is reported as next hop change only.
+            if change.old_next_hop_peer_id != change.new_next_hop_peer_id {
+                change.set_change_type(RouteChangeType::NextHopChanged);
+                changes.push(change);
+                continue;
+            }
+            if change.old_cost != change.new_cost {
+                let mut change = change.clone();
+                change.set_change_type(RouteChangeType::CostChanged);
+                changes.push(change);
+            }
+            if change.old_proxy_cidrs != change.new_proxy_cidrs {
+                change.set_change_type(RouteChangeType::ProxyCidrsChanged);
+                changes.push(change);
+            }
+        }
+        *last_snapshot = cur_snapshot;
+        drop(last_snapshot);
+
+        // the event handler of foreign networks gives up when lagged, do not flood it.
+        let issue_event = !self.global_ctx.get_feature_flags().is_public_server;
+        let mut history = self.route_change_history.lock().unwrap();
+        for change in changes {
+            tracing::debug!(?change, "route changed");
+            if issue_event {
+                self.global_ctx
+                    .issue_event(Self::route_change_to_event(&change));
+            }
+            history.push_back(change);
+            if history.len() > ROUTE_CHANGE_HISTORY_MAX_LEN {
+                history.pop_front();
+            }
+        }
+    }
+
+    fn route_change_to_event(change: &RouteChangeEvent) -> GlobalCtxEvent {
+        match change.change_type() {
+            RouteChangeType::NextHopChanged => GlobalCtxEvent::RouteNextHopChanged(
+                change.dst_peer_id,
+                change.old_next_hop_peer_id,
+                change.new_next_hop_peer_id,
+            ),
+            RouteChangeType::CostChanged => GlobalCtxEvent::RouteCostChanged(
+                change.dst_peer_id,
+                change.old_cost,
+                change.new_cost,
+            ),
+            RouteChangeType::ProxyCidrsChanged => GlobalCtxEvent::RouteProxyCidrsChanged(
+                change.dst_peer_id,
+                change.old_proxy_cidrs.clone(),
+                change.new_proxy_cidrs.clone(),
+            ),
+        }
     }
 
     fn update_foreign_network_owner_map(&self) {
@@ -1449,22 +1991,21 @@ impl PeerRouteServiceImpl {
             .filter(|p| all_dst_peer_ids.contains(&p.0) || self.route_table.peer_reachable(p.0))
             .collect::<Vec<_>>();
 
-        let mut conn_bitmap = RouteConnBitmap::new();
-        conn_bitmap.bitmap = vec![0; (all_peer_ids.len() * all_peer_ids.len()).div_ceil(8)];
-        conn_bitmap.peer_ids = all_peer_ids;
-
-        let all_peer_ids = &conn_bitmap.peer_ids;
-        for (peer_idx, (peer_id, _)) in all_peer_ids.iter().enumerate() {
-            let Some(connected) = self.synced_route_info.conn_map.get(peer_id) else {
+        // keep the adjacency sparse, the dense bitmap is built only when a peer needs it.
+        let all_peer_id_set = all_peer_ids.iter().map(|x| x.0).collect::<BTreeSet<_>>();
+        let mut conn_peer_list = RouteConnPeerList::default();
+        for (peer_id, version) in all_peer_ids.into_iter() {
+            let Some(connected) = self.synced_route_info.conn_map.get(&peer_id) else {
                 continue;
             };
-
-            for (idx, (other_peer_id, _)) in all_peer_ids.iter().enumerate() {
-                if connected.0.contains(other_peer_id) {
-                    let bit_idx = peer_idx * all_peer_ids.len() + idx;
-                    conn_bitmap.bitmap[bit_idx / 8] |= 1 << (bit_idx % 8);
-                }
-            }
+            let connected = connected
+                .0
+                .intersection(&all_peer_id_set)
+                .copied()
+                .collect::<BTreeSet<_>>();
+            conn_peer_list
+                .peer_conn_infos
+                .push(((peer_id, version), connected));
         }
 
         let mut locked = self.cached_local_conn_map.lock().unwrap();
@@ -1472,8 +2013,22 @@ impl PeerRouteServiceImpl {
             .cached_local_conn_map_version
             .set_if_larger(synced_version)
         {
-            *locked = conn_bitmap;
+            *locked = conn_peer_list;
+        }
+    }
+
+    // full route info of a peer is only flooded inside its area, other areas only see border
+    // nodes and their summaries.
+    fn is_peer_in_scope_of_dst(&self, peer_id: PeerId, dst_peer_id: PeerId) -> bool {
+        if peer_id == self.my_peer_id || self.synced_route_info.is_area_border(peer_id) {
+            return true;
         }
+        // before we get the info of dst peer, assume it is in our area as old versions do.
+        let dst_area_id = self
+            .synced_route_info
+            .get_area_id(dst_peer_id)
+            .unwrap_or(self.global_ctx.get_flags().area_id);
+        self.synced_route_info.get_area_id(peer_id) == Some(dst_area_id)
     }
 
     fn build_route_info(&self, session: &SyncRouteSession) -> Option<Vec<RoutePeerInfo>> {
@@ -1485,6 +2040,10 @@ impl PeerRouteServiceImpl {
                 continue;
             }
 
+            if !self.is_peer_in_scope_of_dst(*item.key(), session.dst_peer_id) {
+                continue;
+            }
+
             // do not send unreachable peer info to dst peer.
             if !self.route_table.peer_reachable(*item.key()) {
                 continue;
@@ -1500,24 +2059,69 @@ impl PeerRouteServiceImpl {
         }
     }
 
-    fn build_conn_bitmap(&self, session: &SyncRouteSession) -> Option<RouteConnBitmap> {
-        let mut need_update = false;
-        for (peer_id, local_version) in self.cached_local_conn_map.lock().unwrap().peer_ids.iter() {
-            let peer_version = session
-                .dst_saved_conn_bitmap_version
-                .get(peer_id)
-                .map(|item| item.get());
-            if peer_version.is_none() || peer_version.unwrap() < *local_version {
-                need_update = true;
-                break;
+    fn is_dst_conn_info_outdated(
+        session: &SyncRouteSession,
+        peer_id: PeerId,
+        local_version: Version,
+    ) -> bool {
+        let peer_version = session
+            .dst_saved_conn_bitmap_version
+            .get(&peer_id)
+            .map(|item| item.get());
+        peer_version.is_none() || peer_version.unwrap() < local_version
+    }
+
+    fn is_conn_list_sync_supported(&self, peer_id: PeerId) -> bool {
+        self.synced_route_info
+            .peer_infos
+            .get(&peer_id)
+            .and_then(|x| x.value().feature_flag)
+            .map(|x| x.support_conn_list_sync)
+            .unwrap_or(false)
+    }
+
+    fn build_conn_info(&self, session: &SyncRouteSession) -> Option<RouteConnInfo> {
+        let conn_list_sync_supported = self.is_conn_list_sync_supported(session.dst_peer_id);
+        let in_scope_conn_infos = self
+            .cached_local_conn_map
+            .lock()
+            .unwrap()
+            .peer_conn_infos
+            .iter()
+            .filter(|((peer_id, _), _)| self.is_peer_in_scope_of_dst(*peer_id, session.dst_peer_id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if !conn_list_sync_supported {
+            // old peers need a complete bitmap.
+            let need_update = in_scope_conn_infos.iter().any(|((peer_id, version), _)| {
+                Self::is_dst_conn_info_outdated(session, *peer_id, *version)
+            });
+            if !need_update {
+                return None;
             }
+            let conn_peer_list = RouteConnPeerList {
+                peer_conn_infos: in_scope_conn_infos,
+            };
+            return Some(RouteConnInfo::Bitmap((&conn_peer_list).into()));
         }
 
-        if !need_update {
-            return None;
-        }
+        // only send conn info that dst peer does not have. dst peer always knows its own conns.
+        let peer_conn_infos = in_scope_conn_infos
+            .into_iter()
+            .filter(|((peer_id, version), _)| {
+                *peer_id != session.dst_peer_id
+                    && Self::is_dst_conn_info_outdated(session, *peer_id, *version)
+            })
+            .collect::<Vec<_>>();
 
-        Some(self.cached_local_conn_map.lock().unwrap().clone())
+        if peer_conn_infos.is_empty() {
+            None
+        } else {
+            Some(RouteConnInfo::PeerList(RouteConnPeerList {
+                peer_conn_infos,
+            }))
+        }
     }
 
     fn build_foreign_network_info(
@@ -1553,9 +2157,71 @@ impl PeerRouteServiceImpl {
         }
     }
 
+    // a node directly connected to nodes of other areas becomes a border node, and summarizes
+    // the addresses of its own area as prefixes to other areas.
+    fn update_my_area_info(&self) -> bool {
+        let my_area_id = self.global_ctx.get_flags().area_id;
+        let is_area_border = self
+            .synced_route_info
+            .get_connected_peers::<Vec<_>>(self.my_peer_id)
+            .unwrap_or_default()
+            .into_iter()
+            .any(|peer_id| {
+                self.synced_route_info
+                    .get_area_id(peer_id)
+                    .is_some_and(|area_id| area_id != my_area_id)
+            });
+
+        let mut area_summary = None;
+        if is_area_border {
+            let mut cidrs = Vec::new();
+            let mut cost = 0;
+            for item in self.route_table.peer_infos.iter() {
+                let info = item.value();
+                if info.area_id != my_area_id
+                    || self.synced_route_info.get_area_id(info.peer_id).is_none()
+                {
+                    continue;
+                }
+                if info.peer_id != self.my_peer_id {
+                    let Some(next_hop) = self.route_table.get_next_hop(info.peer_id) else {
+                        continue;
+                    };
+                    cost = std::cmp::max(cost, next_hop.path_len as u32);
+                }
+
+                if let Some(ipv4_addr) = info.ipv4_addr {
+                    cidrs.push(cidr::IpCidr::new_host(Ipv4Addr::from(ipv4_addr).into()));
+                }
+                if let Some(ipv6_addr) = info.ipv6_addr.and_then(|x| x.address) {
+                    cidrs.push(cidr::IpCidr::new_host(Ipv6Addr::from(ipv6_addr).into()));
+                }
+                cidrs.extend(info.proxy_cidrs.iter().filter_map(|x| x.parse().ok()));
+            }
+            area_summary = Some(RouteAreaSummary {
+                prefixes: aggregate_cidrs(cidrs)
+                    .into_iter()
+                    .map(|x| x.to_string())
+                    .collect(),
+                cost,
+            });
+        }
+
+        if self
+            .synced_route_info
+            .update_my_area_info(self.my_peer_id, is_area_border, area_summary)
+        {
+            self.update_route_table_and_cached_local_conn_bitmap();
+            return true;
+        }
+        false
+    }
+
     async fn update_my_infos(&self) -> bool {
         let my_peer_info_updated = self.update_my_peer_info();
         let my_conn_info_updated = self.update_my_conn_info().await;
+        let my_area_info_updated = self.update_my_area_info();
+        let my_peer_info_updated = my_peer_info_updated || my_area_info_updated;
         let my_foreign_network_updated = self.update_my_foreign_network().await;
         if my_conn_info_updated || my_peer_info_updated {
             self.update_foreign_network_owner_map();
@@ -1571,14 +2237,14 @@ impl PeerRouteServiceImpl {
         session: &SyncRouteSession,
     ) -> (
         Option<Vec<RoutePeerInfo>>,
-        Option<RouteConnBitmap>,
+        Option<RouteConnInfo>,
         Option<RouteForeignNetworkInfos>,
     ) {
         let route_infos = self.build_route_info(session);
-        let conn_bitmap = self.build_conn_bitmap(session);
+        let conn_info = self.build_conn_info(session);
         let foreign_network = self.build_foreign_network_info(session);
 
-        (route_infos, conn_bitmap, foreign_network)
+        (route_infos, conn_info, foreign_network)
     }
 
     fn clear_expired_peer(&self) {
@@ -1667,9 +2333,9 @@ impl PeerRouteServiceImpl {
 
         let my_peer_id = self.my_peer_id;
 
-        let (peer_infos, conn_bitmap, foreign_network) = self.build_sync_request(&session);
+        let (peer_infos, conn_info, foreign_network) = self.build_sync_request(&session);
         if peer_infos.is_none()
-            && conn_bitmap.is_none()
+            && conn_info.is_none()
             && foreign_network.is_none()
             && !session.need_sync_initiator_info.load(Ordering::Relaxed)
             && !(sync_as_initiator && session.we_are_initiator.load(Ordering::Relaxed))
@@ -1677,8 +2343,8 @@ impl PeerRouteServiceImpl {
             return true;
         }
 
-        tracing::debug!(?foreign_network, "sync_route request need send to peer. my_id {:?}, pper_id: {:?}, peer_infos: {:?}, conn_bitmap: {:?}, synced_route_info: {:?} session: {:?}",
-                       my_peer_id, dst_peer_id, peer_infos, conn_bitmap, self.synced_route_info, session);
+        tracing::debug!(?foreign_network, "sync_route request need send to peer. my_id {:?}, pper_id: {:?}, peer_infos: {:?}, conn_info: {:?}, synced_route_info: {:?} session: {:?}",
+                       my_peer_id, dst_peer_id, peer_infos, conn_info, self.synced_route_info, session);
 
         session
             .need_sync_initiator_info
@@ -1692,13 +2358,20 @@ impl PeerRouteServiceImpl {
                 self.global_ctx.get_network_name(),
             );
 
+        let (conn_bitmap, conn_peer_list) = match conn_info.clone() {
+            Some(RouteConnInfo::Bitmap(x)) => (Some(x.into()), None),
+            Some(RouteConnInfo::PeerList(x)) => (None, Some(x.into())),
+            None => (None, None),
+        };
+
         let sync_route_info_req = SyncRouteInfoRequest {
             my_peer_id,
             my_session_id: session.my_session_id.load(Ordering::Relaxed),
             is_initiator: session.we_are_initiator.load(Ordering::Relaxed),
             peer_infos: peer_infos.clone().map(|x| RoutePeerInfos { items: x }),
-            conn_bitmap: conn_bitmap.clone().map(Into::into),
+            conn_bitmap,
             foreign_network_infos: foreign_network.clone(),
+            conn_peer_list,
         };
 
         let mut ctrl = BaseController::default();
@@ -1753,8 +2426,8 @@ impl PeerRouteServiceImpl {
                     session.update_dst_saved_peer_info_version(peer_infos);
                 }
 
-                if let Some(conn_bitmap) = &conn_bitmap {
-                    session.update_dst_saved_conn_bitmap_version(conn_bitmap);
+                if let Some(conn_info) = &conn_info {
+                    session.update_dst_saved_conn_bitmap_version(conn_info);
                 }
 
                 if let Some(foreign_network) = &foreign_network {
@@ -1840,7 +2513,11 @@ impl OspfRouteRpc for RouteSessionManager {
         let from_session_id = request.my_session_id;
         let is_initiator = request.is_initiator;
         let peer_infos = request.peer_infos.map(|x| x.items);
-        let conn_bitmap = request.conn_bitmap.map(Into::into);
+        let conn_info = match (request.conn_peer_list, request.conn_bitmap) {
+            (Some(conn_peer_list), _) => Some(RouteConnInfo::PeerList(conn_peer_list.into())),
+            (None, Some(conn_bitmap)) => Some(RouteConnInfo::Bitmap(conn_bitmap.into())),
+            (None, None) => None,
+        };
         let foreign_network = request.foreign_network_infos;
         let raw_peer_infos = if peer_infos.is_some() {
             let r = get_raw_peer_infos(&mut ctrl.get_raw_input().unwrap()).unwrap();
@@ -1857,7 +2534,7 @@ impl OspfRouteRpc for RouteSessionManager {
                 is_initiator,
                 peer_infos,
                 raw_peer_infos,
-                conn_bitmap,
+                conn_info,
                 foreign_network,
             )
             .await;
@@ -2099,7 +2776,7 @@ impl RouteSessionManager {
         is_initiator: bool,
         peer_infos: Option<Vec<RoutePeerInfo>>,
         raw_peer_infos: Option<Vec<DynamicMessage>>,
-        conn_bitmap: Option<RouteConnBitmap>,
+        conn_info: Option<RouteConnInfo>,
         foreign_network: Option<RouteForeignNetworkInfos>,
     ) -> Result<SyncRouteInfoResponse, Error> {
         let Some(service_impl) = self.service_impl.upgrade() else {
@@ -2133,9 +2810,15 @@ impl RouteSessionManager {
             need_update_route_table = true;
         }
 
-        if let Some(conn_bitmap) = &conn_bitmap {
-            service_impl.synced_route_info.update_conn_map(conn_bitmap);
-            session.update_dst_saved_conn_bitmap_version(conn_bitmap);
+        if let Some(conn_info) = &conn_info {
+            let conn_peer_list = match conn_info {
+                RouteConnInfo::Bitmap(conn_bitmap) => conn_bitmap.into(),
+                RouteConnInfo::PeerList(conn_peer_list) => conn_peer_list.clone(),
+            };
+            service_impl
+                .synced_route_info
+                .update_conn_map(&conn_peer_list);
+            session.update_dst_saved_conn_bitmap_version(conn_info);
             need_update_route_table = true;
         }
 
@@ -2155,8 +2838,8 @@ impl RouteSessionManager {
         }
 
         tracing::info!(
-            "handling sync_route_info rpc: from_peer_id: {:?}, is_initiator: {:?}, peer_infos: {:?}, conn_bitmap: {:?}, synced_route_info: {:?} session: {:?}, new_route_table: {:?}",
-            from_peer_id, is_initiator, peer_infos, conn_bitmap, service_impl.synced_route_info, session, service_impl.route_table);
+            "handling sync_route_info rpc: from_peer_id: {:?}, is_initiator: {:?}, peer_infos: {:?}, conn_info: {:?}, synced_route_info: {:?} session: {:?}, new_route_table: {:?}",
+            from_peer_id, is_initiator, peer_infos, conn_info, service_impl.synced_route_info, session, service_impl.route_table);
 
         session
             .dst_is_initiator
@@ -2250,7 +2933,14 @@ impl PeerRoute {
 
             select! {
                 ev = global_event_receiver.recv() => {
-                    tracing::info!(?ev, "global event received in update_my_peer_info_routine");
+                    if !matches!(
+                        ev,
+                        Ok(GlobalCtxEvent::RouteNextHopChanged(..)
+                            | GlobalCtxEvent::RouteCostChanged(..)
+                            | GlobalCtxEvent::RouteProxyCidrsChanged(..))
+                    ) {
+                        tracing::info!(?ev, "global event received in update_my_peer_info_routine");
+                    }
                 }
                 _ = tokio::time::sleep(Duration::from_secs(1)) => {}
             }
@@ -2327,7 +3017,7 @@ impl Route for PeerRoute {
         let route_table = &self.service_impl.route_table;
         route_table
             .get_next_hop(dst_peer_id)
-            .map(|x| x.next_hop_peer_id)
+            .map(|x| self.service_impl.select_live_next_hop(&x))
     }
 
     async fn get_next_hop_with_policy(
@@ -2342,7 +3032,7 @@ impl Route for PeerRoute {
         };
         route_table
             .get_next_hop(dst_peer_id)
-            .map(|x| x.next_hop_peer_id)
+            .map(|x| self.service_impl.select_live_next_hop(&x))
     }
 
     async fn list_routes(&self) -> Vec<crate::proto::cli::Route> {
@@ -2369,6 +3059,13 @@ impl Route for PeerRoute {
 
             route.feature_flag = item.feature_flag;
 
+            // prefixes of other areas are routed through their border nodes.
+            if item.area_id != self.global_ctx.get_flags().area_id {
+                if let Some(summary) = item.area_summary.as_ref() {
+                    route.proxy_cidrs.extend(summary.prefixes.iter().cloned());
+                }
+            }
+
             routes.push(route);
         }
         routes
@@ -2384,6 +3081,10 @@ impl Route for PeerRoute {
             return Some(peer_id);
         }
 
+        if let Some(peer_id) = route_table.get_peer_id_for_area(&(*ipv4_addr).into()) {
+            return Some(peer_id);
+        }
+
         tracing::debug!(?ipv4_addr, "no peer id for ipv4");
         None
     }
@@ -2399,10 +3100,59 @@ impl Route for PeerRoute {
         //     return Some(peer_id);
         // }
 
+        if let Some(peer_id) = route_table.get_peer_id_for_area(&(*ipv6_addr).into()) {
+            return Some(peer_id);
+        }
+
         tracing::debug!(?ipv6_addr, "no peer id for ipv6");
         None
     }
 
+    async fn get_area_relay_peer_id(
+        &self,
+        from_peer_id: PeerId,
+        dst_ip: &std::net::IpAddr,
+    ) -> Option<PeerId> {
+        let synced_route_info = &self.service_impl.synced_route_info;
+        if !synced_route_info.is_area_border(self.my_peer_id) {
+            return None;
+        }
+
+        // packets from my own area are addressed to the dst peer directly.
+        let my_area_id = self.global_ctx.get_flags().area_id;
+        if synced_route_info.get_area_id(from_peer_id) == Some(my_area_id) {
+            return None;
+        }
+
+        let route_table = &self.service_impl.route_table;
+        let peer_id = match dst_ip {
+            std::net::IpAddr::V4(ipv4_addr) => route_table
+                .ipv4_peer_id_map
+                .get(ipv4_addr)
+                .map(|x| *x)
+                .or_else(|| route_table.get_peer_id_for_proxy(ipv4_addr)),
+            std::net::IpAddr::V6(ipv6_addr) => {
+                route_table.ipv6_peer_id_map.get(ipv6_addr).map(|x| *x)
+            }
+        }?;
+
+        if peer_id == self.my_peer_id || synced_route_info.get_area_id(peer_id) != Some(my_area_id)
+        {
+            return None;
+        }
+        Some(peer_id)
+    }
+
+    async fn list_route_history(&self) -> Vec<RouteChangeEvent> {
+        self.service_impl
+            .route_change_history
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
     async fn set_route_cost_fn(&self, _cost_fn: RouteCostCalculator) {
         *self.service_impl.cost_calculator.write().unwrap() = Some(_cost_fn);
         self.service_impl.synced_route_info.version.inc();
@@ -2489,15 +3239,20 @@ impl PeerPacketFilter for Arc<PeerRoute> {}
 mod tests {
     use std::{
         collections::BTreeSet,
+        net::Ipv4Addr,
         sync::{atomic::Ordering, Arc},
         time::Duration,
     };
 
     use dashmap::DashMap;
     use prost_reflect::{DynamicMessage, ReflectMessage};
+    use tokio::sync::broadcast::error::TryRecvError;
 
     use crate::{
-        common::{global_ctx::tests::get_mock_global_ctx, PeerId},
+        common::{
+            global_ctx::{tests::get_mock_global_ctx, GlobalCtxEvent},
+            PeerId,
+        },
         connector::udp_hole_punch::tests::replace_stun_info_collector,
         peers::{
             create_packet_recv_chan,
@@ -2507,14 +3262,19 @@ mod tests {
             tests::connect_peer_manager,
         },
         proto::{
+            cli::RouteChangeType,
             common::NatType,
-            peer_rpc::{RoutePeerInfo, RoutePeerInfos, SyncRouteInfoRequest},
+            peer_rpc::{RouteAreaSummary, RoutePeerInfo, RoutePeerInfos, SyncRouteInfoRequest},
+        },
+        tunnel::{
+            common::tests::wait_for_condition,
+            filter::{tests::LinkCutTunnelFilter, TunnelWithFilter},
+            ring::create_ring_tunnel_pair,
         },
-        tunnel::common::tests::wait_for_condition,
     };
     use prost::Message;
 
-    use super::PeerRoute;
+    use super::{aggregate_cidrs, PeerRoute, RouteConnBitmap, RouteConnPeerList};
 
     async fn create_mock_route(peer_mgr: Arc<PeerManager>) -> Arc<PeerRoute> {
         let peer_route = PeerRoute::new(
@@ -2938,6 +3698,249 @@ mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn test_backup_next_hop() {
+        let p_a = create_mock_pmgr().await;
+        let p_b = create_mock_pmgr().await;
+        let p_c = create_mock_pmgr().await;
+        let p_d = create_mock_pmgr().await;
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+        connect_peer_manager(p_a.clone(), p_c.clone()).await;
+        connect_peer_manager(p_d.clone(), p_b.clone()).await;
+        connect_peer_manager(p_d.clone(), p_c.clone()).await;
+
+        let mut flags = p_d.get_global_ctx().get_flags();
+        flags.link_probe_interval_ms = 100;
+        p_d.get_global_ctx().set_flags(flags);
+
+        let _r_a = create_mock_route(p_a.clone()).await;
+        let _r_b = create_mock_route(p_b.clone()).await;
+        let _r_c = create_mock_route(p_c.clone()).await;
+        let r_d = create_mock_route(p_d.clone()).await;
+
+        // both p_b and p_c can reach p_a without going back through p_d.
+        wait_for_condition(
+            || async {
+                let Some(info) = r_d.service_impl.route_table.get_next_hop(p_a.my_peer_id()) else {
+                    return false;
+                };
+                let Some(backup) = info.backup_next_hop_peer_id else {
+                    return false;
+                };
+                let neighbors = [p_b.my_peer_id(), p_c.my_peer_id()];
+                neighbors.contains(&info.next_hop_peer_id)
+                    && neighbors.contains(&backup)
+                    && backup != info.next_hop_peer_id
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        // p_c may route to p_b through p_d, so it is not a loop-free alternate.
+        let info = r_d
+            .service_impl
+            .route_table
+            .get_next_hop(p_b.my_peer_id())
+            .unwrap();
+        assert_eq!(info.next_hop_peer_id, p_b.my_peer_id());
+        assert_eq!(info.backup_next_hop_peer_id, None);
+
+        // withdraw the link to the primary next hop without rebuilding the route table, the
+        // backup must take over immediately.
+        let info = r_d
+            .service_impl
+            .route_table
+            .get_next_hop(p_a.my_peer_id())
+            .unwrap();
+        let backup = info.backup_next_hop_peer_id.unwrap();
+        r_d.service_impl
+            .synced_route_info
+            .update_my_conn_info(p_d.my_peer_id(), BTreeSet::from([backup]));
+        assert_eq!(r_d.get_next_hop(p_a.my_peer_id()).await, Some(backup));
+    }
+
+    async fn connect_peer_manager_with_cut_filter(
+        client: Arc<PeerManager>,
+        server: Arc<PeerManager>,
+    ) -> Arc<LinkCutTunnelFilter> {
+        let (a_ring, b_ring) = create_ring_tunnel_pair();
+        let filter = Arc::new(LinkCutTunnelFilter::default());
+        let a_ring = Box::new(TunnelWithFilter::new(a_ring, filter.clone()));
+        tokio::spawn(async move {
+            client.add_client_tunnel(a_ring, false).await.unwrap();
+        });
+        tokio::spawn(async move {
+            server.add_tunnel_as_server(b_ring, true).await.unwrap();
+        });
+        filter
+    }
+
+    #[tokio::test]
+    async fn test_link_failure_reroute() {
+        let p_a = create_mock_pmgr().await;
+        let p_b = create_mock_pmgr().await;
+        let p_c = create_mock_pmgr().await;
+        let p_d = create_mock_pmgr().await;
+
+        let mut flags = p_d.get_global_ctx().get_flags();
+        flags.link_probe_interval_ms = 100;
+        flags.link_probe_multiplier = 3;
+        p_d.get_global_ctx().set_flags(flags);
+
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+        connect_peer_manager(p_a.clone(), p_c.clone()).await;
+        let d_b = connect_peer_manager_with_cut_filter(p_d.clone(), p_b.clone()).await;
+        let d_c = connect_peer_manager_with_cut_filter(p_d.clone(), p_c.clone()).await;
+
+        let _r_a = create_mock_route(p_a.clone()).await;
+        let _r_b = create_mock_route(p_b.clone()).await;
+        let _r_c = create_mock_route(p_c.clone()).await;
+        let r_d = create_mock_route(p_d.clone()).await;
+
+        wait_for_condition(
+            || async { r_d.get_next_hop(p_a.my_peer_id()).await.is_some() },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        // the link to the current next hop goes dark without being closed.
+        let next_hop = r_d.get_next_hop(p_a.my_peer_id()).await.unwrap();
+        let alternate = if next_hop == p_b.my_peer_id() {
+            d_b.cut();
+            p_c.my_peer_id()
+        } else {
+            d_c.cut();
+            p_b.my_peer_id()
+        };
+
+        // detected by the liveness probe, far before the pingpong timeout.
+        wait_for_condition(
+            || async { r_d.get_next_hop(p_a.my_peer_id()).await == Some(alternate) },
+            Duration::from_secs(2),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_route_areas() {
+        let p_a1 = create_mock_pmgr().await;
+        let p_a2 = create_mock_pmgr().await;
+        let p_b1 = create_mock_pmgr().await;
+        let p_b2 = create_mock_pmgr().await;
+        for (p, area_id, ipv4) in [
+            (&p_a1, 1, "10.144.0.1/24"),
+            (&p_a2, 1, "10.144.0.2/24"),
+            (&p_b1, 2, "10.144.0.4/24"),
+            (&p_b2, 2, "10.144.0.5/24"),
+        ] {
+            let mut flags = p.get_global_ctx().get_flags();
+            flags.area_id = area_id;
+            p.get_global_ctx().set_flags(flags);
+            p.get_global_ctx().set_ipv4(Some(ipv4.parse().unwrap()));
+        }
+        connect_peer_manager(p_a1.clone(), p_a2.clone()).await;
+        connect_peer_manager(p_a2.clone(), p_b1.clone()).await;
+        connect_peer_manager(p_b1.clone(), p_b2.clone()).await;
+
+        let r_a1 = create_mock_route(p_a1.clone()).await;
+        let _r_a2 = create_mock_route(p_a2.clone()).await;
+        let r_b1 = create_mock_route(p_b1.clone()).await;
+        let _r_b2 = create_mock_route(p_b2.clone()).await;
+
+        // a1 sends packets for b2 to the border node b1, which announces the prefix of area 2.
+        let b2_ipv4: Ipv4Addr = "10.144.0.5".parse().unwrap();
+        wait_for_condition(
+            || async { r_a1.get_peer_id_by_ipv4(&b2_ipv4).await == Some(p_b1.my_peer_id()) },
+            Duration::from_secs(5),
+        )
+        .await;
+        assert_eq!(
+            r_a1.get_next_hop(p_b1.my_peer_id()).await,
+            Some(p_a2.my_peer_id())
+        );
+
+        let b1_info = r_a1.get_peer_info(p_b1.my_peer_id()).await.unwrap();
+        assert!(b1_info.is_area_border);
+        assert_eq!(
+            b1_info.area_summary,
+            Some(RouteAreaSummary {
+                prefixes: vec!["10.144.0.4/31".to_string()],
+                cost: 1,
+            })
+        );
+
+        // no per peer route for peers of other areas.
+        assert!(r_a1.get_next_hop(p_b2.my_peer_id()).await.is_none());
+        assert!(r_a1
+            .list_routes()
+            .await
+            .iter()
+            .all(|r| r.peer_id != p_b2.my_peer_id()));
+
+        // full info of b2 is never flooded into area 1.
+        let synced_info = &r_a1.service_impl.synced_route_info;
+        assert!(synced_info.get_area_id(p_b2.my_peer_id()).is_none());
+        assert!(!synced_info.conn_map.contains_key(&p_b2.my_peer_id()));
+
+        // the border node relays packets from other areas only.
+        let b2_ip = std::net::IpAddr::V4(b2_ipv4);
+        assert_eq!(
+            r_b1.get_area_relay_peer_id(p_a1.my_peer_id(), &b2_ip).await,
+            Some(p_b2.my_peer_id())
+        );
+        assert_eq!(
+            r_b1.get_area_relay_peer_id(p_b2.my_peer_id(), &b2_ip).await,
+            None
+        );
+    }
+
+    #[test]
+    fn test_aggregate_cidrs() {
+        let cidrs = [
+            "10.0.0.0/32",
+            "10.0.0.1/32",
+            "10.0.0.2/32",
+            "10.0.0.3/32",
+            "10.0.0.5/32",
+            "10.0.1.0/24",
+            "10.0.1.7/32",
+            "fd00::1/128",
+        ]
+        .iter()
+        .map(|x| x.parse().unwrap());
+        let aggregated = aggregate_cidrs(cidrs)
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            aggregated,
+            vec!["10.0.0.0/30", "10.0.0.5/32", "10.0.1.0/24", "fd00::1/128"]
+        );
+    }
+
+    #[test]
+    fn test_conn_peer_list_to_bitmap() {
+        let conn_peer_list = RouteConnPeerList {
+            peer_conn_infos: vec![
+                ((1, 3), BTreeSet::from([2, 3])),
+                ((2, 1), BTreeSet::from([1])),
+                ((3, 2), BTreeSet::from([1, 4])),
+            ],
+        };
+
+        let conn_bitmap: RouteConnBitmap = (&conn_peer_list).into();
+        assert_eq!(conn_bitmap.peer_ids, vec![(1, 3), (2, 1), (3, 2)]);
+
+        // peer 4 is not in the peer id list, so it cannot be encoded in the bitmap.
+        let decoded: RouteConnPeerList = (&conn_bitmap).into();
+        assert_eq!(decoded.peer_conn_infos[0], ((1, 3), BTreeSet::from([2, 3])));
+        assert_eq!(decoded.peer_conn_infos[1], ((2, 1), BTreeSet::from([1])));
+        assert_eq!(decoded.peer_conn_infos[2], ((3, 2), BTreeSet::from([1])));
+
+        let pb: crate::proto::peer_rpc::RouteConnPeerList = conn_peer_list.clone().into();
+        assert_eq!(RouteConnPeerList::from(pb), conn_peer_list);
+    }
+
     #[tokio::test]
     async fn test_raw_peer_info() {
         let mut req = SyncRouteInfoRequest::default();
@@ -2964,4 +3967,80 @@ mod tests {
 
         assert_eq!(req, req2);
     }
+
+    #[test]
+    fn test_route_cost_change_threshold() {
+        let f = PeerRouteServiceImpl::is_cost_change_significant;
+        assert!(f(2, 3, false));
+        assert!(!f(2, 2, false));
+        // latency jitter is not recorded.
+        assert!(!f(100, 110, true));
+        assert!(!f(1, 4, true));
+        assert!(f(100, 130, true));
+        assert!(f(100, 70, true));
+        assert!(f(10, 20, true));
+    }
+
+    #[tokio::test]
+    async fn test_route_change_history() {
+        let p_a = create_mock_pmgr().await;
+        let p_b = create_mock_pmgr().await;
+        let p_c = create_mock_pmgr().await;
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+        connect_peer_manager(p_b.clone(), p_c.clone()).await;
+
+        let r_a = create_mock_route(p_a.clone()).await;
+        let _r_b = create_mock_route(p_b.clone()).await;
+        let _r_c = create_mock_route(p_c.clone()).await;
+
+        let has_change = |r: Arc<PeerRoute>, dst: PeerId, old: Option<PeerId>, new: PeerId| async move {
+            r.list_route_history().await.iter().any(|e| {
+                e.change_type() == RouteChangeType::NextHopChanged
+                    && e.dst_peer_id == dst
+                    && e.old_next_hop_peer_id == old
+                    && e.new_next_hop_peer_id == Some(new)
+            })
+        };
+
+        wait_for_condition(
+            || has_change(r_a.clone(), p_c.my_peer_id(), None, p_b.my_peer_id()),
+            Duration::from_secs(5),
+        )
+        .await;
+        // hop count is recorded as cost when latency first is disabled.
+        assert!(r_a.list_route_history().await.iter().any(|e| {
+            e.dst_peer_id == p_c.my_peer_id() && e.old_next_hop_peer_id.is_none() && e.new_cost == 2
+        }));
+
+        let mut events = p_a.get_global_ctx().subscribe();
+        connect_peer_manager(p_a.clone(), p_c.clone()).await;
+
+        wait_for_condition(
+            || {
+                has_change(
+                    r_a.clone(),
+                    p_c.my_peer_id(),
+                    Some(p_b.my_peer_id()),
+                    p_c.my_peer_id(),
+                )
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        let expected = GlobalCtxEvent::RouteNextHopChanged(
+            p_c.my_peer_id(),
+            Some(p_b.my_peer_id()),
+            Some(p_c.my_peer_id()),
+        );
+        let mut found = false;
+        loop {
+            match events.try_recv() {
+                Ok(e) => found |= e == expected,
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+        assert!(found);
+    }
 }
@@ -33,6 +33,7 @@ impl DirectConnectorRpc for DirectConnectorManagerRpcServer {
             .config
             .get_mapped_listeners()
             .into_iter()
+            .chain(self.global_ctx.get_port_mapped_listeners().into_iter())
             .chain(self.global_ctx.get_running_listeners().into_iter())
             .map(Into::into)
             .collect();
@@ -76,6 +76,10 @@ pub trait Route {
 
     async fn list_routes(&self) -> Vec<crate::proto::cli::Route>;
 
+    async fn list_route_history(&self) -> Vec<crate::proto::cli::RouteChangeEvent> {
+        vec![]
+    }
+
     async fn get_peer_id_by_ipv4(&self, _ipv4: &Ipv4Addr) -> Option<PeerId> {
         None
     }
@@ -91,6 +95,16 @@ pub trait Route {
         }
     }
 
+    // only area border nodes relay data packets from other areas, to the peer owning the dst ip
+    // in the area of the border node.
+    async fn get_area_relay_peer_id(
+        &self,
+        _from_peer_id: PeerId,
+        _dst_ip: &std::net::IpAddr,
+    ) -> Option<PeerId> {
+        None
+    }
+
     async fn list_peers_own_foreign_network(
         &self,
         _network_identity: &NetworkIdentity,
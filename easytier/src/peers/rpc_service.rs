@@ -7,8 +7,9 @@ use crate::{
             AclManageRpc, DumpRouteRequest, DumpRouteResponse, GetAclStatsRequest,
             GetAclStatsResponse, GetWhitelistRequest, GetWhitelistResponse,
             ListForeignNetworkRequest, ListForeignNetworkResponse, ListGlobalForeignNetworkRequest,
-            ListGlobalForeignNetworkResponse, ListPeerRequest, ListPeerResponse, ListRouteRequest,
-            ListRouteResponse, PeerInfo, PeerManageRpc, SetWhitelistRequest, SetWhitelistResponse,
+            ListGlobalForeignNetworkResponse, ListPeerRequest, ListPeerResponse,
+            ListRouteHistoryRequest, ListRouteHistoryResponse, ListRouteRequest, ListRouteResponse,
+            PeerInfo, PeerManageRpc, SetWhitelistRequest, SetWhitelistResponse,
             ShowNodeInfoRequest, ShowNodeInfoResponse,
         },
         rpc_types::{self, controller::BaseController},
@@ -101,6 +102,16 @@ impl PeerManageRpc for PeerManagerRpcService {
         Ok(reply)
     }
 
+    async fn list_route_history(
+        &self,
+        _: BaseController,
+        _request: ListRouteHistoryRequest,
+    ) -> Result<ListRouteHistoryResponse, rpc_types::error::Error> {
+        Ok(ListRouteHistoryResponse {
+            events: self.peer_manager.list_route_history().await,
+        })
+    }
+
     async fn dump_route(
         &self,
         _: BaseController,
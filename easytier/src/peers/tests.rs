@@ -127,3 +127,93 @@ async fn foreign_mgr_stress_test() {
         }
     }
 }
+
+async fn total_peer_conn_tx_bytes(peer_mgrs: &[Arc<PeerManager>]) -> u64 {
+    let mut total = 0;
+    for mgr in peer_mgrs.iter() {
+        let peer_map = mgr.get_peer_map();
+        for peer_id in peer_map.list_peers().await {
+            for conn in peer_map.list_peer_conns(peer_id).await.unwrap_or_default() {
+                total += conn.stats.map(|x| x.tx_bytes).unwrap_or_default();
+            }
+        }
+    }
+    total
+}
+
+// benchmark of route sync in a large ring network, run with `cargo test -- --ignored`.
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn route_sync_ring_bench() {
+    const NODE_COUNT: usize = 1000;
+
+    let mut peer_mgrs = Vec::new();
+    for _ in 0..NODE_COUNT {
+        peer_mgrs.push(create_mock_peer_manager().await);
+    }
+
+    let now = std::time::Instant::now();
+    for (i, mgr) in peer_mgrs.iter().enumerate() {
+        connect_peer_manager(mgr.clone(), peer_mgrs[(i + 1) % NODE_COUNT].clone()).await;
+    }
+
+    loop {
+        let mut converged = 0;
+        for mgr in peer_mgrs.iter() {
+            if mgr.list_routes().await.len() == NODE_COUNT - 1 {
+                converged += 1;
+            }
+        }
+        println!(
+            "elapsed: {:?}, converged nodes: {}/{}, tx bytes: {}",
+            now.elapsed(),
+            converged,
+            NODE_COUNT,
+            total_peer_conn_tx_bytes(&peer_mgrs).await
+        );
+        if converged == NODE_COUNT {
+            break;
+        }
+        assert!(now.elapsed() < std::time::Duration::from_secs(600));
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+
+    let converge_time = now.elapsed();
+    let converge_tx_bytes = total_peer_conn_tx_bytes(&peer_mgrs).await;
+
+    // break one link, only the delta of the two endpoints should be synced.
+    let p0 = peer_mgrs[0].clone();
+    let p1_id = peer_mgrs[1].my_peer_id();
+    for conn in p0
+        .get_peer_map()
+        .list_peer_conns(p1_id)
+        .await
+        .unwrap_or_default()
+    {
+        let _ = p0
+            .get_peer_map()
+            .close_peer_conn(p1_id, &conn.conn_id.parse().unwrap())
+            .await;
+    }
+
+    let now = std::time::Instant::now();
+    loop {
+        // p0 can only reach p1 by going around the whole ring.
+        let routes = p0.list_routes().await;
+        let cost_to_p1 = routes.iter().find(|r| r.peer_id == p1_id).map(|r| r.cost);
+        if cost_to_p1 == Some((NODE_COUNT - 1) as i32) {
+            break;
+        }
+        assert!(now.elapsed() < std::time::Duration::from_secs(60));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    println!(
+        "ring of {} nodes converged in {:?} with {} bytes, link down reconverged in {:?} with {} bytes",
+        NODE_COUNT,
+        converge_time,
+        converge_tx_bytes,
+        now.elapsed(),
+        total_peer_conn_tx_bytes(&peer_mgrs).await - converge_tx_bytes
+    );
+}
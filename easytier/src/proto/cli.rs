@@ -1,5 +1,7 @@
 include!(concat!(env!("OUT_DIR"), "/cli.rs"));
 
+use std::collections::BTreeMap;
+
 impl PeerRoutePair {
     pub fn get_latency_ms(&self) -> Option<f64> {
         let mut ret = u64::MAX;
@@ -100,6 +102,98 @@ impl PeerRoutePair {
     }
 }
 
+impl TcpProxyEntry {
+    /// an entry is killed if its src matches and dst matches if given
+    pub fn matches_kill_request(&self, req: &KillTcpProxyEntryRequest) -> bool {
+        req.src.is_some() && self.src == req.src && (req.dst.is_none() || self.dst == req.dst)
+    }
+}
+
+/// Magic dns names of a node, `names[0]` is the one its services are
+/// published under.
+#[derive(Debug, Clone)]
+pub struct DnsHostName<'a> {
+    pub route: &'a Route,
+    pub names: Vec<String>,
+    // other nodes report the same hostname
+    pub conflicted: bool,
+    // the node is named by its bare hostname
+    pub holder: bool,
+}
+
+/// Tells nodes apart for magic dns: the instance id, or the peer id if the
+/// route has no instance id.
+pub fn dns_host_id(route: &Route) -> String {
+    if route.inst_id.is_empty() {
+        route.peer_id.to_string()
+    } else {
+        route.inst_id.clone()
+    }
+}
+
+/// Assigns magic dns names to nodes. Nodes are told apart by `inst_id`, or by
+/// `peer_id` if the route has no instance id. When several of them report the
+/// same hostname, the one that held it before (`holders`, lowercase hostname
+/// -> instance id or peer id) keeps it, or the one with the lowest id if there
+/// is no holder any more, and every one of them is also named
+/// `<hostname>-<first 8 chars of instance id or peer id>`.
+pub fn assign_dns_host_names<'a>(
+    routes: impl IntoIterator<Item = &'a Route>,
+    holders: &BTreeMap<String, String>,
+) -> Vec<DnsHostName<'a>> {
+    // hostname -> (instance id or peer id, routes of the instance) in the
+    // order they are first seen, one instance may be reported by several clients
+    let mut by_hostname: BTreeMap<String, Vec<(String, Vec<&Route>)>> = BTreeMap::new();
+    for route in routes {
+        if route.hostname.is_empty() {
+            continue;
+        }
+        let id = dns_host_id(route);
+        let instances = by_hostname
+            .entry(route.hostname.to_lowercase())
+            .or_default();
+        match instances.iter_mut().find(|(x, _)| *x == id) {
+            Some((_, routes)) => routes.push(route),
+            None => instances.push((id, vec![route])),
+        }
+    }
+
+    let mut ret = vec![];
+    for (hostname, instances) in by_hostname {
+        let conflicted = instances.len() > 1;
+        let holder = holders
+            .get(&hostname)
+            .and_then(|inst_id| instances.iter().position(|(id, _)| id == inst_id))
+            .or_else(|| {
+                instances
+                    .iter()
+                    .enumerate()
+                    .min_by(|(_, (a, _)), (_, (b, _))| a.cmp(b))
+                    .map(|(i, _)| i)
+            })
+            .unwrap_or(0);
+        for (i, (id, routes)) in instances.into_iter().enumerate() {
+            let suffix = id.chars().take(8).collect::<String>();
+            for route in routes {
+                let mut names = vec![];
+                if i == holder {
+                    names.push(route.hostname.clone());
+                }
+                if conflicted {
+                    names.push(format!("{}-{}", route.hostname, suffix));
+                }
+                ret.push(DnsHostName {
+                    route,
+                    names,
+                    conflicted,
+                    holder: i == holder,
+                });
+            }
+        }
+    }
+    ret
+}
+
 pub fn list_peer_route_pair(peers: Vec<PeerInfo>, routes: Vec<Route>) -> Vec<PeerRoutePair> {
     let mut pairs: Vec<PeerRoutePair> = vec![];
 
@@ -2,6 +2,7 @@
 
 use core::panic;
 use std::{
+    net::SocketAddr,
     sync::{atomic::AtomicU32, Arc},
     time::Duration,
 };
@@ -17,8 +18,20 @@ use crate::{
         netns::{NetNS, ROOT_NETNS_NAME},
         stats_manager::{LabelType, MetricName},
     },
+    gateway::{
+        icmp_proxy::IcmpProxyRpcService, tcp_proxy::TcpProxyRpcService,
+        udp_proxy::UdpProxyRpcService,
+    },
     instance::instance::Instance,
-    proto::{cli::TcpProxyEntryTransportType, common::CompressionAlgoPb},
+    proto::{
+        cli::{
+            IcmpProxyRpc, KillIcmpProxyEntryRequest, KillTcpProxyEntryRequest,
+            KillUdpProxyEntryRequest, ListUdpProxyEntryRequest, TcpProxyEntryTransportType,
+            TcpProxyRpc, UdpProxyEntry, UdpProxyRpc,
+        },
+        common::CompressionAlgoPb,
+        rpc_types::controller::BaseController,
+    },
     tunnel::{
         common::tests::{_tunnel_bench_netns, wait_for_condition},
         ring::RingTunnelConnector,
@@ -613,6 +626,310 @@ pub async fn subnet_proxy_three_node_test(
     drop_insts(insts).await;
 }
 
+async fn list_udp_proxy_entries(inst: &Instance) -> Vec<UdpProxyEntry> {
+    UdpProxyRpcService::new(inst.get_udp_proxy().unwrap())
+        .list_udp_proxy_entry(
+            BaseController::default(),
+            ListUdpProxyEntryRequest::default(),
+        )
+        .await
+        .unwrap()
+        .entries
+}
+
+#[rstest::rstest]
+#[tokio::test]
+#[serial_test::serial]
+pub async fn udp_proxy_nat_test(#[values("default", "full_cone", "port_restricted")] mode: &str) {
+    let insts = init_three_node_ex(
+        "udp",
+        |cfg| {
+            if cfg.get_inst_name() == "inst3" {
+                let mut flags = cfg.get_flags();
+                flags.udp_proxy_full_cone = mode == "full_cone";
+                flags.udp_proxy_port_restricted = mode == "port_restricted";
+                cfg.set_flags(flags);
+                cfg.add_proxy_cidr("10.1.2.0/24".parse().unwrap(), None)
+                    .unwrap();
+                cfg.set_udp_proxy_timeouts(vec!["22240:5".parse().unwrap()]);
+            }
+            cfg
+        },
+        false,
+    )
+    .await;
+
+    wait_proxy_route_appear(
+        &insts[0].get_peer_manager(),
+        "10.144.144.3/24",
+        insts[2].peer_id(),
+        "10.1.2.0/24",
+    )
+    .await;
+
+    let (server, other, long_server) = {
+        let _g = NetNS::new(Some("net_d".into())).guard();
+        (
+            UdpSocket::bind("10.1.2.4:22240").await.unwrap(),
+            UdpSocket::bind("10.1.2.4:22241").await.unwrap(),
+            UdpSocket::bind("10.1.2.4:22243").await.unwrap(),
+        )
+    };
+    let (client, long_client) = {
+        let _g = NetNS::new(Some("net_a".into())).guard();
+        (
+            UdpSocket::bind("0.0.0.0:22242").await.unwrap(),
+            UdpSocket::bind("0.0.0.0:22244").await.unwrap(),
+        )
+    };
+
+    let mut buf = [0u8; 64];
+    let mut mapped = None;
+    for _ in 0..10 {
+        client.send_to(b"hello", "10.1.2.4:22240").await.unwrap();
+        let ret = tokio::time::timeout(Duration::from_secs(1), server.recv_from(&mut buf)).await;
+        if let Ok(Ok((_, from))) = ret {
+            mapped = Some(from);
+            break;
+        }
+    }
+    let mapped = mapped.expect("udp proxy does not forward to the proxied subnet");
+    assert_eq!(mapped.ip().to_string(), "10.1.2.3");
+    if mode == "full_cone" {
+        // the source port is kept
+        assert_eq!(mapped.port(), 22242);
+    }
+
+    server.send_to(b"reply", mapped).await.unwrap();
+    let (_, from) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(from, "10.1.2.4:22240".parse::<SocketAddr>().unwrap());
+
+    // an endpoint the source never sent to
+    other.send_to(b"other", mapped).await.unwrap();
+    let ret = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf)).await;
+    if mode == "port_restricted" {
+        assert!(ret.is_err(), "{:?}", ret);
+    } else {
+        let (_, from) = ret.unwrap().unwrap();
+        assert_eq!(from, "10.1.2.4:22241".parse::<SocketAddr>().unwrap());
+    }
+
+    long_client
+        .send_to(b"hello", "10.1.2.4:22243")
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), long_server.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let entries = list_udp_proxy_entries(&insts[2]).await;
+    assert_eq!(entries.len(), 2, "{:?}", entries);
+    let entry = entries
+        .iter()
+        .find(|e| e.src.map(SocketAddr::from) == "10.144.144.1:22242".parse().ok())
+        .unwrap();
+    assert_eq!(
+        entry.dst.map(SocketAddr::from),
+        "10.1.2.4:22240".parse().ok()
+    );
+    assert_eq!(
+        entry.mapped.map(|x| SocketAddr::from(x).port()),
+        Some(mapped.port())
+    );
+    assert_eq!(entry.idle_timeout, 5);
+    assert_eq!(entry.full_cone, mode == "full_cone");
+    assert_eq!(entry.port_restricted, mode == "port_restricted");
+    assert!(entry.tx_packets >= 1);
+    assert!(entry.rx_packets >= 1);
+    let long_entry = entries
+        .iter()
+        .find(|e| e.src.map(SocketAddr::from) == "10.144.144.1:22244".parse().ok())
+        .unwrap();
+    assert_eq!(long_entry.idle_timeout, 180);
+
+    // only the entry with the short timeout of its port expires
+    let inst3 = &insts[2];
+    wait_for_condition(
+        || async move { list_udp_proxy_entries(inst3).await.len() == 1 },
+        Duration::from_secs(30),
+    )
+    .await;
+    let entries = list_udp_proxy_entries(&insts[2]).await;
+    assert_eq!(
+        entries[0].src.map(SocketAddr::from),
+        "10.144.144.1:22244".parse().ok()
+    );
+
+    drop_insts(insts).await;
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn proxy_entry_list_and_kill_test() {
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    let insts = init_three_node_ex(
+        "udp",
+        |cfg| {
+            if cfg.get_inst_name() == "inst3" {
+                cfg.add_proxy_cidr("10.1.2.0/24".parse().unwrap(), None)
+                    .unwrap();
+            }
+            cfg
+        },
+        false,
+    )
+    .await;
+
+    wait_proxy_route_appear(
+        &insts[0].get_peer_manager(),
+        "10.144.144.3/24",
+        insts[2].peer_id(),
+        "10.1.2.0/24",
+    )
+    .await;
+
+    let ctrl = BaseController::default;
+
+    // icmp
+    let icmp_rpc = IcmpProxyRpcService::new(insts[2].get_icmp_proxy().unwrap());
+    subnet_proxy_test_icmp("10.1.2.4").await;
+    let entries = icmp_rpc
+        .list_icmp_proxy_entry(ctrl(), Default::default())
+        .await
+        .unwrap()
+        .entries;
+    assert!(!entries.is_empty());
+    for entry in entries.iter() {
+        assert_eq!(
+            entry.src.map(|x| x.to_string()),
+            Some("10.144.144.1".into())
+        );
+        assert_eq!(entry.dst.map(|x| x.to_string()), Some("10.1.2.4".into()));
+        assert!(entry.tx_packets >= 1 && entry.rx_packets >= 1);
+    }
+    let killed = icmp_rpc
+        .kill_icmp_proxy_entry(
+            ctrl(),
+            KillIcmpProxyEntryRequest {
+                src: Some("10.144.144.1".parse::<std::net::Ipv4Addr>().unwrap().into()),
+                dst: None,
+            },
+        )
+        .await
+        .unwrap()
+        .killed;
+    assert_eq!(killed as usize, entries.len());
+    let entries = icmp_rpc
+        .list_icmp_proxy_entry(ctrl(), Default::default())
+        .await
+        .unwrap()
+        .entries;
+    assert!(entries.is_empty(), "{:?}", entries);
+
+    // udp
+    let udp_rpc = UdpProxyRpcService::new(insts[2].get_udp_proxy().unwrap());
+    let server = {
+        let _g = NetNS::new(Some("net_d".into())).guard();
+        UdpSocket::bind("10.1.2.4:22251").await.unwrap()
+    };
+    let client = {
+        let _g = NetNS::new(Some("net_a".into())).guard();
+        UdpSocket::bind("0.0.0.0:22252").await.unwrap()
+    };
+    let (server, client) = (&server, &client);
+    let send_udp = || async move {
+        let mut buf = [0u8; 64];
+        for _ in 0..10 {
+            client.send_to(b"hello", "10.1.2.4:22251").await.unwrap();
+            let ret =
+                tokio::time::timeout(Duration::from_secs(1), server.recv_from(&mut buf)).await;
+            if matches!(ret, Ok(Ok(_))) {
+                return;
+            }
+        }
+        panic!("udp proxy does not forward to the proxied subnet");
+    };
+    send_udp().await;
+    let udp_src: SocketAddr = "10.144.144.1:22252".parse().unwrap();
+    let entries = list_udp_proxy_entries(&insts[2]).await;
+    assert_eq!(entries.len(), 1, "{:?}", entries);
+    assert_eq!(entries[0].src.map(SocketAddr::from), Some(udp_src));
+    assert!(entries[0].tx_bytes >= 5);
+    let udp_rpc = &udp_rpc;
+    let kill_udp = || async move {
+        udp_rpc
+            .kill_udp_proxy_entry(
+                ctrl(),
+                KillUdpProxyEntryRequest {
+                    src: Some(udp_src.into()),
+                },
+            )
+            .await
+            .unwrap()
+            .killed
+    };
+    assert_eq!(kill_udp().await, 1);
+    assert!(list_udp_proxy_entries(&insts[2]).await.is_empty());
+    assert_eq!(kill_udp().await, 0);
+    // the flow gets a new entry with its next packet
+    send_udp().await;
+    assert_eq!(list_udp_proxy_entries(&insts[2]).await.len(), 1);
+
+    // tcp
+    let tcp_rpc = TcpProxyRpcService::new(insts[2].get_tcp_proxy().unwrap());
+    let listener = {
+        let _g = NetNS::new(Some("net_d".into())).guard();
+        TcpListener::bind("10.1.2.4:22253").await.unwrap()
+    };
+    let client = {
+        let _g = NetNS::new(Some("net_a".into())).guard();
+        TcpStream::connect("10.1.2.4:22253").await.unwrap()
+    };
+    let (mut server, _) = listener.accept().await.unwrap();
+    let tcp_src: SocketAddr = client.local_addr().unwrap();
+    let entries = tcp_rpc
+        .list_tcp_proxy_entry(ctrl(), Default::default())
+        .await
+        .unwrap()
+        .entries;
+    assert!(
+        entries
+            .iter()
+            .any(|e| e.src.map(SocketAddr::from) == Some(tcp_src)),
+        "{:?}",
+        entries
+    );
+    let killed = tcp_rpc
+        .kill_tcp_proxy_entry(
+            ctrl(),
+            KillTcpProxyEntryRequest {
+                src: Some(tcp_src.into()),
+                dst: None,
+            },
+        )
+        .await
+        .unwrap()
+        .killed;
+    assert_eq!(killed, 1);
+    // the proxied connection is closed
+    let mut buf = [0u8; 64];
+    let ret = tokio::time::timeout(Duration::from_secs(5), server.read(&mut buf))
+        .await
+        .unwrap();
+    assert!(matches!(ret, Ok(0) | Err(_)), "{:?}", ret);
+
+    drop(client);
+    drop_insts(insts).await;
+}
+
 #[rstest::rstest]
 #[tokio::test]
 #[serial_test::serial]
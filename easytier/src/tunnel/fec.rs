@@ -0,0 +1,614 @@
+// XOR parity forward error correction for lossy tunnels (udp).
+//
+// Data packets are sent in groups, every group is followed by a parity packet which
+// is the xor of all (length prefixed) packets in the group, so one lost packet per
+// group can be rebuilt by the receiver. The group size follows the loss rate measured
+// by the peer conn pinger, and no parity is sent while the link is clean. A group not
+// filled before the sender goes idle is closed with the parity of the packets sent so far.
+//
+// Peer conns only wrap udp tunnels when enable_fec is set, and the sender is enabled
+// only after both sides announced the feature in the handshake, so fec packets are
+// never sent to a peer which does not decode them.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use futures::{SinkExt, Stream, StreamExt};
+use tokio::{sync::mpsc, time::Instant};
+use tokio_util::sync::PollSender;
+use zerocopy::{AsBytes, FromBytes};
+
+use crate::{common::PeerId, proto::common::TunnelInfo};
+
+use super::{
+    packet_def::{FecHeader, PacketType, ZCPacket, ZCPacketType, FEC_HEADER_SIZE},
+    SinkError, SplitTunnel, StreamItem, Tunnel, TunnelError, ZCPacketSink, ZCPacketStream,
+};
+
+/// handshake feature announced by peers supporting fec.
+pub const FEC_FEATURE: &str = "fec";
+
+const FEC_KIND_DATA: u8 = 0;
+const FEC_KIND_PARITY: u8 = 1;
+
+// groups kept for recovery, older ones are dropped.
+const MAX_RECV_GROUPS: usize = 64;
+// a partial group gets its parity after the sender is idle for this long.
+const PARTIAL_GROUP_FLUSH_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// map the loss rate (percent) to the number of data packets per parity packet.
+/// 0 means no fec.
+pub fn group_size_for_loss_rate(loss_percent: u32) -> u8 {
+    match loss_percent {
+        0 => 0,
+        1..=2 => 10,
+        3..=5 => 5,
+        6..=10 => 3,
+        _ => 2,
+    }
+}
+
+pub struct FecCtrl {
+    enabled: AtomicBool,
+    // loss rate in percent, updated by the pinger.
+    loss_rate: Arc<AtomicU32>,
+}
+
+impl FecCtrl {
+    pub fn new(loss_rate: Arc<AtomicU32>) -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            loss_rate,
+        }
+    }
+
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn group_size(&self) -> u8 {
+        if !self.is_enabled() {
+            return 0;
+        }
+        group_size_for_loss_rate(self.loss_rate.load(Ordering::Relaxed))
+    }
+}
+
+fn xor_into(parity: &mut Vec<u8>, data: &[u8]) {
+    let len = data.len() as u16;
+    let total = data.len() + 2;
+    if parity.len() < total {
+        parity.resize(total, 0);
+    }
+    for (p, d) in parity.iter_mut().zip(len.to_le_bytes().iter().chain(data)) {
+        *p ^= *d;
+    }
+}
+
+fn new_fec_packet(peer_ids: (PeerId, PeerId), hdr: FecHeader, body: &[u8]) -> ZCPacket {
+    let mut payload = Vec::with_capacity(FEC_HEADER_SIZE + body.len());
+    payload.extend_from_slice(hdr.as_bytes());
+    payload.extend_from_slice(body);
+
+    let mut ret = ZCPacket::new_with_payload(&payload);
+    ret.fill_peer_manager_hdr(peer_ids.0, peer_ids.1, PacketType::Fec as u8);
+    ret
+}
+
+#[derive(Default)]
+struct FecEncoder {
+    group_seq: u32,
+    group_size: u8,
+    index: u8,
+    parity: Vec<u8>,
+    // src and dst peer id of the last data packet, used by the parity packet.
+    peer_ids: (PeerId, PeerId),
+    last_send: Option<Instant>,
+}
+
+impl FecEncoder {
+    fn encode(&mut self, ctrl: &FecCtrl, packet: ZCPacket, out: &mut VecDeque<ZCPacket>) {
+        if !packet.is_lossy() {
+            out.push_back(packet);
+            return;
+        }
+
+        if self.index == 0 {
+            self.group_size = ctrl.group_size();
+        }
+        if self.group_size == 0 {
+            out.push_back(packet);
+            return;
+        }
+
+        self.peer_ids = (
+            packet.get_src_peer_id().unwrap_or_default(),
+            packet.get_dst_peer_id().unwrap_or_default(),
+        );
+        let hdr = FecHeader::new(self.group_seq, self.index, self.group_size, FEC_KIND_DATA);
+        let data = new_fec_packet(self.peer_ids, hdr, packet.tunnel_payload());
+        xor_into(&mut self.parity, packet.tunnel_payload());
+        out.push_back(data);
+
+        self.index += 1;
+        self.last_send = Some(Instant::now());
+        if self.index == self.group_size {
+            self.finish_group(out);
+        }
+    }
+
+    // the index of parity is the count of data packets in the group, which is less than
+    // the group size for a partial group.
+    fn finish_group(&mut self, out: &mut VecDeque<ZCPacket>) {
+        let hdr = FecHeader::new(self.group_seq, self.index, self.group_size, FEC_KIND_PARITY);
+        out.push_back(new_fec_packet(self.peer_ids, hdr, &self.parity));
+
+        self.group_seq = self.group_seq.wrapping_add(1);
+        self.index = 0;
+        self.parity.clear();
+        self.last_send = None;
+    }
+
+    fn flush_deadline(&self) -> Option<Instant> {
+        if self.index == 0 {
+            return None;
+        }
+        self.last_send.map(|x| x + PARTIAL_GROUP_FLUSH_TIMEOUT)
+    }
+
+    fn flush_partial_group(&mut self, out: &mut VecDeque<ZCPacket>) {
+        if self.index > 0 {
+            self.finish_group(out);
+        }
+    }
+}
+
+struct FecGroup {
+    // recovered packets are filled in too, so late originals are not delivered twice.
+    data: Vec<Option<BytesMut>>,
+    // count of data packets, less than data.len() if the group is flushed partially.
+    data_count: usize,
+    parity: Option<BytesMut>,
+    done: bool,
+}
+
+#[derive(Default)]
+struct FecDecoder {
+    groups: HashMap<u32, FecGroup>,
+    order: VecDeque<u32>,
+    // packets of groups at or before the last evicted one are too late to be useful.
+    last_evicted: Option<u32>,
+}
+
+impl FecDecoder {
+    fn is_evicted(&self, group_seq: u32) -> bool {
+        self.last_evicted
+            .is_some_and(|last| (last.wrapping_sub(group_seq) as i32) >= 0)
+    }
+
+    fn decode(&mut self, packet: ZCPacket, out: &mut VecDeque<ZCPacket>) {
+        let is_fec = packet
+            .peer_manager_header()
+            .map(|hdr| hdr.packet_type == PacketType::Fec as u8)
+            .unwrap_or(false);
+        if !is_fec {
+            out.push_back(packet);
+            return;
+        }
+
+        let payload = packet.payload();
+        let Some(hdr) = FecHeader::ref_from_prefix(payload) else {
+            tracing::trace!(?packet, "invalid fec packet");
+            return;
+        };
+        let group_seq = hdr.group_seq.get();
+        let (index, group_size, kind) = (hdr.index as usize, hdr.group_size as usize, hdr.kind);
+        if group_size == 0 || index > group_size {
+            tracing::trace!(?hdr, "invalid fec header");
+            return;
+        }
+        let body = BytesMut::from(&payload[FEC_HEADER_SIZE..]);
+
+        if !self.groups.contains_key(&group_seq) {
+            if self.is_evicted(group_seq) {
+                tracing::trace!(?group_seq, "drop fec packet of evicted group");
+                return;
+            }
+            if self.order.len() >= MAX_RECV_GROUPS {
+                if let Some(old) = self.order.pop_front() {
+                    self.groups.remove(&old);
+                    if !self.is_evicted(old) {
+                        self.last_evicted = Some(old);
+                    }
+                }
+            }
+            self.order.push_back(group_seq);
+            self.groups.insert(
+                group_seq,
+                FecGroup {
+                    data: vec![None; group_size],
+                    data_count: group_size,
+                    parity: None,
+                    done: false,
+                },
+            );
+        }
+        let group = self.groups.get_mut(&group_seq).unwrap();
+        if group.data.len() != group_size {
+            tracing::trace!(?group_seq, "fec group size mismatch");
+            return;
+        }
+
+        match kind {
+            FEC_KIND_DATA if index < group.data_count => {
+                if group.data[index].is_some() {
+                    tracing::trace!(?group_seq, ?index, "drop duplicated fec data packet");
+                    return;
+                }
+                out.push_back(ZCPacket::new_from_buf(
+                    body.clone(),
+                    ZCPacketType::DummyTunnel,
+                ));
+                group.data[index] = Some(body);
+            }
+            FEC_KIND_PARITY if index > 0 && group.parity.is_none() => {
+                group.data_count = index;
+                group.parity = Some(body);
+            }
+            _ => {
+                tracing::trace!(?hdr, "unexpected fec packet");
+                return;
+            }
+        }
+
+        if let Some(recovered) = Self::try_recover(group) {
+            tracing::trace!(?group_seq, "fec recovered lost packet");
+            out.push_back(recovered);
+        }
+    }
+
+    fn try_recover(group: &mut FecGroup) -> Option<ZCPacket> {
+        if group.done {
+            return None;
+        }
+        let data = &group.data[..group.data_count];
+        let mut missing = data.iter().enumerate().filter(|(_, d)| d.is_none());
+        let Some((missing_idx, _)) = missing.next() else {
+            group.done = true;
+            return None;
+        };
+        if missing.next().is_some() {
+            return None;
+        }
+        let parity = group.parity.as_ref()?;
+        group.done = true;
+
+        let mut buf = parity.to_vec();
+        for data in data.iter().flatten() {
+            xor_into(&mut buf, data);
+        }
+        if buf.len() < 2 {
+            return None;
+        }
+        let len = u16::from_le_bytes([buf[0], buf[1]]) as usize;
+        if len + 2 > buf.len() {
+            return None;
+        }
+        let recovered = BytesMut::from(&buf[2..2 + len]);
+        group.data[missing_idx] = Some(recovered.clone());
+        Some(ZCPacket::new_from_buf(recovered, ZCPacketType::DummyTunnel))
+    }
+}
+
+// the sender runs in its own task, so the parity of a partial group can be sent on
+// a timer even if no more packets come in.
+struct FecSender {
+    sink: Pin<Box<dyn ZCPacketSink>>,
+    ctrl: Arc<FecCtrl>,
+    encoder: FecEncoder,
+    pending: VecDeque<ZCPacket>,
+}
+
+impl FecSender {
+    async fn send_pending(&mut self) -> Result<(), SinkError> {
+        while let Some(item) = self.pending.pop_front() {
+            self.sink.feed(item).await?;
+        }
+        Ok(())
+    }
+
+    async fn run(mut self, mut rx: mpsc::Receiver<ZCPacket>) {
+        loop {
+            let deadline = self.encoder.flush_deadline();
+            let ret = tokio::select! {
+                item = rx.recv() => {
+                    let Some(item) = item else {
+                        let _ = self.sink.close().await;
+                        return;
+                    };
+                    self.encoder.encode(&self.ctrl, item, &mut self.pending);
+                    while let Ok(item) = rx.try_recv() {
+                        self.encoder.encode(&self.ctrl, item, &mut self.pending);
+                    }
+                    self.send_pending().await
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.encoder.flush_partial_group(&mut self.pending);
+                    self.send_pending().await
+                }
+            };
+            if let Err(e) = ret.and(self.sink.flush().await) {
+                tracing::debug!(?e, "fec sender exit");
+                return;
+            }
+        }
+    }
+}
+
+struct FecStream<S> {
+    stream: S,
+    decoder: FecDecoder,
+    pending: VecDeque<ZCPacket>,
+}
+
+impl<S: ZCPacketStream + Unpin> Stream for FecStream<S> {
+    type Item = StreamItem;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let self_mut = self.get_mut();
+        loop {
+            if let Some(packet) = self_mut.pending.pop_front() {
+                return Poll::Ready(Some(Ok(packet)));
+            }
+            match ready!(self_mut.stream.poll_next_unpin(cx)) {
+                Some(Ok(packet)) => self_mut.decoder.decode(packet, &mut self_mut.pending),
+                ret => return Poll::Ready(ret),
+            }
+        }
+    }
+}
+
+pub struct FecTunnel<T> {
+    inner: T,
+    ctrl: Arc<FecCtrl>,
+}
+
+impl<T: Tunnel> FecTunnel<T> {
+    pub fn new(inner: T, ctrl: Arc<FecCtrl>) -> Self {
+        Self { inner, ctrl }
+    }
+}
+
+impl<T: Tunnel> Tunnel for FecTunnel<T> {
+    fn split(&self) -> SplitTunnel {
+        let (stream, sink) = self.inner.split();
+        let (tx, rx) = mpsc::channel(32);
+        let sender = FecSender {
+            sink,
+            ctrl: self.ctrl.clone(),
+            encoder: FecEncoder::default(),
+            pending: VecDeque::new(),
+        };
+        tokio::spawn(sender.run(rx));
+        (
+            Box::pin(FecStream {
+                stream,
+                decoder: FecDecoder::default(),
+                pending: VecDeque::new(),
+            }),
+            Box::pin(PollSender::new(tx).sink_map_err(|_| TunnelError::Shutdown)),
+        )
+    }
+
+    fn info(&self) -> Option<TunnelInfo> {
+        self.inner.info()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tunnel::ring::create_ring_tunnel_pair;
+
+    use super::*;
+
+    fn data_packet(i: usize) -> ZCPacket {
+        let payload = vec![i as u8; 10 + i * 3];
+        let mut packet = ZCPacket::new_with_payload(&payload);
+        packet.fill_peer_manager_hdr(1, 2, PacketType::Data as u8);
+        packet
+    }
+
+    fn encode_all(ctrl: &FecCtrl, count: usize) -> Vec<ZCPacket> {
+        let mut encoder = FecEncoder::default();
+        let mut out = VecDeque::new();
+        for i in 0..count {
+            encoder.encode(ctrl, data_packet(i), &mut out);
+        }
+        out.into_iter().collect()
+    }
+
+    #[test]
+    fn fec_disabled_or_clean_link_pass_through() {
+        let loss_rate = Arc::new(AtomicU32::new(5));
+        let ctrl = FecCtrl::new(loss_rate.clone());
+        let out = encode_all(&ctrl, 5);
+        assert_eq!(out.len(), 5);
+        assert!(out.iter().all(|p| p.is_lossy()));
+
+        ctrl.enable();
+        loss_rate.store(0, Ordering::Relaxed);
+        let out = encode_all(&ctrl, 5);
+        assert_eq!(out.len(), 5);
+        assert!(out.iter().all(|p| p.is_lossy()));
+    }
+
+    #[test]
+    fn fec_recover_one_lost_packet_per_group() {
+        let ctrl = FecCtrl::new(Arc::new(AtomicU32::new(5)));
+        ctrl.enable();
+        let group_size = group_size_for_loss_rate(5) as usize;
+
+        // two full groups, each with a parity packet
+        let encoded = encode_all(&ctrl, group_size * 2);
+        assert_eq!(encoded.len(), (group_size + 1) * 2);
+
+        let mut decoder = FecDecoder::default();
+        let mut out = VecDeque::new();
+        for (i, p) in encoded.into_iter().enumerate() {
+            // lose the second packet of first group and the last data packet of the second group
+            if i == 1 || i == group_size * 2 {
+                continue;
+            }
+            decoder.decode(p.convert_type(ZCPacketType::UDP), &mut out);
+        }
+
+        let mut payloads = out
+            .into_iter()
+            .map(|p| {
+                let hdr = p.peer_manager_header().unwrap();
+                assert_eq!(hdr.packet_type, PacketType::Data as u8);
+                p.payload().to_vec()
+            })
+            .collect::<Vec<_>>();
+        payloads.sort_by_key(|p| p.len());
+        let expected = (0..group_size * 2)
+            .map(|i| data_packet(i).payload().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(payloads, expected);
+    }
+
+    #[test]
+    fn fec_can_not_recover_two_lost_packets() {
+        let ctrl = FecCtrl::new(Arc::new(AtomicU32::new(5)));
+        ctrl.enable();
+        let group_size = group_size_for_loss_rate(5) as usize;
+
+        let encoded = encode_all(&ctrl, group_size);
+        let mut decoder = FecDecoder::default();
+        let mut out = VecDeque::new();
+        for (i, p) in encoded.into_iter().enumerate() {
+            if i == 0 || i == 1 {
+                continue;
+            }
+            decoder.decode(p, &mut out);
+        }
+        assert_eq!(out.len(), group_size - 2);
+    }
+
+    #[test]
+    fn fec_recover_lost_packet_of_partial_group() {
+        let ctrl = FecCtrl::new(Arc::new(AtomicU32::new(5)));
+        ctrl.enable();
+        let group_size = group_size_for_loss_rate(5) as usize;
+        assert!(group_size > 2);
+
+        let mut encoder = FecEncoder::default();
+        let mut encoded = VecDeque::new();
+        for i in 0..2 {
+            encoder.encode(&ctrl, data_packet(i), &mut encoded);
+        }
+        assert!(encoder.flush_deadline().is_some());
+        encoder.flush_partial_group(&mut encoded);
+        assert!(encoder.flush_deadline().is_none());
+        assert_eq!(encoded.len(), 3);
+
+        let mut decoder = FecDecoder::default();
+        let mut out = VecDeque::new();
+        for (i, p) in encoded.into_iter().enumerate() {
+            if i == 0 {
+                continue;
+            }
+            decoder.decode(p, &mut out);
+        }
+        let payloads = out.iter().map(|p| p.payload().to_vec()).collect::<Vec<_>>();
+        assert_eq!(
+            payloads,
+            vec![
+                data_packet(1).payload().to_vec(),
+                data_packet(0).payload().to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn fec_drop_late_packet_already_recovered() {
+        let ctrl = FecCtrl::new(Arc::new(AtomicU32::new(5)));
+        ctrl.enable();
+        let group_size = group_size_for_loss_rate(5) as usize;
+
+        let encoded = encode_all(&ctrl, group_size);
+        let mut decoder = FecDecoder::default();
+        let mut out = VecDeque::new();
+        for p in encoded.iter().skip(1) {
+            decoder.decode(p.clone(), &mut out);
+        }
+        assert_eq!(out.len(), group_size);
+
+        // the original of the recovered packet comes late, and duplicated packets
+        decoder.decode(encoded[0].clone(), &mut out);
+        decoder.decode(encoded[1].clone(), &mut out);
+        assert_eq!(out.len(), group_size);
+    }
+
+    #[tokio::test]
+    async fn fec_tunnel_flush_partial_group() {
+        let (a, b) = create_ring_tunnel_pair();
+        let ctrl_a = Arc::new(FecCtrl::new(Arc::new(AtomicU32::new(10))));
+        ctrl_a.enable();
+        let a = FecTunnel::new(a, ctrl_a);
+
+        let (_, mut a_sink) = a.split();
+        let (mut b_stream, _) = b.split();
+
+        a_sink.send(data_packet(0)).await.unwrap();
+        let p = b_stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            p.peer_manager_header().unwrap().packet_type,
+            PacketType::Fec as u8
+        );
+
+        // the parity of the partial group is sent after the sender is idle
+        let p = tokio::time::timeout(PARTIAL_GROUP_FLUSH_TIMEOUT * 10, b_stream.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let hdr = FecHeader::ref_from_prefix(p.payload()).unwrap();
+        assert_eq!(hdr.kind, FEC_KIND_PARITY);
+        assert_eq!(hdr.index, 1);
+    }
+
+    #[tokio::test]
+    async fn fec_tunnel_pingpong() {
+        let (a, b) = create_ring_tunnel_pair();
+        let ctrl_a = Arc::new(FecCtrl::new(Arc::new(AtomicU32::new(10))));
+        ctrl_a.enable();
+        let a = FecTunnel::new(a, ctrl_a);
+        let b = FecTunnel::new(b, Arc::new(FecCtrl::new(Arc::new(AtomicU32::new(0)))));
+
+        let (_, mut a_sink) = a.split();
+        let (mut b_stream, _) = b.split();
+
+        let group_size = group_size_for_loss_rate(10) as usize;
+        for i in 0..group_size {
+            a_sink.send(data_packet(i)).await.unwrap();
+        }
+        for i in 0..group_size {
+            let p = b_stream.next().await.unwrap().unwrap();
+            assert_eq!(p.payload(), data_packet(i).payload());
+        }
+    }
+}
@@ -344,6 +344,38 @@ pub mod tests {
         }
     }
 
+    // drops everything in both directions once cut, like a link that goes dark.
+    #[derive(Default)]
+    pub struct LinkCutTunnelFilter {
+        cut: std::sync::atomic::AtomicBool,
+    }
+
+    impl TunnelFilter for LinkCutTunnelFilter {
+        type FilterOutput = ();
+
+        fn before_send(&self, data: SinkItem) -> Option<SinkItem> {
+            if self.cut.load(Ordering::SeqCst) {
+                return None;
+            }
+            Some(data)
+        }
+
+        fn after_received(&self, data: StreamItem) -> Option<StreamItem> {
+            if self.cut.load(Ordering::SeqCst) {
+                return None;
+            }
+            Some(data)
+        }
+
+        fn filter_output(&self) {}
+    }
+
+    impl LinkCutTunnelFilter {
+        pub fn cut(&self) {
+            self.cut.store(true, Ordering::SeqCst);
+        }
+    }
+
     #[tokio::test]
     async fn test_nested_filter() {
         let filter = Arc::new(
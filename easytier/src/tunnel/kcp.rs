@@ -0,0 +1,550 @@
+// KCP as a peer tunnel transport.
+//
+// A kcp tunnel is a udp tunnel carrying the packets of one kcp conversation, the kcp
+// stream on top of it is framed like a tcp tunnel. Each tunnel owns its own kcp
+// endpoint, so the same code serves the kcp:// listener / connector and udp hole
+// punching, where the udp tunnel is created from a punched socket.
+//
+// Tunables are taken from url query parameters, e.g.
+// kcp://1.2.3.4:11013?nodelay=1&interval=10&resend=2&nc=1&snd_wnd=256&rcv_wnd=256&mtu=1200
+// Hole punched tunnels have no url of their own, both sides use the tunables of the
+// local kcp listener instead.
+//
+// On a udp listener shared by udp and kcp peers, the packet type of the first packet
+// tells the protocol: the kcp client always starts with a kcp syn, a udp client with
+// the peer conn handshake.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use kcp_sys::{
+    endpoint::{ConnId, KcpEndpoint},
+    ffi_safe::KcpConfig,
+    packet_def::KcpPacket,
+    stream::KcpStream,
+};
+use tokio::{net::UdpSocket, task::JoinSet};
+
+use crate::proto::common::TunnelInfo;
+
+use super::{
+    common::{FramedReader, FramedWriter, TunnelWrapper},
+    packet_def::{PacketType, ZCPacket},
+    udp::{UdpTunnelConnector, UdpTunnelListener},
+    IpVersion, Tunnel, TunnelConnCounter, TunnelConnector, TunnelError, TunnelListener,
+};
+
+const KCP_MTU_BYTES: usize = 2000;
+const KCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// a peer whose hole punch listener does not speak kcp never answers the kcp syn
+const KCP_PUNCH_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KcpTunnelConfig {
+    nodelay: Option<bool>,
+    interval: Option<u32>,
+    resend: Option<u32>,
+    nc: Option<bool>,
+    snd_wnd: Option<u32>,
+    rcv_wnd: Option<u32>,
+    mtu: Option<u32>,
+}
+
+fn query_value<T: std::str::FromStr>(url: &url::Url, key: &str) -> Option<T> {
+    let (_, v) = url.query_pairs().find(|(k, _)| k == key)?;
+    match v.as_ref() {
+        "true" => "1".parse().ok(),
+        "false" => "0".parse().ok(),
+        v => v.parse().ok(),
+    }
+}
+
+fn query_flag(url: &url::Url, key: &str) -> Option<bool> {
+    query_value::<u32>(url, key).map(|v| v != 0)
+}
+
+impl KcpTunnelConfig {
+    /// Tunables of the first kcp listener of this node, used for hole punched tunnels.
+    pub fn from_listeners(listeners: &[url::Url]) -> Self {
+        listeners
+            .iter()
+            .find(|l| l.scheme() == "kcp")
+            .map(Self::from_url)
+            .unwrap_or_default()
+    }
+
+    pub fn from_url(url: &url::Url) -> Self {
+        Self {
+            nodelay: query_flag(url, "nodelay"),
+            interval: query_value(url, "interval"),
+            resend: query_value(url, "resend"),
+            nc: query_flag(url, "nc"),
+            snd_wnd: query_value(url, "snd_wnd"),
+            rcv_wnd: query_value(url, "rcv_wnd"),
+            mtu: query_value(url, "mtu"),
+        }
+    }
+
+    fn build(&self, conv: u32) -> KcpConfig {
+        let mut cfg = KcpConfig::new_turbo(conv);
+        cfg.interval = Some(self.interval.unwrap_or(5) as _);
+        if let Some(v) = self.nodelay {
+            cfg.nodelay = Some(v as _);
+        }
+        if let Some(v) = self.resend {
+            cfg.resend = Some(v as _);
+        }
+        if let Some(v) = self.nc {
+            cfg.nc = Some(v as _);
+        }
+        if let Some(v) = self.snd_wnd {
+            cfg.snd_wnd = Some(v as _);
+        }
+        if let Some(v) = self.rcv_wnd {
+            cfg.rcv_wnd = Some(v as _);
+        }
+        if let Some(v) = self.mtu {
+            cfg.mtu = Some(v as _);
+        }
+        cfg
+    }
+}
+
+fn url_with_scheme(url: &url::Url, scheme: &str) -> url::Url {
+    let mut ret = url.clone();
+    // udp and kcp are both non-special schemes, so this never fails
+    let _ = ret.set_scheme(scheme);
+    ret
+}
+
+fn kcp_tunnel_info(info: Option<TunnelInfo>, remote_url: Option<&url::Url>) -> TunnelInfo {
+    let mut info = info.unwrap_or_default();
+    info.tunnel_type = "kcp".to_owned();
+    info.local_addr = info
+        .local_addr
+        .map(|addr| url_with_scheme(&addr.into(), "kcp").into());
+    info.remote_addr = match remote_url {
+        Some(url) => Some(url.clone().into()),
+        None => info
+            .remote_addr
+            .map(|addr| url_with_scheme(&addr.into(), "kcp").into()),
+    };
+    info
+}
+
+fn is_kcp_packet(packet: &ZCPacket) -> bool {
+    packet
+        .peer_manager_header()
+        .is_some_and(|hdr| hdr.packet_type == PacketType::KcpTunnel as u8)
+}
+
+/// Runs a kcp conversation over the udp tunnel `inner` and returns the framed
+/// tunnel on top of the kcp stream. `first_packet` is a packet already read from
+/// `inner` by the caller.
+async fn build_kcp_tunnel(
+    inner: Box<dyn Tunnel>,
+    is_client: bool,
+    config: KcpTunnelConfig,
+    remote_url: Option<&url::Url>,
+    first_packet: Option<ZCPacket>,
+) -> Result<Box<dyn Tunnel>, TunnelError> {
+    let info = kcp_tunnel_info(inner.info(), remote_url);
+    let (mut stream, mut sink) = inner.split();
+
+    let mut endpoint = KcpEndpoint::new();
+    endpoint.set_kcp_config_factory(Box::new(move |conv| config.build(conv)));
+    endpoint.run().await;
+    let mut output_receiver = endpoint
+        .output_receiver()
+        .ok_or(anyhow::anyhow!("kcp endpoint has no output receiver"))?;
+    let endpoint = Arc::new(endpoint);
+
+    let mut tasks = JoinSet::new();
+    tasks.spawn(async move {
+        while let Some(packet) = output_receiver.recv().await {
+            let mut packet = ZCPacket::new_with_payload(&packet.inner().freeze());
+            packet.fill_peer_manager_hdr(0, 0, PacketType::KcpTunnel as u8);
+            if let Err(e) = sink.send(packet).await {
+                tracing::debug!(?e, "kcp tunnel send to udp failed");
+                break;
+            }
+        }
+    });
+
+    if let Some(packet) = first_packet {
+        let _ = endpoint
+            .input_sender_ref()
+            .send(KcpPacket::from(packet.payload_bytes()))
+            .await;
+    }
+    let endpoint_clone = endpoint.clone();
+    tasks.spawn(async move {
+        while let Some(Ok(packet)) = stream.next().await {
+            if !is_kcp_packet(&packet) {
+                continue;
+            }
+            if endpoint_clone
+                .input_sender_ref()
+                .send(KcpPacket::from(packet.payload_bytes()))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let conn_id: ConnId = if is_client {
+        endpoint
+            .connect(
+                KCP_CONNECT_TIMEOUT,
+                rand::random(),
+                rand::random(),
+                Bytes::new(),
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("kcp connect failed: {:?}", e))?
+    } else {
+        tokio::time::timeout(KCP_CONNECT_TIMEOUT, endpoint.accept())
+            .await?
+            .map_err(|e| anyhow::anyhow!("kcp accept failed: {:?}", e))?
+    };
+    let kcp_stream =
+        KcpStream::new(&endpoint, conn_id).ok_or(anyhow::anyhow!("failed to create kcp stream"))?;
+
+    let (r, w) = tokio::io::split(kcp_stream);
+    Ok(Box::new(TunnelWrapper::new(
+        // the read half keeps the endpoint and the udp pumps alive
+        FramedReader::new_with_associate_data(r, KCP_MTU_BYTES, Some(Box::new((endpoint, tasks)))),
+        FramedWriter::new(w),
+        Some(info),
+    )))
+}
+
+/// Starts a kcp client conversation over a udp tunnel.
+pub async fn kcp_tunnel_over_udp(
+    inner: Box<dyn Tunnel>,
+    config: KcpTunnelConfig,
+) -> Result<Box<dyn Tunnel>, TunnelError> {
+    build_kcp_tunnel(inner, true, config, None, None).await
+}
+
+/// Used by listeners shared by udp and kcp peers (hole punching): the type of
+/// the first packet tells whether the peer speaks kcp on this udp tunnel. Both
+/// clients send first, so a silent peer fails like a peer conn handshake would.
+pub async fn accept_maybe_kcp(
+    inner: Box<dyn Tunnel>,
+    config: KcpTunnelConfig,
+) -> Result<Box<dyn Tunnel>, TunnelError> {
+    let info = inner.info();
+    let (mut stream, sink) = inner.split();
+    let first = tokio::time::timeout(KCP_CONNECT_TIMEOUT, stream.next())
+        .await?
+        .ok_or(TunnelError::Shutdown)??;
+
+    if !is_kcp_packet(&first) {
+        // give the first packet back to the peer conn
+        return Ok(Box::new(TunnelWrapper::new(
+            futures::stream::iter([Ok(first)]).chain(stream),
+            sink,
+            info,
+        )));
+    }
+    build_kcp_tunnel(
+        Box::new(TunnelWrapper::new(stream, sink, info)),
+        false,
+        config,
+        None,
+        Some(first),
+    )
+    .await
+}
+
+pub struct KcpTunnelListener {
+    addr: url::Url,
+    config: KcpTunnelConfig,
+    inner: UdpTunnelListener,
+    handshakes: JoinSet<Result<Box<dyn Tunnel>, TunnelError>>,
+}
+
+impl KcpTunnelListener {
+    pub fn new(addr: url::Url) -> Self {
+        let config = KcpTunnelConfig::from_url(&addr);
+        let inner = UdpTunnelListener::new(url_with_scheme(&addr, "udp"));
+        Self {
+            addr,
+            config,
+            inner,
+            handshakes: JoinSet::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl TunnelListener for KcpTunnelListener {
+    async fn listen(&mut self) -> Result<(), TunnelError> {
+        if self.addr.scheme() != "kcp" {
+            return Err(TunnelError::InvalidProtocol(self.addr.scheme().to_string()));
+        }
+        self.inner.listen().await?;
+        self.addr
+            .set_port(self.inner.local_url().port())
+            .map_err(|_| TunnelError::InvalidAddr(self.addr.to_string()))?;
+        Ok(())
+    }
+
+    async fn accept(&mut self) -> Result<Box<dyn Tunnel>, TunnelError> {
+        loop {
+            tokio::select! {
+                ret = self.inner.accept() => {
+                    let inner = ret?;
+                    let config = self.config;
+                    // handshakes run in background so a slow peer does not block others
+                    self.handshakes.spawn(async move {
+                        build_kcp_tunnel(inner, false, config, None, None).await
+                    });
+                }
+                Some(ret) = self.handshakes.join_next() => {
+                    match ret {
+                        Ok(Ok(tunnel)) => return Ok(tunnel),
+                        ret => tracing::warn!(?ret, "kcp tunnel handshake failed"),
+                    }
+                }
+            }
+        }
+    }
+
+    fn local_url(&self) -> url::Url {
+        self.addr.clone()
+    }
+
+    fn get_conn_counter(&self) -> Arc<Box<dyn TunnelConnCounter>> {
+        self.inner.get_conn_counter()
+    }
+}
+
+pub struct KcpTunnelConnector {
+    addr: url::Url,
+    config: KcpTunnelConfig,
+    inner: UdpTunnelConnector,
+}
+
+impl KcpTunnelConnector {
+    pub fn new(addr: url::Url) -> Self {
+        let config = KcpTunnelConfig::from_url(&addr);
+        let inner = UdpTunnelConnector::new(url_with_scheme(&addr, "udp"));
+        Self {
+            addr,
+            config,
+            inner,
+        }
+    }
+
+    /// Overrides the tunables of the url, e.g. with the ones of the local kcp
+    /// listener for hole punched tunnels.
+    pub fn set_config(&mut self, config: KcpTunnelConfig) {
+        self.config = config;
+    }
+
+    /// Connects over a hole punched socket. When the peer does not answer the
+    /// kcp handshake, e.g. an old version without kcp, the tunnel falls back to
+    /// plain udp on the same socket.
+    pub async fn try_connect_with_socket(
+        &self,
+        socket: Arc<UdpSocket>,
+        addr: SocketAddr,
+    ) -> Result<Box<dyn Tunnel>, TunnelError> {
+        let inner = self
+            .inner
+            .try_connect_with_socket(socket.clone(), addr)
+            .await?;
+        let kcp = build_kcp_tunnel(inner, true, self.config, Some(&self.addr), None);
+        match tokio::time::timeout(KCP_PUNCH_CONNECT_TIMEOUT, kcp).await {
+            Ok(ret) => ret,
+            Err(_) => {
+                tracing::info!(?addr, "kcp handshake timeout, fallback to udp");
+                // let the dropped tunnel stop reading the socket, it would swallow the sack
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                self.inner.try_connect_with_socket(socket, addr).await
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for KcpTunnelConnector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KcpTunnelConnector")
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl TunnelConnector for KcpTunnelConnector {
+    async fn connect(&mut self) -> Result<Box<dyn Tunnel>, TunnelError> {
+        if self.addr.scheme() != "kcp" {
+            return Err(TunnelError::InvalidProtocol(self.addr.scheme().to_string()));
+        }
+        let inner = self.inner.connect().await?;
+        build_kcp_tunnel(inner, true, self.config, Some(&self.addr), None).await
+    }
+
+    fn remote_url(&self) -> url::Url {
+        self.addr.clone()
+    }
+
+    fn set_bind_addrs(&mut self, addrs: Vec<SocketAddr>) {
+        self.inner.set_bind_addrs(addrs);
+    }
+
+    fn set_ip_version(&mut self, ip_version: IpVersion) {
+        self.inner.set_ip_version(ip_version);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tunnel::common::tests::{
+        _tunnel_bench, _tunnel_echo_server, _tunnel_pingpong, ListeningTunnelListener,
+    };
+
+    use super::*;
+
+    // listens on a port picked by the os and returns a connector to it
+    async fn listen_on_free_port(
+        query: &str,
+    ) -> (
+        ListeningTunnelListener<KcpTunnelListener>,
+        KcpTunnelConnector,
+    ) {
+        let mut listener =
+            KcpTunnelListener::new(format!("kcp://127.0.0.1:0{}", query).parse().unwrap());
+        listener.listen().await.unwrap();
+        let port = listener.local_url().port().unwrap();
+        let connector = KcpTunnelConnector::new(
+            format!("kcp://127.0.0.1:{}{}", port, query)
+                .parse()
+                .unwrap(),
+        );
+        (ListeningTunnelListener(listener), connector)
+    }
+
+    #[tokio::test]
+    async fn kcp_pingpong() {
+        let (listener, connector) = listen_on_free_port("").await;
+        _tunnel_pingpong(listener, connector).await
+    }
+
+    #[tokio::test]
+    async fn kcp_bench() {
+        let (listener, connector) = listen_on_free_port("?nodelay=1&snd_wnd=512&rcv_wnd=512").await;
+        _tunnel_bench(listener, connector).await
+    }
+
+    #[tokio::test]
+    async fn kcp_punch_fallback_to_udp() {
+        // the hole punch listener of a peer without kcp
+        let mut listener = UdpTunnelListener::new("udp://127.0.0.1:0".parse().unwrap());
+        listener.listen().await.unwrap();
+        let addr: SocketAddr = format!("127.0.0.1:{}", listener.local_url().port().unwrap())
+            .parse()
+            .unwrap();
+        let lis = tokio::spawn(async move {
+            // the kcp syn is never answered
+            let _kcp_attempt = listener.accept().await.unwrap();
+            let udp = listener.accept().await.unwrap();
+            _tunnel_echo_server(udp, false).await
+        });
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let connector = KcpTunnelConnector::new(format!("kcp://{}", addr).parse().unwrap());
+        let client = connector
+            .try_connect_with_socket(socket, addr)
+            .await
+            .unwrap();
+        assert_eq!(client.info().unwrap().tunnel_type, "udp");
+
+        let (mut recv, mut send) = client.split();
+        send.send(ZCPacket::new_with_payload(b"hello"))
+            .await
+            .unwrap();
+        let packet = tokio::time::timeout(Duration::from_secs(5), recv.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(packet.payload(), b"hello");
+        lis.abort();
+    }
+
+    #[test]
+    fn kcp_config_from_url() {
+        let cfg = KcpTunnelConfig::from_url(
+            &"kcp://1.2.3.4:1?nodelay=true&interval=20&nc=0&mtu=1200"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(cfg.nodelay, Some(true));
+        assert_eq!(cfg.interval, Some(20));
+        assert_eq!(cfg.nc, Some(false));
+        assert_eq!(cfg.mtu, Some(1200));
+        assert_eq!(cfg.resend, None);
+
+        let cfg = KcpTunnelConfig::from_listeners(&[
+            "tcp://0.0.0.0:11010".parse().unwrap(),
+            "kcp://0.0.0.0:11013?interval=10".parse().unwrap(),
+        ]);
+        assert_eq!(cfg.interval, Some(10));
+    }
+
+    #[tokio::test]
+    async fn accept_maybe_kcp_detects_both() {
+        let mut listener = UdpTunnelListener::new("udp://127.0.0.1:0".parse().unwrap());
+        listener.listen().await.unwrap();
+        let port = listener.local_url().port().unwrap();
+        let remote_url = move |scheme: &str| -> url::Url {
+            format!("{}://127.0.0.1:{}", scheme, port).parse().unwrap()
+        };
+
+        // plain udp peer, the first packet must be replayed
+        let mut connector = UdpTunnelConnector::new(remote_url("udp"));
+        let client = connector.connect().await.unwrap();
+        let (_, mut client_sink) = client.split();
+        client_sink
+            .send(ZCPacket::new_with_payload(b"hello"))
+            .await
+            .unwrap();
+        let server = accept_maybe_kcp(listener.accept().await.unwrap(), Default::default())
+            .await
+            .unwrap();
+        assert_eq!(server.info().unwrap().tunnel_type, "udp");
+        let (mut server_stream, _) = server.split();
+        let packet = server_stream.next().await.unwrap().unwrap();
+        assert_eq!(packet.payload(), b"hello");
+
+        // kcp peer on the same listener
+        let client = tokio::spawn(async move {
+            let mut connector = UdpTunnelConnector::new(remote_url("udp"));
+            let inner = connector.connect().await.unwrap();
+            kcp_tunnel_over_udp(inner, Default::default())
+                .await
+                .unwrap()
+        });
+        let server = accept_maybe_kcp(listener.accept().await.unwrap(), Default::default())
+            .await
+            .unwrap();
+        let client = client.await.unwrap();
+        assert_eq!(server.info().unwrap().tunnel_type, "kcp");
+
+        let (_, mut client_sink) = client.split();
+        let (mut server_stream, _) = server.split();
+        client_sink
+            .send(ZCPacket::new_with_payload(b"over kcp"))
+            .await
+            .unwrap();
+        let packet = server_stream.next().await.unwrap().unwrap();
+        assert_eq!(packet.payload(), b"over kcp");
+    }
+}
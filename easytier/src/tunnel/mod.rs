@@ -15,13 +15,18 @@ use self::packet_def::ZCPacket;
 
 pub mod buf;
 pub mod common;
+pub mod fec;
 pub mod filter;
+pub mod kcp;
 pub mod mpsc;
+pub mod obfs;
 pub mod packet_def;
 pub mod ring;
 pub mod stats;
 pub mod tcp;
 pub mod udp;
+pub mod udp_batch;
+pub mod upstream_proxy;
 
 pub const PROTO_PORT_OFFSET: &[(&str, u16)] =
     &[("tcp", 0), ("udp", 0), ("wg", 1), ("ws", 1), ("wss", 2)];
@@ -201,6 +206,7 @@ fn default_port(scheme: &str) -> Option<u16> {
         "ws" => Some(11011),
         "wss" => Some(11012),
         "quic" => Some(11012),
+        "kcp" => Some(11013),
         "wg" => Some(11011),
         _ => None,
     }
@@ -0,0 +1,654 @@
+// Traffic obfuscation for all tunnel types, enabled by `?obfs=<key>` in the listener
+// or connector url, e.g. `udp://1.2.3.4:11010?obfs=my-key`. Both sides must use the
+// same key.
+//
+// tcp and udp obfuscate everything they put on the wire. A tcp stream starts with a
+// random nonce and a random length padding, and all bytes after the nonce (length
+// prefix included) are xored with a ChaCha20 keystream derived from the key and the
+// nonce. Every udp datagram, handshake included, gets a random length padding and a
+// random nonce at its tail, and the rest of the datagram is xored the same way.
+// Other transports have their own handshakes (tls, quic, wireguard), only the peer
+// manager header and payload of each packet are obfuscated like a udp datagram.
+// So no fixed byte pattern or exact packet size is left on the wire.
+//
+// This is obfuscation against protocol classification, not encryption. The peer
+// traffic still relies on the network secret for confidentiality.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use async_trait::async_trait;
+use bytes::{Buf, BytesMut};
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::{
+    filter::{TunnelFilter, TunnelWithFilter},
+    IpVersion, SinkItem, StreamItem, Tunnel, TunnelConnector, TunnelError, TunnelListener,
+};
+
+/// url query key to enable obfuscation of a listener or connector.
+pub const OBFS_QUERY_KEY: &str = "obfs";
+
+const NONCE_LEN: usize = 12;
+const MAX_STREAM_PADDING: usize = 255;
+const MAX_PACKET_PADDING: usize = 32;
+// packets larger than this are not padded, so obfuscation adds at most
+// NONCE_LEN + 1 bytes to a full sized packet.
+const MAX_PADDED_PACKET_SIZE: usize = 1400;
+// bytes written to the inner stream at most for one poll_write.
+const MAX_WRITE_CHUNK: usize = 64 * 1024;
+
+pub type ObfsKey = [u8; 32];
+
+fn derive_key(key: &str) -> ObfsKey {
+    let mut hasher = Sha256::new();
+    hasher.update(b"easytier-obfs");
+    hasher.update(key.as_bytes());
+    hasher.finalize().into()
+}
+
+/// removes the obfs query from the url and returns the derived key, so the key is
+/// not exposed by the listener urls published to other peers or by tunnel info.
+pub fn take_obfs_key_from_url(url: &mut url::Url) -> Option<ObfsKey> {
+    let key = url
+        .query_pairs()
+        .find(|(k, _)| k == OBFS_QUERY_KEY)
+        .map(|(_, v)| v.into_owned())?;
+
+    let others = url
+        .query_pairs()
+        .filter(|(k, _)| k != OBFS_QUERY_KEY)
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect::<Vec<_>>();
+    if others.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(others);
+    }
+
+    if key.is_empty() {
+        return None;
+    }
+    Some(derive_key(&key))
+}
+
+/// the url without the obfs query, same as the url in tunnel info.
+pub fn remove_obfs_key_from_url(url: &str) -> String {
+    let Ok(mut parsed) = url::Url::parse(url) else {
+        return url.to_owned();
+    };
+    if !parsed.query_pairs().any(|(k, _)| k == OBFS_QUERY_KEY) {
+        return url.to_owned();
+    }
+    take_obfs_key_from_url(&mut parsed);
+    parsed.to_string()
+}
+
+struct KeyStream {
+    rng: ChaCha20Rng,
+    block: [u8; 64],
+    pos: usize,
+}
+
+impl KeyStream {
+    fn new(key: &ObfsKey, nonce: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(key);
+        hasher.update(nonce);
+        Self {
+            rng: ChaCha20Rng::from_seed(hasher.finalize().into()),
+            block: [0; 64],
+            pos: 64,
+        }
+    }
+
+    // keystream is generated in whole blocks, so the result does not depend on how
+    // the data is chunked.
+    fn apply(&mut self, data: &mut [u8]) {
+        for b in data.iter_mut() {
+            if self.pos == self.block.len() {
+                self.rng.fill_bytes(&mut self.block);
+                self.pos = 0;
+            }
+            *b ^= self.block[self.pos];
+            self.pos += 1;
+        }
+    }
+}
+
+// pads and xors `buf[offset..]`, the nonce is appended to the tail.
+fn obfs_buf(key: &ObfsKey, buf: &mut BytesMut, offset: usize) {
+    let len = buf.len() - offset;
+    let room = MAX_PADDED_PACKET_SIZE.saturating_sub(len + 1);
+    let mut rng = rand::thread_rng();
+    let pad_len = rng.gen_range(0..=MAX_PACKET_PADDING.min(room));
+
+    let mut tail = [0u8; MAX_PACKET_PADDING + 1 + NONCE_LEN];
+    rng.fill_bytes(&mut tail[..pad_len]);
+    tail[pad_len] = pad_len as u8;
+    rng.fill_bytes(&mut tail[pad_len + 1..pad_len + 1 + NONCE_LEN]);
+
+    buf.extend_from_slice(&tail[..pad_len + 1 + NONCE_LEN]);
+    let (body, nonce) = buf[offset..].split_at_mut(len + pad_len + 1);
+    KeyStream::new(key, nonce).apply(body);
+}
+
+fn deobfs_buf(key: &ObfsKey, buf: &mut BytesMut, offset: usize) -> Result<(), TunnelError> {
+    let len = buf.len() - offset;
+    if len < NONCE_LEN + 1 {
+        return Err(TunnelError::InvalidPacket(
+            "obfs packet too short".to_owned(),
+        ));
+    }
+
+    let (body, nonce) = buf[offset..].split_at_mut(len - NONCE_LEN);
+    KeyStream::new(key, nonce).apply(body);
+    let pad_len = body[body.len() - 1] as usize;
+    if pad_len + 1 > body.len() {
+        return Err(TunnelError::InvalidPacket(
+            "invalid obfs padding".to_owned(),
+        ));
+    }
+    let new_len = offset + body.len() - pad_len - 1;
+    buf.truncate(new_len);
+    Ok(())
+}
+
+/// obfuscates a whole udp datagram in place.
+pub fn obfs_datagram(key: &ObfsKey, buf: &mut BytesMut) {
+    obfs_buf(key, buf, 0)
+}
+
+/// restores a datagram obfuscated by [`obfs_datagram`].
+pub fn deobfs_datagram(key: &ObfsKey, buf: &mut BytesMut) -> Result<(), TunnelError> {
+    deobfs_buf(key, buf, 0)
+}
+
+enum ReadState {
+    Nonce { buf: [u8; NONCE_LEN], filled: usize },
+    PaddingLen,
+    Padding(usize),
+    Data,
+}
+
+/// obfuscates all bytes of a stream, used by the tcp tunnel below the framing.
+pub struct ObfsStream<S> {
+    inner: S,
+    key: ObfsKey,
+
+    read_state: ReadState,
+    read_ks: Option<KeyStream>,
+
+    write_ks: KeyStream,
+    // obfuscated bytes not yet written to inner stream
+    write_buf: BytesMut,
+}
+
+impl<S> ObfsStream<S> {
+    pub fn new(inner: S, key: ObfsKey) -> Self {
+        let mut rng = rand::thread_rng();
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+        let mut write_ks = KeyStream::new(&key, &nonce);
+
+        let pad_len = rng.gen_range(0..=MAX_STREAM_PADDING);
+        let mut preamble = vec![0u8; 1 + pad_len];
+        preamble[0] = pad_len as u8;
+        rng.fill_bytes(&mut preamble[1..]);
+        write_ks.apply(&mut preamble);
+
+        let mut write_buf = BytesMut::with_capacity(NONCE_LEN + preamble.len());
+        write_buf.extend_from_slice(&nonce);
+        write_buf.extend_from_slice(&preamble);
+
+        Self {
+            inner,
+            key,
+            read_state: ReadState::Nonce {
+                buf: [0; NONCE_LEN],
+                filled: 0,
+            },
+            read_ks: None,
+            write_ks,
+            write_buf,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> ObfsStream<S> {
+    // read the nonce and skip the padding. return false on eof.
+    fn poll_read_preamble(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        loop {
+            match &mut self.read_state {
+                ReadState::Nonce { buf, filled } => {
+                    let mut read_buf = ReadBuf::new(&mut buf[*filled..]);
+                    ready!(Pin::new(&mut self.inner).poll_read(cx, &mut read_buf))?;
+                    let n = read_buf.filled().len();
+                    if n == 0 {
+                        return Poll::Ready(Ok(false));
+                    }
+                    *filled += n;
+                    if *filled == NONCE_LEN {
+                        self.read_ks = Some(KeyStream::new(&self.key, &buf[..]));
+                        self.read_state = ReadState::PaddingLen;
+                    }
+                }
+                ReadState::PaddingLen => {
+                    let mut b = [0u8; 1];
+                    let mut read_buf = ReadBuf::new(&mut b);
+                    ready!(Pin::new(&mut self.inner).poll_read(cx, &mut read_buf))?;
+                    if read_buf.filled().is_empty() {
+                        return Poll::Ready(Ok(false));
+                    }
+                    self.read_ks.as_mut().unwrap().apply(&mut b);
+                    self.read_state = ReadState::Padding(b[0] as usize);
+                }
+                ReadState::Padding(0) => {
+                    self.read_state = ReadState::Data;
+                }
+                ReadState::Padding(remain) => {
+                    let mut pad = [0u8; MAX_STREAM_PADDING];
+                    let mut read_buf = ReadBuf::new(&mut pad[..*remain]);
+                    ready!(Pin::new(&mut self.inner).poll_read(cx, &mut read_buf))?;
+                    let n = read_buf.filled().len();
+                    if n == 0 {
+                        return Poll::Ready(Ok(false));
+                    }
+                    // keep the keystream in sync
+                    self.read_ks.as_mut().unwrap().apply(&mut pad[..n]);
+                    *remain -= n;
+                }
+                ReadState::Data => return Poll::Ready(Ok(true)),
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> ObfsStream<S> {
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ObfsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let self_mut = self.get_mut();
+        if !ready!(self_mut.poll_read_preamble(cx))? {
+            return Poll::Ready(Ok(()));
+        }
+
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self_mut.inner).poll_read(cx, buf))?;
+        self_mut
+            .read_ks
+            .as_mut()
+            .unwrap()
+            .apply(&mut buf.filled_mut()[before..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ObfsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let self_mut = self.get_mut();
+        ready!(self_mut.poll_write_buf(cx))?;
+
+        let len = data.len().min(MAX_WRITE_CHUNK);
+        let start = self_mut.write_buf.len();
+        self_mut.write_buf.extend_from_slice(&data[..len]);
+        self_mut.write_ks.apply(&mut self_mut.write_buf[start..]);
+
+        // the data is buffered, try to send it but do not wait.
+        if let Poll::Ready(Err(e)) = self_mut.poll_write_buf(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let self_mut = self.get_mut();
+        ready!(self_mut.poll_write_buf(cx))?;
+        Pin::new(&mut self_mut.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let self_mut = self.get_mut();
+        ready!(self_mut.poll_write_buf(cx))?;
+        Pin::new(&mut self_mut.inner).poll_shutdown(cx)
+    }
+}
+
+/// pads and obfuscates the peer manager header and payload of each packet. tcp
+/// uses it to hide the packet sizes inside the obfuscated stream, the other
+/// non-udp transports to obfuscate the packets.
+pub struct ObfsFilter {
+    key: ObfsKey,
+}
+
+impl ObfsFilter {
+    pub fn new(key: ObfsKey) -> Self {
+        Self { key }
+    }
+}
+
+impl TunnelFilter for ObfsFilter {
+    type FilterOutput = ();
+
+    fn before_send(&self, mut data: SinkItem) -> Option<SinkItem> {
+        let offset = data
+            .packet_type()
+            .get_packet_offsets()
+            .peer_manager_header_offset;
+        obfs_buf(&self.key, data.mut_inner(), offset);
+        Some(data)
+    }
+
+    fn after_received(&self, data: StreamItem) -> Option<StreamItem> {
+        let mut data = match data {
+            Ok(v) => v,
+            Err(e) => return Some(Err(e)),
+        };
+        let offset = data
+            .packet_type()
+            .get_packet_offsets()
+            .peer_manager_header_offset;
+        if let Err(e) = deobfs_buf(&self.key, data.mut_inner(), offset) {
+            return Some(Err(e));
+        }
+        Some(Ok(data))
+    }
+
+    fn filter_output(&self) {}
+}
+
+fn wrap_tunnel(tunnel: Box<dyn Tunnel>, key: ObfsKey) -> Box<dyn Tunnel> {
+    Box::new(TunnelWithFilter::new(tunnel, ObfsFilter::new(key)))
+}
+
+pub struct ObfsTunnelListener<L> {
+    inner: L,
+    key: ObfsKey,
+}
+
+impl<L: TunnelListener> ObfsTunnelListener<L> {
+    pub fn new(inner: L, key: ObfsKey) -> Self {
+        Self { inner, key }
+    }
+}
+
+#[async_trait]
+impl<L: TunnelListener> TunnelListener for ObfsTunnelListener<L> {
+    async fn listen(&mut self) -> Result<(), TunnelError> {
+        self.inner.listen().await
+    }
+
+    async fn accept(&mut self) -> Result<Box<dyn Tunnel>, TunnelError> {
+        let tunnel = self.inner.accept().await?;
+        Ok(wrap_tunnel(tunnel, self.key))
+    }
+
+    fn local_url(&self) -> url::Url {
+        self.inner.local_url()
+    }
+
+    fn get_conn_counter(&self) -> std::sync::Arc<Box<dyn super::TunnelConnCounter>> {
+        self.inner.get_conn_counter()
+    }
+}
+
+pub struct ObfsTunnelConnector<C> {
+    inner: C,
+    key: ObfsKey,
+}
+
+impl<C: TunnelConnector> ObfsTunnelConnector<C> {
+    pub fn new(inner: C, key: ObfsKey) -> Self {
+        Self { inner, key }
+    }
+}
+
+#[async_trait]
+impl<C: TunnelConnector> TunnelConnector for ObfsTunnelConnector<C> {
+    async fn connect(&mut self) -> Result<Box<dyn Tunnel>, TunnelError> {
+        let tunnel = self.inner.connect().await?;
+        Ok(wrap_tunnel(tunnel, self.key))
+    }
+
+    fn remote_url(&self) -> url::Url {
+        self.inner.remote_url()
+    }
+
+    fn set_bind_addrs(&mut self, addrs: Vec<std::net::SocketAddr>) {
+        self.inner.set_bind_addrs(addrs);
+    }
+
+    fn set_ip_version(&mut self, ip_version: IpVersion) {
+        self.inner.set_ip_version(ip_version);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, UdpSocket},
+    };
+    use zerocopy::AsBytes;
+
+    use crate::tunnel::{
+        common::tests::_tunnel_pingpong,
+        packet_def::{
+            PacketType, UdpPacketType, ZCPacket, ZCPacketType, TCP_TUNNEL_HEADER_SIZE,
+            UDP_TUNNEL_HEADER_SIZE,
+        },
+        tcp::{TcpTunnelConnector, TcpTunnelListener},
+        udp::{UdpTunnelConnector, UdpTunnelListener},
+    };
+
+    use super::*;
+
+    fn obfs_connector(url: &str) -> TcpTunnelConnector {
+        let mut url: url::Url = url.parse().unwrap();
+        let key = take_obfs_key_from_url(&mut url);
+        let mut connector = TcpTunnelConnector::new(url);
+        connector.set_obfs_key(key);
+        connector
+    }
+
+    #[test]
+    fn obfs_key_removed_from_url() {
+        let mut url: url::Url = "udp://1.1.1.1:1?obfs=abc&x=1".parse().unwrap();
+        assert!(take_obfs_key_from_url(&mut url).is_some());
+        assert_eq!(url.as_str(), "udp://1.1.1.1:1?x=1");
+
+        let mut url: url::Url = "tcp://1.1.1.1:1?obfs=abc".parse().unwrap();
+        let key = take_obfs_key_from_url(&mut url);
+        assert_eq!(key, Some(derive_key("abc")));
+        assert_eq!(url.as_str(), "tcp://1.1.1.1:1");
+
+        let mut url: url::Url = "tcp://1.1.1.1:1".parse().unwrap();
+        assert!(take_obfs_key_from_url(&mut url).is_none());
+    }
+
+    #[tokio::test]
+    async fn obfs_stream_roundtrip() {
+        let key = derive_key("abc");
+        let (a, b) = tokio::io::duplex(7);
+        let mut a = ObfsStream::new(a, key);
+        let mut b = ObfsStream::new(b, key);
+
+        let data = (0..10000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let data_clone = data.clone();
+        let writer = tokio::spawn(async move {
+            a.write_all(&data_clone).await.unwrap();
+            a.shutdown().await.unwrap();
+        });
+
+        let mut out = vec![];
+        b.read_to_end(&mut out).await.unwrap();
+        writer.await.unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[tokio::test]
+    async fn obfs_tcp_pingpong() {
+        let mut listener = TcpTunnelListener::new("tcp://0.0.0.0:31031".parse().unwrap());
+        listener.set_obfs_key(Some(derive_key("abc")));
+        let connector = obfs_connector("tcp://127.0.0.1:31031?obfs=abc");
+        _tunnel_pingpong(listener, connector).await
+    }
+
+    #[tokio::test]
+    async fn obfs_udp_pingpong() {
+        let key = derive_key("abc");
+        let mut listener = UdpTunnelListener::new("udp://0.0.0.0:31032".parse().unwrap());
+        listener.set_obfs_key(Some(key));
+        let mut connector = UdpTunnelConnector::new("udp://127.0.0.1:31032".parse().unwrap());
+        connector.set_obfs_key(Some(key));
+        _tunnel_pingpong(listener, connector).await
+    }
+
+    async fn capture_tcp_wire_bytes(obfs: bool, packet: &ZCPacket, count: usize) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let capture = tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            let mut out = vec![];
+            s.read_to_end(&mut out).await.unwrap();
+            out
+        });
+
+        let url = if obfs {
+            format!("tcp://127.0.0.1:{}?obfs=abc", port)
+        } else {
+            format!("tcp://127.0.0.1:{}", port)
+        };
+        let mut connector = obfs_connector(&url);
+        let tunnel = connector.connect().await.unwrap();
+        let (_r, mut s) = tunnel.split();
+        for _ in 0..count {
+            s.send(packet.clone()).await.unwrap();
+        }
+        s.close().await.unwrap();
+        capture.await.unwrap()
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[tokio::test]
+    async fn obfs_no_fixed_pattern_on_wire() {
+        let mut packet = ZCPacket::new_with_payload(b"obfs-test-payload-0123456789");
+        packet.fill_peer_manager_hdr(0x11223344, 0x55667788, PacketType::Data as u8);
+        let pm_hdr = packet.peer_manager_header().unwrap().as_bytes().to_vec();
+
+        // without obfs the length prefix, header and payload are visible
+        let plain = capture_tcp_wire_bytes(false, &packet, 4).await;
+        let len_prefix = plain[..TCP_TUNNEL_HEADER_SIZE].to_vec();
+        assert!(contains(&plain, &pm_hdr));
+        assert!(contains(&plain, b"obfs-test-payload"));
+
+        let a = capture_tcp_wire_bytes(true, &packet, 4).await;
+        let b = capture_tcp_wire_bytes(true, &packet, 4).await;
+        for wire in [&a, &b] {
+            assert!(!contains(wire, &len_prefix));
+            assert!(!contains(wire, &pm_hdr));
+            assert!(!contains(wire, b"obfs-test-payload"));
+        }
+        // same packets on two connections look different from the first byte
+        assert_ne!(a[..NONCE_LEN], b[..NONCE_LEN]);
+
+        // udp, the handshake is captured by a plain socket acting as the listener
+        let key = derive_key("abc");
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut connector = UdpTunnelConnector::new(
+            format!("udp://127.0.0.1:{}", server.local_addr().unwrap().port())
+                .parse()
+                .unwrap(),
+        );
+        connector.set_obfs_key(Some(key));
+        let connect = tokio::spawn(async move { connector.connect().await.unwrap() });
+
+        let mut buf = vec![0u8; 2048];
+        let (n, peer) = server.recv_from(&mut buf).await.unwrap();
+        let syn_wire = buf[..n].to_vec();
+        let mut syn = BytesMut::from(&syn_wire[..]);
+        deobfs_datagram(&key, &mut syn).unwrap();
+        let mut sack = ZCPacket::new_from_buf(syn, ZCPacketType::UDP);
+        let syn_hdr = sack.udp_tunnel_header().unwrap().as_bytes().to_vec();
+        assert_eq!(
+            sack.udp_tunnel_header().unwrap().msg_type,
+            UdpPacketType::Syn as u8
+        );
+        assert!(!contains(&syn_wire, &syn_hdr));
+        assert!(!contains(&syn_wire, sack.udp_payload()));
+
+        sack.mut_udp_tunnel_header().unwrap().msg_type = UdpPacketType::Sack as u8;
+        let mut sack_wire = sack.inner();
+        obfs_datagram(&key, &mut sack_wire);
+        server.send_to(&sack_wire, peer).await.unwrap();
+
+        let tunnel = connect.await.unwrap();
+        let (_r, mut s) = tunnel.split();
+        s.send(packet.clone()).await.unwrap();
+        let (n, _) = server.recv_from(&mut buf).await.unwrap();
+        let data_wire = buf[..n].to_vec();
+        let mut data = BytesMut::from(&data_wire[..]);
+        deobfs_datagram(&key, &mut data).unwrap();
+        assert!(contains(&data, &pm_hdr));
+        assert!(!contains(&data_wire, &data[..UDP_TUNNEL_HEADER_SIZE]));
+        assert!(!contains(&data_wire, &pm_hdr));
+        assert!(!contains(&data_wire, b"obfs-test-payload"));
+    }
+
+    #[test]
+    fn obfs_filter_roundtrip() {
+        let filter = ObfsFilter::new(derive_key("abc"));
+        let mut packet = ZCPacket::new_with_payload(b"hello");
+        packet.fill_peer_manager_hdr(1, 2, PacketType::Data as u8);
+
+        let mut lens = std::collections::BTreeSet::new();
+        for _ in 0..16 {
+            let sent = filter.before_send(packet.clone()).unwrap();
+            lens.insert(sent.buf_len());
+            let restored = filter.after_received(Ok(sent)).unwrap().unwrap();
+            assert_eq!(restored.tunnel_payload(), packet.tunnel_payload());
+        }
+        assert!(lens.len() > 1);
+
+        // a peer with another key can not read the packet
+        let other = ObfsFilter::new(derive_key("abd"));
+        let sent = filter.before_send(packet.clone()).unwrap();
+        if let Some(Ok(p)) = other.after_received(Ok(sent)) {
+            assert_ne!(p.tunnel_payload(), packet.tunnel_payload());
+        }
+
+        // large packets are not padded
+        let big = ZCPacket::new_with_payload(&[0u8; MAX_PADDED_PACKET_SIZE]);
+        let sent = filter.before_send(big.clone()).unwrap();
+        assert_eq!(sent.buf_len(), big.buf_len() + 1 + NONCE_LEN);
+    }
+}
@@ -72,6 +72,31 @@ pub enum PacketType {
     ForeignNetworkPacket = 10,
     KcpSrc = 11,
     KcpDst = 12,
+    Fec = 13,
+    KcpTunnel = 14,
+}
+
+#[repr(C, packed)]
+#[derive(AsBytes, FromBytes, FromZeroes, Clone, Debug, Default)]
+pub struct FecHeader {
+    pub group_seq: U32<DefaultEndian>,
+    pub index: u8,
+    pub group_size: u8,
+    pub kind: u8,
+    reserved: u8,
+}
+pub const FEC_HEADER_SIZE: usize = std::mem::size_of::<FecHeader>();
+
+impl FecHeader {
+    pub fn new(group_seq: u32, index: u8, group_size: u8, kind: u8) -> Self {
+        Self {
+            group_seq: group_seq.into(),
+            index,
+            group_size,
+            kind,
+            reserved: 0,
+        }
+    }
 }
 
 bitflags::bitflags! {
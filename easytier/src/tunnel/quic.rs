@@ -3,33 +3,89 @@
 //! Checkout the `README.md` for guidance.
 
 use std::{
-    error::Error, io::IoSliceMut, net::SocketAddr, pin::Pin, sync::Arc, task::Poll, time::Duration,
+    collections::{HashMap, VecDeque},
+    error::Error,
+    io::IoSliceMut,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::Poll,
+    time::Duration,
 };
 
-use crate::tunnel::{
-    common::{setup_sokcet2, FramedReader, FramedWriter, TunnelWrapper},
-    TunnelInfo,
+use crate::{
+    common::{netns::ThreadNetNS, scoped_task::ScopedTask},
+    tunnel::{
+        common::{
+            setup_sokcet2, FramedReader, FramedWriter, TcpZCPacketToBytes, TunnelWrapper,
+            ZCPacketToBytes as _,
+        },
+        packet_def::{ZCPacket, ZCPacketType},
+        SinkItem, StreamItem, TunnelInfo,
+    },
 };
 use anyhow::Context;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Sink, Stream};
+use once_cell::sync::Lazy;
+use pin_project_lite::pin_project;
 
 use quinn::{
-    congestion::BbrConfig, crypto::rustls::QuicClientConfig, udp::RecvMeta, AsyncUdpSocket,
-    ClientConfig, Connection, Endpoint, EndpointConfig, ServerConfig, TransportConfig, UdpPoller,
+    congestion::BbrConfig,
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    udp::RecvMeta,
+    AsyncUdpSocket, ClientConfig, Connection, Endpoint, EndpointConfig, MtuDiscoveryConfig,
+    RecvStream, SendDatagramError, SendStream, ServerConfig, TransportConfig, UdpPoller,
 };
 
 use super::{
     check_scheme_and_get_socket_addr,
-    insecure_tls::{get_insecure_tls_cert, get_insecure_tls_client_config},
+    insecure_tls::{get_insecure_tls_cert, get_insecure_tls_client_config, init_crypto_provider},
     IpVersion, Tunnel, TunnelConnector, TunnelError, TunnelListener,
 };
 
+// quinn accepts datagrams by default, so an old peer announces them in its
+// transport parameters but never reads them. each side sends probe datagrams
+// and only moves data packets to datagrams once the peer answered, an old peer
+// never does and everything stays on the stream.
+const DATAGRAM_PROBE_INTERVAL: Duration = Duration::from_millis(500);
+const DATAGRAM_PROBE_COUNT: usize = 10;
+
+// how often a client endpoint checks whether its routes to the servers changed
+const LOCAL_ADDR_CHECK_INTERVAL: Duration = Duration::from_secs(3);
+
+// every datagram starts with the packet sequence, the fragment index and the
+// fragment count, so packets larger than the datagram limit are split instead
+// of moving to the stream.
+const DATAGRAM_HDR_LEN: usize = 4;
+// fragments of at most this many packets wait for the rest at the same time
+const MAX_PENDING_DATAGRAM_GROUPS: usize = 16;
+// a datagram with fragment count 0 carries no packet but one of these in the
+// fragment index.
+const DATAGRAM_PROBE: u8 = 0;
+const DATAGRAM_PROBE_ACK: u8 = 1;
+
+fn configure_transport(transport_config: &mut TransportConfig) {
+    transport_config.congestion_controller_factory(Arc::new(BbrConfig::default()));
+    // probe up to a full ethernet frame so most data packets fit in one datagram
+    let mut mtu_discovery = MtuDiscoveryConfig::default();
+    mtu_discovery.upper_bound(1472);
+    transport_config.mtu_discovery_config(Some(mtu_discovery));
+    transport_config.datagram_receive_buffer_size(Some(4 * 1024 * 1024));
+    transport_config.datagram_send_buffer_size(4 * 1024 * 1024);
+}
+
 pub fn configure_client() -> ClientConfig {
-    let client_crypto = QuicClientConfig::try_from(get_insecure_tls_client_config()).unwrap();
+    let client_tls = get_insecure_tls_client_config();
+    let client_crypto = QuicClientConfig::try_from(client_tls).unwrap();
     let mut client_config = ClientConfig::new(Arc::new(client_crypto));
 
     // // Create a new TransportConfig and set BBR
     let mut transport_config = TransportConfig::default();
-    transport_config.congestion_controller_factory(Arc::new(BbrConfig::default()));
+    configure_transport(&mut transport_config);
     transport_config.keep_alive_interval(Some(Duration::from_secs(5)));
     // Replace the default TransportConfig with the transport_config() method
     client_config.transport_config(Arc::new(transport_config));
@@ -118,12 +174,20 @@ pub fn make_server_endpoint(bind_addr: SocketAddr) -> Result<(Endpoint, Vec<u8>)
 pub fn configure_server() -> Result<(ServerConfig, Vec<u8>), Box<dyn Error>> {
     let (certs, key) = get_insecure_tls_cert();
 
-    let mut server_config = ServerConfig::with_single_cert(certs.clone(), key)?;
+    init_crypto_provider();
+    let server_tls = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs.clone(), key)?;
+
+    let mut server_config =
+        ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(server_tls)?));
+    // clients keep the connection when their address changes
+    server_config.migration(true);
     let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
     transport_config.max_concurrent_uni_streams(10_u8.into());
     transport_config.max_concurrent_bidi_streams(10_u8.into());
-    // Setting BBR congestion control
-    transport_config.congestion_controller_factory(Arc::new(BbrConfig::default()));
+    // Setting BBR congestion control and enable datagrams
+    configure_transport(transport_config);
 
     Ok((server_config, certs[0].to_vec()))
 }
@@ -141,6 +205,367 @@ impl Drop for ConnWrapper {
     }
 }
 
+fn send_datagram_control(conn: &Connection, kind: u8) -> Result<(), SendDatagramError> {
+    conn.send_datagram(Bytes::copy_from_slice(&[0, 0, kind, 0]))
+}
+
+/// probes until the peer answers or the probes run out, the connection stays
+/// on the stream for a peer that never answers.
+async fn probe_peer_datagram(conn: Connection, peer_datagram: Arc<AtomicBool>) {
+    for _ in 0..DATAGRAM_PROBE_COUNT {
+        if peer_datagram.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Err(e) = send_datagram_control(&conn, DATAGRAM_PROBE) {
+            tracing::debug!(?e, "send quic datagram probe failed");
+            return;
+        }
+        tokio::time::sleep(DATAGRAM_PROBE_INTERVAL).await;
+    }
+    tracing::info!(
+        remote = ?conn.remote_address(),
+        "peer does not answer datagram probes, keep data packets on the stream"
+    );
+}
+
+pin_project! {
+    /// Once the peer answered a datagram probe all lossy data packets of the
+    /// connection are sent as QUIC datagrams, so a lost packet does not block
+    /// the ones behind it. A packet larger than the datagram limit is split
+    /// into several datagrams. Control packets (handshake, rpc) use the stream.
+    struct QuicDatagramSink {
+        #[pin]
+        stream: FramedWriter<SendStream, TcpZCPacketToBytes>,
+        conn: Connection,
+        datagram: Arc<AtomicBool>,
+        next_seq: u16,
+    }
+}
+
+impl QuicDatagramSink {
+    fn send_datagrams(conn: &Connection, seq: u16, bytes: Bytes) -> Result<(), TunnelError> {
+        let Some(max) = conn.max_datagram_size() else {
+            return Err(anyhow::anyhow!("peer does not accept datagrams").into());
+        };
+        let chunk = max.saturating_sub(DATAGRAM_HDR_LEN);
+        let count = if chunk == 0 {
+            0
+        } else {
+            bytes.len().div_ceil(chunk)
+        };
+        if count == 0 || count > u8::MAX as usize {
+            tracing::trace!(
+                len = bytes.len(),
+                max,
+                "packet can not be sent as datagrams"
+            );
+            return Ok(());
+        }
+
+        for (index, part) in bytes.chunks(chunk).enumerate() {
+            let mut buf = BytesMut::with_capacity(DATAGRAM_HDR_LEN + part.len());
+            buf.put_u16(seq);
+            buf.put_u8(index as u8);
+            buf.put_u8(count as u8);
+            buf.extend_from_slice(part);
+            match conn.send_datagram(buf.freeze()) {
+                Ok(()) => {}
+                // the path mtu shrank after the check, a lost data packet is fine
+                Err(SendDatagramError::TooLarge) => {}
+                Err(e) => return Err(anyhow::anyhow!("send datagram failed: {:?}", e).into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink<SinkItem> for QuicDatagramSink {
+    type Error = TunnelError;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.project().stream.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SinkItem) -> Result<(), Self::Error> {
+        let this = self.project();
+        if !this.datagram.load(Ordering::Relaxed) || !item.is_lossy() {
+            return this.stream.start_send(item);
+        }
+
+        let bytes = TcpZCPacketToBytes.zcpacket_into_bytes(item.convert_type(ZCPacketType::TCP))?;
+        let seq = *this.next_seq;
+        *this.next_seq = seq.wrapping_add(1);
+        Self::send_datagrams(this.conn, seq, bytes)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.project().stream.poll_close(cx)
+    }
+}
+
+/// Puts split packets back together, a packet with a lost fragment is dropped
+/// once newer packets push it out, like a lost datagram.
+#[derive(Default)]
+struct DatagramReassembler {
+    groups: VecDeque<(u16, Vec<Option<Bytes>>)>,
+}
+
+impl DatagramReassembler {
+    fn push(&mut self, buf: Bytes) -> Option<BytesMut> {
+        if buf.len() < DATAGRAM_HDR_LEN {
+            return None;
+        }
+        let seq = u16::from_be_bytes([buf[0], buf[1]]);
+        let index = buf[2] as usize;
+        let count = buf[3] as usize;
+        let part = buf.slice(DATAGRAM_HDR_LEN..);
+        if index >= count {
+            return None;
+        }
+        if count == 1 {
+            return Some(BytesMut::from(&part[..]));
+        }
+
+        let pos = match self
+            .groups
+            .iter()
+            .position(|(s, parts)| *s == seq && parts.len() == count)
+        {
+            Some(pos) => pos,
+            None => {
+                if self.groups.len() >= MAX_PENDING_DATAGRAM_GROUPS {
+                    self.groups.pop_front();
+                }
+                self.groups.push_back((seq, vec![None; count]));
+                self.groups.len() - 1
+            }
+        };
+        self.groups[pos].1[index] = Some(part);
+        if self.groups[pos].1.iter().any(Option::is_none) {
+            return None;
+        }
+
+        let (_, parts) = self.groups.remove(pos)?;
+        let mut ret = BytesMut::new();
+        for part in parts.into_iter().flatten() {
+            ret.extend_from_slice(&part);
+        }
+        Some(ret)
+    }
+}
+
+fn datagram_stream(
+    conn: Connection,
+    peer_datagram: Arc<AtomicBool>,
+) -> impl Stream<Item = StreamItem> + Send + 'static {
+    let reassembler = DatagramReassembler::default();
+    let state = (conn, peer_datagram, reassembler);
+    futures::stream::unfold(state, |(conn, peer_datagram, mut reassembler)| async move {
+        loop {
+            match conn.read_datagram().await {
+                Ok(buf) => {
+                    if buf.len() >= DATAGRAM_HDR_LEN && buf[3] == 0 {
+                        // the peer reads datagrams too
+                        if buf[2] == DATAGRAM_PROBE {
+                            let _ = send_datagram_control(&conn, DATAGRAM_PROBE_ACK);
+                        }
+                        peer_datagram.store(true, Ordering::Relaxed);
+                        continue;
+                    }
+                    let Some(buf) = reassembler.push(buf) else {
+                        continue;
+                    };
+                    let packet = ZCPacket::new_from_buf(buf, ZCPacketType::TCP);
+                    return Some((Ok(packet), (conn, peer_datagram, reassembler)));
+                }
+                Err(e) => {
+                    tracing::debug!(?e, "quic datagram stream closed");
+                    return None;
+                }
+            }
+        }
+    })
+}
+
+fn build_quic_tunnel(
+    conn: Connection,
+    w: SendStream,
+    r: RecvStream,
+    max_packet_size: usize,
+    associate_data: Arc<dyn std::any::Any + Send + Sync>,
+    info: TunnelInfo,
+) -> Box<dyn Tunnel> {
+    // a peer without datagram support does not announce them at all
+    let datagram = conn.max_datagram_size().is_some();
+    tracing::info!(datagram, remote = ?conn.remote_address(), "quic tunnel established");
+    let peer_datagram = Arc::new(AtomicBool::new(false));
+
+    let stream = FramedReader::new_with_associate_data(
+        r,
+        max_packet_size,
+        Some(Box::new(associate_data.clone())),
+    );
+    let sink = QuicDatagramSink {
+        stream: FramedWriter::new_with_associate_data(w, Some(Box::new(associate_data))),
+        conn: conn.clone(),
+        datagram: peer_datagram.clone(),
+        next_seq: 0,
+    };
+
+    if datagram {
+        tokio::spawn(probe_peer_datagram(conn.clone(), peer_datagram.clone()));
+        let stream = futures::stream::select(stream, datagram_stream(conn, peer_datagram));
+        Box::new(TunnelWrapper::new(stream, sink, Some(info)))
+    } else {
+        Box::new(TunnelWrapper::new(stream, sink, Some(info)))
+    }
+}
+
+async fn local_addr_for(net_ns: &ThreadNetNS, remote: SocketAddr) -> Option<IpAddr> {
+    let bind_addr: SocketAddr = if remote.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = {
+        let _g = net_ns.guard();
+        tokio::net::UdpSocket::bind(bind_addr).await.ok()?
+    };
+    socket.connect(remote).await.ok()?;
+    socket.local_addr().ok().map(|a| a.ip())
+}
+
+/// The client socket is bound to the unspecified address, so when the
+/// interface used to reach a server changes (wifi to cellular, dhcp renew)
+/// the endpoint is moved to a fresh socket and quinn migrates its connections
+/// to the new path without another handshake. The watcher runs on any thread,
+/// so it switches to the netns of the endpoint for the probe and the rebind.
+async fn watch_local_addr_change(
+    endpoint: Endpoint,
+    conns: Arc<Mutex<Vec<Connection>>>,
+    net_ns: ThreadNetNS,
+) {
+    let mut last = HashMap::<SocketAddr, Option<IpAddr>>::new();
+    loop {
+        let remotes = {
+            let mut conns = conns.lock().unwrap();
+            conns.retain(|c| c.close_reason().is_none());
+            conns.iter().map(|c| c.remote_address()).collect::<Vec<_>>()
+        };
+
+        let mut cur = HashMap::new();
+        for remote in remotes {
+            if let std::collections::hash_map::Entry::Vacant(e) = cur.entry(remote) {
+                e.insert(local_addr_for(&net_ns, remote).await);
+            }
+        }
+        let changed = cur.iter().any(|(remote, addr)| {
+            addr.is_some() && last.get(remote).is_some_and(|last| last != addr)
+        });
+        if changed {
+            tracing::info!(?last, ?cur, "local address changed, rebind quic endpoint");
+            let bind_addr = endpoint.local_addr().map(|mut a| {
+                a.set_port(0);
+                a
+            });
+            let ret = bind_addr.and_then(|a| {
+                let _g = net_ns.guard();
+                std::net::UdpSocket::bind(a)
+            });
+            match ret.and_then(|s| endpoint.rebind(s)) {
+                Ok(()) => last = cur,
+                // keep the old addresses so the next check tries again
+                Err(e) => tracing::warn!(?e, "rebind quic endpoint failed"),
+            }
+        } else {
+            last = cur;
+        }
+
+        tokio::time::sleep(LOCAL_ADDR_CHECK_INTERVAL).await;
+    }
+}
+
+/// Client connections of the same ip family (and netns) share one endpoint,
+/// so the route to the servers is watched once per endpoint instead of once
+/// per connection.
+struct ClientEndpoint {
+    endpoint: Endpoint,
+    conns: Arc<Mutex<Vec<Connection>>>,
+    _watcher: ScopedTask<()>,
+}
+
+type ClientEndpointKey = (u64, bool);
+
+static CLIENT_ENDPOINTS: Lazy<Mutex<HashMap<ClientEndpointKey, Weak<ClientEndpoint>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+impl ClientEndpoint {
+    fn current_netns() -> u64 {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::MetadataExt as _;
+            // the netns guard switches the namespace of the current thread
+            std::fs::metadata("/proc/thread-self/ns/net")
+                .map(|m| m.ino())
+                .unwrap_or_default()
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            0
+        }
+    }
+
+    fn get_or_create(ipv4: bool) -> Result<Arc<Self>, TunnelError> {
+        let key = (Self::current_netns(), ipv4);
+        let mut endpoints = CLIENT_ENDPOINTS.lock().unwrap();
+        endpoints.retain(|_, e| e.strong_count() > 0);
+        if let Some(e) = endpoints.get(&key).and_then(Weak::upgrade) {
+            return Ok(e);
+        }
+
+        let ret = Self::new(ipv4)?;
+        endpoints.insert(key, Arc::downgrade(&ret));
+        Ok(ret)
+    }
+
+    // the endpoint is bound in the netns of the current thread.
+    fn new(ipv4: bool) -> Result<Arc<Self>, TunnelError> {
+        let local_addr = if ipv4 { "0.0.0.0:0" } else { "[::]:0" };
+        let mut endpoint = Endpoint::client(local_addr.parse().unwrap())?;
+        endpoint.set_default_client_config(configure_client());
+        let conns = Arc::new(Mutex::new(Vec::new()));
+        let watcher = tokio::spawn(watch_local_addr_change(
+            endpoint.clone(),
+            conns.clone(),
+            ThreadNetNS::current(),
+        ));
+        Ok(Arc::new(Self {
+            endpoint,
+            conns,
+            _watcher: watcher.into(),
+        }))
+    }
+}
+
+struct ClientConnData {
+    _conn: ConnWrapper,
+    // keeps the shared endpoint alive while the connection is in use
+    _endpoint: Arc<ClientEndpoint>,
+}
+
 pub struct QUICTunnelListener {
     addr: url::Url,
     endpoint: Option<Endpoint>,
@@ -169,7 +594,7 @@ impl QUICTunnelListener {
         let remote_addr = conn.remote_address();
         let (w, r) = conn.accept_bi().await.with_context(|| "accept_bi failed")?;
 
-        let arc_conn = Arc::new(ConnWrapper { conn });
+        let arc_conn = Arc::new(ConnWrapper { conn: conn.clone() });
 
         let info = TunnelInfo {
             tunnel_type: "quic".to_owned(),
@@ -179,11 +604,7 @@ impl QUICTunnelListener {
             ),
         };
 
-        Ok(Box::new(TunnelWrapper::new(
-            FramedReader::new_with_associate_data(r, 2000, Some(Box::new(arc_conn.clone()))),
-            FramedWriter::new_with_associate_data(w, Some(Box::new(arc_conn))),
-            Some(info),
-        )))
+        Ok(build_quic_tunnel(conn, w, r, 2000, arc_conn, info))
     }
 }
 
@@ -226,6 +647,7 @@ pub struct QUICTunnelConnector {
     addr: url::Url,
     endpoint: Option<Endpoint>,
     ip_version: IpVersion,
+    share_endpoint: bool,
 }
 
 impl QUICTunnelConnector {
@@ -234,6 +656,7 @@ impl QUICTunnelConnector {
             addr,
             endpoint: None,
             ip_version: IpVersion::Both,
+            share_endpoint: true,
         }
     }
 }
@@ -244,14 +667,12 @@ impl TunnelConnector for QUICTunnelConnector {
         let addr =
             check_scheme_and_get_socket_addr::<SocketAddr>(&self.addr, "quic", self.ip_version)
                 .await?;
-        let local_addr = if addr.is_ipv4() {
-            "0.0.0.0:0"
+        let client_endpoint = if self.share_endpoint {
+            ClientEndpoint::get_or_create(addr.is_ipv4())?
         } else {
-            "[::]:0"
+            ClientEndpoint::new(addr.is_ipv4())?
         };
-
-        let mut endpoint = Endpoint::client(local_addr.parse().unwrap())?;
-        endpoint.set_default_client_config(configure_client());
+        let endpoint = client_endpoint.endpoint.clone();
 
         // connect to server
         let connection = endpoint
@@ -263,7 +684,7 @@ impl TunnelConnector for QUICTunnelConnector {
 
         let local_addr = endpoint.local_addr()?;
 
-        self.endpoint = Some(endpoint);
+        self.endpoint = Some(endpoint.clone());
 
         let (w, r) = connection
             .open_bi()
@@ -278,12 +699,18 @@ impl TunnelConnector for QUICTunnelConnector {
             remote_addr: Some(self.addr.clone().into()),
         };
 
-        let arc_conn = Arc::new(ConnWrapper { conn: connection });
-        Ok(Box::new(TunnelWrapper::new(
-            FramedReader::new_with_associate_data(r, 4500, Some(Box::new(arc_conn.clone()))),
-            FramedWriter::new_with_associate_data(w, Some(Box::new(arc_conn))),
-            Some(info),
-        )))
+        client_endpoint
+            .conns
+            .lock()
+            .unwrap()
+            .push(connection.clone());
+        let arc_conn = Arc::new(ClientConnData {
+            _conn: ConnWrapper {
+                conn: connection.clone(),
+            },
+            _endpoint: client_endpoint,
+        });
+        Ok(build_quic_tunnel(connection, w, r, 4500, arc_conn, info))
     }
 
     fn remote_url(&self) -> url::Url {
@@ -318,6 +745,197 @@ mod tests {
         _tunnel_bench(listener, connector).await
     }
 
+    #[tokio::test]
+    async fn quic_survive_client_rebind() {
+        use crate::tunnel::packet_def::PacketType;
+        use futures::{SinkExt as _, StreamExt as _};
+
+        let mut listener = QUICTunnelListener::new("quic://127.0.0.1:21013".parse().unwrap());
+        let mut connector = QUICTunnelConnector::new("quic://127.0.0.1:21013".parse().unwrap());
+        // the rebind below must not move the endpoint shared with other tests
+        connector.share_endpoint = false;
+        listener.listen().await.unwrap();
+        let accept_task = tokio::spawn(async move {
+            let tunnel = listener.accept().await.unwrap();
+            (listener, tunnel)
+        });
+        let client = connector.connect().await.unwrap();
+        let (_listener, server) = accept_task.await.unwrap();
+
+        let (_c_recv, mut c_send) = client.split();
+        let (mut s_recv, _s_send) = server.split();
+
+        let make_packet = |payload: &[u8], packet_type: PacketType| {
+            let mut packet = ZCPacket::new_with_payload(payload);
+            packet.fill_peer_manager_hdr(1, 2, packet_type as u8);
+            packet
+        };
+
+        c_send
+            .send(make_packet(b"before", PacketType::Data))
+            .await
+            .unwrap();
+        let ret = tokio::time::timeout(Duration::from_secs(5), s_recv.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(ret.payload(), b"before");
+
+        // move the client to another local port, the server must follow it
+        connector
+            .endpoint
+            .as_ref()
+            .unwrap()
+            .rebind(std::net::UdpSocket::bind("127.0.0.1:0").unwrap())
+            .unwrap();
+
+        c_send
+            .send(make_packet(b"control", PacketType::HandShake))
+            .await
+            .unwrap();
+        let ret = tokio::time::timeout(Duration::from_secs(5), s_recv.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(ret.payload(), b"control");
+
+        // data packets are datagrams and may be lost while the path changes
+        let got_data = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                c_send
+                    .send(make_packet(b"after", PacketType::Data))
+                    .await
+                    .unwrap();
+                if let Ok(Some(Ok(p))) =
+                    tokio::time::timeout(Duration::from_millis(200), s_recv.next()).await
+                {
+                    if p.payload() == b"after" {
+                        return;
+                    }
+                }
+            }
+        })
+        .await;
+        assert!(got_data.is_ok());
+
+        // larger than one datagram, it is split and put back together
+        let big = vec![0x5au8; 3000];
+        let got_big = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                c_send
+                    .send(make_packet(&big, PacketType::Data))
+                    .await
+                    .unwrap();
+                if let Ok(Some(Ok(p))) =
+                    tokio::time::timeout(Duration::from_millis(200), s_recv.next()).await
+                {
+                    if p.payload() == big.as_slice() {
+                        return;
+                    }
+                }
+            }
+        })
+        .await;
+        assert!(got_big.is_ok());
+    }
+
+    #[tokio::test]
+    async fn quic_stream_fallback() {
+        use crate::tunnel::packet_def::PacketType;
+        use futures::{SinkExt as _, StreamExt as _};
+
+        let make_packet = |payload: &[u8]| {
+            let mut packet = ZCPacket::new_with_payload(payload);
+            packet.fill_peer_manager_hdr(1, 2, PacketType::Data as u8);
+            packet
+        };
+
+        // a server without datagram support, and an old server which announces
+        // datagrams in its transport parameters but only reads the stream.
+        for server_datagram in [false, true] {
+            let (endpoint, _) = make_server_endpoint("127.0.0.1:0".parse().unwrap()).unwrap();
+            if !server_datagram {
+                let (mut server_config, _) = configure_server().unwrap();
+                Arc::get_mut(&mut server_config.transport)
+                    .unwrap()
+                    .datagram_receive_buffer_size(None);
+                endpoint.set_server_config(Some(server_config));
+            }
+            let port = endpoint.local_addr().unwrap().port();
+            let accept_task = tokio::spawn(async move {
+                let conn = endpoint.accept().await.unwrap().await.unwrap();
+                let (w, r) = conn.accept_bi().await.unwrap();
+                let tunnel =
+                    TunnelWrapper::new(FramedReader::new(r, 2000), FramedWriter::new(w), None);
+                (endpoint, conn, tunnel)
+            });
+
+            let mut connector =
+                QUICTunnelConnector::new(format!("quic://127.0.0.1:{}", port).parse().unwrap());
+            connector.share_endpoint = false;
+            let client = connector.connect().await.unwrap();
+            let (mut c_recv, mut c_send) = client.split();
+            c_send.send(make_packet(b"first")).await.unwrap();
+            let (_endpoint, _conn, server) = accept_task.await.unwrap();
+            let (mut s_recv, mut s_send) = server.split();
+
+            // probes are not answered, data packets stay on the stream
+            tokio::time::sleep(DATAGRAM_PROBE_INTERVAL * 2).await;
+            c_send.send(make_packet(b"second")).await.unwrap();
+            for expected in [b"first".as_slice(), b"second".as_slice()] {
+                let ret = tokio::time::timeout(Duration::from_secs(5), s_recv.next())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(ret.payload(), expected);
+            }
+
+            s_send.send(make_packet(b"reply")).await.unwrap();
+            let ret = tokio::time::timeout(Duration::from_secs(5), c_recv.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(ret.payload(), b"reply");
+        }
+    }
+
+    #[test]
+    fn datagram_reassemble() {
+        let frag = |seq: u16, index: u8, count: u8, data: &[u8]| {
+            let mut buf = BytesMut::new();
+            buf.put_u16(seq);
+            buf.put_u8(index);
+            buf.put_u8(count);
+            buf.extend_from_slice(data);
+            buf.freeze()
+        };
+
+        let mut r = DatagramReassembler::default();
+        assert_eq!(&r.push(frag(1, 0, 1, b"single")).unwrap()[..], b"single");
+        // fragments of two packets interleaved and out of order
+        assert!(r.push(frag(2, 1, 2, b"world")).is_none());
+        assert!(r.push(frag(3, 0, 2, b"foo")).is_none());
+        assert_eq!(
+            &r.push(frag(2, 0, 2, b"hello ")).unwrap()[..],
+            b"hello world"
+        );
+        assert_eq!(&r.push(frag(3, 1, 2, b"bar")).unwrap()[..], b"foobar");
+        assert!(r.groups.is_empty());
+
+        // a packet with a lost fragment is pushed out by newer ones
+        assert!(r.push(frag(4, 0, 2, b"lost")).is_none());
+        for seq in 5..5 + MAX_PENDING_DATAGRAM_GROUPS as u16 {
+            assert!(r.push(frag(seq, 0, 2, b"x")).is_none());
+        }
+        assert!(r.push(frag(4, 1, 2, b"late")).is_none());
+        assert!(r.groups.len() <= MAX_PENDING_DATAGRAM_GROUPS);
+        assert!(r.push(frag(1, 3, 2, b"bad index")).is_none());
+    }
+
     #[tokio::test]
     async fn ipv6_pingpong() {
         let listener = QUICTunnelListener::new("quic://[::1]:31015".parse().unwrap());
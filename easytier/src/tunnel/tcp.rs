@@ -10,6 +10,9 @@ use crate::tunnel::common::setup_sokcet2;
 use super::{
     check_scheme_and_get_socket_addr,
     common::{wait_for_connect_futures, FramedReader, FramedWriter, TunnelWrapper},
+    filter::TunnelWithFilter,
+    obfs::{ObfsFilter, ObfsKey, ObfsStream},
+    upstream_proxy::{connect_via_upstream_proxy, UpstreamProxy},
     IpVersion, Tunnel, TunnelError, TunnelListener,
 };
 
@@ -19,6 +22,7 @@ const TCP_MTU_BYTES: usize = 2000;
 pub struct TcpTunnelListener {
     addr: url::Url,
     listener: Option<TcpListener>,
+    obfs_key: Option<ObfsKey>,
 }
 
 impl TcpTunnelListener {
@@ -26,9 +30,14 @@ impl TcpTunnelListener {
         TcpTunnelListener {
             addr,
             listener: None,
+            obfs_key: None,
         }
     }
 
+    pub fn set_obfs_key(&mut self, key: Option<ObfsKey>) {
+        self.obfs_key = key;
+    }
+
     async fn do_accept(&mut self) -> Result<Box<dyn Tunnel>, std::io::Error> {
         let listener = self.listener.as_ref().unwrap();
         let (stream, _) = listener.accept().await?;
@@ -45,13 +54,31 @@ impl TcpTunnelListener {
             ),
         };
 
-        let (r, w) = stream.into_split();
-        Ok(Box::new(TunnelWrapper::new(
+        Ok(build_tcp_tunnel(stream, info, self.obfs_key))
+    }
+}
+
+fn build_tcp_tunnel(
+    stream: TcpStream,
+    info: TunnelInfo,
+    obfs_key: Option<ObfsKey>,
+) -> Box<dyn Tunnel> {
+    if let Some(key) = obfs_key {
+        let (r, w) = tokio::io::split(ObfsStream::new(stream, key));
+        let tunnel = TunnelWrapper::new(
             FramedReader::new(r, TCP_MTU_BYTES),
             FramedWriter::new(w),
             Some(info),
-        )))
+        );
+        return Box::new(TunnelWithFilter::new(tunnel, ObfsFilter::new(key)));
     }
+
+    let (r, w) = stream.into_split();
+    Box::new(TunnelWrapper::new(
+        FramedReader::new(r, TCP_MTU_BYTES),
+        FramedWriter::new(w),
+        Some(info),
+    ))
 }
 
 #[async_trait]
@@ -107,9 +134,10 @@ impl TunnelListener for TcpTunnelListener {
     }
 }
 
-fn get_tunnel_with_tcp_stream(
+pub(crate) fn get_tunnel_with_tcp_stream(
     stream: TcpStream,
     remote_url: url::Url,
+    obfs_key: Option<ObfsKey>,
 ) -> Result<Box<dyn Tunnel>, super::TunnelError> {
     if let Err(e) = stream.set_nodelay(true) {
         tracing::warn!(?e, "set_nodelay fail in get_tunnel_with_tcp_stream");
@@ -123,12 +151,7 @@ fn get_tunnel_with_tcp_stream(
         remote_addr: Some(remote_url.into()),
     };
 
-    let (r, w) = stream.into_split();
-    Ok(Box::new(TunnelWrapper::new(
-        FramedReader::new(r, TCP_MTU_BYTES),
-        FramedWriter::new(w),
-        Some(info),
-    )))
+    Ok(build_tcp_tunnel(stream, info, obfs_key))
 }
 
 #[derive(Debug)]
@@ -137,6 +160,8 @@ pub struct TcpTunnelConnector {
 
     bind_addrs: Vec<SocketAddr>,
     ip_version: IpVersion,
+    upstream_proxy: Option<UpstreamProxy>,
+    obfs_key: Option<ObfsKey>,
 }
 
 impl TcpTunnelConnector {
@@ -145,9 +170,19 @@ impl TcpTunnelConnector {
             addr,
             bind_addrs: vec![],
             ip_version: IpVersion::Both,
+            upstream_proxy: None,
+            obfs_key: None,
         }
     }
 
+    pub fn set_upstream_proxy(&mut self, proxy: Option<url::Url>) {
+        self.upstream_proxy = proxy.map(UpstreamProxy::new);
+    }
+
+    pub fn set_obfs_key(&mut self, key: Option<ObfsKey>) {
+        self.obfs_key = key;
+    }
+
     async fn connect_with_default_bind(
         &mut self,
         addr: SocketAddr,
@@ -155,7 +190,7 @@ impl TcpTunnelConnector {
         tracing::info!(url = ?self.addr, ?addr, "connect tcp start, bind addrs: {:?}", self.bind_addrs);
         let stream = TcpStream::connect(addr).await?;
         tracing::info!(url = ?self.addr, ?addr, "connect tcp succ");
-        get_tunnel_with_tcp_stream(stream, self.addr.clone())
+        get_tunnel_with_tcp_stream(stream, self.addr.clone(), self.obfs_key)
     }
 
     async fn connect_with_custom_bind(
@@ -183,13 +218,19 @@ impl TcpTunnelConnector {
         }
 
         let ret = wait_for_connect_futures(futures).await;
-        get_tunnel_with_tcp_stream(ret?, self.addr.clone())
+        get_tunnel_with_tcp_stream(ret?, self.addr.clone(), self.obfs_key)
     }
 }
 
 #[async_trait]
 impl super::TunnelConnector for TcpTunnelConnector {
     async fn connect(&mut self) -> Result<Box<dyn Tunnel>, super::TunnelError> {
+        if let Some(proxy) = &self.upstream_proxy {
+            let stream = connect_via_upstream_proxy(proxy.url(), &self.addr).await?;
+            tracing::info!(url = ?self.addr, "connect tcp through upstream proxy succ");
+            return get_tunnel_with_tcp_stream(stream, self.addr.clone(), self.obfs_key);
+        }
+
         let addr =
             check_scheme_and_get_socket_addr::<SocketAddr>(&self.addr, "tcp", self.ip_version)
                 .await?;
@@ -8,7 +8,7 @@ use anyhow::Context;
 use async_trait::async_trait;
 use bytes::BytesMut;
 use dashmap::DashMap;
-use futures::{stream::FuturesUnordered, StreamExt};
+use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
 use rand::{Rng, SeedableRng};
 use zerocopy::{AsBytes, FromBytes};
 
@@ -26,7 +26,7 @@ use crate::{
     common::{join_joinset_background, scoped_task::ScopedTask},
     tunnel::{
         build_url_from_socket_addr,
-        common::{reserve_buf, TunnelWrapper},
+        common::TunnelWrapper,
         packet_def::{UdpPacketType, ZCPacket, ZCPacketType},
         ring::RingTunnel,
     },
@@ -34,8 +34,10 @@ use crate::{
 
 use super::{
     common::{setup_sokcet2, setup_sokcet2_ext, wait_for_connect_futures},
+    obfs::{deobfs_datagram, obfs_datagram, ObfsKey},
     packet_def::{UDPTunnelHeader, UDP_TUNNEL_HEADER_SIZE},
     ring::{RingSink, RingStream},
+    udp_batch::{send_batch, UdpBatchReceiver, UDP_BATCH_SIZE},
     IpVersion, Tunnel, TunnelConnCounter, TunnelError, TunnelListener, TunnelUrl,
 };
 
@@ -230,71 +232,96 @@ fn get_zcpacket_from_buf(buf: BytesMut, allow_stun: bool) -> Result<ZCPacket, Tu
     Ok(zc_packet)
 }
 
-#[instrument]
+#[instrument(skip(obfs_key))]
 async fn forward_from_ring_to_udp(
     mut ring_recv: RingStream,
     socket: &Arc<UdpSocket>,
     addr: &SocketAddr,
     conn_id: u32,
+    obfs_key: Option<ObfsKey>,
+    batch_io: bool,
 ) -> Option<TunnelError> {
     tracing::debug!("udp forward from ring to udp");
+    let mut bufs = Vec::with_capacity(UDP_BATCH_SIZE);
     loop {
-        let buf = ring_recv.next().await?;
-        let packet = match buf {
-            Ok(v) => v,
-            Err(e) => {
-                return Some(e);
+        // wait for one packet, then take whatever else is already queued
+        let mut next = Some(ring_recv.next().await?);
+        bufs.clear();
+        while let Some(buf) = next.take() {
+            let packet = match buf {
+                Ok(v) => v,
+                Err(e) => {
+                    return Some(e);
+                }
+            };
+
+            let mut packet = packet.convert_type(ZCPacketType::UDP);
+            let udp_payload_len = packet.udp_payload().len();
+            let header = packet.mut_udp_tunnel_header().unwrap();
+            header.conn_id.set(conn_id);
+            header.len.set(udp_payload_len as u16);
+            header.msg_type = UdpPacketType::Data as u8;
+
+            let mut buf = packet.inner();
+            if let Some(key) = &obfs_key {
+                obfs_datagram(key, &mut buf);
             }
-        };
+            tracing::trace!(?udp_payload_len, ?buf, "udp forward from ring to udp");
+            bufs.push(buf.freeze());
 
-        let mut packet = packet.convert_type(ZCPacketType::UDP);
-        let udp_payload_len = packet.udp_payload().len();
-        let header = packet.mut_udp_tunnel_header().unwrap();
-        header.conn_id.set(conn_id);
-        header.len.set(udp_payload_len as u16);
-        header.msg_type = UdpPacketType::Data as u8;
-
-        let buf = packet.into_bytes();
-        tracing::trace!(?udp_payload_len, ?buf, "udp forward from ring to udp");
-        let ret = socket.send_to(&buf, &addr).await;
-        if ret.is_err() {
-            return Some(TunnelError::IOError(ret.unwrap_err()));
-        } else if ret.unwrap() == 0 {
-            return None;
+            if bufs.len() < UDP_BATCH_SIZE {
+                next = ring_recv.next().now_or_never().flatten();
+            }
+        }
+
+        if let Err(e) = send_batch(socket, *addr, &bufs, batch_io).await {
+            return Some(TunnelError::IOError(e));
         }
     }
 }
 
-async fn udp_recv_from_socket_forward_task<F>(socket: Arc<UdpSocket>, allow_stun: bool, mut f: F)
-where
+async fn udp_recv_from_socket_forward_task<F>(
+    socket: Arc<UdpSocket>,
+    allow_stun: bool,
+    obfs_key: Option<ObfsKey>,
+    batch_io: bool,
+    mut f: F,
+) where
     F: FnMut(ZCPacket, SocketAddr),
 {
-    let mut buf = BytesMut::new();
+    let mut receiver = UdpBatchReceiver::new(&socket, UDP_DATA_MTU, batch_io);
+    let mut bufs = Vec::with_capacity(UDP_BATCH_SIZE);
     loop {
-        reserve_buf(&mut buf, UDP_DATA_MTU, UDP_DATA_MTU * 4);
-        let (dg_size, addr) = match socket.recv_buf_from(&mut buf).await {
-            Ok(v) => v,
-            Err(e) => {
-                tracing::error!(?e, "udp recv from socket error");
-                break;
-            }
-        };
-        tracing::trace!(
-            "udp recv packet: {:?}, buf: {:?}, size: {}",
-            addr,
-            buf,
-            dg_size
-        );
+        if let Err(e) = receiver.recv(&socket, &mut bufs).await {
+            tracing::error!(?e, "udp recv from socket error");
+            break;
+        }
 
-        let zc_packet = match get_zcpacket_from_buf(buf.split(), allow_stun) {
-            Ok(v) => v,
-            Err(e) => {
-                tracing::warn!(?e, "udp get zc packet from buf error");
-                continue;
+        for (mut buf, addr) in bufs.drain(..) {
+            tracing::trace!(
+                "udp recv packet: {:?}, buf: {:?}, size: {}",
+                addr,
+                buf,
+                buf.len()
+            );
+
+            if let Some(key) = &obfs_key {
+                if let Err(e) = deobfs_datagram(key, &mut buf) {
+                    tracing::trace!(?e, ?addr, "udp deobfs packet error");
+                    continue;
+                }
             }
-        };
 
-        f(zc_packet, addr);
+            let zc_packet = match get_zcpacket_from_buf(buf, allow_stun) {
+                Ok(v) => v,
+                Err(e) => {
+                    tracing::warn!(?e, "udp get zc packet from buf error");
+                    continue;
+                }
+            };
+
+            f(zc_packet, addr);
+        }
     }
 }
 
@@ -315,11 +342,15 @@ impl UdpConnection {
         ring_sender: RingSink,
         ring_recv: RingStream,
         close_event_sender: UdpCloseEventSender,
+        obfs_key: Option<ObfsKey>,
+        batch_io: bool,
     ) -> Self {
         let s = socket.clone();
         let forward_task = tokio::spawn(async move {
             let close_event_sender = close_event_sender;
-            let err = forward_from_ring_to_udp(ring_recv, &s, &dst_addr, conn_id).await;
+            let err =
+                forward_from_ring_to_udp(ring_recv, &s, &dst_addr, conn_id, obfs_key, batch_io)
+                    .await;
             if let Err(e) = close_event_sender.send((dst_addr, err)) {
                 tracing::error!(?e, "udp send close event error");
             }
@@ -366,6 +397,8 @@ struct UdpTunnelListenerData {
     sock_map: Arc<DashMap<SocketAddr, UdpConnection>>,
     conn_send: Sender<Box<dyn Tunnel>>,
     close_event_sender: UdpCloseEventSender,
+    obfs_key: Option<ObfsKey>,
+    batch_io: bool,
 }
 
 impl UdpTunnelListenerData {
@@ -380,6 +413,8 @@ impl UdpTunnelListenerData {
             sock_map: Arc::new(DashMap::new()),
             conn_send,
             close_event_sender,
+            obfs_key: None,
+            batch_io: true,
         }
     }
 
@@ -399,7 +434,10 @@ impl UdpTunnelListenerData {
         tracing::info!(?conn_id, ?remote_addr, "udp connection accept handling",);
         let socket = self.socket.as_ref().unwrap().clone();
 
-        let sack_buf = new_sack_packet(conn_id, magic).into_bytes();
+        let mut sack_buf = new_sack_packet(conn_id, magic).inner();
+        if let Some(key) = &self.obfs_key {
+            obfs_datagram(key, &mut sack_buf);
+        }
         if let Err(e) = socket.send_to(&sack_buf, remote_addr).await {
             tracing::error!(?e, "udp send sack packet error");
             return;
@@ -420,6 +458,8 @@ impl UdpTunnelListenerData {
             RingSink::new(ring_for_recv_udp.clone()),
             RingStream::new(ring_for_send_udp.clone()),
             self.close_event_sender.clone(),
+            self.obfs_key,
+            self.batch_io,
         );
         self.sock_map.insert(remote_addr, internal_conn);
 
@@ -491,9 +531,15 @@ impl UdpTunnelListenerData {
 
     async fn do_forward_task(self) {
         let socket = self.socket.as_ref().unwrap().clone();
-        udp_recv_from_socket_forward_task(socket, true, |zc_packet, addr| {
-            self.do_forward_one_packet_to_conn(zc_packet, addr);
-        })
+        udp_recv_from_socket_forward_task(
+            socket,
+            true,
+            self.obfs_key,
+            self.batch_io,
+            |zc_packet, addr| {
+                self.do_forward_one_packet_to_conn(zc_packet, addr);
+            },
+        )
         .await;
     }
 }
@@ -525,6 +571,16 @@ impl UdpTunnelListener {
     pub fn get_socket(&self) -> Option<Arc<UdpSocket>> {
         self.socket.clone()
     }
+
+    pub fn set_obfs_key(&mut self, key: Option<ObfsKey>) {
+        self.data.obfs_key = key;
+    }
+
+    /// batched io is on by default and still falls back to one datagram per
+    /// syscall when the kernel lacks support.
+    pub fn set_batch_io(&mut self, enabled: bool) {
+        self.data.batch_io = enabled;
+    }
 }
 
 #[async_trait]
@@ -622,6 +678,8 @@ pub struct UdpTunnelConnector {
     addr: url::Url,
     bind_addrs: Vec<SocketAddr>,
     ip_version: IpVersion,
+    obfs_key: Option<ObfsKey>,
+    batch_io: bool,
 }
 
 impl UdpTunnelConnector {
@@ -630,14 +688,26 @@ impl UdpTunnelConnector {
             addr,
             bind_addrs: vec![],
             ip_version: IpVersion::Both,
+            obfs_key: None,
+            batch_io: true,
         }
     }
 
+    pub fn set_obfs_key(&mut self, key: Option<ObfsKey>) {
+        self.obfs_key = key;
+    }
+
+    /// see [`UdpTunnelListener::set_batch_io`]
+    pub fn set_batch_io(&mut self, enabled: bool) {
+        self.batch_io = enabled;
+    }
+
     async fn wait_sack(
         socket: &UdpSocket,
         addr: SocketAddr,
         conn_id: u32,
         magic: u64,
+        obfs_key: Option<&ObfsKey>,
     ) -> Result<SocketAddr, TunnelError> {
         let mut buf = BytesMut::new();
         buf.reserve(UDP_DATA_MTU);
@@ -647,7 +717,11 @@ impl UdpTunnelConnector {
             socket.recv_buf_from(&mut buf),
         )
         .await??;
-        let zc_packet = get_zcpacket_from_buf(buf.split(), false)?;
+        let mut buf = buf.split();
+        if let Some(key) = obfs_key {
+            deobfs_datagram(key, &mut buf)?;
+        }
+        let zc_packet = get_zcpacket_from_buf(buf, false)?;
         if recv_addr != addr {
             tracing::warn!(?recv_addr, ?addr, ?usize, "udp wait sack addr not match");
         }
@@ -687,9 +761,10 @@ impl UdpTunnelConnector {
         addr: SocketAddr,
         conn_id: u32,
         magic: u64,
+        obfs_key: Option<&ObfsKey>,
     ) -> Result<SocketAddr, super::TunnelError> {
         loop {
-            let ret = Self::wait_sack(socket, addr, conn_id, magic).await;
+            let ret = Self::wait_sack(socket, addr, conn_id, magic, obfs_key).await;
             if ret.is_err() {
                 tracing::debug!(?ret, "udp wait sack error");
                 continue;
@@ -724,16 +799,20 @@ impl UdpTunnelConnector {
             ring_sender,
             ring_recv,
             close_event_sender,
+            self.obfs_key,
+            self.batch_io,
         );
 
         let socket_clone = socket.clone();
+        let obfs_key = self.obfs_key;
+        let batch_io = self.batch_io;
         tokio::spawn(
             async move {
                 tokio::select! {
                     _ = close_event_recv.recv() => {
                         tracing::debug!("connector udp close event");
                     }
-                    _ = udp_recv_from_socket_forward_task(socket_clone,false, |zc_packet, addr| {
+                    _ = udp_recv_from_socket_forward_task(socket_clone, false, obfs_key, batch_io, |zc_packet, addr| {
                         tracing::trace!(?addr, "connector udp forward task done");
                         if let Err(e) = udp_conn.handle_packet_from_remote(zc_packet) {
                             tracing::trace!(?e, ?addr, "udp forward packet error");
@@ -776,14 +855,17 @@ impl UdpTunnelConnector {
         // send syn
         let conn_id = rand::random();
         let magic = rand::random();
-        let udp_packet = new_syn_packet(conn_id, magic).into_bytes();
+        let mut udp_packet = new_syn_packet(conn_id, magic).inner();
+        if let Some(key) = &self.obfs_key {
+            obfs_datagram(key, &mut udp_packet);
+        }
         let ret = socket.send_to(&udp_packet, &addr).await?;
         tracing::warn!(?udp_packet, ?ret, "udp send syn");
 
         // wait sack
         let recv_addr = tokio::time::timeout(
             tokio::time::Duration::from_secs(3),
-            Self::wait_sack_loop(&socket, addr, conn_id, magic),
+            Self::wait_sack_loop(&socket, addr, conn_id, magic, self.obfs_key.as_ref()),
         )
         .await??;
 
@@ -868,12 +950,15 @@ mod tests {
 
     use super::*;
     use crate::{
-        common::global_ctx::tests::get_mock_global_ctx,
+        common::{global_ctx::tests::get_mock_global_ctx, netns::NetNS},
         tunnel::{
             check_scheme_and_get_socket_addr,
             common::{
                 get_interface_name_by_ip,
-                tests::{_tunnel_bench, _tunnel_echo_server, _tunnel_pingpong, wait_for_condition},
+                tests::{
+                    _tunnel_bench, _tunnel_bench_netns, _tunnel_echo_server, _tunnel_pingpong,
+                    wait_for_condition,
+                },
             },
             TunnelConnector,
         },
@@ -893,6 +978,74 @@ mod tests {
         _tunnel_bench(listener, connector).await
     }
 
+    #[tokio::test]
+    #[ignore]
+    async fn udp_batch_io_bench() {
+        // run with --ignored --nocapture to compare the throughput of the same
+        // load with and without batched io
+        let mut results = vec![];
+        for (batch_io, port) in [(true, 5551), (false, 5552)] {
+            let mut listener =
+                UdpTunnelListener::new(format!("udp://0.0.0.0:{}", port).parse().unwrap());
+            let mut connector =
+                UdpTunnelConnector::new(format!("udp://127.0.0.1:{}", port).parse().unwrap());
+            listener.set_batch_io(batch_io);
+            connector.set_batch_io(batch_io);
+            let bps =
+                _tunnel_bench_netns(listener, connector, NetNS::new(None), NetNS::new(None)).await;
+            results.push((batch_io, bps));
+        }
+        for (batch_io, bps) in results {
+            println!("batch io: {}, bps: {}", batch_io, bps);
+        }
+    }
+
+    #[tokio::test]
+    async fn udp_batch_io_round_trip() {
+        // many packets fed before one flush leave the ring as one batch, the
+        // echoed ones come back through the batch receiver
+        let mut listener = UdpTunnelListener::new("udp://127.0.0.1:0".parse().unwrap());
+        listener.listen().await.unwrap();
+        let port = listener.local_url().port().unwrap();
+        let mut connector =
+            UdpTunnelConnector::new(format!("udp://127.0.0.1:{}", port).parse().unwrap());
+        let lis = tokio::spawn(async move {
+            let ret = listener.accept().await.unwrap();
+            _tunnel_echo_server(ret, false).await
+        });
+
+        let tunnel = connector.connect().await.unwrap();
+        let (mut recv, mut send) = tunnel.split();
+
+        // equal sized runs with a short tail exercise gso grouping
+        let payloads: Vec<Vec<u8>> = (0..64u8)
+            .map(|i| {
+                let len = if i % 10 == 9 { 100 } else { 1200 };
+                vec![i; len]
+            })
+            .collect();
+        for payload in payloads.iter() {
+            send.feed(ZCPacket::new_with_payload(payload))
+                .await
+                .unwrap();
+        }
+        send.flush().await.unwrap();
+
+        let mut received = vec![];
+        while received.len() < payloads.len() {
+            let packet = timeout(Duration::from_secs(5), recv.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            received.push(packet.payload().to_vec());
+        }
+        received.sort();
+        assert_eq!(received, payloads);
+
+        lis.abort();
+    }
+
     #[tokio::test]
     async fn udp_bench_with_bind() {
         let listener = UdpTunnelListener::new("udp://127.0.0.1:5554".parse().unwrap());
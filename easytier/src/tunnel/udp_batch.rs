@@ -0,0 +1,468 @@
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{io, net::SocketAddr};
+
+use bytes::{Bytes, BytesMut};
+use tokio::net::UdpSocket;
+
+use super::common::reserve_buf;
+
+// max datagrams handled by one recvmmsg / sendmmsg call
+pub const UDP_BATCH_SIZE: usize = 32;
+
+// the kernel refuses gso sends with more segments than this
+#[cfg(target_os = "linux")]
+const UDP_MAX_GSO_SEGMENTS: usize = 64;
+#[cfg(target_os = "linux")]
+const UDP_MAX_GSO_BYTES: usize = 64000;
+// a gro coalesced datagram never exceeds the max ip packet size
+#[cfg(target_os = "linux")]
+const UDP_GRO_BUF_SIZE: usize = 65536;
+#[cfg(target_os = "linux")]
+const UDP_GRO_BATCH_SIZE: usize = 8;
+
+// cleared once the kernel or the nic reports the feature is missing
+#[cfg(target_os = "linux")]
+static BATCH_IO_SUPPORTED: AtomicBool = AtomicBool::new(true);
+#[cfg(target_os = "linux")]
+static GSO_SUPPORTED: AtomicBool = AtomicBool::new(true);
+
+#[cfg(target_os = "linux")]
+fn batch_io_available(batch_io: bool) -> bool {
+    batch_io && BATCH_IO_SUPPORTED.load(Ordering::Relaxed)
+}
+
+#[cfg(target_os = "linux")]
+fn disable_batch_io(e: &io::Error) {
+    if BATCH_IO_SUPPORTED.swap(false, Ordering::Relaxed) {
+        tracing::warn!(
+            ?e,
+            "udp batch io not supported, fallback to single datagram io"
+        );
+    }
+}
+
+/// Receives datagrams in batches with recvmmsg, splitting gro coalesced
+/// datagrams back into the original ones. Falls back to one datagram per
+/// call on other platforms, when the kernel lacks support or when `batch_io`
+/// is off for the socket.
+pub struct UdpBatchReceiver {
+    max_datagram_size: usize,
+    out_buf: BytesMut,
+    #[cfg(target_os = "linux")]
+    batch_io: bool,
+    #[cfg(target_os = "linux")]
+    arena: Vec<u8>,
+    #[cfg(target_os = "linux")]
+    slot_size: usize,
+    #[cfg(target_os = "linux")]
+    gro: bool,
+}
+
+impl UdpBatchReceiver {
+    pub fn new(_socket: &UdpSocket, max_datagram_size: usize, _batch_io: bool) -> Self {
+        #[cfg(target_os = "linux")]
+        {
+            let gro = batch_io_available(_batch_io) && linux::enable_gro(_socket);
+            let (slot_size, batch) = if gro {
+                (UDP_GRO_BUF_SIZE, UDP_GRO_BATCH_SIZE)
+            } else {
+                (max_datagram_size, UDP_BATCH_SIZE)
+            };
+            Self {
+                max_datagram_size,
+                out_buf: BytesMut::new(),
+                batch_io: _batch_io,
+                arena: vec![0u8; slot_size * batch],
+                slot_size,
+                gro,
+            }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            Self {
+                max_datagram_size,
+                out_buf: BytesMut::new(),
+            }
+        }
+    }
+
+    /// Waits for at least one datagram and appends all received ones to `out`.
+    pub async fn recv(
+        &mut self,
+        socket: &UdpSocket,
+        out: &mut Vec<(BytesMut, SocketAddr)>,
+    ) -> io::Result<()> {
+        // a gro socket must keep using recvmmsg to split coalesced datagrams
+        #[cfg(target_os = "linux")]
+        if self.gro || batch_io_available(self.batch_io) {
+            use std::os::fd::AsRawFd;
+            let fd = socket.as_raw_fd();
+            let ret = socket
+                .async_io(tokio::io::Interest::READABLE, || self.recv_mmsg(fd, out))
+                .await;
+            match ret {
+                Ok(()) => return Ok(()),
+                Err(e) if e.raw_os_error() == Some(nix::libc::ENOSYS) => disable_batch_io(&e),
+                Err(e) => return Err(e),
+            }
+        }
+
+        reserve_buf(
+            &mut self.out_buf,
+            self.max_datagram_size,
+            self.max_datagram_size * 4,
+        );
+        let (_, addr) = socket.recv_buf_from(&mut self.out_buf).await?;
+        out.push((self.out_buf.split(), addr));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn recv_mmsg(
+        &mut self,
+        fd: std::os::fd::RawFd,
+        out: &mut Vec<(BytesMut, SocketAddr)>,
+    ) -> io::Result<()> {
+        let received = linux::recv_mmsg(fd, &mut self.arena, self.slot_size, self.gro)?;
+        for (i, (len, segment_size, addr)) in received.into_iter().enumerate() {
+            let data = &self.arena[i * self.slot_size..i * self.slot_size + len];
+            for segment in data.chunks(segment_size.max(1)) {
+                reserve_buf(&mut self.out_buf, segment.len(), UDP_GRO_BUF_SIZE);
+                self.out_buf.extend_from_slice(segment);
+                if let Some(addr) = addr {
+                    out.push((self.out_buf.split(), addr));
+                } else {
+                    self.out_buf.clear();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends all datagrams to `dst`, with sendmmsg and udp gso when available
+/// and `batch_io` is on for the socket.
+pub async fn send_batch(
+    socket: &UdpSocket,
+    dst: SocketAddr,
+    bufs: &[Bytes],
+    _batch_io: bool,
+) -> io::Result<()> {
+    #[allow(unused_mut)]
+    let mut sent = 0;
+
+    #[cfg(target_os = "linux")]
+    if bufs.len() > 1 && batch_io_available(_batch_io) {
+        use std::os::fd::AsRawFd;
+        let fd = socket.as_raw_fd();
+        while sent < bufs.len() {
+            let gso = GSO_SUPPORTED.load(Ordering::Relaxed);
+            let ret = socket
+                .async_io(tokio::io::Interest::WRITABLE, || {
+                    linux::send_mmsg(fd, &dst, &bufs[sent..], gso)
+                })
+                .await;
+            match ret {
+                Ok(n) => sent += n,
+                Err(e) if gso && linux::is_gso_error(&e) => {
+                    if GSO_SUPPORTED.swap(false, Ordering::Relaxed) {
+                        tracing::warn!(?e, "udp gso not supported, fallback to sendmmsg");
+                    }
+                }
+                Err(e) if e.raw_os_error() == Some(nix::libc::ENOSYS) => {
+                    disable_batch_io(&e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    for buf in &bufs[sent..] {
+        socket.send_to(buf, dst).await?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        io, mem,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+        os::fd::{AsRawFd, RawFd},
+    };
+
+    use bytes::Bytes;
+    use nix::libc;
+    use tokio::net::UdpSocket;
+
+    use super::{UDP_BATCH_SIZE, UDP_MAX_GSO_BYTES, UDP_MAX_GSO_SEGMENTS};
+
+    // enough for one UDP_GRO / UDP_SEGMENT control message, u64 keeps it aligned
+    type CmsgBuf = [u64; 4];
+
+    pub(super) fn enable_gro(socket: &UdpSocket) -> bool {
+        let on: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_GRO,
+                &on as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        ret == 0
+    }
+
+    pub(super) fn is_gso_error(e: &io::Error) -> bool {
+        // EIO comes from nics without tx checksum offload
+        matches!(e.raw_os_error(), Some(libc::EIO) | Some(libc::EINVAL))
+    }
+
+    fn to_std_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                    u16::from_be(addr.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+
+    fn to_sys_addr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        match addr {
+            SocketAddr::V4(addr) => {
+                let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr = libc::in_addr {
+                    s_addr: u32::from(*addr.ip()).to_be(),
+                };
+                (
+                    storage,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_addr = libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                };
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_scope_id = addr.scope_id();
+                (
+                    storage,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    }
+
+    fn gro_segment_size(hdr: &libc::msghdr) -> Option<usize> {
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                    let size =
+                        std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                    return Some(size as usize);
+                }
+                cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+            }
+        }
+        None
+    }
+
+    /// Returns (len, segment size, source) of each datagram, datagram i is
+    /// stored at `arena[i * slot_size..]`.
+    pub(super) fn recv_mmsg(
+        fd: RawFd,
+        arena: &mut [u8],
+        slot_size: usize,
+        gro: bool,
+    ) -> io::Result<Vec<(usize, usize, Option<SocketAddr>)>> {
+        let mut addrs: [libc::sockaddr_storage; UDP_BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovs: [libc::iovec; UDP_BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut cmsgs: [CmsgBuf; UDP_BATCH_SIZE] = [[0; 4]; UDP_BATCH_SIZE];
+        let mut hdrs: [libc::mmsghdr; UDP_BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        let mut batch = 0;
+        for (i, slot) in arena
+            .chunks_exact_mut(slot_size)
+            .take(UDP_BATCH_SIZE)
+            .enumerate()
+        {
+            iovs[i].iov_base = slot.as_mut_ptr() as *mut libc::c_void;
+            iovs[i].iov_len = slot.len();
+            let hdr = &mut hdrs[i].msg_hdr;
+            hdr.msg_name = &mut addrs[i] as *mut _ as *mut libc::c_void;
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = &mut iovs[i];
+            hdr.msg_iovlen = 1;
+            if gro {
+                hdr.msg_control = cmsgs[i].as_mut_ptr() as *mut libc::c_void;
+                hdr.msg_controllen = mem::size_of::<CmsgBuf>() as _;
+            }
+            batch += 1;
+        }
+
+        let n = unsafe {
+            libc::recvmmsg(
+                fd,
+                hdrs.as_mut_ptr(),
+                batch as _,
+                libc::MSG_DONTWAIT as _,
+                std::ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(hdrs[..n as usize]
+            .iter()
+            .zip(addrs.iter())
+            .map(|(hdr, addr)| {
+                let len = hdr.msg_len as usize;
+                // truncated datagrams are useless, report them without source
+                if hdr.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                    return (0, 0, None);
+                }
+                let segment_size = if gro {
+                    gro_segment_size(&hdr.msg_hdr).unwrap_or(len)
+                } else {
+                    len
+                };
+                (len, segment_size, to_std_addr(addr))
+            })
+            .collect())
+    }
+
+    /// Sends a prefix of `bufs` and returns how many datagrams were sent.
+    /// With gso, runs of equal sized datagrams go out as one message.
+    pub(super) fn send_mmsg(
+        fd: RawFd,
+        dst: &SocketAddr,
+        bufs: &[Bytes],
+        gso: bool,
+    ) -> io::Result<usize> {
+        let (mut addr, addr_len) = to_sys_addr(dst);
+        let bufs = &bufs[..bufs.len().min(UDP_BATCH_SIZE)];
+        let mut iovs: [libc::iovec; UDP_BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut cmsgs: [CmsgBuf; UDP_BATCH_SIZE] = [[0; 4]; UDP_BATCH_SIZE];
+        let mut hdrs: [libc::mmsghdr; UDP_BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut group_sizes = [0usize; UDP_BATCH_SIZE];
+
+        for (iov, buf) in iovs.iter_mut().zip(bufs.iter()) {
+            iov.iov_base = buf.as_ptr() as *mut libc::c_void;
+            iov.iov_len = buf.len();
+        }
+
+        let mut msgs = 0;
+        let mut i = 0;
+        while i < bufs.len() {
+            let segment_size = bufs[i].len();
+            let mut total = segment_size;
+            let mut j = i + 1;
+            // only the last datagram of a gso run may be shorter
+            while gso
+                && j < bufs.len()
+                && j - i < UDP_MAX_GSO_SEGMENTS
+                && bufs[j - 1].len() == segment_size
+                && bufs[j].len() <= segment_size
+                && total + bufs[j].len() <= UDP_MAX_GSO_BYTES
+            {
+                total += bufs[j].len();
+                j += 1;
+            }
+
+            let hdr = &mut hdrs[msgs].msg_hdr;
+            hdr.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+            hdr.msg_namelen = addr_len;
+            hdr.msg_iov = iovs[i..].as_mut_ptr();
+            hdr.msg_iovlen = (j - i) as _;
+            if j - i > 1 {
+                unsafe {
+                    hdr.msg_control = cmsgs[msgs].as_mut_ptr() as *mut libc::c_void;
+                    hdr.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as _) as _;
+                    let cmsg = libc::CMSG_FIRSTHDR(hdr);
+                    (*cmsg).cmsg_level = libc::SOL_UDP;
+                    (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as _) as _;
+                    std::ptr::write_unaligned(
+                        libc::CMSG_DATA(cmsg) as *mut u16,
+                        segment_size as u16,
+                    );
+                }
+            }
+            group_sizes[msgs] = j - i;
+            msgs += 1;
+            i = j;
+        }
+
+        let n =
+            unsafe { libc::sendmmsg(fd, hdrs.as_mut_ptr(), msgs as _, libc::MSG_DONTWAIT as _) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(group_sizes[..n as usize].iter().sum())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn batch_send_recv() {
+        let recv_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let send_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dst = recv_socket.local_addr().unwrap();
+        let src = send_socket.local_addr().unwrap();
+        let mut receiver = UdpBatchReceiver::new(&recv_socket, 2000, true);
+
+        // equal sized runs with a short tail exercise gso grouping
+        let bufs: Vec<Bytes> = (0..40u8)
+            .map(|i| {
+                let len = if i % 10 == 9 { 100 } else { 1200 };
+                Bytes::from(vec![i; len])
+            })
+            .collect();
+        send_batch(&send_socket, dst, &bufs, true).await.unwrap();
+
+        let mut received = Vec::new();
+        while received.len() < bufs.len() {
+            tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                receiver.recv(&recv_socket, &mut received),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        }
+
+        assert_eq!(received.len(), bufs.len());
+        for ((buf, addr), expected) in received.iter().zip(bufs.iter()) {
+            assert_eq!(*addr, src);
+            assert_eq!(buf.as_ref(), expected.as_ref());
+        }
+    }
+}
@@ -18,6 +18,7 @@ use super::{
     common::{setup_sokcet2, wait_for_connect_futures, TunnelWrapper},
     insecure_tls::{get_insecure_tls_cert, init_crypto_provider},
     packet_def::{ZCPacket, ZCPacketType},
+    upstream_proxy::{connect_via_upstream_proxy, UpstreamProxy},
     FromUrl, IpVersion, Tunnel, TunnelConnector, TunnelError, TunnelListener,
 };
 
@@ -164,6 +165,7 @@ pub struct WSTunnelConnector {
     ip_version: IpVersion,
 
     bind_addrs: Vec<SocketAddr>,
+    upstream_proxy: Option<UpstreamProxy>,
 }
 
 impl WSTunnelConnector {
@@ -173,18 +175,29 @@ impl WSTunnelConnector {
             ip_version: IpVersion::Both,
 
             bind_addrs: vec![],
+            upstream_proxy: None,
         }
     }
 
+    pub fn set_upstream_proxy(&mut self, proxy: Option<url::Url>) {
+        self.upstream_proxy = proxy.map(UpstreamProxy::new);
+    }
+
     async fn connect_with(
         addr: url::Url,
         ip_version: IpVersion,
         tcp_socket: TcpSocket,
     ) -> Result<Box<dyn Tunnel>, TunnelError> {
-        let is_wss = is_wss(&addr)?;
         let socket_addr = SocketAddr::from_url(addr.clone(), ip_version).await?;
         let stream = tcp_socket.connect(socket_addr).await?;
+        Self::connect_on_stream(addr, stream).await
+    }
 
+    async fn connect_on_stream(
+        addr: url::Url,
+        stream: TcpStream,
+    ) -> Result<Box<dyn Tunnel>, TunnelError> {
+        let is_wss = is_wss(&addr)?;
         let info = TunnelInfo {
             tunnel_type: addr.scheme().to_owned(),
             local_addr: Some(
@@ -269,6 +282,12 @@ impl WSTunnelConnector {
 #[async_trait::async_trait]
 impl TunnelConnector for WSTunnelConnector {
     async fn connect(&mut self) -> Result<Box<dyn Tunnel>, super::TunnelError> {
+        if let Some(proxy) = &self.upstream_proxy {
+            is_wss(&self.addr)?;
+            let stream = connect_via_upstream_proxy(proxy.url(), &self.addr).await?;
+            return Self::connect_on_stream(self.addr.clone(), stream).await;
+        }
+
         let addr = SocketAddr::from_url(self.addr.clone(), self.ip_version).await?;
         if self.bind_addrs.is_empty() || addr.is_ipv6() {
             self.connect_with_default_bind(addr).await